@@ -71,6 +71,17 @@ class ApiService {
     return response.data
   }
 
+  async downloadArchive(hostId: string, paths: string[], format: 'zip' | 'tar.gz' = 'zip') {
+    const response = await this.api.post('/files/archive', {
+      host_id: hostId,
+      paths,
+      format
+    }, {
+      responseType: 'blob'
+    })
+    return response.data
+  }
+
   async uploadFile(hostId: string, path: string, file: File) {
     const formData = new FormData()
     formData.append('host_id', hostId)
@@ -2,6 +2,8 @@ class WebSocketService {
   private ws: WebSocket | null = null
   private reconnectTimeout: number = 5000
   private reconnectTimer: any = null
+  // Directory whose changes are pushed to us, re-requested after reconnecting
+  private watched: { host_id: string; path: string } | null = null
 
   connect() {
     const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:'
@@ -11,12 +13,17 @@ class WebSocketService {
 
     this.ws.onopen = () => {
       console.log('WebSocket connected')
+      // The first message authenticates the session
+      this.ws?.send(JSON.stringify({ event: 'auth', token: localStorage.getItem('token') ?? '' }))
     }
 
     this.ws.onmessage = (event) => {
       try {
         const message = JSON.parse(event.data)
         console.log('WebSocket message:', message)
+        if (message.event === 'authenticated' && this.watched) {
+          this.send({ event: 'watch', ...this.watched })
+        }
         // Emit custom event for components to listen
         window.dispatchEvent(new CustomEvent('ws-message', { detail: message }))
       } catch (e) {
@@ -54,6 +61,17 @@ class WebSocketService {
     }, this.reconnectTimeout)
   }
 
+  watch(hostId: string, path: string) {
+    if (this.watched?.host_id === hostId && this.watched.path === path) return
+    if (this.watched) {
+      this.send({ event: 'unwatch', ...this.watched })
+    }
+    this.watched = { host_id: hostId, path }
+    if (this.ws && this.ws.readyState === WebSocket.OPEN) {
+      this.send({ event: 'watch', ...this.watched })
+    }
+  }
+
   send(message: any) {
     if (this.ws && this.ws.readyState === WebSocket.OPEN) {
       this.ws.send(JSON.stringify(message))
@@ -1,15 +1,28 @@
 use crate::auth::{verify_jwt, Encryptor};
 use crate::db::Database;
-use crate::hosts;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::range::{self, RangeRequest};
+use crate::hosts::search::{self, SearchCriteria};
+use crate::hosts::archive;
+use crate::hosts::transfer::{self, TransferProgress};
+use crate::hosts::{self, StorageBackend, StorageError};
 use crate::metrics::Metrics;
-use crate::models::BrowseRequest;
+use crate::trash::TrashManager;
+use crate::models::{
+    ArchiveFormat, BrowseRequest, BrowseResponse, Host, OverwritePolicy, ProgressSnapshot, SearchEvent, SearchOptions,
+};
 use actix_multipart::Multipart;
-use actix_web::{web, HttpResponse};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use bytes::Bytes;
 use futures::StreamExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
 #[derive(Debug, Deserialize)]
 pub struct FileActionRequest {
@@ -18,13 +31,71 @@ pub struct FileActionRequest {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct UploadRequest {
-    pub host_id: String,
+pub struct DeleteRequest {
+    pub host_id: serde_json::Value,
     pub path: String,
+    /// Delete for good instead of moving into the trash.
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveRequest {
+    pub host_id: serde_json::Value,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyRequest {
+    pub host_id: serde_json::Value,
+    pub from: String,
+    /// Host to copy to; the source host when absent.
+    #[serde(default)]
+    pub target_host_id: Option<serde_json::Value>,
+    pub to: String,
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+/// One line of the newline-delimited JSON a copy responds with.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum CopyEvent {
+    Progress(ProgressSnapshot),
+    Done {
+        path: String,
+        #[serde(flatten)]
+        progress: ProgressSnapshot,
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    pub host_id: serde_json::Value,
+    #[serde(flatten)]
+    pub options: SearchOptions,
+}
+
+/// How often a running copy reports its progress.
+const COPY_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Deserialize)]
+pub struct RenameRequest {
+    pub host_id: serde_json::Value,
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
 }
 
 pub async fn browse_files(
     db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
     auth: BearerAuth,
     req: web::Json<BrowseRequest>,
 ) -> HttpResponse {
@@ -37,78 +108,57 @@ pub async fn browse_files(
         }
     };
 
-    // Extract host_id string from Value
-    let host_id_str = match &req.host_id {
-        serde_json::Value::String(s) => s.clone(),
-        serde_json::Value::Object(obj) => {
-            if let Some(serde_json::Value::Object(id_obj)) = obj.get("id") {
-                if let Some(serde_json::Value::String(s)) = id_obj.get("String") {
-                    s.clone()
-                } else {
-                    return HttpResponse::BadRequest().json(json!({
-                        "error": "Invalid host_id format"
-                    }));
-                }
-            } else {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Invalid host_id format"
-                }));
-            }
-        }
-        _ => {
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
             return HttpResponse::BadRequest().json(json!({
                 "error": "Invalid host_id format"
             }));
         }
     };
 
-    // Get host
-    let host = match db.get_host(&host_id_str).await {
-        Ok(Some(host)) => host,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(json!({
-                "error": "Host not found"
-            }));
-        }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to get host: {}", e)
-            }));
-        }
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
     };
 
-    // Verify ownership
-    if host.user_id != claims.sub {
-        return HttpResponse::Forbidden().json(json!({
-            "error": "Access denied"
-        }));
-    }
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
 
-    // Browse files
-    let encryptor = match Encryptor::new() {
-        Ok(enc) => enc,
+    // Paths like `/builds/app.zip!/lib` lead into archives
+    let listing = match archive::ArchivePath::parse(&req.path) {
+        Ok(Some(inside)) => archive::list(backend.as_ref(), &inside)
+            .await
+            .map(|files| (files, archive::CAPABILITIES)),
+        Ok(None) => backend.list(&req.path).await.map(|mut files| {
+            files.retain(|f| f.path != hosts::TRASH_DIR);
+            (files, backend.capabilities())
+        }),
         Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to initialize encryptor: {}", e)
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
             }));
         }
     };
 
-    match hosts::browse_host(&host, &req.path, &encryptor).await {
-        Ok(files) => HttpResponse::Ok().json(json!({
-            "path": req.path,
-            "files": files
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(json!({
-            "error": format!("Failed to browse files: {}", e)
-        })),
+    match listing {
+        Ok((files, capabilities)) => HttpResponse::Ok().json(BrowseResponse {
+            path: req.path.clone(),
+            files,
+            capabilities,
+        }),
+        Err(e) => storage_error("browse files", e),
     }
 }
 
 pub async fn download_file(
     db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
     metrics: web::Data<Arc<Metrics>>,
     auth: BearerAuth,
+    http_req: HttpRequest,
     req: web::Json<FileActionRequest>,
 ) -> HttpResponse {
     let claims = match verify_jwt(auth.token()) {
@@ -120,80 +170,252 @@ pub async fn download_file(
         }
     };
 
-    // Extract host_id string from Value
-    let host_id_str = match &req.host_id {
-        serde_json::Value::String(s) => s.clone(),
-        serde_json::Value::Object(obj) => {
-            if let Some(serde_json::Value::Object(id_obj)) = obj.get("id") {
-                if let Some(serde_json::Value::String(s)) = id_obj.get("String") {
-                    s.clone()
-                } else {
-                    return HttpResponse::BadRequest().json(json!({
-                        "error": "Invalid host_id format"
-                    }));
-                }
-            } else {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Invalid host_id format"
-                }));
-            }
-        }
-        _ => {
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
             return HttpResponse::BadRequest().json(json!({
                 "error": "Invalid host_id format"
             }));
         }
     };
 
-    // Get host
-    let host = match db.get_host(&host_id_str).await {
-        Ok(Some(host)) => host,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(json!({
-                "error": "Host not found"
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    let header_value = |name| http_req.headers().get(name).and_then(|v| v.to_str().ok());
+    let range = header_value(header::RANGE)
+        .and_then(|r| RangeRequest::parse(r, header_value(header::IF_RANGE)));
+
+    // Members of archives are streamed whole
+    let (file, capabilities) = match archive::ArchivePath::parse(&req.path) {
+        Ok(Some(inside)) => (archive::open(backend.as_ref(), &inside).await, archive::CAPABILITIES),
+        Ok(None) => (backend.read_stream(&req.path, range).await, backend.capabilities()),
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
             }));
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to get host: {}", e)
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => return storage_error("read file", e),
+    };
+    metrics.file_downloads.inc();
+
+    let mime_type = mime_guess::from_path(&req.path).first_or_octet_stream();
+    let (mut response, length) = match file.range {
+        Some((start, end)) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file.size)));
+            (response, end - start + 1)
+        }
+        None => (HttpResponse::Ok(), file.size),
+    };
+
+    let accept_ranges = if capabilities.ranges { "bytes" } else { "none" };
+    response
+        .content_type(mime_type.as_ref())
+        .insert_header((header::ACCEPT_RANGES, accept_ranges));
+    if let Some(etag) = range::entity_tag(file.size, file.modified) {
+        response.insert_header((header::ETAG, etag));
+    }
+    if let Some(modified) = file.modified {
+        response.insert_header((header::LAST_MODIFIED, range::http_date(modified)));
+    }
+
+    response.no_chunking(length).streaming(file.stream)
+}
+
+/// Streams directories and files of a host as one ZIP or tar.gz archive,
+/// built while it is sent. Each path appears under its own name, so
+/// `/share/photos` becomes `photos/` in the archive.
+pub async fn download_archive(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    metrics: web::Data<Arc<Metrics>>,
+    auth: BearerAuth,
+    req: web::Json<ArchiveRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
             }));
         }
     };
 
-    // Verify ownership
-    if host.user_id != claims.sub {
-        return HttpResponse::Forbidden().json(json!({
-            "error": "Access denied"
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
+            }));
+        }
+    };
+
+    if req.paths.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "No paths to archive"
         }));
     }
-
-    let encryptor = match Encryptor::new() {
-        Ok(enc) => enc,
+    let paths = match req.paths.iter().map(|p| hosts::normalize_path(p)).collect::<anyhow::Result<Vec<_>>>() {
+        Ok(paths) => paths,
         Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to initialize encryptor: {}", e)
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
             }));
         }
     };
 
-    match hosts::read_file(&host, &req.path, &encryptor).await {
-        Ok(content) => {
-            metrics.file_downloads.inc();
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let backend: Arc<dyn StorageBackend> = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend.into(),
+        Err(resp) => return resp,
+    };
+    if !backend.capabilities().read {
+        return storage_error("archive", backend.unsupported("Read"));
+    }
+
+    // Walked up front, so a missing path is still a proper error response
+    let entries = match archive::collect_entries(backend.as_ref(), &paths).await {
+        Ok(entries) => entries,
+        Err(e) => return storage_error("archive", e),
+    };
+    metrics.file_downloads.inc();
+
+    let name = match paths.as_slice() {
+        [path] if path != "/" => path.rsplit('/').next().unwrap_or("archive"),
+        _ => "archive",
+    };
+    let filename = format!("{}.{}", name, req.format.extension());
+
+    HttpResponse::Ok()
+        .content_type(req.format.content_type())
+        .insert_header(header::ContentDisposition::attachment(filename))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(archive::archive_stream(backend, entries, req.format, Arc::default()))
+}
+
+/// Limits applied to uploads.
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Uploads larger than this many bytes are rejected with 413.
+    pub max_size: u64,
+    /// Where resumable uploads are kept until they are complete.
+    pub staging_dir: PathBuf,
+    /// How long after its creation an unfinished resumable upload is
+    /// discarded; never when `None`.
+    pub expiry: Option<chrono::Duration>,
+    /// Unfinished resumable uploads a user may have at once.
+    pub max_open_per_user: u64,
+}
+
+impl UploadConfig {
+    /// Reads `MAX_UPLOAD_SIZE` (bytes, default 10 GiB), `UPLOAD_STAGING_DIR`
+    /// (default `uploads` in the working directory), `UPLOAD_EXPIRY_HOURS`
+    /// (default 24, 0 keeps uploads until they are finished or discarded)
+    /// and `MAX_OPEN_UPLOADS` (per user, default 16).
+    pub fn from_env() -> Self {
+        let max_size = std::env::var("MAX_UPLOAD_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10 * 1024 * 1024 * 1024);
+        let staging_dir = std::env::var("UPLOAD_STAGING_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("uploads"));
+        let expiry_hours = std::env::var("UPLOAD_EXPIRY_HOURS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .filter(|&v| v >= 0)
+            .unwrap_or(24);
+        let max_open_per_user = std::env::var("MAX_OPEN_UPLOADS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16);
 
-            let mime_type = mime_guess::from_path(&req.path).first_or_octet_stream();
-            HttpResponse::Ok()
-                .content_type(mime_type.as_ref())
-                .body(content)
+        Self {
+            max_size,
+            staging_dir,
+            expiry: (expiry_hours > 0).then(|| chrono::Duration::hours(expiry_hours)),
+            max_open_per_user,
         }
-        Err(e) => HttpResponse::InternalServerError().json(json!({
-            "error": format!("Failed to read file: {}", e)
-        })),
     }
 }
 
+/// Chunks buffered between the request body and the backend.
+const UPLOAD_BUFFER_CHUNKS: usize = 16;
+
+/// Why the request body stopped before the upload could complete.
+enum UploadAbort {
+    TooLarge,
+    Read(String),
+}
+
+/// Forwards the chunks of `field` to `tx`, cutting the upload off once it
+/// exceeds `max_size`. Errors are forwarded too, so the backend discards what
+/// it has written so far.
+async fn forward_field(
+    field: &mut actix_multipart::Field,
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+    max_size: u64,
+) -> Result<(), UploadAbort> {
+    let mut received = 0u64;
+
+    while let Some(chunk) = field.next().await {
+        let abort = match chunk {
+            Ok(data) => {
+                received += data.len() as u64;
+                if received <= max_size {
+                    // The backend gave up; its error is reported instead
+                    if tx.send(Ok(data)).await.is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                UploadAbort::TooLarge
+            }
+            Err(e) => UploadAbort::Read(e.to_string()),
+        };
+
+        let _ = tx.send(Err(std::io::Error::other("upload aborted"))).await;
+        return Err(abort);
+    }
+
+    Ok(())
+}
+
+async fn read_text_field(field: &mut actix_multipart::Field) -> Result<String, HttpResponse> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk.map_err(|e| {
+            HttpResponse::BadRequest().json(json!({
+                "error": format!("Failed to read field: {}", e)
+            }))
+        })?;
+        bytes.extend_from_slice(&data);
+    }
+    Ok(String::from_utf8(bytes).unwrap_or_default())
+}
+
+/// Expects the `host_id` and `path` fields before `file`, whose content is
+/// streamed to the host as it arrives instead of being buffered.
 pub async fn upload_file(
     db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
     metrics: web::Data<Arc<Metrics>>,
+    upload_config: web::Data<UploadConfig>,
     auth: BearerAuth,
     mut payload: Multipart,
 ) -> HttpResponse {
@@ -208,7 +430,6 @@ pub async fn upload_file(
 
     let mut host_id: Option<String> = None;
     let mut path: Option<String> = None;
-    let mut file_data: Vec<u8> = Vec::new();
 
     // Parse multipart form data
     while let Some(item) = payload.next().await {
@@ -222,108 +443,297 @@ pub async fn upload_file(
         };
 
         let content_disposition = field.content_disposition();
-        let field_name = content_disposition.get_name().unwrap_or("");
-
-        match field_name {
-            "host_id" => {
-                let mut bytes = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    bytes.extend_from_slice(&data);
-                }
-                host_id = Some(String::from_utf8(bytes).unwrap_or_default());
-            }
-            "path" => {
-                let mut bytes = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    bytes.extend_from_slice(&data);
-                }
-                path = Some(String::from_utf8(bytes).unwrap_or_default());
-            }
+        let field_name = content_disposition.get_name().unwrap_or("").to_string();
+
+        match field_name.as_str() {
+            "host_id" => match read_text_field(&mut field).await {
+                Ok(value) => host_id = Some(value),
+                Err(resp) => return resp,
+            },
+            "path" => match read_text_field(&mut field).await {
+                Ok(value) => path = Some(value),
+                Err(resp) => return resp,
+            },
             "file" => {
-                while let Some(chunk) = field.next().await {
-                    let data = match chunk {
-                        Ok(data) => data,
-                        Err(e) => {
-                            return HttpResponse::BadRequest().json(json!({
-                                "error": format!("Failed to read chunk: {}", e)
-                            }));
-                        }
-                    };
-                    file_data.extend_from_slice(&data);
+                let Some(host_id) = &host_id else {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": "Missing host_id"
+                    }));
+                };
+                let Some(path) = &path else {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": "Missing path"
+                    }));
+                };
+                let path = match hosts::normalize_path(path) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        return HttpResponse::BadRequest().json(json!({
+                            "error": e.to_string()
+                        }));
+                    }
+                };
+                if let Err(message) = check_outside_trash(&path) {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": message
+                    }));
                 }
+
+                let mut host = match load_owned_host(&db, &claims.sub, host_id).await {
+                    Ok(host) => host,
+                    Err(resp) => return resp,
+                };
+
+                let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+                    Ok(backend) => backend,
+                    Err(resp) => return resp,
+                };
+
+                if !backend.capabilities().write {
+                    return storage_error("upload file", backend.unsupported("Write"));
+                }
+
+                let (tx, rx) = mpsc::channel(UPLOAD_BUFFER_CHUNKS);
+                let (forwarded, written) = futures::join!(
+                    forward_field(&mut field, tx, upload_config.max_size),
+                    backend.write_stream(&path, hosts::bridge::channel_stream(rx)),
+                );
+
+                return match (forwarded, written) {
+                    (Err(UploadAbort::TooLarge), _) => HttpResponse::PayloadTooLarge().json(json!({
+                        "error": format!("Upload exceeds the maximum size of {} bytes", upload_config.max_size)
+                    })),
+                    (Err(UploadAbort::Read(e)), _) => HttpResponse::BadRequest().json(json!({
+                        "error": format!("Failed to read chunk: {}", e)
+                    })),
+                    (Ok(()), Ok(size)) => {
+                        metrics.file_uploads.inc();
+                        HttpResponse::Ok().json(json!({
+                            "message": "File uploaded successfully",
+                            "size": size
+                        }))
+                    }
+                    (Ok(()), Err(e)) => storage_error("upload file", e),
+                };
             }
             _ => {}
         }
     }
 
-    let host_id = match host_id {
+    let message = match (host_id, path) {
+        (None, _) => "Missing host_id",
+        (_, None) => "Missing path",
+        _ => "Missing file",
+    };
+    HttpResponse::BadRequest().json(json!({
+        "error": message
+    }))
+}
+
+pub async fn delete_file(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    req: web::Json<DeleteRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host_id_str = match parse_host_id(&req.host_id) {
         Some(id) => id,
         None => {
             return HttpResponse::BadRequest().json(json!({
-                "error": "Missing host_id"
+                "error": "Invalid host_id format"
             }));
         }
     };
 
-    let path = match path {
-        Some(p) => p,
-        None => {
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    let path = match hosts::normalize_path(&req.path) {
+        Ok(path) => path,
+        Err(e) => {
             return HttpResponse::BadRequest().json(json!({
-                "error": "Missing path"
+                "error": e.to_string()
             }));
         }
     };
+    if let Err(message) = check_delete_path(&path) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
+        }));
+    }
 
-    // Get host
-    let host = match db.get_host(&host_id).await {
-        Ok(Some(host)) => host,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(json!({
-                "error": "Host not found"
+    // Hosts that can't move entries have no trash to move them into
+    if req.permanent || !backend.capabilities().rename {
+        return match backend.delete(&path).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "message": "File deleted successfully"
+            })),
+            Err(e) => storage_error("delete file", e),
+        };
+    }
+
+    match trash.trash(&claims.sub, &host.id, backend.as_ref(), &path).await {
+        Ok(item) => HttpResponse::Ok().json(json!({
+            "message": "Moved to trash",
+            "trash_item": item
+        })),
+        Err(e) => storage_error("delete file", e),
+    }
+}
+
+pub async fn create_directory(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    auth: BearerAuth,
+    req: web::Json<FileActionRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
             }));
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to get host: {}", e)
+    };
+
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
             }));
         }
     };
 
-    // Verify ownership
-    if host.user_id != claims.sub {
-        return HttpResponse::Forbidden().json(json!({
-            "error": "Access denied"
+    let path = match hosts::normalize_path(&req.path) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    if let Err(message) = check_outside_trash(&path) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
         }));
     }
 
-    let encryptor = match Encryptor::new() {
-        Ok(enc) => enc,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to initialize encryptor: {}", e)
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    match backend.mkdir(&path).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "message": "Directory created successfully"
+        })),
+        Err(e) => storage_error("create directory", e),
+    }
+}
+
+/// Renames or moves a file or directory within one host.
+pub async fn rename_file(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    req: web::Json<RenameRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
             }));
         }
     };
 
-    match hosts::write_file(&host, &path, &file_data, &encryptor).await {
-        Ok(_) => {
-            metrics.file_uploads.inc();
-            HttpResponse::Ok().json(json!({
-                "message": "File uploaded successfully"
-            }))
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
+            }));
+        }
+    };
+
+    let (from, to) = match (hosts::normalize_path(&req.from), hosts::normalize_path(&req.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
         }
-        Err(e) => HttpResponse::InternalServerError().json(json!({
-            "error": format!("Failed to upload file: {}", e)
+    };
+    if let Err(message) = check_transfer_paths(&from, &to, req.overwrite) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
+        }));
+    }
+
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    if !backend.capabilities().rename {
+        return storage_error("rename", backend.unsupported("Rename"));
+    }
+
+    let (backend, from) = (backend.as_ref(), from.as_str());
+    let displace = |to: String| async move { trash.displace(&claims.sub, &host.id, backend, &to).await };
+    let renamed = hosts::apply_overwrite_policy(&to, req.overwrite, displace, |to| async move {
+        backend.rename(from, &to).await
+    })
+    .await;
+
+    match renamed {
+        Ok(path) => HttpResponse::Ok().json(json!({
+            "message": "Renamed successfully",
+            "path": path
         })),
+        Err(e) => storage_error("rename", e),
     }
 }
 
-pub async fn delete_file(
+/// Copies a file or directory tree within a host or to another host. The
+/// response streams newline-delimited JSON: `progress` events while the copy
+/// runs, then one `done` or `error` event. The copy stops if the client
+/// disconnects.
+pub async fn copy_file(
     db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
     auth: BearerAuth,
-    req: web::Json<FileActionRequest>,
+    req: web::Json<CopyRequest>,
 ) -> HttpResponse {
     let claims = match verify_jwt(auth.token()) {
         Ok(claims) => claims,
@@ -334,87 +744,134 @@ pub async fn delete_file(
         }
     };
 
-    // Extract host_id string from Value
-    let host_id_str = match &req.host_id {
-        serde_json::Value::String(s) => s.clone(),
-        serde_json::Value::Object(obj) => {
-            if let Some(serde_json::Value::Object(id_obj)) = obj.get("id") {
-                if let Some(serde_json::Value::String(s)) = id_obj.get("String") {
-                    s.clone()
-                } else {
-                    return HttpResponse::BadRequest().json(json!({
-                        "error": "Invalid host_id format"
-                    }));
-                }
-            } else {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Invalid host_id format"
-                }));
-            }
-        }
+    let host_ids = (
+        parse_host_id(&req.host_id),
+        req.target_host_id.as_ref().map_or_else(|| parse_host_id(&req.host_id), parse_host_id),
+    );
+    let (source_id, target_id) = match host_ids {
+        (Some(source_id), Some(target_id)) => (source_id, target_id),
         _ => {
             return HttpResponse::BadRequest().json(json!({
                 "error": "Invalid host_id format"
             }));
         }
     };
+    let same_host = source_id == target_id;
 
-    let host = match db.get_host(&host_id_str).await {
-        Ok(Some(host)) => host,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(json!({
-                "error": "Host not found"
+    let (from, to) = match (hosts::normalize_path(&req.from), hosts::normalize_path(&req.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
             }));
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to get host: {}", e)
-            }));
+    };
+    let checked = if same_host {
+        check_transfer_paths(&from, &to, req.overwrite)
+    } else {
+        check_outside_trash(&from).and(check_outside_trash(&to))
+    };
+    if let Err(message) = checked {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
+        }));
+    }
+
+    let mut source_host = match load_owned_host(&db, &claims.sub, &source_id).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let source: Arc<dyn StorageBackend> = match open_backend(&db, &sftp_pool, &mut source_host).await {
+        Ok(backend) => backend.into(),
+        Err(resp) => return resp,
+    };
+    let target: Arc<dyn StorageBackend> = if same_host {
+        source.clone()
+    } else {
+        let mut target_host = match load_owned_host(&db, &claims.sub, &target_id).await {
+            Ok(host) => host,
+            Err(resp) => return resp,
+        };
+        match open_backend(&db, &sftp_pool, &mut target_host).await {
+            Ok(backend) => backend.into(),
+            Err(resp) => return resp,
         }
     };
 
-    if host.user_id != claims.sub {
-        return HttpResponse::Forbidden().json(json!({
-            "error": "Access denied"
-        }));
+    if !source.capabilities().read {
+        return storage_error("copy", source.unsupported("Read"));
+    }
+    if !target.capabilities().write {
+        return storage_error("copy", target.unsupported("Write"));
     }
 
-    // Only support local filesystem for delete
-    match &host.host_type {
-        crate::models::HostType::Local => {
-            let base_path = host
-                .config
-                .path
-                .as_ref()
-                .ok_or_else(|| "Local path not configured");
+    // Settle what can be settled before the response is committed to a 200
+    if let Err(e) = source.stat(&from).await {
+        return storage_error("copy", e);
+    }
+    if req.overwrite == OverwritePolicy::Fail && target.stat(&to).await.is_ok() {
+        return storage_error("copy", StorageError::AlreadyExists(to).into());
+    }
 
-            if let Err(e) = base_path {
-                return HttpResponse::InternalServerError().json(json!({
-                    "error": e
-                }));
-            }
+    let progress = Arc::new(TransferProgress::default());
+    let overwrite = req.overwrite;
+    let trash = trash.get_ref().clone();
+    let copy = {
+        let progress = progress.clone();
+        async move {
+            let (source, target, from, progress) = (source.as_ref(), target.as_ref(), from.as_str(), &progress);
+            let displace = |to: String| async move { trash.displace(&claims.sub, &target_id, target, &to).await };
+            hosts::apply_overwrite_policy(&to, overwrite, displace, |to| async move {
+                transfer::ensure_vacant(target, &to).await?;
+                let staging = transfer::staging_name(&to);
+                let copied = if same_host {
+                    transfer::copy_within(source, from, &staging, progress).await
+                } else {
+                    transfer::copy_between(source, from, target, &staging, progress).await
+                };
+                transfer::commit_staged(target, &staging, &to, copied).await
+            })
+            .await
+        }
+    };
 
-            match crate::hosts::local::LocalFileSystem::delete_file(base_path.unwrap(), &req.path)
-                .await
-            {
-                Ok(_) => HttpResponse::Ok().json(json!({
-                    "message": "File deleted successfully"
-                })),
-                Err(e) => HttpResponse::InternalServerError().json(json!({
-                    "error": format!("Failed to delete file: {}", e)
-                })),
-            }
+    let ticker = tokio::time::interval(COPY_PROGRESS_INTERVAL);
+    let events = futures::stream::unfold(Some((Box::pin(copy), ticker)), move |state| {
+        let progress = progress.clone();
+        async move {
+            let (mut copy, mut ticker) = state?;
+            let (event, next) = tokio::select! {
+                result = &mut copy => {
+                    let event = match result {
+                        Ok(path) => CopyEvent::Done { path, progress: progress.snapshot() },
+                        Err(e) => CopyEvent::Error { error: e.to_string() },
+                    };
+                    (event, None)
+                }
+                _ = ticker.tick() => (CopyEvent::Progress(progress.snapshot()), Some((copy, ticker))),
+            };
+
+            let mut line = serde_json::to_vec(&event).unwrap_or_default();
+            line.push(b'\n');
+            Some((Ok::<_, actix_web::Error>(Bytes::from(line)), next))
         }
-        _ => HttpResponse::BadRequest().json(json!({
-            "error": "Delete operation only supported for local filesystem"
-        })),
-    }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(events)
 }
 
-pub async fn create_directory(
+/// Searches a host below `path` by name, size, age and content. The
+/// response streams newline-delimited JSON: a `search_match` event per match
+/// as it is found, then one `search_done` or `search_error` event. The search
+/// stops if the client disconnects.
+pub async fn search_files(
     db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
     auth: BearerAuth,
-    req: web::Json<FileActionRequest>,
+    req: web::Json<SearchRequest>,
 ) -> HttpResponse {
     let claims = match verify_jwt(auth.token()) {
         Ok(claims) => claims,
@@ -425,82 +882,274 @@ pub async fn create_directory(
         }
     };
 
-    // Extract host_id string from Value
-    let host_id_str = match &req.host_id {
-        serde_json::Value::String(s) => s.clone(),
-        serde_json::Value::Object(obj) => {
-            if let Some(serde_json::Value::Object(id_obj)) = obj.get("id") {
-                if let Some(serde_json::Value::String(s)) = id_obj.get("String") {
-                    s.clone()
-                } else {
-                    return HttpResponse::BadRequest().json(json!({
-                        "error": "Invalid host_id format"
-                    }));
-                }
-            } else {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Invalid host_id format"
-                }));
-            }
-        }
-        _ => {
+    let host_id_str = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
             return HttpResponse::BadRequest().json(json!({
                 "error": "Invalid host_id format"
             }));
         }
     };
 
-    let host = match db.get_host(&host_id_str).await {
+    let root = match hosts::normalize_path(req.options.path.as_deref().unwrap_or("/")) {
+        Ok(root) => root,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    let criteria = match SearchCriteria::new(&req.options) {
+        Ok(criteria) => criteria,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("{:#}", e)
+            }));
+        }
+    };
+
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id_str).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let backend: Arc<dyn StorageBackend> = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend.into(),
+        Err(resp) => return resp,
+    };
+    if criteria.reads_content() && !backend.capabilities().read {
+        return storage_error("search", backend.unsupported("Read"));
+    }
+
+    // A missing start directory is still a proper error response
+    if root != "/" {
+        match backend.stat(&root).await {
+            Ok(info) if info.is_dir => {}
+            Ok(_) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": format!("{} is not a directory", root)
+                }));
+            }
+            Err(e) => return storage_error("search", e),
+        }
+    }
+
+    let (rx, _) = search::spawn(backend, root, criteria);
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        let event: SearchEvent = rx.recv().await?;
+        let mut line = serde_json::to_vec(&event).unwrap_or_default();
+        line.push(b'\n');
+        Some((Ok::<_, actix_web::Error>(Bytes::from(line)), rx))
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(events)
+}
+
+/// Rejects moving or copying the root or a directory into itself, and replacing a
+/// destination that contains the source (deleting it would delete the source).
+/// Neither may lie in the trash area.
+pub(super) fn check_transfer_paths(from: &str, to: &str, overwrite: OverwritePolicy) -> Result<(), &'static str> {
+    check_outside_trash(from)?;
+    check_outside_trash(to)?;
+    if from == "/" {
+        return Err("Cannot move or copy the root directory");
+    }
+    if to != from && hosts::is_within(to, from) {
+        return Err("Cannot move or copy a directory into itself");
+    }
+    if overwrite == OverwritePolicy::Replace && hosts::is_within(from, to) {
+        return Err("Cannot replace a destination that contains the source");
+    }
+    Ok(())
+}
+
+/// Rejects deleting the root, whether into the trash or for good, and
+/// entries already in the trash, which are purged through `/api/trash`.
+pub(super) fn check_delete_path(path: &str) -> Result<(), &'static str> {
+    if path == "/" {
+        return Err("Cannot delete the root directory");
+    }
+    check_outside_trash(path)
+}
+
+/// Rejects reaching into the trash area other than through `/api/trash`,
+/// so its entries stay as recorded until they are restored or purged.
+pub(super) fn check_outside_trash(path: &str) -> Result<(), &'static str> {
+    if hosts::is_trash_path(path) {
+        return Err("Items in the trash are managed through /api/trash");
+    }
+    Ok(())
+}
+
+/// Extracts the host id from either a plain string or a `{ "id": { "String": .. } }` record.
+pub(super) fn parse_host_id(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Object(obj) => match obj.get("id") {
+            Some(serde_json::Value::Object(id_obj)) => match id_obj.get("String") {
+                Some(serde_json::Value::String(s)) => Some(s.clone()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fetches a host and checks that it belongs to `user_id`.
+pub(super) async fn load_owned_host(db: &Database, user_id: &str, host_id: &str) -> Result<Host, HttpResponse> {
+    let host = match db.get_host(host_id).await {
         Ok(Some(host)) => host,
         Ok(None) => {
-            return HttpResponse::NotFound().json(json!({
+            return Err(HttpResponse::NotFound().json(json!({
                 "error": "Host not found"
-            }));
+            })));
         }
         Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
+            return Err(HttpResponse::InternalServerError().json(json!({
                 "error": format!("Failed to get host: {}", e)
-            }));
+            })));
         }
     };
 
-    if host.user_id != claims.sub {
-        return HttpResponse::Forbidden().json(json!({
+    if host.user_id != user_id {
+        return Err(HttpResponse::Forbidden().json(json!({
             "error": "Access denied"
-        }));
+        })));
     }
 
-    // Only support local filesystem for mkdir
-    match &host.host_type {
-        crate::models::HostType::Local => {
-            let base_path = host
-                .config
-                .path
-                .as_ref()
-                .ok_or_else(|| "Local path not configured");
+    Ok(host)
+}
 
-            if let Err(e) = base_path {
-                return HttpResponse::InternalServerError().json(json!({
-                    "error": e
-                }));
-            }
+pub(super) async fn open_backend(
+    db: &Database,
+    sftp_pool: &Arc<SftpPool>,
+    host: &mut Host,
+) -> Result<Box<dyn StorageBackend>, HttpResponse> {
+    hosts::ensure_host_key(host, db).await.map_err(|e| {
+        HttpResponse::BadGateway().json(json!({
+            "error": format!("Failed to verify host key: {}", e)
+        }))
+    })?;
 
-            match crate::hosts::local::LocalFileSystem::create_directory(
-                base_path.unwrap(),
-                &req.path,
-            )
-            .await
-            {
-                Ok(_) => HttpResponse::Ok().json(json!({
-                    "message": "Directory created successfully"
-                })),
-                Err(e) => HttpResponse::InternalServerError().json(json!({
-                    "error": format!("Failed to create directory: {}", e)
-                })),
-            }
+    let encryptor = Encryptor::new().map_err(|e| {
+        HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to initialize encryptor: {}", e)
+        }))
+    })?;
+
+    hosts::open(host, &encryptor, sftp_pool).map_err(|e| {
+        HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to open host: {}", e)
+        }))
+    })
+}
+
+/// Maps a backend error to a response; unsupported operations are client errors.
+pub(super) fn storage_error(action: &str, e: anyhow::Error) -> HttpResponse {
+    match e.downcast_ref::<StorageError>() {
+        Some(StorageError::Unsupported { .. }) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
         }
-        _ => HttpResponse::BadRequest().json(json!({
-            "error": "Create directory operation only supported for local filesystem"
-        })),
+        Some(StorageError::AlreadyExists(_)) => {
+            return HttpResponse::Conflict().json(json!({
+                "error": e.to_string()
+            }));
+        }
+        Some(StorageError::HostKeyMismatch { trusted, presented }) => {
+            return HttpResponse::Conflict().json(json!({
+                "error": e.to_string(),
+                "trusted_host_key": trusted,
+                "presented_host_key": presented
+            }));
+        }
+        Some(StorageError::RangeNotSatisfiable { size }) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .json(json!({
+                    "error": e.to_string()
+                }));
+        }
+        None => {}
+    }
+
+    HttpResponse::InternalServerError().json(json!({
+        "error": format!("Failed to {}: {}", action, e)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+
+    #[test]
+    fn storage_error_maps_unsupported_to_bad_request() {
+        let err = StorageError::Unsupported {
+            operation: "Delete",
+            host_type: "HTTP",
+        };
+
+        let resp = storage_error("delete file", err.into());
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn storage_error_maps_already_exists_to_conflict() {
+        let resp = storage_error("rename", StorageError::AlreadyExists("/a".into()).into());
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn storage_error_maps_unsatisfiable_range_to_416() {
+        let resp = storage_error("read file", StorageError::RangeNotSatisfiable { size: 10 }.into());
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */10");
+    }
+
+    #[test]
+    fn storage_error_maps_other_errors_to_server_error() {
+        let resp = storage_error("delete file", anyhow::anyhow!("disk on fire"));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn check_transfer_paths_protects_the_source() {
+        assert!(check_transfer_paths("/a", "/b", OverwritePolicy::Fail).is_ok());
+        assert!(check_transfer_paths("/a", "/a/b", OverwritePolicy::Fail).is_err());
+        assert!(check_transfer_paths("/", "/b", OverwritePolicy::Fail).is_err());
+        assert!(check_transfer_paths("/a/b", "/a", OverwritePolicy::Fail).is_ok());
+        assert!(check_transfer_paths("/a/b", "/a", OverwritePolicy::Replace).is_err());
+        assert!(check_transfer_paths("/a", "/a", OverwritePolicy::Replace).is_err());
+        assert!(check_transfer_paths("/.fm-trash/item-1/a", "/a", OverwritePolicy::Fail).is_err());
+        assert!(check_transfer_paths("/a", "/.fm-trash/a", OverwritePolicy::Fail).is_err());
+    }
+
+    #[test]
+    fn check_outside_trash_covers_the_whole_trash_area() {
+        assert!(check_outside_trash("/a").is_ok());
+        assert!(check_outside_trash("/.fm-trash-notes").is_ok());
+        assert!(check_outside_trash("/.fm-trash").is_err());
+        assert!(check_outside_trash("/.fm-trash/item-1/a/b").is_err());
+    }
+
+    #[test]
+    fn check_delete_path_protects_the_root_and_the_trash() {
+        assert!(check_delete_path("/a").is_ok());
+        assert!(check_delete_path("/.fm-trash-notes").is_ok());
+        assert_eq!(check_delete_path("/"), Err("Cannot delete the root directory"));
+        assert!(check_delete_path("/.fm-trash").is_err());
+        assert!(check_delete_path("/.fm-trash/item-1/a").is_err());
+    }
+
+    #[test]
+    fn parse_host_id_accepts_string_and_record_forms() {
+        assert_eq!(parse_host_id(&json!("abc")).as_deref(), Some("abc"));
+        assert_eq!(parse_host_id(&json!({"id": {"String": "abc"}})).as_deref(), Some("abc"));
+        assert_eq!(parse_host_id(&json!(42)), None);
     }
 }
@@ -1,13 +1,18 @@
 use crate::auth::{verify_jwt, Encryptor};
 use crate::db::Database;
-use crate::models::{CreateHostRequest, Host};
+use crate::hosts::sftp::{self, SftpFileSystem};
+use crate::index::Indexer;
+use crate::models::{CreateHostRequest, Host, HostConfig, HostType, SshAuthMethod};
+use crate::watch::{MAX_POLL_INTERVAL, MIN_POLL_INTERVAL};
 use actix_web::{web, HttpResponse};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::Deserialize;
 use serde_json::json;
 use std::sync::Arc;
 
 pub async fn create_host(
     db: web::Data<Arc<Database>>,
+    indexer: web::Data<Arc<Indexer>>,
     auth: BearerAuth,
     req: web::Json<CreateHostRequest>,
 ) -> HttpResponse {
@@ -21,28 +26,24 @@ pub async fn create_host(
         }
     };
 
-    // Encrypt password if present
-    let mut config = req.config.clone();
-    if let Some(password) = &config.password_encrypted {
-        let encryptor = match Encryptor::new() {
-            Ok(enc) => enc,
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({
-                    "error": format!("Failed to initialize encryptor: {}", e)
-                }));
-            }
-        };
+    let validation = match req.host_type {
+        HostType::Sftp => validate_sftp_credentials(&req.config, sftp::agent_auth_enabled()),
+        HostType::WebDav => validate_webdav_config(&req.config),
+        HostType::S3 => validate_s3_config(&req.config),
+        HostType::Ftp => validate_ftp_config(&req.config),
+        _ => Ok(()),
+    }
+    .and_then(|()| validate_poll_interval(&req.host_type, &req.config));
+    if let Err(e) = validation {
+        return HttpResponse::BadRequest().json(json!({
+            "error": e
+        }));
+    }
 
-        match encryptor.encrypt(password) {
-            Ok(encrypted) => {
-                config.password_encrypted = Some(encrypted);
-            }
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({
-                    "error": format!("Failed to encrypt password: {}", e)
-                }));
-            }
-        }
+    // Encrypt credentials if present
+    let mut config = req.config.clone();
+    if let Err(resp) = encrypt_secrets(&mut config) {
+        return resp;
     }
 
     let host = Host::new(
@@ -60,7 +61,8 @@ pub async fn create_host(
                 created_host.id,
                 claims.sub
             );
-            HttpResponse::Ok().json(created_host)
+            indexer.add_host(&created_host);
+            HttpResponse::Ok().json(created_host.redacted())
         }
         Err(e) => {
             // Log the detailed error (including debug info)
@@ -77,10 +79,10 @@ pub async fn create_host(
 
             // Return DB error details in response to aid debugging (caller requested)
             // Note: this may expose DB messages; ensure this is acceptable in your environment.
-            return HttpResponse::InternalServerError().json(json!({
+            HttpResponse::InternalServerError().json(json!({
                 "error": "Failed to create host",
                 "details": err_string
-            }));
+            }))
         }
     }
 }
@@ -96,7 +98,10 @@ pub async fn list_hosts(db: web::Data<Arc<Database>>, auth: BearerAuth) -> HttpR
     };
 
     match db.get_hosts_by_user(&claims.sub).await {
-        Ok(hosts) => HttpResponse::Ok().json(hosts),
+        Ok(hosts) => {
+            let hosts: Vec<Host> = hosts.iter().map(Host::redacted).collect();
+            HttpResponse::Ok().json(hosts)
+        }
         Err(e) => HttpResponse::InternalServerError().json(json!({
             "error": format!("Failed to get hosts: {}", e)
         })),
@@ -126,7 +131,7 @@ pub async fn get_host(
                     "error": "Access denied"
                 }));
             }
-            HttpResponse::Ok().json(host)
+            HttpResponse::Ok().json(host.redacted())
         }
         Ok(None) => HttpResponse::NotFound().json(json!({
             "error": "Host not found"
@@ -139,6 +144,7 @@ pub async fn get_host(
 
 pub async fn delete_host(
     db: web::Data<Arc<Database>>,
+    indexer: web::Data<Arc<Indexer>>,
     auth: BearerAuth,
     path: web::Path<String>,
 ) -> HttpResponse {
@@ -175,11 +181,412 @@ pub async fn delete_host(
     }
 
     match db.delete_host(&host_id).await {
-        Ok(_) => HttpResponse::Ok().json(json!({
-            "message": "Host deleted successfully"
-        })),
+        Ok(_) => {
+            if let Err(e) = indexer.remove_host(&host_id).await {
+                log::warn!("Failed to remove host {} from the search index: {:#}", host_id, e);
+            }
+            HttpResponse::Ok().json(json!({
+                "message": "Host deleted successfully"
+            }))
+        }
         Err(e) => HttpResponse::InternalServerError().json(json!({
             "error": format!("Failed to delete host: {}", e)
         })),
     }
 }
+
+/// Checks that the credentials needed by the chosen SFTP auth method were
+/// supplied, and that the agent is only chosen when the server allows it.
+fn validate_sftp_credentials(config: &HostConfig, agent_enabled: bool) -> Result<(), String> {
+    let method = config.ssh_auth_method();
+
+    match method {
+        SshAuthMethod::Password if config.password_encrypted.is_none() => {
+            return Err("Password authentication requires a password".to_string());
+        }
+        SshAuthMethod::PublicKey if config.private_key_encrypted.is_none() => {
+            return Err("Public key authentication requires a private key".to_string());
+        }
+        SshAuthMethod::Agent if !agent_enabled => {
+            return Err("SSH agent authentication is not enabled on this server".to_string());
+        }
+        _ => {}
+    }
+
+    if config.passphrase_encrypted.is_some() && method != SshAuthMethod::PublicKey {
+        return Err("A passphrase is only used with public key authentication".to_string());
+    }
+
+    Ok(())
+}
+
+/// WebDAV hosts need a URL; credentials are optional but must be complete.
+fn validate_webdav_config(config: &HostConfig) -> Result<(), String> {
+    if config.url.is_none() {
+        return Err("WebDAV hosts require a URL".to_string());
+    }
+
+    if config.username.is_some() != config.password_encrypted.is_some() {
+        return Err("WebDAV authentication requires both a username and a password".to_string());
+    }
+
+    if config.auth_method.is_some() || config.private_key_encrypted.is_some() || config.passphrase_encrypted.is_some() {
+        return Err("WebDAV hosts only support username and password authentication".to_string());
+    }
+
+    Ok(())
+}
+
+/// S3 hosts need a bucket; access keys are optional (public buckets) but come in pairs.
+fn validate_s3_config(config: &HostConfig) -> Result<(), String> {
+    if config.bucket.as_deref().is_none_or(str::is_empty) {
+        return Err("S3 hosts require a bucket".to_string());
+    }
+
+    if config.access_key_encrypted.is_some() != config.secret_key_encrypted.is_some() {
+        return Err("S3 authentication requires both an access key and a secret key".to_string());
+    }
+
+    if let Some(endpoint) = &config.endpoint {
+        let valid = reqwest::Url::parse(endpoint)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+        if !valid {
+            return Err(format!("Invalid S3 endpoint '{}', expected an http(s) URL", endpoint));
+        }
+    }
+
+    Ok(())
+}
+
+/// FTP hosts need a server; a password without a username would be ignored.
+fn validate_ftp_config(config: &HostConfig) -> Result<(), String> {
+    if config.host.as_deref().is_none_or(str::is_empty) {
+        return Err("FTP hosts require a host".to_string());
+    }
+
+    if config.password_encrypted.is_some() && config.username.is_none() {
+        return Err("An FTP password requires a username".to_string());
+    }
+
+    Ok(())
+}
+
+/// Only polled hosts take a poll interval, and it must be within bounds.
+fn validate_poll_interval(host_type: &HostType, config: &HostConfig) -> Result<(), String> {
+    let Some(interval) = config.poll_interval else {
+        return Ok(());
+    };
+
+    if !matches!(host_type, HostType::Sftp | HostType::Http) {
+        return Err("A poll interval only applies to SFTP and HTTP hosts".to_string());
+    }
+
+    if !(MIN_POLL_INTERVAL..=MAX_POLL_INTERVAL).contains(&interval) {
+        return Err(format!(
+            "The poll interval must be between {} and {} seconds",
+            MIN_POLL_INTERVAL, MAX_POLL_INTERVAL
+        ));
+    }
+
+    Ok(())
+}
+
+/// Replaces the plaintext secrets sent by the client with their encrypted form.
+fn encrypt_secrets(config: &mut HostConfig) -> Result<(), HttpResponse> {
+    let has_secrets = config.password_encrypted.is_some()
+        || config.private_key_encrypted.is_some()
+        || config.passphrase_encrypted.is_some()
+        || config.access_key_encrypted.is_some()
+        || config.secret_key_encrypted.is_some();
+    if !has_secrets {
+        return Ok(());
+    }
+
+    let encryptor = Encryptor::new().map_err(|e| {
+        HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to initialize encryptor: {}", e)
+        }))
+    })?;
+
+    let secrets = [
+        ("password", &mut config.password_encrypted),
+        ("private key", &mut config.private_key_encrypted),
+        ("passphrase", &mut config.passphrase_encrypted),
+        ("access key", &mut config.access_key_encrypted),
+        ("secret key", &mut config.secret_key_encrypted),
+    ];
+
+    for (name, secret) in secrets {
+        if let Some(plaintext) = secret.as_deref() {
+            let encrypted = encryptor.encrypt(plaintext).map_err(|e| {
+                HttpResponse::InternalServerError().json(json!({
+                    "error": format!("Failed to encrypt {}: {}", name, e)
+                }))
+            })?;
+            *secret = Some(encrypted);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcceptHostKeyRequest {
+    pub fingerprint: String,
+}
+
+/// Shows the trusted SSH host key next to the one the server presents right now.
+pub async fn get_host_key(
+    db: web::Data<Arc<Database>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host = match load_sftp_host(&db, &claims.sub, &path.into_inner()).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let (addr, port) = sftp_address(&host);
+    match SftpFileSystem::fetch_host_key(addr, port).await {
+        Ok(presented) => HttpResponse::Ok().json(json!({
+            "trusted": host.host_key_fingerprint,
+            "presented": presented,
+            "matches": host.host_key_fingerprint.as_deref() == Some(presented.as_str())
+        })),
+        Err(e) => HttpResponse::BadGateway().json(json!({
+            "error": format!("Failed to fetch host key: {}", e)
+        })),
+    }
+}
+
+/// Trusts the host key the server currently presents. The client must echo the
+/// fingerprint it reviewed so a key that changes in between is not accepted.
+pub async fn accept_host_key(
+    db: web::Data<Arc<Database>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+    req: web::Json<AcceptHostKeyRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host = match load_sftp_host(&db, &claims.sub, &path.into_inner()).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+
+    let (addr, port) = sftp_address(&host);
+    let presented = match SftpFileSystem::fetch_host_key(addr, port).await {
+        Ok(presented) => presented,
+        Err(e) => {
+            return HttpResponse::BadGateway().json(json!({
+                "error": format!("Failed to fetch host key: {}", e)
+            }));
+        }
+    };
+
+    if presented != req.fingerprint {
+        return HttpResponse::Conflict().json(json!({
+            "error": "Server presented a different host key than the one reviewed",
+            "presented": presented
+        }));
+    }
+
+    match db.set_host_key(&host.id, &presented).await {
+        Ok(_) => {
+            log::info!(
+                "Host key {} accepted for host {} by user {}",
+                presented,
+                host.id,
+                claims.sub
+            );
+            HttpResponse::Ok().json(json!({
+                "message": "Host key accepted",
+                "trusted": presented
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to update host key: {}", e)
+        })),
+    }
+}
+
+async fn load_sftp_host(db: &Database, user_id: &str, host_id: &str) -> Result<Host, HttpResponse> {
+    let host = match db.get_host(host_id).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Err(HttpResponse::NotFound().json(json!({
+                "error": "Host not found"
+            })));
+        }
+        Err(e) => {
+            return Err(HttpResponse::InternalServerError().json(json!({
+                "error": format!("Failed to get host: {}", e)
+            })));
+        }
+    };
+
+    if host.user_id != user_id {
+        return Err(HttpResponse::Forbidden().json(json!({
+            "error": "Access denied"
+        })));
+    }
+
+    if !matches!(host.host_type, HostType::Sftp) || host.config.host.is_none() {
+        return Err(HttpResponse::BadRequest().json(json!({
+            "error": "Host keys only apply to SFTP hosts"
+        })));
+    }
+
+    Ok(host)
+}
+
+fn sftp_address(host: &Host) -> (&str, u16) {
+    (
+        host.config.host.as_deref().unwrap_or_default(),
+        host.config.port.unwrap_or(22),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(method: Option<SshAuthMethod>, password: bool, key: bool, passphrase: bool) -> HostConfig {
+        let secret = |present: bool| present.then(|| "secret".to_string());
+        HostConfig {
+            host: Some("example.com".to_string()),
+            port: Some(22),
+            username: Some("deploy".to_string()),
+            password_encrypted: secret(password),
+            auth_method: method,
+            private_key_encrypted: secret(key),
+            passphrase_encrypted: secret(passphrase),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_consistent_sftp_credentials() {
+        assert!(validate_sftp_credentials(&config(None, true, false, false), false).is_ok());
+        assert!(validate_sftp_credentials(&config(None, false, true, true), false).is_ok());
+        assert!(validate_sftp_credentials(&config(Some(SshAuthMethod::Agent), false, false, false), true).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_or_mismatched_sftp_credentials() {
+        assert!(validate_sftp_credentials(&config(None, false, false, false), true).is_err());
+        assert!(validate_sftp_credentials(&config(Some(SshAuthMethod::Password), false, true, false), true).is_err());
+        assert!(validate_sftp_credentials(&config(Some(SshAuthMethod::PublicKey), true, false, false), true).is_err());
+        assert!(validate_sftp_credentials(&config(Some(SshAuthMethod::Agent), false, false, true), true).is_err());
+        // Only with the operator's consent
+        assert!(validate_sftp_credentials(&config(Some(SshAuthMethod::Agent), false, false, false), false).is_err());
+    }
+
+    #[test]
+    fn webdav_credentials_must_be_complete() {
+        let mut webdav = HostConfig {
+            url: Some("https://nas.local/dav".to_string()),
+            username: Some("deploy".to_string()),
+            password_encrypted: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_webdav_config(&webdav).is_ok());
+
+        webdav.password_encrypted = None;
+        assert!(validate_webdav_config(&webdav).is_err());
+
+        webdav.username = None;
+        assert!(validate_webdav_config(&webdav).is_ok());
+
+        webdav.url = None;
+        assert!(validate_webdav_config(&webdav).is_err());
+    }
+
+    #[test]
+    fn s3_config_requires_bucket_and_paired_keys() {
+        let mut s3 = HostConfig {
+            endpoint: Some("http://localhost:9000".to_string()),
+            bucket: Some("artifacts".to_string()),
+            access_key_encrypted: Some("minio".to_string()),
+            secret_key_encrypted: Some("minio123".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_s3_config(&s3).is_ok());
+
+        s3.secret_key_encrypted = None;
+        assert!(validate_s3_config(&s3).is_err());
+
+        s3.access_key_encrypted = None;
+        s3.endpoint = Some("localhost:9000".to_string());
+        assert!(validate_s3_config(&s3).is_err());
+
+        s3.endpoint = None;
+        s3.bucket = None;
+        assert!(validate_s3_config(&s3).is_err());
+    }
+
+    #[test]
+    fn ftp_config_requires_host() {
+        let mut ftp = HostConfig {
+            host: Some("ftp.partner.example".to_string()),
+            tls: Some(true),
+            ..Default::default()
+        };
+        assert!(validate_ftp_config(&ftp).is_ok());
+
+        ftp.password_encrypted = Some("secret".to_string());
+        assert!(validate_ftp_config(&ftp).is_err());
+
+        ftp.username = Some("drop".to_string());
+        assert!(validate_ftp_config(&ftp).is_ok());
+
+        ftp.host = None;
+        assert!(validate_ftp_config(&ftp).is_err());
+    }
+
+    #[test]
+    fn poll_interval_is_bounded_and_for_polled_hosts() {
+        let polled = HostConfig { poll_interval: Some(60), ..Default::default() };
+        assert!(validate_poll_interval(&HostType::Sftp, &polled).is_ok());
+        assert!(validate_poll_interval(&HostType::Http, &polled).is_ok());
+        assert!(validate_poll_interval(&HostType::Local, &polled).is_err());
+        assert!(validate_poll_interval(&HostType::Local, &HostConfig::default()).is_ok());
+
+        let too_often = HostConfig { poll_interval: Some(1), ..Default::default() };
+        assert!(validate_poll_interval(&HostType::Sftp, &too_often).is_err());
+    }
+
+    #[test]
+    fn redacted_hosts_carry_no_secrets() {
+        let mut config = config(None, true, true, true);
+        config.access_key_encrypted = Some("key".to_string());
+        config.secret_key_encrypted = Some("secret".to_string());
+        let host = Host::new("user".into(), "box".into(), HostType::Sftp, config);
+
+        let json = serde_json::to_value(host.redacted()).unwrap();
+        let config = json["config"].as_object().unwrap();
+
+        assert!(!config.contains_key("password_encrypted"));
+        assert!(!config.contains_key("private_key_encrypted"));
+        assert!(!config.contains_key("passphrase_encrypted"));
+        assert!(!config.contains_key("access_key_encrypted"));
+        assert!(!config.contains_key("secret_key_encrypted"));
+        assert_eq!(config["username"], "deploy");
+    }
+}
@@ -0,0 +1,541 @@
+//! Background jobs: copy, move, recursive delete and archive extraction and
+//! creation run by `JobManager` instead of inside the request that asked for them.
+
+use super::files::{
+    check_delete_path, check_outside_trash, check_transfer_paths, load_owned_host, open_backend, parse_host_id,
+    storage_error,
+};
+use crate::auth::verify_jwt;
+use crate::db::Database;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::archive::ArchiveKind;
+use crate::hosts::{self, StorageBackend};
+use crate::jobs::JobManager;
+use crate::models::{Job, JobKind, JobStatus, OverwritePolicy};
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Jobs returned by `GET /api/jobs`.
+const JOB_LIST_LIMIT: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateJobRequest {
+    pub kind: JobKind,
+    pub host_id: serde_json::Value,
+    pub path: String,
+    /// Destination host of a copy or move; `host_id` when absent.
+    #[serde(default)]
+    pub target_host_id: Option<serde_json::Value>,
+    #[serde(default)]
+    pub target_path: Option<String>,
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+    /// Deletes for good instead of moving to the trash; delete jobs only.
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+pub async fn create_job(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    req: web::Json<CreateJobRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host_ids = (
+        parse_host_id(&req.host_id),
+        req.target_host_id.as_ref().map_or_else(|| parse_host_id(&req.host_id), parse_host_id),
+    );
+    let (host_id, target_host_id) = match host_ids {
+        (Some(host_id), Some(target_host_id)) => (host_id, target_host_id),
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
+            }));
+        }
+    };
+
+    let path = match hosts::normalize_path(&req.path) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let source = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    let (target_host_id, target_path) = match req.kind {
+        JobKind::Extract | JobKind::Compress => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Archive jobs are queued through /api/files/extract and /api/files/compress"
+            }));
+        }
+        JobKind::Delete => {
+            if let Err(message) = check_delete_path(&path) {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": message
+                }));
+            }
+            let trashed = !req.permanent && source.capabilities().rename;
+            if !trashed && !source.capabilities().delete {
+                return storage_error("queue job", source.unsupported("Delete"));
+            }
+            (None, None)
+        }
+        JobKind::Copy | JobKind::Move => {
+            let to = match req.target_path.as_deref().map(hosts::normalize_path) {
+                Some(Ok(to)) => to,
+                Some(Err(e)) => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": e.to_string()
+                    }));
+                }
+                None => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "error": "target_path is required"
+                    }));
+                }
+            };
+
+            let same_host = target_host_id == host_id;
+            let checked = if same_host {
+                check_transfer_paths(&path, &to, req.overwrite)
+            } else {
+                check_outside_trash(&path).and(check_outside_trash(&to))
+            };
+            if let Err(message) = checked {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": message
+                }));
+            }
+
+            let target = if same_host {
+                None
+            } else {
+                let mut target_host = match load_owned_host(&db, &claims.sub, &target_host_id).await {
+                    Ok(host) => host,
+                    Err(resp) => return resp,
+                };
+                match open_backend(&db, &sftp_pool, &mut target_host).await {
+                    Ok(backend) => Some(backend),
+                    Err(resp) => return resp,
+                }
+            };
+            let target = target.as_deref().unwrap_or(source.as_ref());
+
+            if let Err(resp) = check_transfer_capabilities(req.kind, source.as_ref(), target, same_host) {
+                return resp;
+            }
+            (Some(target_host_id), Some(to))
+        }
+    };
+
+    let job = Job {
+        target_host_id,
+        target_path,
+        overwrite: req.overwrite,
+        permanent: req.permanent && req.kind == JobKind::Delete,
+        ..Job::new(claims.sub, req.kind, host_id, path)
+    };
+    queue_job(&db, &jobs, job).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtractRequest {
+    pub host_id: serde_json::Value,
+    /// The archive to extract.
+    pub path: String,
+    /// The directory to create and extract into.
+    pub target_path: String,
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+/// Queues extracting a ZIP or tar archive into a new directory on the same host.
+pub async fn extract_archive(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    req: web::Json<ExtractRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host_id = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
+            }));
+        }
+    };
+
+    let (path, to) = match (hosts::normalize_path(&req.path), hosts::normalize_path(&req.target_path)) {
+        (Ok(path), Ok(to)) => (path, to),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    if ArchiveKind::detect(&path).is_none() {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Only .zip, .tar, .tar.gz and .tar.zst archives can be extracted"
+        }));
+    }
+    if to == "/" {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Cannot extract into the root directory"
+        }));
+    }
+    if let Err(message) = check_outside_trash(&path).and(check_outside_trash(&to)) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
+        }));
+    }
+
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = check_archive_capabilities(backend.as_ref(), true) {
+        return resp;
+    }
+
+    let job = Job {
+        target_path: Some(to),
+        overwrite: req.overwrite,
+        ..Job::new(claims.sub, JobKind::Extract, host_id, path)
+    };
+    queue_job(&db, &jobs, job).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompressRequest {
+    pub host_id: serde_json::Value,
+    /// Files and directories to archive, each under its own name.
+    pub paths: Vec<String>,
+    /// The archive to create; its extension, `.zip` or `.tar.gz`, picks the format.
+    pub target_path: String,
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+/// Queues archiving files and directories into a new archive on the same host.
+pub async fn compress_files(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    req: web::Json<CompressRequest>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let host_id = match parse_host_id(&req.host_id) {
+        Some(id) => id,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Invalid host_id format"
+            }));
+        }
+    };
+
+    if req.paths.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "No paths to archive"
+        }));
+    }
+    let paths = match req.paths.iter().map(|p| hosts::normalize_path(p)).collect::<anyhow::Result<Vec<_>>>() {
+        Ok(paths) => paths,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    let to = match hosts::normalize_path(&req.target_path) {
+        Ok(to) => to,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    if ArchiveKind::detect(&to).and_then(ArchiveKind::format).is_none() {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Archives can only be created as .zip or .tar.gz"
+        }));
+    }
+    if paths.iter().any(|path| hosts::is_within(&to, path)) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Cannot create an archive inside a path it archives"
+        }));
+    }
+    if let Err(message) = paths.iter().chain([&to]).try_for_each(|path| check_outside_trash(path)) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": message
+        }));
+    }
+
+    let mut host = match load_owned_host(&db, &claims.sub, &host_id).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = check_archive_capabilities(backend.as_ref(), false) {
+        return resp;
+    }
+
+    let path = paths[0].clone();
+    let job = Job {
+        target_path: Some(to),
+        sources: paths,
+        overwrite: req.overwrite,
+        ..Job::new(claims.sub, JobKind::Compress, host_id, path)
+    };
+    queue_job(&db, &jobs, job).await
+}
+
+async fn queue_job(db: &Database, jobs: &JobManager, job: Job) -> HttpResponse {
+    if let Err(e) = db.create_job(&job).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to queue job: {}", e)
+        }));
+    }
+    jobs.notify();
+
+    HttpResponse::Accepted().json(job)
+}
+
+/// Archive jobs read and write the same host; extracting also creates directories.
+fn check_archive_capabilities(backend: &dyn StorageBackend, extract: bool) -> Result<(), HttpResponse> {
+    let caps = backend.capabilities();
+    let missing = if !caps.read {
+        Some(backend.unsupported("Read"))
+    } else if !caps.write {
+        Some(backend.unsupported("Write"))
+    } else if extract && !caps.mkdir {
+        Some(backend.unsupported("Create directory"))
+    } else {
+        None
+    };
+
+    match missing {
+        Some(e) => Err(storage_error("queue job", e)),
+        None => Ok(()),
+    }
+}
+
+/// A same-host move renames; anything else reads the source and writes the target.
+fn check_transfer_capabilities(
+    kind: JobKind,
+    source: &dyn StorageBackend,
+    target: &dyn StorageBackend,
+    same_host: bool,
+) -> Result<(), HttpResponse> {
+    let (source_caps, target_caps) = (source.capabilities(), target.capabilities());
+    let missing = if kind == JobKind::Move && same_host {
+        (!source_caps.rename).then(|| source.unsupported("Rename"))
+    } else if !source_caps.read {
+        Some(source.unsupported("Read"))
+    } else if !target_caps.write {
+        Some(target.unsupported("Write"))
+    } else if kind == JobKind::Move && !source_caps.delete {
+        Some(source.unsupported("Delete"))
+    } else {
+        None
+    };
+
+    match missing {
+        Some(e) => Err(storage_error("queue job", e)),
+        None => Ok(()),
+    }
+}
+
+pub async fn list_jobs(
+    db: web::Data<Arc<Database>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    match db.get_jobs_by_user(&claims.sub, JOB_LIST_LIMIT).await {
+        Ok(list) => {
+            let list: Vec<Job> = list.into_iter().map(|job| with_live_progress(&jobs, job)).collect();
+            HttpResponse::Ok().json(list)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to list jobs: {}", e)
+        })),
+    }
+}
+
+pub async fn get_job(
+    db: web::Data<Arc<Database>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    match load_owned_job(&db, &claims.sub, &path).await {
+        Ok(job) => HttpResponse::Ok().json(with_live_progress(&jobs, job)),
+        Err(resp) => resp,
+    }
+}
+
+pub async fn cancel_job(
+    db: web::Data<Arc<Database>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let job = match load_owned_job(&db, &claims.sub, &path).await {
+        Ok(job) => job,
+        Err(resp) => return resp,
+    };
+    if job.status.is_finished() {
+        return HttpResponse::Conflict().json(json!({
+            "error": "Job has already finished"
+        }));
+    }
+
+    match jobs.cancel(&job).await {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "message": "Cancellation requested"
+        })),
+        Ok(false) => HttpResponse::Conflict().json(json!({
+            "error": "Job has already finished"
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to cancel job: {}", e)
+        })),
+    }
+}
+
+pub async fn retry_job(
+    db: web::Data<Arc<Database>>,
+    jobs: web::Data<Arc<JobManager>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let job = match load_owned_job(&db, &claims.sub, &path).await {
+        Ok(job) => job,
+        Err(resp) => return resp,
+    };
+
+    match db.retry_job(&job.id).await {
+        Ok(true) => {
+            jobs.notify();
+            match load_owned_job(&db, &claims.sub, &job.id).await {
+                Ok(job) => HttpResponse::Accepted().json(job),
+                Err(resp) => resp,
+            }
+        }
+        Ok(false) => HttpResponse::Conflict().json(json!({
+            "error": "Only failed or cancelled jobs can be retried"
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to retry job: {}", e)
+        })),
+    }
+}
+
+async fn load_owned_job(db: &Database, user_id: &str, job_id: &str) -> Result<Job, HttpResponse> {
+    match db.get_job(job_id).await {
+        Ok(Some(job)) if job.user_id == user_id => Ok(job),
+        Ok(Some(_)) => Err(HttpResponse::Forbidden().json(json!({
+            "error": "Access denied"
+        }))),
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "error": "Job not found"
+        }))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to get job: {}", e)
+        }))),
+    }
+}
+
+/// The database only sees a running job's progress once a second.
+fn with_live_progress(jobs: &JobManager, mut job: Job) -> Job {
+    if job.status == JobStatus::Running {
+        if let Some(progress) = jobs.progress(&job.id) {
+            job.progress = progress;
+        }
+    }
+    job
+}
@@ -1,12 +1,21 @@
+use actix_web::http::Method;
 use actix_web::web;
 
 mod auth;
 mod hosts;
 mod files;
+mod jobs;
+mod search;
+mod trash;
+mod tus;
 
 pub use auth::*;
 pub use hosts::*;
 pub use files::*;
+pub use jobs::*;
+pub use search::*;
+pub use trash::*;
+pub use tus::*;
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -20,13 +29,43 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .route("", web::get().to(list_hosts))
             .route("/{id}", web::get().to(get_host))
             .route("/{id}", web::delete().to(delete_host))
+            .route("/{id}/host-key", web::get().to(get_host_key))
+            .route("/{id}/host-key", web::post().to(accept_host_key))
     )
     .service(
         web::scope("/files")
             .route("/browse", web::post().to(browse_files))
             .route("/download", web::post().to(download_file))
+            .route("/archive", web::post().to(download_archive))
+            .route("/search", web::post().to(search_files))
             .route("/upload", web::post().to(upload_file))
             .route("/delete", web::post().to(delete_file))
             .route("/mkdir", web::post().to(create_directory))
-    );
+            .route("/rename", web::post().to(rename_file))
+            .route("/move", web::post().to(rename_file))
+            .route("/copy", web::post().to(copy_file))
+            .route("/extract", web::post().to(extract_archive))
+            .route("/compress", web::post().to(compress_files))
+            .route("/tus", web::route().method(Method::OPTIONS).to(tus_options))
+            .route("/tus", web::post().to(tus_create))
+            .route("/tus/{id}", web::head().to(tus_head))
+            .route("/tus/{id}", web::patch().to(tus_patch))
+            .route("/tus/{id}", web::delete().to(tus_delete))
+    )
+    .service(
+        web::scope("/jobs")
+            .route("", web::post().to(create_job))
+            .route("", web::get().to(list_jobs))
+            .route("/{id}", web::get().to(get_job))
+            .route("/{id}/cancel", web::post().to(cancel_job))
+            .route("/{id}/retry", web::post().to(retry_job))
+    )
+    .service(
+        web::scope("/trash")
+            .route("", web::get().to(list_trash))
+            .route("", web::delete().to(empty_trash))
+            .route("/{id}/restore", web::post().to(restore_trash_item))
+            .route("/{id}", web::delete().to(purge_trash_item))
+    )
+    .route("/search", web::get().to(search_index));
 }
@@ -0,0 +1,112 @@
+//! Queries against the full-text index of local hosts, see `crate::index`.
+
+use super::files::load_owned_host;
+use crate::auth::verify_jwt;
+use crate::db::Database;
+use crate::hosts;
+use crate::index::{match_expression, Indexer, Query};
+use crate::models::HostType;
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+const DEFAULT_RESULTS: u32 = 50;
+const MAX_RESULTS: u32 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct IndexSearchQuery {
+    pub q: String,
+    /// Only this host; all of the user's local hosts when absent.
+    #[serde(default)]
+    pub host_id: Option<String>,
+    /// Only entries at or below this path.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+pub async fn search_index(
+    db: web::Data<Arc<Database>>,
+    indexer: web::Data<Arc<Indexer>>,
+    auth: BearerAuth,
+    query: web::Query<IndexSearchQuery>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    if !indexer.is_enabled() {
+        return HttpResponse::ServiceUnavailable().json(json!({
+            "error": "Search index is not enabled"
+        }));
+    }
+
+    let Some(expression) = match_expression(&query.q) else {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Query has no searchable terms"
+        }));
+    };
+
+    let path = match hosts::normalize_path(query.path.as_deref().unwrap_or("/")) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+
+    let host_ids = match &query.host_id {
+        Some(host_id) => {
+            let host = match load_owned_host(&db, &claims.sub, host_id).await {
+                Ok(host) => host,
+                Err(resp) => return resp,
+            };
+            if !matches!(host.host_type, HostType::Local) {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": "Only local hosts are indexed"
+                }));
+            }
+            vec![host.id]
+        }
+        None => match db.get_hosts_by_user(&claims.sub).await {
+            Ok(hosts) => hosts
+                .into_iter()
+                .filter(|h| matches!(h.host_type, HostType::Local))
+                .map(|h| h.id)
+                .collect(),
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(json!({
+                    "error": format!("Failed to get hosts: {}", e)
+                }));
+            }
+        },
+    };
+
+    let search = Query {
+        expression: &expression,
+        host_ids: &host_ids,
+        path: &path,
+        limit: query.limit.unwrap_or(DEFAULT_RESULTS).clamp(1, MAX_RESULTS),
+        offset: query.offset.unwrap_or(0),
+    };
+    match indexer.search(&search).await {
+        Ok(results) => HttpResponse::Ok().json(json!({
+            "results": results,
+            "indexing": indexer.is_crawling(&host_ids),
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to search index: {:#}", e)
+        })),
+    }
+}
@@ -0,0 +1,198 @@
+//! The trash: entries deleted through `/api/files/delete`, listed, restored
+//! and purged by their owner. See `crate::trash`.
+
+use super::files::{load_owned_host, open_backend, storage_error};
+use crate::auth::verify_jwt;
+use crate::db::Database;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::StorageBackend;
+use crate::models::{OverwritePolicy, TrashItem};
+use crate::trash::TrashManager;
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct TrashQuery {
+    /// Only this host's trash; all of the user's when absent.
+    #[serde(default)]
+    pub host_id: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RestoreRequest {
+    /// What happens when something new is at the original path.
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+pub async fn list_trash(
+    db: web::Data<Arc<Database>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    query: web::Query<TrashQuery>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    match db.get_trash_by_user(&claims.sub, query.host_id.as_deref()).await {
+        Ok(items) => {
+            let items: Vec<TrashItem> = items.into_iter().map(|item| trash.with_expiry(item)).collect();
+            HttpResponse::Ok().json(items)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to list trash: {}", e)
+        })),
+    }
+}
+
+pub async fn restore_trash_item(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+    req: Option<web::Json<RestoreRequest>>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let item = match load_owned_item(&db, &claims.sub, &path).await {
+        Ok(item) => item,
+        Err(resp) => return resp,
+    };
+    let backend = match open_item_host(&db, &sftp_pool, &claims.sub, &item).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    let overwrite = req.map(|req| req.overwrite).unwrap_or_default();
+    match trash.restore(&item, backend.as_ref(), overwrite).await {
+        Ok(path) => HttpResponse::Ok().json(json!({
+            "message": "Restored",
+            "path": path
+        })),
+        Err(e) => storage_error("restore from trash", e),
+    }
+}
+
+pub async fn purge_trash_item(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let item = match load_owned_item(&db, &claims.sub, &path).await {
+        Ok(item) => item,
+        Err(resp) => return resp,
+    };
+    let backend = match open_item_host(&db, &sftp_pool, &claims.sub, &item).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+
+    match trash.purge(&item, backend.as_ref()).await {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "message": "Purged"
+        })),
+        Err(e) => storage_error("purge from trash", e),
+    }
+}
+
+/// Purges every item of the user's trash, or of one host's. Stops at the
+/// first item that can't be purged; those before it stay purged.
+pub async fn empty_trash(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    trash: web::Data<Arc<TrashManager>>,
+    auth: BearerAuth,
+    query: web::Query<TrashQuery>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            }));
+        }
+    };
+
+    let mut items = match db.get_trash_by_user(&claims.sub, query.host_id.as_deref()).await {
+        Ok(items) => items,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "error": format!("Failed to list trash: {}", e)
+            }));
+        }
+    };
+    items.sort_by(|a, b| a.host_id.cmp(&b.host_id));
+
+    let mut purged = 0;
+    let mut backend: Option<(String, Box<dyn StorageBackend>)> = None;
+    for item in &items {
+        if backend.as_ref().is_none_or(|(host_id, _)| *host_id != item.host_id) {
+            match open_item_host(&db, &sftp_pool, &claims.sub, item).await {
+                Ok(opened) => backend = Some((item.host_id.clone(), opened)),
+                Err(resp) => return resp,
+            }
+        }
+        let (_, host) = backend.as_ref().unwrap();
+        if let Err(e) = trash.purge(item, host.as_ref()).await {
+            return storage_error("empty trash", e);
+        }
+        purged += 1;
+    }
+
+    HttpResponse::Ok().json(json!({
+        "purged": purged
+    }))
+}
+
+async fn load_owned_item(db: &Database, user_id: &str, item_id: &str) -> Result<TrashItem, HttpResponse> {
+    match db.get_trash_item(item_id).await {
+        Ok(Some(item)) if item.user_id == user_id => Ok(item),
+        Ok(Some(_)) => Err(HttpResponse::Forbidden().json(json!({
+            "error": "Access denied"
+        }))),
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "error": "Trash item not found"
+        }))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to get trash item: {}", e)
+        }))),
+    }
+}
+
+async fn open_item_host(
+    db: &Database,
+    sftp_pool: &Arc<SftpPool>,
+    user_id: &str,
+    item: &TrashItem,
+) -> Result<Box<dyn StorageBackend>, HttpResponse> {
+    let mut host = load_owned_host(db, user_id, &item.host_id).await?;
+    open_backend(db, sftp_pool, &mut host).await
+}
@@ -0,0 +1,578 @@
+//! Resumable uploads over the tus 1.0 protocol (core, creation, termination
+//! and expiration). Chunks are appended to a staging file whose offset is kept
+//! in SQLite; once the last byte arrives the file is written to its host.
+//! Uploads left unfinished are discarded once they expire.
+
+use super::files::{check_outside_trash, load_owned_host, open_backend, storage_error, UploadConfig};
+use crate::auth::verify_jwt;
+use crate::db::Database;
+use crate::hosts;
+use crate::hosts::pool::SftpPool;
+use crate::metrics::Metrics;
+use crate::models::TusUpload;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Received data is flushed and its offset persisted at least this often, so
+/// an interrupted PATCH loses little when the request is dropped outright.
+const PERSIST_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// How often expired uploads are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Uploads currently receiving a PATCH. A second concurrent PATCH (or a
+/// DELETE) for the same upload is refused rather than interleaved.
+#[derive(Default)]
+pub struct ActiveUploads(Mutex<HashSet<String>>);
+
+impl ActiveUploads {
+    fn claim(&self, id: &str) -> Option<ActiveClaim<'_>> {
+        let mut active = self.0.lock().unwrap();
+        active.insert(id.to_string()).then(|| ActiveClaim {
+            uploads: self,
+            id: id.to_string(),
+        })
+    }
+}
+
+struct ActiveClaim<'a> {
+    uploads: &'a ActiveUploads,
+    id: String,
+}
+
+impl Drop for ActiveClaim<'_> {
+    fn drop(&mut self) {
+        self.uploads.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder
+}
+
+fn tus_error(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    tus_response(status).json(json!({
+        "error": message.into()
+    }))
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Every request but OPTIONS must declare the protocol version it speaks.
+fn check_version(req: &HttpRequest) -> Result<(), HttpResponse> {
+    if header_str(req, "Tus-Resumable") == Some(TUS_VERSION) {
+        return Ok(());
+    }
+
+    Err(HttpResponse::PreconditionFailed()
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .json(json!({
+            "error": format!("Unsupported tus version; this server speaks {}", TUS_VERSION)
+        })))
+}
+
+/// Parses `Upload-Metadata`: comma-separated `key base64value` pairs where the
+/// value may be omitted.
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, encoded)) => {
+                let decoded = general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|_| format!("Invalid base64 value for metadata key {}", key))?;
+                let value = String::from_utf8(decoded)
+                    .map_err(|_| format!("Metadata value for {} is not UTF-8", key))?;
+                (key, value)
+            }
+            None => (pair, String::new()),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+
+    Ok(metadata)
+}
+
+fn staging_file(config: &UploadConfig, upload_id: &str) -> PathBuf {
+    config.staging_dir.join(upload_id)
+}
+
+/// `Upload-Expires`, when uploads expire at all.
+fn expires_header(config: &UploadConfig, upload: &TusUpload) -> Option<(&'static str, String)> {
+    let expires = upload.created_at + config.expiry?;
+    Some(("Upload-Expires", http_date(expires)))
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Forgets an unfinished upload and removes what was staged of it.
+async fn discard(db: &Database, config: &UploadConfig, upload_id: &str) -> anyhow::Result<()> {
+    db.delete_tus_upload(upload_id).await?;
+    let _ = fs::remove_file(staging_file(config, upload_id)).await;
+    Ok(())
+}
+
+/// Discards uploads created longer than `UploadConfig::expiry` ago, now and
+/// every `EXPIRY_INTERVAL`.
+pub fn spawn_upload_expiry(db: Arc<Database>, config: UploadConfig, active: Arc<ActiveUploads>) {
+    let Some(expiry) = config.expiry else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = expire_uploads(&db, &config, &active, Utc::now() - expiry).await {
+                log::error!("Failed to discard expired uploads: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Discards the uploads created before `cutoff`. One receiving data right
+/// now is left for the next round.
+async fn expire_uploads(
+    db: &Database,
+    config: &UploadConfig,
+    active: &ActiveUploads,
+    cutoff: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut expired = 0;
+    for upload in db.get_tus_uploads_created_before(cutoff).await? {
+        let Some(_claim) = active.claim(&upload.id) else {
+            continue;
+        };
+        discard(db, config, &upload.id).await?;
+        expired += 1;
+    }
+    if expired > 0 {
+        log::info!("Discarded {} expired upload(s)", expired);
+    }
+    Ok(())
+}
+
+/// Fetches an upload and checks that it belongs to `user_id`.
+async fn load_owned_upload(db: &Database, user_id: &str, upload_id: &str) -> Result<TusUpload, HttpResponse> {
+    match db.get_tus_upload(upload_id).await {
+        Ok(Some(upload)) if upload.user_id == user_id => Ok(upload),
+        Ok(Some(_)) => Err(tus_error(StatusCode::FORBIDDEN, "Access denied")),
+        Ok(None) => Err(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        Err(e) => Err(tus_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get upload: {}", e),
+        )),
+    }
+}
+
+/// Writes a complete staging file to its host, then forgets the upload.
+async fn commit(
+    db: &Database,
+    sftp_pool: &Arc<SftpPool>,
+    config: &UploadConfig,
+    upload: &TusUpload,
+) -> Result<(), HttpResponse> {
+    let mut host = load_owned_host(db, &upload.user_id, &upload.host_id).await?;
+    let backend = open_backend(db, sftp_pool, &mut host).await?;
+
+    let staging = staging_file(config, &upload.id);
+    let file = fs::File::open(&staging).await.map_err(|e| {
+        tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open staged upload: {}", e))
+    })?;
+
+    backend
+        .write_stream(&upload.path, Box::pin(ReaderStream::new(file)))
+        .await
+        .map_err(|e| storage_error("upload file", e))?;
+
+    let _ = fs::remove_file(&staging).await;
+    db.delete_tus_upload(&upload.id).await.map_err(|e| {
+        tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to finish upload: {}", e))
+    })
+}
+
+pub async fn tus_options(upload_config: web::Data<UploadConfig>) -> HttpResponse {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", upload_config.max_size.to_string()))
+        .finish()
+}
+
+/// Creation extension. `Upload-Metadata` must carry the target `host_id` and `path`.
+pub async fn tus_create(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    metrics: web::Data<Arc<Metrics>>,
+    upload_config: web::Data<UploadConfig>,
+    auth: BearerAuth,
+    http_req: HttpRequest,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => return tus_error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+    if let Err(resp) = check_version(&http_req) {
+        return resp;
+    }
+
+    let Some(length) = header_str(&http_req, "Upload-Length").and_then(|v| v.parse::<u64>().ok()) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Length");
+    };
+    if length > upload_config.max_size {
+        return tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Upload exceeds the maximum size of {} bytes", upload_config.max_size),
+        );
+    }
+
+    let metadata = match parse_metadata(header_str(&http_req, "Upload-Metadata").unwrap_or("")) {
+        Ok(metadata) => metadata,
+        Err(e) => return tus_error(StatusCode::BAD_REQUEST, e),
+    };
+    let (Some(host_id), Some(path)) = (metadata.get("host_id"), metadata.get("path")) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Upload-Metadata must include host_id and path");
+    };
+    let path = match hosts::normalize_path(path) {
+        Ok(path) => path,
+        Err(e) => return tus_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    if let Err(message) = check_outside_trash(&path) {
+        return tus_error(StatusCode::BAD_REQUEST, message);
+    }
+
+    // Each unfinished upload holds a staging file, so their number is capped
+    match db.count_tus_uploads_by_user(&claims.sub).await {
+        Ok(open) if open >= upload_config.max_open_per_user => {
+            return tus_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "At most {} uploads may be unfinished at once; finish or discard one first",
+                    upload_config.max_open_per_user
+                ),
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to count uploads: {}", e));
+        }
+    }
+
+    // Fail before any data is sent if the host cannot take the file
+    let mut host = match load_owned_host(&db, &claims.sub, host_id).await {
+        Ok(host) => host,
+        Err(resp) => return resp,
+    };
+    let backend = match open_backend(&db, &sftp_pool, &mut host).await {
+        Ok(backend) => backend,
+        Err(resp) => return resp,
+    };
+    if !backend.capabilities().write {
+        return storage_error("upload file", backend.unsupported("Write"));
+    }
+
+    let upload = TusUpload {
+        id: Uuid::new_v4().to_string(),
+        user_id: claims.sub.clone(),
+        host_id: host.id.clone(),
+        path,
+        length,
+        offset: 0,
+        created_at: Utc::now(),
+    };
+
+    let staged = async {
+        fs::create_dir_all(&upload_config.staging_dir).await?;
+        fs::File::create(staging_file(&upload_config, &upload.id)).await?;
+        anyhow::Ok(())
+    };
+    if let Err(e) = staged.await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to stage upload: {}", e));
+    }
+    if let Err(e) = db.create_tus_upload(&upload).await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create upload: {}", e));
+    }
+
+    // An empty file is complete as soon as it exists
+    if length == 0 {
+        if let Err(resp) = commit(&db, &sftp_pool, &upload_config, &upload).await {
+            return resp;
+        }
+        metrics.file_uploads.inc();
+    }
+
+    let mut resp = tus_response(StatusCode::CREATED);
+    resp.insert_header((header::LOCATION, format!("{}/{}", http_req.path().trim_end_matches('/'), upload.id)));
+    if let Some(expires) = expires_header(&upload_config, &upload).filter(|_| length > 0) {
+        resp.insert_header(expires);
+    }
+    resp.finish()
+}
+
+pub async fn tus_head(
+    db: web::Data<Arc<Database>>,
+    upload_config: web::Data<UploadConfig>,
+    auth: BearerAuth,
+    http_req: HttpRequest,
+    upload_id: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => return tus_error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+    if let Err(resp) = check_version(&http_req) {
+        return resp;
+    }
+
+    match load_owned_upload(&db, &claims.sub, &upload_id).await {
+        Ok(upload) => {
+            let mut resp = tus_response(StatusCode::OK);
+            resp.insert_header(("Upload-Offset", upload.offset.to_string()))
+                .insert_header(("Upload-Length", upload.length.to_string()))
+                .insert_header((header::CACHE_CONTROL, "no-store"));
+            if let Some(expires) = expires_header(&upload_config, &upload) {
+                resp.insert_header(expires);
+            }
+            resp.finish()
+        }
+        Err(resp) => resp,
+    }
+}
+
+/// Core protocol: appends the body at `Upload-Offset`, which must match the
+/// stored offset. The upload is written to its host once complete.
+#[allow(clippy::too_many_arguments)]
+pub async fn tus_patch(
+    db: web::Data<Arc<Database>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+    metrics: web::Data<Arc<Metrics>>,
+    upload_config: web::Data<UploadConfig>,
+    active: web::Data<Arc<ActiveUploads>>,
+    auth: BearerAuth,
+    http_req: HttpRequest,
+    upload_id: web::Path<String>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => return tus_error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+    if let Err(resp) = check_version(&http_req) {
+        return resp;
+    }
+    if header_str(&http_req, header::CONTENT_TYPE.as_str()) != Some(OFFSET_CONTENT_TYPE) {
+        return tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Content-Type must be {}", OFFSET_CONTENT_TYPE),
+        );
+    }
+    let Some(client_offset) = header_str(&http_req, "Upload-Offset").and_then(|v| v.parse::<u64>().ok()) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Offset");
+    };
+
+    let mut upload = match load_owned_upload(&db, &claims.sub, &upload_id).await {
+        Ok(upload) => upload,
+        Err(resp) => return resp,
+    };
+    let Some(_claim) = active.claim(&upload.id) else {
+        return tus_error(StatusCode::LOCKED, "Upload is already receiving data");
+    };
+    if client_offset != upload.offset {
+        return tus_response(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", upload.offset.to_string()))
+            .json(json!({
+                "error": format!("Upload-Offset {} does not match the current offset {}", client_offset, upload.offset)
+            }));
+    }
+
+    let staging = staging_file(&upload_config, &upload.id);
+    let opened = async {
+        let mut file = fs::OpenOptions::new().write(true).open(&staging).await?;
+        // Bytes past the persisted offset came from a request that was dropped
+        // before it could record them; they are received again
+        file.set_len(upload.offset).await?;
+        file.seek(SeekFrom::End(0)).await?;
+        anyhow::Ok(file)
+    };
+    let mut file = match opened.await {
+        Ok(file) => file,
+        Err(e) => return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open staged upload: {}", e)),
+    };
+
+    let mut persisted = upload.offset;
+    let mut failure = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                failure = Some(tus_error(StatusCode::BAD_REQUEST, format!("Failed to read chunk: {}", e)));
+                break;
+            }
+        };
+
+        if upload.offset + chunk.len() as u64 > upload.length {
+            failure = Some(tus_error(StatusCode::BAD_REQUEST, "Body exceeds the declared Upload-Length"));
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            failure = Some(tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to stage chunk: {}", e)));
+            break;
+        }
+        upload.offset += chunk.len() as u64;
+
+        if upload.offset - persisted >= PERSIST_INTERVAL
+            && file.sync_data().await.is_ok()
+            && db.set_tus_upload_offset(&upload.id, upload.offset).await.is_ok()
+        {
+            persisted = upload.offset;
+        }
+    }
+
+    // Whatever reached the staging file is kept, even when the body was cut short
+    let saved = async {
+        file.sync_data().await?;
+        db.set_tus_upload_offset(&upload.id, upload.offset).await
+    };
+    if let Err(e) = saved.await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to record upload offset: {}", e));
+    }
+    drop(file);
+
+    if let Some(resp) = failure {
+        return resp;
+    }
+
+    if upload.offset == upload.length {
+        if let Err(resp) = commit(&db, &sftp_pool, &upload_config, &upload).await {
+            return resp;
+        }
+        metrics.file_uploads.inc();
+    }
+
+    let mut resp = tus_response(StatusCode::NO_CONTENT);
+    resp.insert_header(("Upload-Offset", upload.offset.to_string()));
+    if let Some(expires) = expires_header(&upload_config, &upload).filter(|_| upload.offset < upload.length) {
+        resp.insert_header(expires);
+    }
+    resp.finish()
+}
+
+/// Termination extension: discards an unfinished upload.
+pub async fn tus_delete(
+    db: web::Data<Arc<Database>>,
+    upload_config: web::Data<UploadConfig>,
+    active: web::Data<Arc<ActiveUploads>>,
+    auth: BearerAuth,
+    http_req: HttpRequest,
+    upload_id: web::Path<String>,
+) -> HttpResponse {
+    let claims = match verify_jwt(auth.token()) {
+        Ok(claims) => claims,
+        Err(_) => return tus_error(StatusCode::UNAUTHORIZED, "Invalid token"),
+    };
+    if let Err(resp) = check_version(&http_req) {
+        return resp;
+    }
+
+    let upload = match load_owned_upload(&db, &claims.sub, &upload_id).await {
+        Ok(upload) => upload,
+        Err(resp) => return resp,
+    };
+    let Some(_claim) = active.claim(&upload.id) else {
+        return tus_error(StatusCode::LOCKED, "Upload is receiving data");
+    };
+
+    if let Err(e) = discard(&db, &upload_config, &upload.id).await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete upload: {}", e));
+    }
+
+    tus_response(StatusCode::NO_CONTENT).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn parses_upload_metadata() {
+        let metadata = parse_metadata("host_id aG9zdC0x, path L2ltYWdlcy9kaXNrLmltZw==,is_confidential").unwrap();
+
+        assert_eq!(metadata["host_id"], "host-1");
+        assert_eq!(metadata["path"], "/images/disk.img");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_metadata("path not-base64!").is_err());
+        assert!(parse_metadata("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn requires_supported_protocol_version() {
+        let req = TestRequest::default().insert_header(("Tus-Resumable", "1.0.0")).to_http_request();
+        assert!(check_version(&req).is_ok());
+
+        let req = TestRequest::default().insert_header(("Tus-Resumable", "0.2.2")).to_http_request();
+        let resp = check_version(&req).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(resp.headers().get("Tus-Version").unwrap(), TUS_VERSION);
+    }
+
+    #[test]
+    fn upload_expiry_is_an_http_date() {
+        let created_at = DateTime::parse_from_rfc3339("2024-03-02T09:15:00Z").unwrap().with_timezone(&Utc);
+        let upload = TusUpload {
+            id: "upload-1".to_string(),
+            user_id: "user-1".to_string(),
+            host_id: "host-1".to_string(),
+            path: "/disk.img".to_string(),
+            length: 10,
+            offset: 0,
+            created_at,
+        };
+        let mut config = UploadConfig {
+            max_size: 10,
+            staging_dir: PathBuf::from("uploads"),
+            expiry: Some(chrono::Duration::hours(24)),
+            max_open_per_user: 1,
+        };
+
+        assert_eq!(expires_header(&config, &upload).unwrap().1, "Sun, 03 Mar 2024 09:15:00 GMT");
+        config.expiry = None;
+        assert!(expires_header(&config, &upload).is_none());
+    }
+
+    #[test]
+    fn uploads_can_only_be_claimed_once() {
+        let active = ActiveUploads::default();
+
+        let claim = active.claim("a").unwrap();
+        assert!(active.claim("a").is_none());
+        assert!(active.claim("b").is_some());
+
+        drop(claim);
+        assert!(active.claim("a").is_some());
+    }
+}
@@ -1,8 +1,8 @@
-use crate::models::{Host, HostConfig, HostType, User};
+use crate::models::{Host, HostConfig, HostType, Job, JobStatus, ProgressSnapshot, TrashItem, TusUpload, User};
 use anyhow::{anyhow, Context, Result};
 use log::info;
-use serde_json;
 
+use sqlx::sqlite::SqliteRow;
 use sqlx::{Row, SqlitePool};
 use std::env;
 
@@ -126,6 +126,118 @@ impl Database {
         .await
         .context("Failed to create hosts table")?;
 
+        // Columns added after the initial schema
+        self.add_column_if_missing("hosts", "host_key_fingerprint", "TEXT")
+            .await?;
+
+        // resumable uploads staged on disk until complete
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tus_uploads (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                length INTEGER NOT NULL,
+                upload_offset INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+                FOREIGN KEY(host_id) REFERENCES hosts(id) ON DELETE CASCADE
+            );
+        "#,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to create tus_uploads table")?;
+
+        // background jobs, kept after they finish so clients can see the outcome
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS jobs (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                target_host_id TEXT,
+                target_path TEXT,
+                overwrite TEXT NOT NULL,
+                status TEXT NOT NULL,
+                bytes_total INTEGER NOT NULL DEFAULT 0,
+                bytes_done INTEGER NOT NULL DEFAULT 0,
+                files_total INTEGER NOT NULL DEFAULT 0,
+                files_done INTEGER NOT NULL DEFAULT 0,
+                result_path TEXT,
+                partial_path TEXT,
+                error TEXT,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+                FOREIGN KEY(host_id) REFERENCES hosts(id) ON DELETE CASCADE,
+                FOREIGN KEY(target_host_id) REFERENCES hosts(id) ON DELETE CASCADE
+            );
+        "#,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to create jobs table")?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS jobs_by_status ON jobs (status, created_at)")
+            .execute(&self.pool)
+            .await
+            .context("Failed to create jobs index")?;
+
+        // JSON array of the paths a compress job archives
+        self.add_column_if_missing("jobs", "sources", "TEXT").await?;
+        // delete jobs that skip the trash
+        self.add_column_if_missing("jobs", "permanent", "INTEGER NOT NULL DEFAULT 0").await?;
+
+        // deleted entries waiting in their host's trash area
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS trash (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                original_path TEXT NOT NULL,
+                trash_path TEXT NOT NULL,
+                is_dir INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                deleted_at TEXT NOT NULL,
+                FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+                FOREIGN KEY(host_id) REFERENCES hosts(id) ON DELETE CASCADE
+            );
+        "#,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to create trash table")?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS trash_by_deleted_at ON trash (deleted_at)")
+            .execute(&self.pool)
+            .await
+            .context("Failed to create trash index")?;
+
+        Ok(())
+    }
+
+    /// SQLite has no `ADD COLUMN IF NOT EXISTS`, so check `pragma_table_info` first.
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_optional(&self.pool)
+            .await
+            .with_context(|| format!("Failed to inspect {} table", table))?;
+
+        if exists.is_none() {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await
+                .with_context(|| format!("Failed to add {}.{} column", table, column))?;
+        }
+
         Ok(())
     }
 
@@ -191,12 +303,7 @@ impl Database {
         }
 
         let created_at = host.created_at.to_rfc3339();
-        let host_type = match &host.host_type {
-            HostType::Local => "local",
-            HostType::Http => "http",
-            HostType::Sftp => "sftp",
-        }
-        .to_string();
+        let host_type = host.host_type.as_str();
 
         let config_json =
             serde_json::to_string(&host.config).context("Failed to serialize host config")?;
@@ -211,7 +318,7 @@ impl Database {
         .bind(&host.id)
         .bind(&host.user_id)
         .bind(&host.name)
-        .bind(&host_type)
+        .bind(host_type)
         .bind(&config_json)
         .bind(&created_at)
         .execute(&self.pool)
@@ -229,103 +336,110 @@ impl Database {
                 );
 
                 // Return an anyhow error with the SQL error message so handlers can decide response
-                return Err(anyhow!("Failed to insert host: {}", e));
+                Err(anyhow!("Failed to insert host: {}", e))
             }
         }
     }
 
     pub async fn get_host(&self, host_id: &str) -> Result<Option<Host>> {
         let row = sqlx::query(
-            "SELECT id, user_id, name, host_type, config, created_at FROM hosts WHERE id = ?",
+            "SELECT id, user_id, name, host_type, config, created_at, host_key_fingerprint FROM hosts WHERE id = ?",
         )
         .bind(host_id)
         .fetch_optional(&self.pool)
         .await
         .context("Failed to query host by id")?;
 
-        if let Some(r) = row {
-            let id: String = r.try_get("id")?;
-            let user_id: String = r.try_get("user_id")?;
-            let name: String = r.try_get("name")?;
-            let host_type_str: String = r.try_get("host_type")?;
-            let config_str: String = r.try_get("config")?;
-            let created_at_str: String = r.try_get("created_at")?;
-            let created_at = chrono::DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&chrono::Utc))
-                .context("Failed to parse host created_at")?;
-
-            let host_type = match host_type_str.as_str() {
-                "local" => HostType::Local,
-                "http" => HostType::Http,
-                "sftp" => HostType::Sftp,
-                other => {
-                    // default to local if unknown
-                    log::warn!("Unknown host_type '{}' for host {}", other, id);
-                    HostType::Local
-                }
-            };
-
-            let config: HostConfig =
-                serde_json::from_str(&config_str).context("Failed to deserialize host config")?;
-
-            Ok(Some(Host {
-                id,
-                user_id,
-                name,
-                host_type,
-                config,
-                created_at,
-            }))
-        } else {
-            Ok(None)
-        }
+        row.as_ref().map(Self::host_from_row).transpose()
     }
 
     pub async fn get_hosts_by_user(&self, user_id: &str) -> Result<Vec<Host>> {
         let rows = sqlx::query(
-            "SELECT id, user_id, name, host_type, config, created_at FROM hosts WHERE user_id = ?",
+            "SELECT id, user_id, name, host_type, config, created_at, host_key_fingerprint FROM hosts WHERE user_id = ?",
         )
         .bind(user_id)
         .fetch_all(&self.pool)
         .await
         .context("Failed to query hosts by user")?;
 
-        let mut hosts = Vec::new();
-        for r in rows {
-            let id: String = r.try_get("id")?;
-            let user_id: String = r.try_get("user_id")?;
-            let name: String = r.try_get("name")?;
-            let host_type_str: String = r.try_get("host_type")?;
-            let config_str: String = r.try_get("config")?;
-            let created_at_str: String = r.try_get("created_at")?;
-            let created_at = chrono::DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&chrono::Utc))
-                .context("Failed to parse host created_at")?;
-
-            let host_type = match host_type_str.as_str() {
-                "local" => HostType::Local,
-                "http" => HostType::Http,
-                "sftp" => HostType::Sftp,
-                other => {
-                    log::warn!("Unknown host_type '{}' for host {}", other, id);
-                    HostType::Local
-                }
-            };
+        rows.iter().map(Self::host_from_row).collect()
+    }
 
-            let config: HostConfig =
-                serde_json::from_str(&config_str).context("Failed to deserialize host config")?;
+    pub async fn get_all_hosts(&self) -> Result<Vec<Host>> {
+        let rows = sqlx::query(
+            "SELECT id, user_id, name, host_type, config, created_at, host_key_fingerprint FROM hosts",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to query hosts")?;
 
-            hosts.push(Host {
-                id,
-                user_id,
-                name,
-                host_type,
-                config,
-                created_at,
-            });
-        }
+        rows.iter().map(Self::host_from_row).collect()
+    }
 
-        Ok(hosts)
+    fn host_from_row(r: &SqliteRow) -> Result<Host> {
+        let id: String = r.try_get("id")?;
+        let user_id: String = r.try_get("user_id")?;
+        let name: String = r.try_get("name")?;
+        let host_type_str: String = r.try_get("host_type")?;
+        let config_str: String = r.try_get("config")?;
+        let created_at_str: String = r.try_get("created_at")?;
+        let host_key_fingerprint: Option<String> = r.try_get("host_key_fingerprint")?;
+        let created_at = chrono::DateTime::parse_from_rfc3339(&created_at_str)
+            .map(|dt| dt.with_timezone(&chrono::Utc))
+            .context("Failed to parse host created_at")?;
+
+        let host_type = host_type_str.parse::<HostType>().unwrap_or_else(|_| {
+            // default to local if unknown
+            log::warn!("Unknown host_type '{}' for host {}", host_type_str, id);
+            HostType::Local
+        });
+
+        let config: HostConfig =
+            serde_json::from_str(&config_str).context("Failed to deserialize host config")?;
+
+        Ok(Host {
+            id,
+            user_id,
+            name,
+            host_type,
+            config,
+            created_at,
+            host_key_fingerprint,
+        })
+    }
+
+    /// Records `fingerprint` as the trusted SSH host key unless one is already
+    /// stored, and returns whichever key is trusted afterwards.
+    pub async fn trust_host_key_if_unset(&self, host_id: &str, fingerprint: &str) -> Result<String> {
+        sqlx::query(
+            "UPDATE hosts SET host_key_fingerprint = ? WHERE id = ? AND host_key_fingerprint IS NULL",
+        )
+        .bind(fingerprint)
+        .bind(host_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record host key")?;
+
+        let trusted: Option<String> =
+            sqlx::query_scalar("SELECT host_key_fingerprint FROM hosts WHERE id = ?")
+                .bind(host_id)
+                .fetch_optional(&self.pool)
+                .await
+                .context("Failed to query host key")?
+                .flatten();
+
+        trusted.ok_or_else(|| anyhow!("Host not found"))
+    }
+
+    pub async fn set_host_key(&self, host_id: &str, fingerprint: &str) -> Result<()> {
+        sqlx::query("UPDATE hosts SET host_key_fingerprint = ? WHERE id = ?")
+            .bind(fingerprint)
+            .bind(host_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to update host key")?;
+
+        Ok(())
     }
 
     pub async fn delete_host(&self, host_id: &str) -> Result<()> {
@@ -337,4 +451,421 @@ impl Database {
 
         Ok(())
     }
+
+    pub async fn create_tus_upload(&self, upload: &TusUpload) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO tus_uploads (id, user_id, host_id, path, length, upload_offset, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(&upload.id)
+        .bind(&upload.user_id)
+        .bind(&upload.host_id)
+        .bind(&upload.path)
+        .bind(upload.length as i64)
+        .bind(upload.offset as i64)
+        .bind(upload.created_at.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .context("Failed to insert upload")?;
+
+        Ok(())
+    }
+
+    pub async fn get_tus_upload(&self, upload_id: &str) -> Result<Option<TusUpload>> {
+        let row = sqlx::query(&format!("SELECT {} FROM tus_uploads WHERE id = ?", TUS_COLUMNS))
+            .bind(upload_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query upload")?;
+
+        row.as_ref().map(Self::tus_upload_from_row).transpose()
+    }
+
+    pub async fn count_tus_uploads_by_user(&self, user_id: &str) -> Result<u64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tus_uploads WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to count uploads")?;
+
+        Ok(count as u64)
+    }
+
+    pub async fn get_tus_uploads_created_before(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<TusUpload>> {
+        let rows = sqlx::query(&format!("SELECT {} FROM tus_uploads WHERE created_at < ?", TUS_COLUMNS))
+            .bind(cutoff.to_rfc3339())
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query expired uploads")?;
+
+        rows.iter().map(Self::tus_upload_from_row).collect()
+    }
+
+    pub async fn set_tus_upload_offset(&self, upload_id: &str, offset: u64) -> Result<()> {
+        sqlx::query("UPDATE tus_uploads SET upload_offset = ? WHERE id = ?")
+            .bind(offset as i64)
+            .bind(upload_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to update upload offset")?;
+
+        Ok(())
+    }
+
+    pub async fn delete_tus_upload(&self, upload_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+            .bind(upload_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete upload")?;
+
+        Ok(())
+    }
+
+    pub async fn create_job(&self, job: &Job) -> Result<()> {
+        let sources = match job.sources.as_slice() {
+            [] => None,
+            sources => Some(serde_json::to_string(sources).context("Failed to serialize job sources")?),
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO jobs (id, user_id, kind, host_id, path, target_host_id, target_path, sources, overwrite,
+                              permanent, status, attempts, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(&job.id)
+        .bind(&job.user_id)
+        .bind(job.kind.as_str())
+        .bind(&job.host_id)
+        .bind(&job.path)
+        .bind(&job.target_host_id)
+        .bind(&job.target_path)
+        .bind(sources)
+        .bind(job.overwrite.as_str())
+        .bind(job.permanent)
+        .bind(job.status.as_str())
+        .bind(job.attempts as i64)
+        .bind(job.created_at.to_rfc3339())
+        .bind(job.updated_at.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .context("Failed to insert job")?;
+
+        Ok(())
+    }
+
+    pub async fn get_job(&self, job_id: &str) -> Result<Option<Job>> {
+        let row = sqlx::query(&format!("SELECT {} FROM jobs WHERE id = ?", JOB_COLUMNS))
+            .bind(job_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query job")?;
+
+        row.as_ref().map(Self::job_from_row).transpose()
+    }
+
+    /// The user's most recent jobs, newest first.
+    pub async fn get_jobs_by_user(&self, user_id: &str, limit: u32) -> Result<Vec<Job>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM jobs WHERE user_id = ? ORDER BY created_at DESC LIMIT ?",
+            JOB_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to query jobs by user")?;
+
+        rows.iter().map(Self::job_from_row).collect()
+    }
+
+    /// Queued jobs, oldest first.
+    pub async fn get_queued_jobs(&self) -> Result<Vec<Job>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM jobs WHERE status = 'queued' ORDER BY created_at",
+            JOB_COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to query queued jobs")?;
+
+        rows.iter().map(Self::job_from_row).collect()
+    }
+
+    /// Moves a queued job to running. Returns false if it was no longer queued,
+    /// e.g. because it was cancelled meanwhile.
+    pub async fn start_job(&self, job_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'running', attempts = attempts + 1, error = NULL, updated_at = ?
+            WHERE id = ? AND status = 'queued'
+        "#,
+        )
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(job_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to start job")?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn set_job_progress(&self, job_id: &str, progress: &ProgressSnapshot) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE jobs SET bytes_total = ?, bytes_done = ?, files_total = ?, files_done = ?, updated_at = ?
+            WHERE id = ?
+        "#,
+        )
+        .bind(progress.bytes_total as i64)
+        .bind(progress.bytes_done as i64)
+        .bind(progress.files_total as i64)
+        .bind(progress.files_done as i64)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(job_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to update job progress")?;
+
+        Ok(())
+    }
+
+    pub async fn set_job_partial_path(&self, job_id: &str, partial_path: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE jobs SET partial_path = ? WHERE id = ?")
+            .bind(partial_path)
+            .bind(job_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to update job partial path")?;
+
+        Ok(())
+    }
+
+    /// Records how a running job ended.
+    pub async fn finish_job(
+        &self,
+        job_id: &str,
+        status: JobStatus,
+        result_path: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE jobs SET status = ?, result_path = ?, error = ?, updated_at = ? WHERE id = ?",
+        )
+        .bind(status.as_str())
+        .bind(result_path)
+        .bind(error)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(job_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to finish job")?;
+
+        Ok(())
+    }
+
+    /// Cancels a job that has not started. Returns false if it is not queued.
+    pub async fn cancel_queued_job(&self, job_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE jobs SET status = 'cancelled', updated_at = ? WHERE id = ? AND status = 'queued'",
+        )
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(job_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to cancel job")?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Queues a failed or cancelled job again from scratch. Returns false if
+    /// the job is not in one of those states.
+    pub async fn retry_job(&self, job_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'queued', bytes_total = 0, bytes_done = 0, files_total = 0,
+                            files_done = 0, result_path = NULL, error = NULL, updated_at = ?
+            WHERE id = ? AND status IN ('failed', 'cancelled')
+        "#,
+        )
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(job_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to retry job")?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Puts jobs that were running when the server stopped back in the queue.
+    pub async fn requeue_interrupted_jobs(&self) -> Result<u64> {
+        let result = sqlx::query("UPDATE jobs SET status = 'queued', updated_at = ? WHERE status = 'running'")
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(&self.pool)
+            .await
+            .context("Failed to requeue interrupted jobs")?;
+
+        Ok(result.rows_affected())
+    }
+
+    fn job_from_row(r: &SqliteRow) -> Result<Job> {
+        let id: String = r.try_get("id")?;
+        let kind: String = r.try_get("kind")?;
+        let overwrite: String = r.try_get("overwrite")?;
+        let status: String = r.try_get("status")?;
+        let count = |column: &str| -> Result<u64> { Ok(r.try_get::<i64, _>(column)? as u64) };
+        let timestamp = |column: &str| -> Result<chrono::DateTime<chrono::Utc>> {
+            let value: String = r.try_get(column)?;
+            chrono::DateTime::parse_from_rfc3339(&value)
+                .map(|dt| dt.with_timezone(&chrono::Utc))
+                .with_context(|| format!("Failed to parse job {}", column))
+        };
+
+        Ok(Job {
+            user_id: r.try_get("user_id")?,
+            kind: kind.parse().map_err(|e: String| anyhow!("Job {}: {}", id, e))?,
+            host_id: r.try_get("host_id")?,
+            path: r.try_get("path")?,
+            target_host_id: r.try_get("target_host_id")?,
+            target_path: r.try_get("target_path")?,
+            sources: match r.try_get::<Option<String>, _>("sources")? {
+                Some(sources) => serde_json::from_str(&sources).with_context(|| format!("Failed to parse job {} sources", id))?,
+                None => Vec::new(),
+            },
+            overwrite: overwrite.parse().map_err(|e: String| anyhow!("Job {}: {}", id, e))?,
+            permanent: r.try_get("permanent")?,
+            status: status.parse().map_err(|e: String| anyhow!("Job {}: {}", id, e))?,
+            progress: ProgressSnapshot {
+                bytes_total: count("bytes_total")?,
+                bytes_done: count("bytes_done")?,
+                files_total: count("files_total")?,
+                files_done: count("files_done")?,
+            },
+            result_path: r.try_get("result_path")?,
+            partial_path: r.try_get("partial_path")?,
+            error: r.try_get("error")?,
+            attempts: count("attempts")? as u32,
+            created_at: timestamp("created_at")?,
+            updated_at: timestamp("updated_at")?,
+            id,
+        })
+    }
+
+    pub async fn create_trash_item(&self, item: &TrashItem) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO trash (id, user_id, host_id, original_path, trash_path, is_dir, size, deleted_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(&item.id)
+        .bind(&item.user_id)
+        .bind(&item.host_id)
+        .bind(&item.original_path)
+        .bind(&item.trash_path)
+        .bind(item.is_dir)
+        .bind(item.size as i64)
+        .bind(item.deleted_at.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .context("Failed to insert trash item")?;
+
+        Ok(())
+    }
+
+    pub async fn get_trash_item(&self, item_id: &str) -> Result<Option<TrashItem>> {
+        let row = sqlx::query(&format!("SELECT {} FROM trash WHERE id = ?", TRASH_COLUMNS))
+            .bind(item_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query trash item")?;
+
+        row.as_ref().map(Self::trash_item_from_row).transpose()
+    }
+
+    /// The user's trash, or one host's part of it, most recently deleted first.
+    pub async fn get_trash_by_user(&self, user_id: &str, host_id: Option<&str>) -> Result<Vec<TrashItem>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM trash WHERE user_id = ? AND (? IS NULL OR host_id = ?) ORDER BY deleted_at DESC",
+            TRASH_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(host_id)
+        .bind(host_id)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to query trash")?;
+
+        rows.iter().map(Self::trash_item_from_row).collect()
+    }
+
+    /// Items of every user deleted before `cutoff`.
+    pub async fn get_trash_deleted_before(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<TrashItem>> {
+        let rows = sqlx::query(&format!("SELECT {} FROM trash WHERE deleted_at < ?", TRASH_COLUMNS))
+            .bind(cutoff.to_rfc3339())
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query expired trash")?;
+
+        rows.iter().map(Self::trash_item_from_row).collect()
+    }
+
+    pub async fn delete_trash_item(&self, item_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM trash WHERE id = ?")
+            .bind(item_id)
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete trash item")?;
+
+        Ok(())
+    }
+
+    fn tus_upload_from_row(r: &SqliteRow) -> Result<TusUpload> {
+        let length: i64 = r.try_get("length")?;
+        let offset: i64 = r.try_get("upload_offset")?;
+        let created_at_str: String = r.try_get("created_at")?;
+        let created_at = chrono::DateTime::parse_from_rfc3339(&created_at_str)
+            .map(|dt| dt.with_timezone(&chrono::Utc))
+            .context("Failed to parse upload created_at")?;
+
+        Ok(TusUpload {
+            id: r.try_get("id")?,
+            user_id: r.try_get("user_id")?,
+            host_id: r.try_get("host_id")?,
+            path: r.try_get("path")?,
+            length: length as u64,
+            offset: offset as u64,
+            created_at,
+        })
+    }
+
+    fn trash_item_from_row(r: &SqliteRow) -> Result<TrashItem> {
+        let deleted_at: String = r.try_get("deleted_at")?;
+        let deleted_at = chrono::DateTime::parse_from_rfc3339(&deleted_at)
+            .map(|dt| dt.with_timezone(&chrono::Utc))
+            .context("Failed to parse trash item deleted_at")?;
+
+        Ok(TrashItem {
+            id: r.try_get("id")?,
+            user_id: r.try_get("user_id")?,
+            host_id: r.try_get("host_id")?,
+            original_path: r.try_get("original_path")?,
+            trash_path: r.try_get("trash_path")?,
+            is_dir: r.try_get("is_dir")?,
+            size: r.try_get::<i64, _>("size")? as u64,
+            deleted_at,
+            expires_at: None,
+        })
+    }
 }
+
+const JOB_COLUMNS: &str = "id, user_id, kind, host_id, path, target_host_id, target_path, sources, overwrite, permanent, status, \
+    bytes_total, bytes_done, files_total, files_done, result_path, partial_path, error, attempts, created_at, updated_at";
+
+const TUS_COLUMNS: &str = "id, user_id, host_id, path, length, upload_offset, created_at";
+
+const TRASH_COLUMNS: &str = "id, user_id, host_id, original_path, trash_path, is_dir, size, deleted_at";
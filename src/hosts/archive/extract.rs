@@ -0,0 +1,242 @@
+//! Extracting an archive on a host into a directory of the same host.
+//! Member names come from whoever made the archive, so each one is checked
+//! to stay inside the directory before anything is written.
+
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::tarball::TarReader;
+use super::{zipfile, ArchiveKind};
+use crate::hosts::bridge::{channel_stream, STREAM_BUFFER_CHUNKS};
+use crate::hosts::transfer::{self, TransferProgress};
+use crate::hosts::{is_within, join_path, normalize_path, StorageBackend};
+
+/// Extracts the archive at `path` into the directory `to`, which must not
+/// exist yet; a failed extraction removes it. Links in the archive are
+/// skipped, so nothing is ever written through one.
+pub async fn extract(
+    backend: &dyn StorageBackend,
+    path: &str,
+    to: &str,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let kind = ArchiveKind::detect(path).with_context(|| format!("{} is not an archive", path))?;
+
+    let mut dirs = Directories::new(backend);
+    let result = match dirs.create(to).await {
+        Ok(()) if kind == ArchiveKind::Zip => extract_zip(backend, path, to, &mut dirs, progress).await,
+        Ok(()) => extract_tar(backend, path, kind, to, &mut dirs, progress).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        transfer::discard(backend, to).await;
+    }
+    result?;
+    progress.finish();
+    Ok(())
+}
+
+async fn extract_zip(
+    backend: &dyn StorageBackend,
+    path: &str,
+    to: &str,
+    dirs: &mut Directories<'_>,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let (size, entries) = zipfile::directory(backend, path).await?;
+    let files = entries.iter().filter(|e| !e.member.is_dir);
+    progress.start(files.clone().map(|e| e.member.size).sum(), files.count() as u64);
+
+    for entry in &entries {
+        if progress.is_cancelled() {
+            anyhow::bail!("Transfer cancelled");
+        }
+        let dest = extraction_path(to, &entry.member.name)?;
+        if entry.member.is_dir {
+            dirs.create(&dest).await?;
+            continue;
+        }
+
+        dirs.create_parent(&dest).await?;
+        let file = zipfile::open_entry(backend, path, size, entry).await?;
+        backend.write_stream(&dest, progress.count(file.stream)).await?;
+        progress.file_done();
+    }
+    Ok(())
+}
+
+/// Tar archives are read in one pass, so progress counts the archive's own
+/// bytes, and files as they turn up.
+async fn extract_tar(
+    backend: &dyn StorageBackend,
+    path: &str,
+    kind: ArchiveKind,
+    to: &str,
+    dirs: &mut Directories<'_>,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let file = backend.read_stream(path, None).await?;
+    progress.start(file.size, 0);
+    let mut tar = TarReader::new(progress.count(file.stream), kind)?;
+
+    while let Some(member) = tar.next().await? {
+        let Some(member) = member else {
+            continue;
+        };
+        let dest = extraction_path(to, &member.name)?;
+        if member.is_dir {
+            dirs.create(&dest).await?;
+            continue;
+        }
+
+        progress.file_found();
+        dirs.create_parent(&dest).await?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        let (forwarded, written) = tokio::join!(tar.forward(&member, tx), backend.write_stream(&dest, channel_stream(rx)));
+        forwarded?;
+        written?;
+        progress.file_done();
+    }
+    Ok(())
+}
+
+/// Where the member `name` goes when extracting into `dir`. Names that are
+/// absolute or climb out of `dir` fail the extraction rather than being
+/// skipped, so a tampered archive never extracts halfway unnoticed.
+pub fn extraction_path(dir: &str, name: &str) -> Result<String> {
+    // Archives made on Windows may use either separator
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') {
+        anyhow::bail!("Path traversal attempt detected");
+    }
+    let path = join_path(dir, normalize_path(&name)?.trim_start_matches('/'));
+    let path = normalize_path(&path)?;
+    if !is_within(&path, dir) {
+        anyhow::bail!("Path traversal attempt detected");
+    }
+    Ok(path)
+}
+
+/// Creates the directories files are extracted into, each only once.
+/// Archives needn't list a directory ahead of its contents, or at all.
+struct Directories<'a> {
+    backend: &'a dyn StorageBackend,
+    created: HashSet<String>,
+}
+
+impl<'a> Directories<'a> {
+    fn new(backend: &'a dyn StorageBackend) -> Self {
+        Self {
+            backend,
+            created: HashSet::new(),
+        }
+    }
+
+    /// Creates `dir` and any of its parents not created yet.
+    async fn create(&mut self, dir: &str) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut next = Some(dir);
+        while let Some(dir) = next.filter(|dir| !self.created.contains(*dir) && *dir != "/") {
+            missing.push(dir.to_string());
+            next = dir.rsplit_once('/').map(|(parent, _)| if parent.is_empty() { "/" } else { parent });
+        }
+
+        for dir in missing.into_iter().rev() {
+            self.backend.mkdir(&dir).await?;
+            self.created.insert(dir);
+        }
+        Ok(())
+    }
+
+    async fn create_parent(&mut self, path: &str) -> Result<()> {
+        match path.rsplit_once('/') {
+            Some((parent, _)) if !parent.is_empty() => self.create(parent).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::archive::compress;
+    use crate::hosts::local::LocalFileSystem;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn host_with_tree() -> (TempDir, Arc<dyn StorageBackend>) {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("share/photos/2024")).unwrap();
+        std::fs::write(dir.path().join("share/notes.txt"), b"notes").unwrap();
+        std::fs::write(dir.path().join("share/photos/2024/cat.jpg"), vec![7; 300_000]).unwrap();
+        let fs: Arc<dyn StorageBackend> = Arc::new(LocalFileSystem::new(dir.path().to_str().unwrap()));
+        (dir, fs)
+    }
+
+    #[test]
+    fn member_names_stay_inside_the_target() {
+        assert_eq!(extraction_path("/out", "a/b.txt").unwrap(), "/out/a/b.txt");
+        assert_eq!(extraction_path("/out", "./a/../b.txt").unwrap(), "/out/b.txt");
+        assert_eq!(extraction_path("/out", "dir\\file.txt").unwrap(), "/out/dir/file.txt");
+        assert_eq!(extraction_path("/out", "./").unwrap(), "/out");
+
+        for name in ["../evil.sh", "a/../../evil.sh", "/etc/passwd", "..\\..\\evil.sh"] {
+            let err = extraction_path("/out", name).unwrap_err();
+            assert_eq!(err.to_string(), "Path traversal attempt detected", "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn archives_round_trip_through_the_host() {
+        let (dir, fs) = host_with_tree();
+
+        for name in ["share.zip", "share.tar.gz"] {
+            let archive = format!("/{}", name);
+            let progress = Arc::new(TransferProgress::default());
+            compress(fs.clone(), &["/share".to_string()], &archive, &progress).await.unwrap();
+            assert_eq!(progress.snapshot().files_done, 2);
+
+            let out = format!("/out-{}", name);
+            let progress = Arc::new(TransferProgress::default());
+            extract(fs.as_ref(), &archive, &out, &progress).await.unwrap();
+
+            let root = dir.path().join(&out[1..]);
+            assert_eq!(std::fs::read(root.join("share/notes.txt")).unwrap(), b"notes");
+            assert_eq!(std::fs::read(root.join("share/photos/2024/cat.jpg")).unwrap(), vec![7; 300_000]);
+            let snapshot = progress.snapshot();
+            assert_eq!((snapshot.files_total, snapshot.files_done), (2, 2));
+            assert_eq!(snapshot.bytes_done, snapshot.bytes_total);
+        }
+
+        let err = compress(fs.clone(), &["/share".to_string()], "/share.tar.zst", &Arc::default()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Archives can only be created as .zip or .tar.gz");
+    }
+
+    #[tokio::test]
+    async fn escaping_members_fail_and_clean_up() {
+        let (dir, fs) = host_with_tree();
+
+        // tar::Builder refuses such names, so the header is filled in by hand
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut add = |name: &str, content: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, content).unwrap();
+        };
+        add("fine.txt", b"fine");
+        add("../../evil.sh", b"rm -rf /");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&builder.into_inner().unwrap()).unwrap();
+        std::fs::write(dir.path().join("evil.tar.gz"), gz.finish().unwrap()).unwrap();
+
+        let err = extract(fs.as_ref(), "/evil.tar.gz", "/share/out", &Arc::default()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Path traversal attempt detected");
+        assert!(!dir.path().join("share/out").exists());
+        assert!(!dir.path().join("evil.sh").exists());
+    }
+}
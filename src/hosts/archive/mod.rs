@@ -0,0 +1,249 @@
+//! Archives as seen from a host: directories and files downloaded as one
+//! archive built on the fly, archives on a host browsed like directories
+//! through paths such as `/builds/app.zip!/lib/foo.so`, and archives
+//! extracted or created on the host itself. None of these ever holds a whole
+//! archive in memory.
+
+mod extract;
+mod stream;
+mod tarball;
+mod write;
+mod zipfile;
+
+pub use extract::extract;
+pub use write::{archive_stream, collect_entries, compress};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+use crate::hosts::{normalize_path, sort_entries, Capabilities, FileStream, StorageBackend};
+use crate::models::{ArchiveFormat, FileInfo};
+
+/// What can be done inside an archive: looking, not touching.
+pub const CAPABILITIES: Capabilities = Capabilities {
+    list: true,
+    read: true,
+    write: false,
+    delete: false,
+    mkdir: false,
+    rename: false,
+    copy: false,
+    ranges: false,
+};
+
+/// Archive formats that can be browsed, told apart by file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl ArchiveKind {
+    pub fn detect(path: &str) -> Option<Self> {
+        let name = path.rsplit('/').next()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(ArchiveKind::TarZst)
+        } else {
+            None
+        }
+    }
+
+    /// The format archives of this kind are created in, for those that can be.
+    pub fn format(self) -> Option<ArchiveFormat> {
+        match self {
+            ArchiveKind::Zip => Some(ArchiveFormat::Zip),
+            ArchiveKind::TarGz => Some(ArchiveFormat::TarGz),
+            ArchiveKind::Tar | ArchiveKind::TarZst => None,
+        }
+    }
+}
+
+/// A path into an archive, e.g. `/builds/app.zip!/lib/foo.so`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivePath {
+    /// The archive on the host, e.g. `/builds/app.zip`.
+    pub archive: String,
+    pub kind: ArchiveKind,
+    /// The member inside the archive, e.g. `/lib/foo.so`; `/` is the archive's root.
+    pub member: String,
+}
+
+impl ArchivePath {
+    /// Splits `path` at the first `!` that follows an archive's name.
+    /// Paths that don't lead into an archive yield `None`.
+    pub fn parse(path: &str) -> Result<Option<Self>> {
+        for (at, _) in path.match_indices('!') {
+            let rest = &path[at + 1..];
+            if !rest.is_empty() && !rest.starts_with('/') {
+                continue;
+            }
+            let Some(kind) = ArchiveKind::detect(&path[..at]) else {
+                continue;
+            };
+            return Ok(Some(Self {
+                archive: normalize_path(&path[..at])?,
+                kind,
+                member: normalize_path(rest)?,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// The path `member` of this archive is listed under.
+    fn host_path(&self, member: &str) -> String {
+        format!("{}!{}", self.archive, member)
+    }
+}
+
+/// A file or directory inside an archive.
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    /// The name as stored in the archive, which may be anything at all.
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl Member {
+    /// The member's name as a path with a leading `/`, or `None` for names
+    /// that would lead out of the archive.
+    fn path(&self) -> Option<String> {
+        normalize_path(&self.name).ok().filter(|path| path != "/")
+    }
+}
+
+/// The directory `path.member` inside the archive, listed like a directory
+/// on the host.
+pub async fn list(source: &dyn StorageBackend, path: &ArchivePath) -> Result<Vec<FileInfo>> {
+    let members = match path.kind {
+        ArchiveKind::Zip => zipfile::entries(source, &path.archive)
+            .await?
+            .into_iter()
+            .map(|entry| entry.member)
+            .collect(),
+        kind => tarball::members(source, &path.archive, kind).await?,
+    };
+    children(path, &members)
+}
+
+/// Opens the file `path.member` inside the archive for streaming.
+pub async fn open(source: &dyn StorageBackend, path: &ArchivePath) -> Result<FileStream> {
+    match path.kind {
+        ArchiveKind::Zip => zipfile::open(source, &path.archive, &path.member).await,
+        kind => tarball::open(source, &path.archive, kind, &path.member).await,
+    }
+}
+
+/// The entries directly inside `path.member`. Archives needn't list every
+/// directory, so those only implied by deeper members are made up.
+fn children(path: &ArchivePath, members: &[Member]) -> Result<Vec<FileInfo>> {
+    let dir = path.member.as_str();
+    let mut found = dir == "/";
+    let mut entries: BTreeMap<String, FileInfo> = BTreeMap::new();
+
+    for member in members {
+        let Some(member_path) = member.path() else {
+            continue;
+        };
+        if member_path == dir {
+            if !member.is_dir {
+                anyhow::bail!("{} is not a directory", path.host_path(dir));
+            }
+            found = true;
+            continue;
+        }
+        let Some(rest) = member_path.strip_prefix(dir.trim_end_matches('/')).and_then(|r| r.strip_prefix('/')) else {
+            continue;
+        };
+        found = true;
+
+        let (name, nested) = match rest.split_once('/') {
+            Some((name, _)) => (name, true),
+            None => (rest, false),
+        };
+        let entry = FileInfo {
+            name: name.to_string(),
+            path: path.host_path(&format!("{}/{}", dir.trim_end_matches('/'), name)),
+            is_dir: nested || member.is_dir,
+            size: if nested { 0 } else { member.size },
+            modified: if nested { None } else { member.modified },
+        };
+        // Members themselves win over directories their descendants imply
+        if nested {
+            entries.entry(name.to_string()).or_insert(entry);
+        } else {
+            entries.insert(name.to_string(), entry);
+        }
+    }
+
+    if !found {
+        anyhow::bail!("{} not found", path.host_path(dir));
+    }
+    let mut files: Vec<FileInfo> = entries.into_values().collect();
+    sort_entries(&mut files);
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(path: &str, is_dir: bool) -> Member {
+        Member {
+            name: path.to_string(),
+            is_dir,
+            size: if is_dir { 0 } else { 10 },
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn paths_are_split_after_the_archive() {
+        let path = ArchivePath::parse("/builds/app.zip!/lib/foo.so").unwrap().unwrap();
+        assert_eq!(path.archive, "/builds/app.zip");
+        assert_eq!(path.kind, ArchiveKind::Zip);
+        assert_eq!(path.member, "/lib/foo.so");
+
+        let root = ArchivePath::parse("/builds/app.tar.zst!").unwrap().unwrap();
+        assert_eq!((root.kind, root.member.as_str()), (ArchiveKind::TarZst, "/"));
+
+        assert_eq!(ArchivePath::parse("/notes/wow!/a.txt").unwrap(), None);
+        assert_eq!(ArchivePath::parse("/builds/app.zip!.bak").unwrap(), None);
+        assert!(ArchivePath::parse("/builds/app.tgz!/../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn directories_implied_by_members_are_listed() {
+        let members = [
+            member("/lib/foo.so", false),
+            member("/lib/x86/bar.so", false),
+            member("/README", false),
+            member("/docs", true),
+        ];
+
+        let root = ArchivePath::parse("/app.tar!/").unwrap().unwrap();
+        let files = children(&root, &members).unwrap();
+        let names: Vec<(&str, bool)> = files.iter().map(|f| (f.name.as_str(), f.is_dir)).collect();
+        assert_eq!(names, [("docs", true), ("lib", true), ("README", false)]);
+        assert_eq!(files[1].path, "/app.tar!/lib");
+
+        let lib = ArchivePath::parse("/app.tar!/lib").unwrap().unwrap();
+        let files = children(&lib, &members).unwrap();
+        assert_eq!(files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>(), ["/app.tar!/lib/x86", "/app.tar!/lib/foo.so"]);
+
+        let missing = ArchivePath::parse("/app.tar!/src").unwrap().unwrap();
+        assert!(children(&missing, &members).is_err());
+        let file = ArchivePath::parse("/app.tar!/README").unwrap().unwrap();
+        assert!(children(&file, &members).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Reading archives off a byte stream: pieces of a given size, byte ranges
+//! on hosts that can't seek, and decompression one chunk at a time.
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+
+use crate::hosts::bridge::STREAM_CHUNK_SIZE;
+use crate::hosts::range::RangeRequest;
+use crate::hosts::{ByteStream, StorageBackend};
+
+/// Compressed input handed to a decoder at once. Bounds what one piece can
+/// inflate to, however well it compresses.
+const DECODE_PIECE_SIZE: usize = 8 * 1024;
+
+/// Reads a byte stream in pieces of the caller's choosing.
+pub struct StreamReader {
+    stream: ByteStream,
+    buffer: Bytes,
+}
+
+impl StreamReader {
+    pub fn new(stream: ByteStream) -> Self {
+        Self {
+            stream,
+            buffer: Bytes::new(),
+        }
+    }
+
+    /// Up to `max` bytes, or `None` at the end of the stream.
+    async fn chunk(&mut self, max: usize) -> io::Result<Option<Bytes>> {
+        while self.buffer.is_empty() {
+            match self.stream.next().await {
+                Some(chunk) => self.buffer = chunk?,
+                None => return Ok(None),
+            }
+        }
+        let len = max.min(self.buffer.len());
+        Ok(Some(self.buffer.split_to(len)))
+    }
+
+    /// Exactly `len` bytes, or `None` if the stream ended right here.
+    pub async fn read_exact(&mut self, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            match self.chunk(len - out.len()).await? {
+                Some(chunk) => out.extend_from_slice(&chunk),
+                None if out.is_empty() => return Ok(None),
+                None => return Err(truncated()),
+            }
+        }
+        Ok(Some(out))
+    }
+
+    pub async fn skip(&mut self, mut len: u64) -> io::Result<()> {
+        while len > 0 {
+            let chunk = self.chunk(len.min(STREAM_CHUNK_SIZE as u64) as usize).await?.ok_or_else(truncated)?;
+            len -= chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Sends the next `len` bytes to `tx`. A read error is sent along too,
+    /// so whoever consumes them doesn't take a short read for the whole.
+    /// Stops early, without error, once the receiver is gone.
+    pub async fn forward(&mut self, mut len: u64, tx: mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+        while len > 0 {
+            let chunk = match self.chunk(len.min(STREAM_CHUNK_SIZE as u64) as usize).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => return Err(forward_error(&tx, truncated()).await),
+                Err(e) => return Err(forward_error(&tx, e).await),
+            };
+            len -= chunk.len() as u64;
+            if tx.send(Ok(chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// The next `len` bytes as a stream of their own.
+    pub fn take(self, len: u64) -> ByteStream {
+        Box::pin(futures::stream::unfold((self, len), |(mut reader, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            match reader.chunk(remaining.min(STREAM_CHUNK_SIZE as u64) as usize).await {
+                Ok(Some(chunk)) => {
+                    let remaining = remaining - chunk.len() as u64;
+                    Some((Ok(chunk), (reader, remaining)))
+                }
+                Ok(None) => Some((Err(truncated()), (reader, 0))),
+                Err(e) => Some((Err(e), (reader, 0))),
+            }
+        }))
+    }
+}
+
+/// Passes a copy of `e` on to `tx` and returns it.
+async fn forward_error(tx: &mpsc::Sender<io::Result<Bytes>>, e: io::Error) -> io::Error {
+    let _ = tx.send(Err(io::Error::new(e.kind(), e.to_string()))).await;
+    e
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Archive is truncated")
+}
+
+/// `len` bytes of `path` starting at `start`. Hosts that can't seek send the
+/// whole file, and what comes before `start` is skipped.
+pub async fn read_range(source: &dyn StorageBackend, path: &str, start: u64, len: u64) -> Result<ByteStream> {
+    if len == 0 {
+        return Ok(Box::pin(futures::stream::empty()));
+    }
+    let file = source.read_stream(path, Some(RangeRequest::bytes(start, start + len - 1))).await?;
+    let mut reader = StreamReader::new(file.stream);
+    if file.range.is_none() {
+        reader.skip(start).await?;
+    }
+    Ok(reader.take(len))
+}
+
+/// Collects a stream that is known to be small.
+pub async fn collect(mut stream: ByteStream) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        out.extend_from_slice(&chunk?);
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Codec {
+    Deflate,
+    Gzip,
+    Zstd,
+}
+
+/// A decoder that is written compressed input and collects the output.
+trait Decoder: Write + Send {
+    fn finish(&mut self) -> io::Result<()>;
+
+    /// The output produced since the last call.
+    fn take(&mut self) -> Vec<u8>;
+}
+
+impl Decoder for flate2::write::DeflateDecoder<Vec<u8>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+}
+
+impl Decoder for flate2::write::GzDecoder<Vec<u8>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+}
+
+impl Decoder for zstd::stream::write::Decoder<'static, Vec<u8>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+}
+
+/// Decompresses `stream` as it is read.
+pub fn decompress(stream: ByteStream, codec: Codec) -> io::Result<ByteStream> {
+    let decoder: Box<dyn Decoder> = match codec {
+        Codec::Deflate => Box::new(flate2::write::DeflateDecoder::new(Vec::new())),
+        Codec::Gzip => Box::new(flate2::write::GzDecoder::new(Vec::new())),
+        Codec::Zstd => Box::new(zstd::stream::write::Decoder::new(Vec::new())?),
+    };
+
+    let state = Some((stream, decoder, Bytes::new()));
+    Ok(Box::pin(futures::stream::unfold(state, |state| async move {
+        let (mut stream, mut decoder, mut pending) = state?;
+        loop {
+            if pending.is_empty() {
+                match stream.next().await {
+                    Some(Ok(chunk)) => pending = chunk,
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        if let Err(e) = decoder.finish() {
+                            return Some((Err(e), None));
+                        }
+                        let output = decoder.take();
+                        return (!output.is_empty()).then(|| (Ok(Bytes::from(output)), None));
+                    }
+                }
+                continue;
+            }
+
+            let piece = pending.split_to(pending.len().min(DECODE_PIECE_SIZE));
+            if let Err(e) = decoder.write_all(&piece) {
+                return Some((Err(e), None));
+            }
+            let output = decoder.take();
+            if !output.is_empty() {
+                return Some((Ok(Bytes::from(output)), Some((stream, decoder, pending))));
+            }
+        }
+    })))
+}
@@ -0,0 +1,222 @@
+//! Reading tar archives, plain or compressed, front to back in one pass.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use chrono::DateTime;
+use std::io;
+use tar::EntryType;
+use tokio::sync::mpsc;
+
+use super::stream::{decompress, Codec, StreamReader};
+use super::{ArchiveKind, Member};
+use crate::hosts::{ByteStream, FileStream, StorageBackend};
+
+const BLOCK_SIZE: usize = 512;
+/// Largest GNU long name or pax header read.
+const MAX_EXTENSION_SIZE: u64 = 1024 * 1024;
+
+/// Every file and directory in the tar archive at `path`.
+pub async fn members(source: &dyn StorageBackend, path: &str, kind: ArchiveKind) -> Result<Vec<Member>> {
+    let mut tar = TarReader::open(source, path, kind).await?;
+    let mut members = Vec::new();
+    while let Some(member) = tar.next().await? {
+        members.extend(member);
+    }
+    Ok(members)
+}
+
+/// Streams the file `member` out of the tar archive at `path`. The archive
+/// is read up to the member, and no further than the member's end.
+pub async fn open(source: &dyn StorageBackend, path: &str, kind: ArchiveKind, member: &str) -> Result<FileStream> {
+    let mut tar = TarReader::open(source, path, kind).await?;
+    while let Some(found) = tar.next().await? {
+        let Some(found) = found.filter(|m| m.path().as_deref() == Some(member)) else {
+            continue;
+        };
+        if found.is_dir {
+            anyhow::bail!("{}!{} is a directory", path, member);
+        }
+        return Ok(FileStream {
+            stream: tar.reader.take(found.size),
+            size: found.size,
+            modified: found.modified,
+            range: None,
+        });
+    }
+    anyhow::bail!("{}!{} not found", path, member)
+}
+
+pub struct TarReader {
+    reader: StreamReader,
+    /// Bytes of the current member's content, with padding, not read yet.
+    unread: u64,
+}
+
+impl TarReader {
+    async fn open(source: &dyn StorageBackend, path: &str, kind: ArchiveKind) -> Result<Self> {
+        let file = source.read_stream(path, None).await?;
+        Ok(Self::new(file.stream, kind)?)
+    }
+
+    /// Reads the archive of the given kind from `stream`, as stored.
+    pub fn new(stream: ByteStream, kind: ArchiveKind) -> io::Result<Self> {
+        let stream = match kind {
+            ArchiveKind::TarGz => decompress(stream, Codec::Gzip)?,
+            ArchiveKind::TarZst => decompress(stream, Codec::Zstd)?,
+            _ => stream,
+        };
+        Ok(Self {
+            reader: StreamReader::new(stream),
+            unread: 0,
+        })
+    }
+
+    /// Sends the content of the member `next` just returned to `tx`.
+    pub async fn forward(&mut self, member: &Member, tx: mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+        self.reader.forward(member.size, tx).await?;
+        self.unread -= member.size;
+        Ok(())
+    }
+
+    /// The next entry, or `Some(None)` for entries other than files and
+    /// directories, such as links.
+    pub async fn next(&mut self) -> Result<Option<Option<Member>>> {
+        self.reader.skip(std::mem::take(&mut self.unread)).await?;
+
+        let mut long_name = None;
+        let mut pax_size = None;
+        loop {
+            // Archives end with zero blocks, though not all bother
+            let Some(block) = self.reader.read_exact(BLOCK_SIZE).await? else {
+                return Ok(None);
+            };
+            if block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            if !checksum_matches(&block) {
+                anyhow::bail!("Not a tar archive");
+            }
+
+            let header = tar::Header::from_byte_slice(&block);
+            let size = header.entry_size().context("Not a tar archive")?;
+            let padded = size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+            match header.entry_type() {
+                EntryType::GNULongName | EntryType::XHeader => {
+                    if size > MAX_EXTENSION_SIZE {
+                        anyhow::bail!("Tar header is too large");
+                    }
+                    let data = self.reader.read_exact(padded as usize).await?.context("Archive is truncated")?;
+                    let data = &data[..size as usize];
+                    if header.entry_type() == EntryType::GNULongName {
+                        long_name = Some(String::from_utf8_lossy(data).trim_end_matches('\0').to_string());
+                        continue;
+                    }
+                    for extension in tar::PaxExtensions::new(data).flatten() {
+                        match extension.key() {
+                            Ok("path") => long_name = extension.value().ok().map(str::to_string),
+                            Ok("size") => pax_size = extension.value().ok().and_then(|v| v.parse().ok()),
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                // Global pax headers, long link names and the like
+                EntryType::XGlobalHeader | EntryType::GNULongLink => {
+                    self.reader.skip(padded).await?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let size = pax_size.unwrap_or(size);
+            self.unread = size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+            let name = long_name.unwrap_or_else(|| String::from_utf8_lossy(&header.path_bytes()).into_owned());
+            let is_dir = match header.entry_type() {
+                EntryType::Directory => true,
+                EntryType::Regular | EntryType::Continuous => name.ends_with('/'),
+                _ => return Ok(Some(None)),
+            };
+
+            let modified = header.mtime().ok().and_then(|t| DateTime::from_timestamp(t as i64, 0));
+            return Ok(Some(Some(Member { name, is_dir, size, modified })));
+        }
+    }
+}
+
+/// Whether the header's checksum, the sum of its bytes with the checksum
+/// field counted as spaces, is right. Tells tar archives from anything else.
+fn checksum_matches(block: &[u8]) -> bool {
+    let field = &block[148..156];
+    let stored = std::str::from_utf8(field)
+        .ok()
+        .map(|f| f.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|f| u32::from_str_radix(f, 8).ok());
+    let sum: u32 = block[..148].iter().chain(&block[156..]).map(|&b| b as u32).sum::<u32>() + 8 * b' ' as u32;
+    stored == Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::archive::stream::collect;
+    use crate::hosts::local::LocalFileSystem;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    /// A tar archive of a small tree, with a name too long for the header.
+    fn tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut add = |path: &str, content: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            builder.append_data(&mut header, path, content).unwrap();
+        };
+        add("lib/foo.so", &[5; 100_000]);
+        add(&format!("docs/{}.md", "n".repeat(150)), b"long");
+        add("README", b"read me");
+        builder.into_inner().unwrap()
+    }
+
+    fn host_with(name: &str, content: &[u8]) -> (TempDir, LocalFileSystem) {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join(name), content).unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        (dir, fs)
+    }
+
+    #[tokio::test]
+    async fn compressed_tarballs_are_listed_and_read() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tarball()).unwrap();
+        let zst = zstd::stream::encode_all(tarball().as_slice(), 0).unwrap();
+
+        for (name, content, kind) in [
+            ("app.tar", tarball(), ArchiveKind::Tar),
+            ("app.tar.gz", gz.finish().unwrap(), ArchiveKind::TarGz),
+            ("app.tar.zst", zst, ArchiveKind::TarZst),
+        ] {
+            let (_dir, fs) = host_with(name, &content);
+            let path = format!("/{}", name);
+
+            let members = members(&fs, &path, kind).await.unwrap();
+            let names: Vec<&str> = members.iter().map(|m| m.name.as_str()).collect();
+            assert_eq!(names, ["lib/foo.so", &format!("docs/{}.md", "n".repeat(150)), "README"]);
+            assert_eq!(members[0].modified.unwrap().timestamp(), 1_700_000_000);
+
+            let file = open(&fs, &path, kind, "/README").await.unwrap();
+            assert_eq!(file.size, 7);
+            assert_eq!(collect(file.stream).await.unwrap(), b"read me");
+            assert!(open(&fs, &path, kind, "/lib").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn other_files_are_not_tar_archives() {
+        let (_dir, fs) = host_with("fake.tar", &[b'x'; 2048]);
+
+        let err = members(&fs, "/fake.tar", ArchiveKind::Tar).await.unwrap_err();
+        assert_eq!(err.to_string(), "Not a tar archive");
+    }
+}
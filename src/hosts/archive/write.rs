@@ -0,0 +1,683 @@
+//! Archives of files and directory trees, built while they are downloaded.
+//! Each file is written into the archive as its content streams in from the host.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use futures::StreamExt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::hosts::bridge::{channel_stream, STREAM_BUFFER_CHUNKS};
+use crate::hosts::transfer::{walk, TransferProgress};
+use crate::hosts::{ByteStream, StorageBackend};
+use crate::models::ArchiveFormat;
+use super::ArchiveKind;
+use super::zipfile::{
+    CENTRAL_HEADER, DATA_DESCRIPTOR, END_OF_DIRECTORY, LOCAL_HEADER, METHOD_DEFLATED, METHOD_STORED, ZIP64_END_OF_DIRECTORY,
+    ZIP64_LOCATOR,
+};
+
+/// Something to put into an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    /// Where the entry is on the host.
+    pub path: String,
+    /// Where the entry is in the archive, without a leading `/`.
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Everything under `paths`, named relative to the directory each path is
+/// in: archiving `/share/photos` yields `photos/...`.
+pub async fn collect_entries(source: &dyn StorageBackend, paths: &[String]) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    for path in paths {
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        for entry in walk(source, path).await? {
+            let name = entry.path[parent.len()..].trim_start_matches('/');
+            // The root itself has no name of its own
+            if name.is_empty() {
+                continue;
+            }
+            entries.push(ArchiveEntry {
+                name: name.to_string(),
+                path: entry.path,
+                is_dir: entry.is_dir,
+                size: entry.size,
+                modified: entry.modified,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Archives `paths` of `source` into the new file `to` on the same host,
+/// which must not exist yet. The format follows from the name of `to`.
+pub async fn compress(
+    source: Arc<dyn StorageBackend>,
+    paths: &[String],
+    to: &str,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let format = ArchiveKind::detect(to)
+        .and_then(ArchiveKind::format)
+        .context("Archives can only be created as .zip or .tar.gz")?;
+    let entries = collect_entries(source.as_ref(), paths).await?;
+    let files = entries.iter().filter(|e| !e.is_dir);
+    progress.start(files.clone().map(|e| e.size).sum(), files.count() as u64);
+
+    // Written under a staging name, so a failed archive leaves nothing behind
+    let stream = archive_stream(source.clone(), entries, format, progress.clone());
+    source.write_stream(to, stream).await?;
+    progress.finish();
+    Ok(())
+}
+
+/// Streams `entries` of `source` as an archive. A failure partway through
+/// ends the stream with an error rather than a truncated archive that looks
+/// complete; the archive stops being built once the stream is dropped or
+/// `progress` is cancelled.
+pub fn archive_stream(
+    source: Arc<dyn StorageBackend>,
+    entries: Vec<ArchiveEntry>,
+    format: ArchiveFormat,
+    progress: Arc<TransferProgress>,
+) -> ByteStream {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    tokio::spawn(async move {
+        let (source, entries, progress) = (source.as_ref(), &entries, &progress);
+        let result = match format {
+            ArchiveFormat::Zip => write_archive(source, entries, ZipWriter::default(), progress, &tx).await,
+            ArchiveFormat::TarGz => write_archive(source, entries, TarGzWriter::default(), progress, &tx).await,
+        };
+        if let Err(e) = result {
+            if !tx.is_closed() {
+                log::warn!("Failed to build archive: {:#}", e);
+                let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+            }
+        }
+    });
+    channel_stream(rx)
+}
+
+async fn write_archive<W: ArchiveWriter>(
+    source: &dyn StorageBackend,
+    entries: &[ArchiveEntry],
+    mut writer: W,
+    progress: &Arc<TransferProgress>,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> Result<()> {
+    for entry in entries {
+        if progress.is_cancelled() {
+            anyhow::bail!("Transfer cancelled");
+        }
+        if entry.is_dir {
+            writer.add_dir(entry)?;
+            flush(&mut writer, tx).await?;
+            continue;
+        }
+
+        let file = source.read_stream(&entry.path, None).await?;
+        writer.start_file(entry, file.size)?;
+        let mut stream = progress.count(file.stream);
+        while let Some(chunk) = stream.next().await {
+            writer.write(&chunk?)?;
+            flush(&mut writer, tx).await?;
+        }
+        writer
+            .end_file()
+            .with_context(|| format!("{} changed while it was being archived", entry.path))?;
+        flush(&mut writer, tx).await?;
+        progress.file_done();
+    }
+
+    writer.finish()?;
+    flush(&mut writer, tx).await
+}
+
+/// Passes on what `writer` has produced so far.
+async fn flush<W: ArchiveWriter>(writer: &mut W, tx: &mpsc::Sender<io::Result<Bytes>>) -> Result<()> {
+    let output = writer.take();
+    if !output.is_empty() && tx.send(Ok(Bytes::from(output))).await.is_err() {
+        anyhow::bail!("Download aborted");
+    }
+    Ok(())
+}
+
+/// Writes an archive one entry at a time into a buffer that `take` drains.
+trait ArchiveWriter: Send {
+    fn add_dir(&mut self, entry: &ArchiveEntry) -> io::Result<()>;
+
+    /// Begins a file of `size` bytes, whose content follows through `write`.
+    fn start_file(&mut self, entry: &ArchiveEntry, size: u64) -> io::Result<()>;
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Ends the current file; fails if its size isn't what was announced.
+    fn end_file(&mut self) -> io::Result<()>;
+
+    fn finish(&mut self) -> io::Result<()>;
+
+    /// The output produced since the last call.
+    fn take(&mut self) -> Vec<u8>;
+}
+
+fn size_mismatch() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "file size changed")
+}
+
+const TAR_BLOCK: usize = 512;
+
+/// Writes a gzip-compressed tar archive in the GNU format.
+struct TarGzWriter {
+    encoder: GzEncoder<Vec<u8>>,
+    size: u64,
+    remaining: u64,
+}
+
+impl Default for TarGzWriter {
+    fn default() -> Self {
+        Self {
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+            size: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl TarGzWriter {
+    fn header(&mut self, entry: &ArchiveEntry, size: u64) -> io::Result<()> {
+        let mut name = entry.name.clone().into_bytes();
+        if entry.is_dir {
+            name.push(b'/');
+        }
+
+        let mut header = tar::Header::new_gnu();
+        if name.len() > 100 {
+            // Longer names go in an entry of their own ahead of the header
+            header.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+            header.set_entry_type(tar::EntryType::GNULongName);
+            header.set_mode(0o644);
+            header.set_size(name.len() as u64 + 1);
+            header.set_cksum();
+            self.encoder.write_all(header.as_bytes())?;
+            self.encoder.write_all(&name)?;
+            self.encoder.write_all(&[0])?;
+            self.pad(name.len() as u64 + 1)?;
+            header = tar::Header::new_gnu();
+        }
+
+        let field = &mut header.as_old_mut().name;
+        let len = name.len().min(field.len());
+        field[..len].copy_from_slice(&name[..len]);
+        if entry.is_dir {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+        }
+        header.set_size(size);
+        header.set_mtime(entry.modified.map_or(0, |m| m.timestamp().max(0) as u64));
+        header.set_cksum();
+        self.encoder.write_all(header.as_bytes())
+    }
+
+    /// Fills the last block of `len` bytes of content with zeros.
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rest = (TAR_BLOCK - (len % TAR_BLOCK as u64) as usize) % TAR_BLOCK;
+        self.encoder.write_all(&[0; TAR_BLOCK][..rest])
+    }
+}
+
+impl ArchiveWriter for TarGzWriter {
+    fn add_dir(&mut self, entry: &ArchiveEntry) -> io::Result<()> {
+        self.header(entry, 0)
+    }
+
+    fn start_file(&mut self, entry: &ArchiveEntry, size: u64) -> io::Result<()> {
+        self.header(entry, size)?;
+        self.size = size;
+        self.remaining = size;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        // The header already promised a size
+        self.remaining = self.remaining.checked_sub(data.len() as u64).ok_or_else(size_mismatch)?;
+        self.encoder.write_all(data)
+    }
+
+    fn end_file(&mut self) -> io::Result<()> {
+        if self.remaining != 0 {
+            return Err(size_mismatch());
+        }
+        self.pad(self.size)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.encoder.write_all(&[0; 2 * TAR_BLOCK])?;
+        self.encoder.try_finish()
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.encoder.get_mut())
+    }
+}
+
+/// Sizes and offsets from this on need the Zip64 extensions.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// Files this large are announced as Zip64 up front. Deflate can grow
+/// content that doesn't compress, so the margin is generous.
+const ZIP64_FILE_SIZE: u64 = 0xF000_0000;
+const ZIP_VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+/// Made by a Unix host, so the external attributes hold permissions.
+const ZIP_MADE_BY: u16 = (3 << 8) | ZIP64_VERSION;
+/// Sizes and checksum follow the content, in a data descriptor.
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// A file or directory already written, as the central directory lists it.
+struct ZipEntry {
+    name: Vec<u8>,
+    is_dir: bool,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+}
+
+impl ZipEntry {
+    fn flags(&self) -> u16 {
+        if self.is_dir {
+            FLAG_UTF8
+        } else {
+            FLAG_UTF8 | FLAG_DATA_DESCRIPTOR
+        }
+    }
+
+    fn method(&self) -> u16 {
+        if self.is_dir {
+            METHOD_STORED
+        } else {
+            METHOD_DEFLATED
+        }
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64 || self.offset >= ZIP64_LIMIT {
+            ZIP64_VERSION
+        } else {
+            ZIP_VERSION
+        }
+    }
+}
+
+/// The file being written.
+struct ZipFile {
+    entry: ZipEntry,
+    hasher: crc32fast::Hasher,
+    encoder: DeflateEncoder<Vec<u8>>,
+}
+
+/// Writes a ZIP archive front to back. Every file's sizes and checksum
+/// follow its content in a data descriptor, so nothing has to be patched
+/// afterwards and the output can go straight out.
+#[derive(Default)]
+struct ZipWriter {
+    out: Vec<u8>,
+    /// Bytes written so far, including those already taken.
+    offset: u64,
+    entries: Vec<ZipEntry>,
+    current: Option<ZipFile>,
+}
+
+impl ZipWriter {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+        self.offset += bytes.len() as u64;
+    }
+
+    fn entry(&self, entry: &ArchiveEntry, zip64: bool) -> ZipEntry {
+        let mut name = entry.name.clone().into_bytes();
+        if entry.is_dir {
+            name.push(b'/');
+        }
+        let (time, date) = dos_datetime(entry.modified.unwrap_or_else(Utc::now));
+        ZipEntry {
+            name,
+            is_dir: entry.is_dir,
+            time,
+            date,
+            crc: 0,
+            compressed: 0,
+            size: 0,
+            offset: self.offset,
+            zip64,
+        }
+    }
+
+    fn local_header(&mut self, entry: &ZipEntry) {
+        // Sizes and checksum are zero here and follow in the data descriptor
+        let mut extra = Vec::new();
+        let sizes = if entry.zip64 {
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&16u16.to_le_bytes());
+            extra.extend_from_slice(&[0; 16]);
+            u32::MAX
+        } else {
+            0
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len() + extra.len());
+        header.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        header.extend_from_slice(&entry.version().to_le_bytes());
+        header.extend_from_slice(&entry.flags().to_le_bytes());
+        header.extend_from_slice(&entry.method().to_le_bytes());
+        header.extend_from_slice(&entry.time.to_le_bytes());
+        header.extend_from_slice(&entry.date.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&sizes.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(&entry.name);
+        header.extend_from_slice(&extra);
+        self.emit(&header);
+    }
+
+    /// Moves what the encoder has compressed so far into the output.
+    fn drain(&mut self) {
+        if let Some(file) = &mut self.current {
+            let compressed = std::mem::take(file.encoder.get_mut());
+            file.entry.compressed += compressed.len() as u64;
+            self.emit(&compressed);
+        }
+    }
+
+    fn central_directory(&mut self) {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            // Values that don't fit are replaced by all ones and go in the extra field
+            let mut extra = Vec::new();
+            let mut field = |value: u64, large: bool| -> u32 {
+                if large {
+                    extra.extend_from_slice(&value.to_le_bytes());
+                    u32::MAX
+                } else {
+                    value as u32
+                }
+            };
+            let size = field(entry.size, entry.zip64);
+            let compressed = field(entry.compressed, entry.zip64);
+            let offset = field(entry.offset, entry.offset >= ZIP64_LIMIT);
+            if !extra.is_empty() {
+                let header = [1u16.to_le_bytes(), (extra.len() as u16).to_le_bytes()].concat();
+                extra.splice(0..0, header);
+            }
+            let attributes: u32 = if entry.is_dir { (0o40755 << 16) | 0x10 } else { 0o100644 << 16 };
+
+            directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&ZIP_MADE_BY.to_le_bytes());
+            directory.extend_from_slice(&entry.version().to_le_bytes());
+            directory.extend_from_slice(&entry.flags().to_le_bytes());
+            directory.extend_from_slice(&entry.method().to_le_bytes());
+            directory.extend_from_slice(&entry.time.to_le_bytes());
+            directory.extend_from_slice(&entry.date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&compressed.to_le_bytes());
+            directory.extend_from_slice(&size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            // Comment length, disk number and internal attributes
+            directory.extend_from_slice(&[0; 6]);
+            directory.extend_from_slice(&attributes.to_le_bytes());
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(&entry.name);
+            directory.extend_from_slice(&extra);
+        }
+        self.emit(&directory);
+    }
+}
+
+impl ArchiveWriter for ZipWriter {
+    fn add_dir(&mut self, entry: &ArchiveEntry) -> io::Result<()> {
+        let entry = self.entry(entry, false);
+        self.local_header(&entry);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn start_file(&mut self, entry: &ArchiveEntry, size: u64) -> io::Result<()> {
+        let entry = self.entry(entry, size >= ZIP64_FILE_SIZE);
+        self.local_header(&entry);
+        self.current = Some(ZipFile {
+            entry,
+            hasher: crc32fast::Hasher::new(),
+            encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self.current.as_mut().expect("no file started");
+        file.hasher.update(data);
+        file.entry.size += data.len() as u64;
+        file.encoder.write_all(data)?;
+        self.drain();
+        Ok(())
+    }
+
+    fn end_file(&mut self) -> io::Result<()> {
+        self.current.as_mut().expect("no file started").encoder.try_finish()?;
+        self.drain();
+        let file = self.current.take().expect("no file started");
+        let mut entry = file.entry;
+        entry.crc = file.hasher.finalize();
+        if !entry.zip64 && (entry.size >= ZIP64_LIMIT || entry.compressed >= ZIP64_LIMIT) {
+            // Grew past what its header allowed for
+            return Err(size_mismatch());
+        }
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+        if entry.zip64 {
+            descriptor.extend_from_slice(&entry.compressed.to_le_bytes());
+            descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(entry.compressed as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+        }
+        self.emit(&descriptor);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let start = self.offset;
+        self.central_directory();
+        let size = self.offset - start;
+        let count = self.entries.len() as u64;
+
+        let mut end = Vec::with_capacity(98);
+        if count >= 0xFFFF || start >= ZIP64_LIMIT || size >= ZIP64_LIMIT {
+            let record = self.offset;
+            end.extend_from_slice(&ZIP64_END_OF_DIRECTORY.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&ZIP_MADE_BY.to_le_bytes());
+            end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+            end.extend_from_slice(&[0; 8]);
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&size.to_le_bytes());
+            end.extend_from_slice(&start.to_le_bytes());
+            // Locator of the record above
+            end.extend_from_slice(&ZIP64_LOCATOR.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&record.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        end.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend_from_slice(&(size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend_from_slice(&(start.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.emit(&end);
+        Ok(())
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+}
+
+/// `at` as an MS-DOS time and date, the only timestamps every ZIP tool reads.
+fn dos_datetime(at: DateTime<Utc>) -> (u16, u16) {
+    if at.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = at.year().min(2107) as u16 - 1980;
+    let time = ((at.hour() as u16) << 11) | ((at.minute() as u16) << 5) | (at.second() as u16 / 2);
+    let date = (year << 9) | ((at.month() as u16) << 5) | at.day() as u16;
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::local::LocalFileSystem;
+    use futures::TryStreamExt;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn host_with_tree() -> (TempDir, Arc<dyn StorageBackend>) {
+        let dir = TempDir::new().unwrap();
+        let long = "a".repeat(120);
+        std::fs::create_dir_all(dir.path().join("share/photos/2024")).unwrap();
+        std::fs::write(dir.path().join("share/notes.txt"), b"notes").unwrap();
+        std::fs::write(dir.path().join("share/photos/2024/cat.jpg"), vec![7; 300_000]).unwrap();
+        std::fs::write(dir.path().join("share/photos").join(&long), b"long").unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        (dir, Arc::new(fs))
+    }
+
+    async fn build(source: Arc<dyn StorageBackend>, paths: &[&str], format: ArchiveFormat) -> Vec<u8> {
+        let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        let entries = collect_entries(source.as_ref(), &paths).await.unwrap();
+        let chunks: Vec<Bytes> = archive_stream(source, entries, format, Arc::default()).try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn entries_are_named_from_the_selected_paths() {
+        let (_dir, fs) = host_with_tree();
+        let paths = ["/share/photos".to_string(), "/share/notes.txt".to_string()];
+        let entries = collect_entries(fs.as_ref(), &paths).await.unwrap();
+
+        let mut names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names[..3], ["notes.txt", "photos", "photos/2024"]);
+        assert_eq!(names[3], "photos/2024/cat.jpg");
+        assert_eq!(entries.iter().find(|e| e.name == "photos/2024/cat.jpg").unwrap().path, "/share/photos/2024/cat.jpg");
+    }
+
+    #[tokio::test]
+    async fn tar_gz_archives_round_trip() {
+        let (_dir, fs) = host_with_tree();
+        let archive = build(fs, &["/share"], ArchiveFormat::TarGz).await;
+
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive.as_slice()));
+        let mut files = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            files.push((path, content.len()));
+        }
+        files.sort();
+
+        assert!(files.contains(&("share/notes.txt".to_string(), 5)));
+        assert!(files.contains(&("share/photos/2024/cat.jpg".to_string(), 300_000)));
+        assert!(files.contains(&(format!("share/photos/{}", "a".repeat(120)), 4)));
+        assert!(files.contains(&("share/photos/2024/".to_string(), 0)));
+    }
+
+    #[tokio::test]
+    async fn zip_archives_have_a_central_directory() {
+        let (_dir, fs) = host_with_tree();
+        let archive = build(fs, &["/share/photos/2024", "/share/notes.txt"], ArchiveFormat::Zip).await;
+
+        // The central directory is the last thing before the end record
+        let end = archive.len() - 22;
+        assert_eq!(archive[end..end + 4], END_OF_DIRECTORY.to_le_bytes());
+        let count = u16::from_le_bytes([archive[end + 10], archive[end + 11]]);
+        let start = u32::from_le_bytes(archive[end + 16..end + 20].try_into().unwrap()) as usize;
+        assert_eq!(count, 3);
+
+        let mut files = Vec::new();
+        let mut at = start;
+        for _ in 0..count {
+            let header = &archive[at..];
+            assert_eq!(header[..4], CENTRAL_HEADER.to_le_bytes());
+            let crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+            let size = u32::from_le_bytes(header[24..28].try_into().unwrap());
+            let name_len = u16::from_le_bytes([header[28], header[29]]) as usize;
+            let extra_len = u16::from_le_bytes([header[30], header[31]]) as usize;
+            let offset = u32::from_le_bytes(header[42..46].try_into().unwrap()) as usize;
+            let name = String::from_utf8(header[46..46 + name_len].to_vec()).unwrap();
+
+            // Local header, then the deflated content
+            let local = &archive[offset..];
+            assert_eq!(local[..4], LOCAL_HEADER.to_le_bytes());
+            let data = 30 + u16::from_le_bytes([local[26], local[27]]) as usize + u16::from_le_bytes([local[28], local[29]]) as usize;
+            let mut content = Vec::new();
+            if !name.ends_with('/') {
+                flate2::read::DeflateDecoder::new(&local[data..]).read_to_end(&mut content).unwrap();
+                assert_eq!(crc32fast::hash(&content), crc);
+            }
+            assert_eq!(content.len(), size as usize);
+
+            files.push(name);
+            at += 46 + name_len + extra_len;
+        }
+
+        assert_eq!(files, ["2024/", "2024/cat.jpg", "notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn missing_files_fail_the_stream() {
+        let (_dir, fs) = host_with_tree();
+        let entries = vec![ArchiveEntry {
+            path: "/share/gone.txt".to_string(),
+            name: "gone.txt".to_string(),
+            is_dir: false,
+            size: 5,
+            modified: None,
+        }];
+
+        let result: io::Result<Vec<Bytes>> =
+            archive_stream(fs, entries, ArchiveFormat::Zip, Arc::default()).try_collect().await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn dos_timestamps_are_clamped() {
+        let at = DateTime::parse_from_rfc3339("2024-03-05T10:20:31Z").unwrap().with_timezone(&Utc);
+        assert_eq!(dos_datetime(at), ((10 << 11) | (20 << 5) | 15, (44 << 9) | (3 << 5) | 5));
+        assert_eq!(dos_datetime(DateTime::UNIX_EPOCH), (0, (1 << 5) | 1));
+    }
+}
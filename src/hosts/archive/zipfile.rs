@@ -0,0 +1,274 @@
+//! Reading ZIP archives through their central directory, fetching only the
+//! parts needed from hosts that can seek.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use super::stream::{collect, decompress, read_range, Codec, StreamReader};
+use super::Member;
+use crate::hosts::{FileStream, StorageBackend};
+
+pub const LOCAL_HEADER: u32 = 0x0403_4b50;
+pub const CENTRAL_HEADER: u32 = 0x0201_4b50;
+pub const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+pub const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+pub const ZIP64_END_OF_DIRECTORY: u32 = 0x0606_4b50;
+pub const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+pub const METHOD_STORED: u16 = 0;
+pub const METHOD_DEFLATED: u16 = 8;
+const METHOD_ZSTD: u16 = 93;
+const FLAG_ENCRYPTED: u16 = 1;
+const EXTRA_ZIP64: u16 = 0x0001;
+const EXTRA_TIMESTAMP: u16 = 0x5455;
+
+/// Read from the end of an archive at first, in the hope that the central
+/// directory lies within.
+const TAIL_SIZE: u64 = 256 * 1024;
+/// Largest central directory read, about a million members.
+const MAX_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+/// Fixed part of a local file header; name and extra field follow.
+const LOCAL_HEADER_SIZE: u64 = 30;
+
+/// A member as the central directory describes it.
+pub struct ZipEntry {
+    pub member: Member,
+    /// Where the member's local header starts.
+    offset: u64,
+    compressed: u64,
+    method: u16,
+    encrypted: bool,
+}
+
+/// Every member of the archive at `path`, in the order they are stored.
+pub async fn entries(source: &dyn StorageBackend, path: &str) -> Result<Vec<ZipEntry>> {
+    Ok(directory(source, path).await?.1)
+}
+
+/// The size of the archive at `path` and its members.
+pub async fn directory(source: &dyn StorageBackend, path: &str) -> Result<(u64, Vec<ZipEntry>)> {
+    let size = source.stat(path).await?.size;
+    let tail_start = size.saturating_sub(TAIL_SIZE);
+    let tail = collect(read_range(source, path, tail_start, size - tail_start).await?).await?;
+    let read = |start: u64, len: u64| {
+        let tail = &tail;
+        async move {
+            match start.checked_sub(tail_start) {
+                Some(at) if at + len <= tail.len() as u64 => Ok(tail[at as usize..(at + len) as usize].to_vec()),
+                _ => Ok::<_, anyhow::Error>(collect(read_range(source, path, start, len).await?).await?),
+            }
+        }
+    };
+
+    // The end record is last, save for a comment of up to 64 KiB
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| u32_at(&tail, at) == END_OF_DIRECTORY)
+        .context("Not a ZIP archive")?;
+    let record = &tail[end..];
+    let mut count = u16_at(record, 10) as u64;
+    let mut directory_size = u32_at(record, 12) as u64;
+    let mut directory_start = u32_at(record, 16) as u64;
+
+    if count == 0xFFFF || directory_size == 0xFFFF_FFFF || directory_start == 0xFFFF_FFFF {
+        let locator = end.checked_sub(20).filter(|&at| u32_at(&tail, at) == ZIP64_LOCATOR);
+        let locator = locator.context("Zip64 end record locator missing")?;
+        let record = read(u64_at(&tail, locator + 8), 56).await?;
+        if u32_at(&record, 0) != ZIP64_END_OF_DIRECTORY {
+            anyhow::bail!("Zip64 end record missing");
+        }
+        count = u64_at(&record, 32);
+        directory_size = u64_at(&record, 40);
+        directory_start = u64_at(&record, 48);
+    }
+    if directory_size > MAX_DIRECTORY_SIZE || directory_start + directory_size > size {
+        anyhow::bail!("ZIP central directory is too large or damaged");
+    }
+
+    let directory = read(directory_start, directory_size).await?;
+    let mut entries = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        let (entry, len) = directory.get(at..).and_then(central_entry).context("ZIP central directory is damaged")?;
+        entries.push(entry);
+        at += len;
+    }
+    Ok((size, entries))
+}
+
+/// Parses the central directory header at the start of `header`, returning
+/// the member and the header's length.
+fn central_entry(header: &[u8]) -> Option<(ZipEntry, usize)> {
+    if header.len() < 46 || u32_at(header, 0) != CENTRAL_HEADER {
+        return None;
+    }
+    let name_len = u16_at(header, 28) as usize;
+    let extra_len = u16_at(header, 30) as usize;
+    let comment_len = u16_at(header, 32) as usize;
+    let len = 46 + name_len + extra_len + comment_len;
+    let name = header.get(46..46 + name_len)?;
+    let extra = header.get(46 + name_len..46 + name_len + extra_len)?;
+
+    let mut size = u32_at(header, 24) as u64;
+    let mut compressed = u32_at(header, 20) as u64;
+    let mut offset = u32_at(header, 42) as u64;
+    let mut modified = dos_datetime(u16_at(header, 12), u16_at(header, 14));
+
+    let mut fields = extra;
+    while fields.len() >= 4 {
+        let (id, field_len) = (u16_at(fields, 0), u16_at(fields, 2) as usize);
+        let data = fields.get(4..4 + field_len)?;
+        match id {
+            // Only the values that didn't fit are here, in this order
+            EXTRA_ZIP64 => {
+                let mut values = data.chunks_exact(8).map(|v| u64::from_le_bytes(v.try_into().unwrap()));
+                for value in [&mut size, &mut compressed, &mut offset] {
+                    if *value == 0xFFFF_FFFF {
+                        *value = values.next()?;
+                    }
+                }
+            }
+            EXTRA_TIMESTAMP if data.len() >= 5 && data[0] & 1 != 0 => {
+                modified = DateTime::from_timestamp(i32::from_le_bytes(data[1..5].try_into().unwrap()) as i64, 0);
+            }
+            _ => {}
+        }
+        fields = &fields[4 + field_len..];
+    }
+
+    let name = String::from_utf8_lossy(name).into_owned();
+    let entry = ZipEntry {
+        member: Member {
+            is_dir: name.ends_with('/'),
+            name,
+            size,
+            modified,
+        },
+        offset,
+        compressed,
+        method: u16_at(header, 10),
+        encrypted: u16_at(header, 8) & FLAG_ENCRYPTED != 0,
+    };
+    Some((entry, len))
+}
+
+/// Streams the file `member` out of the archive at `path`.
+pub async fn open(source: &dyn StorageBackend, path: &str, member: &str) -> Result<FileStream> {
+    let (size, entries) = directory(source, path).await?;
+    let entry = entries.iter().rev().find(|e| e.member.path().as_deref() == Some(member));
+    match entry {
+        Some(entry) if !entry.member.is_dir => open_entry(source, path, size, entry).await,
+        Some(_) => anyhow::bail!("{}!{} is a directory", path, member),
+        None => anyhow::bail!("{}!{} not found", path, member),
+    }
+}
+
+/// Streams `entry` out of the archive at `path`, which is `size` bytes long.
+pub async fn open_entry(source: &dyn StorageBackend, path: &str, size: u64, entry: &ZipEntry) -> Result<FileStream> {
+    let codec = match entry.method {
+        _ if entry.encrypted => anyhow::bail!("{}!/{} is encrypted", path, entry.member.name),
+        METHOD_STORED => None,
+        METHOD_DEFLATED => Some(Codec::Deflate),
+        METHOD_ZSTD => Some(Codec::Zstd),
+        method => anyhow::bail!("ZIP compression method {} is not supported", method),
+    };
+
+    // The local header's name and extra field can differ from the central
+    // directory's, so its length is only known once it is read
+    let end = (entry.offset + LOCAL_HEADER_SIZE + 2 * 0xFFFF + entry.compressed).min(size);
+    let mut reader = StreamReader::new(read_range(source, path, entry.offset, end.saturating_sub(entry.offset)).await?);
+    let header = reader.read_exact(LOCAL_HEADER_SIZE as usize).await?;
+    let header = header.filter(|h| u32_at(h, 0) == LOCAL_HEADER).context("ZIP member is damaged")?;
+    reader.skip(u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64).await?;
+
+    let data = reader.take(entry.compressed);
+    Ok(FileStream {
+        stream: match codec {
+            Some(codec) => decompress(data, codec)?,
+            None => data,
+        },
+        size: entry.member.size,
+        modified: entry.member.modified,
+        range: None,
+    })
+}
+
+/// An MS-DOS time and date; archives keep them in local time, which is
+/// taken to be UTC.
+fn dos_datetime(time: u16, date: u16) -> Option<DateTime<Utc>> {
+    let day = NaiveDate::from_ymd_opt(1980 + (date >> 9) as i32, ((date >> 5) & 0xF) as u32, (date & 0x1F) as u32)?;
+    let at = day.and_hms_opt((time >> 11) as u32, ((time >> 5) & 0x3F) as u32, ((time & 0x1F) * 2) as u32)?;
+    Some(at.and_utc())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::archive::{archive_stream, collect_entries};
+    use crate::hosts::local::LocalFileSystem;
+    use crate::models::ArchiveFormat;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// A host holding `/builds/app.zip`, built by our own writer from `/app`.
+    async fn host_with_zip() -> (TempDir, Arc<dyn StorageBackend>) {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("app/lib")).unwrap();
+        std::fs::create_dir_all(dir.path().join("builds")).unwrap();
+        std::fs::write(dir.path().join("app/lib/foo.so"), vec![3; 200_000]).unwrap();
+        std::fs::write(dir.path().join("app/README"), b"read me").unwrap();
+        let fs: Arc<dyn StorageBackend> = Arc::new(LocalFileSystem::new(dir.path().to_str().unwrap()));
+
+        let entries = collect_entries(fs.as_ref(), &["/app".to_string()]).await.unwrap();
+        let archive = collect(archive_stream(fs.clone(), entries, ArchiveFormat::Zip, Arc::default())).await.unwrap();
+        std::fs::write(dir.path().join("builds/app.zip"), archive).unwrap();
+        (dir, fs)
+    }
+
+    #[tokio::test]
+    async fn members_are_read_from_the_central_directory() {
+        let (_dir, fs) = host_with_zip().await;
+
+        let entries = entries(fs.as_ref(), "/builds/app.zip").await.unwrap();
+        let members: Vec<(&str, bool, u64)> =
+            entries.iter().map(|e| (e.member.name.as_str(), e.member.is_dir, e.member.size)).collect();
+        assert_eq!(
+            members,
+            [("app/", true, 0), ("app/README", false, 7), ("app/lib/", true, 0), ("app/lib/foo.so", false, 200_000)]
+        );
+
+        let file = open(fs.as_ref(), "/builds/app.zip", "/app/lib/foo.so").await.unwrap();
+        assert_eq!(file.size, 200_000);
+        assert_eq!(collect(file.stream).await.unwrap(), vec![3; 200_000]);
+
+        assert!(open(fs.as_ref(), "/builds/app.zip", "/app/lib").await.is_err());
+        assert!(open(fs.as_ref(), "/builds/app.zip", "/app/missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_files_are_not_zip_archives() {
+        let (dir, fs) = host_with_zip().await;
+        std::fs::write(dir.path().join("builds/fake.zip"), b"not a zip at all, honestly").unwrap();
+
+        let err = entries(fs.as_ref(), "/builds/fake.zip").await.err().unwrap();
+        assert_eq!(err.to_string(), "Not a ZIP archive");
+    }
+
+    #[test]
+    fn dos_timestamps_are_read() {
+        let at = dos_datetime((10 << 11) | (20 << 5) | 15, (44 << 9) | (3 << 5) | 5).unwrap();
+        assert_eq!(at.to_rfc3339(), "2024-03-05T10:20:30+00:00");
+        assert_eq!(dos_datetime(0, 0), None);
+    }
+}
@@ -0,0 +1,290 @@
+//! Parsers for the directory index pages web servers generate: nginx autoindex
+//! (HTML and JSON), Apache mod_autoindex, lighttpd mod_dirlisting and Python's
+//! `http.server`.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use percent_encoding::percent_decode_str;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::hosts::{join_path, sort_entries};
+use crate::models::FileInfo;
+
+/// Timestamp layouts used by the supported servers, after whitespace is collapsed.
+const DATE_FORMATS: &[&str] = &[
+    "%d-%b-%Y %H:%M",    // nginx, Apache <pre> listings
+    "%d-%b-%Y %H:%M:%S",
+    "%Y-%m-%d %H:%M",    // Apache tables
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%b-%d %H:%M:%S", // lighttpd
+];
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    mtime: Option<String>,
+    size: Option<u64>,
+}
+
+/// Parses a directory listing served for `dir_path`. `listing_path` is the URL
+/// path the listing was actually served from, used to resolve absolute links.
+pub fn parse_listing(body: &str, content_type: Option<&str>, dir_path: &str, listing_path: &str) -> Result<Vec<FileInfo>> {
+    let is_json = content_type.is_some_and(|ct| ct.contains("json")) || body.trim_start().starts_with('[');
+
+    let mut files = if is_json {
+        parse_json(body, dir_path)?
+    } else {
+        parse_html(body, dir_path, listing_path)
+    };
+
+    sort_entries(&mut files);
+    Ok(files)
+}
+
+/// nginx `autoindex_format json`.
+fn parse_json(body: &str, dir_path: &str) -> Result<Vec<FileInfo>> {
+    let entries: Vec<JsonEntry> = serde_json::from_str(body).context("Invalid JSON directory listing")?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|e| e.kind != "other")
+        .map(|e| {
+            let is_dir = e.kind == "directory";
+            FileInfo {
+                path: join_path(dir_path, &e.name),
+                is_dir,
+                size: if is_dir { 0 } else { e.size.unwrap_or(0) },
+                modified: e.mtime
+                    .and_then(|m| DateTime::parse_from_rfc2822(&m).ok())
+                    .map(|dt| dt.with_timezone(&Utc)),
+                name: e.name,
+            }
+        })
+        .collect())
+}
+
+/// Every server lists entries as links; size and date, when present, follow the
+/// link either in the same table row or on the same `<pre>` line.
+fn parse_html(body: &str, dir_path: &str, listing_path: &str) -> Vec<FileInfo> {
+    let document = Html::parse_document(body);
+    let links = Selector::parse("a[href]").unwrap();
+
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+
+    for link in document.select(&links) {
+        // Icon links carry no text; the named link next to them is the entry
+        if link.text().all(|t| t.trim().is_empty()) {
+            continue;
+        }
+
+        let Some((name, is_dir)) = link.value().attr("href").and_then(|h| entry_name(h, listing_path)) else {
+            continue;
+        };
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+
+        let (modified, size) = parse_details(&trailing_text(link));
+
+        files.push(FileInfo {
+            path: join_path(dir_path, &name),
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { size.unwrap_or(0) },
+            modified,
+        });
+    }
+
+    files
+}
+
+/// Turns a link target into an entry name, skipping sort links, parent links
+/// and anything that points outside the listed directory.
+fn entry_name(href: &str, listing_path: &str) -> Option<(String, bool)> {
+    if href.starts_with('?') || href.starts_with('#') || href.contains("://") {
+        return None;
+    }
+
+    let href = href.split(['?', '#']).next().unwrap_or("");
+    let relative = if href.starts_with('/') {
+        let dir = if listing_path.ends_with('/') {
+            listing_path.to_string()
+        } else {
+            format!("{}/", listing_path)
+        };
+        href.strip_prefix(dir.as_str())?
+    } else {
+        href.strip_prefix("./").unwrap_or(href)
+    };
+
+    let is_dir = relative.ends_with('/');
+    let name = relative.trim_end_matches('/');
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+
+    let name = percent_decode_str(name).decode_utf8().ok()?.into_owned();
+    Some((name, is_dir))
+}
+
+/// Text describing the entry a link belongs to: the remaining cells of its
+/// table row, or the rest of its `<pre>` line.
+fn trailing_text(link: ElementRef) -> String {
+    let cell = link.ancestors().filter_map(ElementRef::wrap).find(|e| e.value().name() == "td");
+    if let Some(cell) = cell {
+        return cell
+            .next_siblings()
+            .filter_map(ElementRef::wrap)
+            .filter(|e| e.value().name() == "td")
+            .flat_map(|e| e.text())
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+
+    match link.next_sibling().map(|n| n.value()) {
+        Some(Node::Text(text)) => text.lines().next().unwrap_or("").to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Extracts the modification time and size from text such as
+/// `01-Jan-2024 12:00    1234` or `2024-01-01 12:00  1.2K`.
+fn parse_details(text: &str) -> (Option<DateTime<Utc>>, Option<u64>) {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+
+    for (i, pair) in tokens.windows(2).enumerate() {
+        let candidate = pair.join(" ");
+        let parsed = DATE_FORMATS
+            .iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(&candidate, f).ok());
+
+        if let Some(naive) = parsed {
+            let size = tokens.get(i + 2).and_then(|t| parse_size(t));
+            return (Some(naive.and_utc()), size);
+        }
+    }
+
+    (None, tokens.first().and_then(|t| parse_size(t)))
+}
+
+/// Parses exact byte counts and the rounded `1.2K`/`4.0M` forms; `-` means none.
+fn parse_size(token: &str) -> Option<u64> {
+    if let Ok(bytes) = token.parse() {
+        return Some(bytes);
+    }
+
+    let upper = token.to_ascii_uppercase();
+    let trimmed = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, unit) = trimmed.split_at(trimmed.len().checked_sub(1)?);
+    let exponent = match unit {
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    let value: f64 = number.parse().ok()?;
+
+    Some((value * 1024f64.powi(exponent)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(files: &[FileInfo]) -> Vec<(&str, bool, u64)> {
+        files.iter().map(|f| (f.name.as_str(), f.is_dir, f.size)).collect()
+    }
+
+    #[test]
+    fn parses_nginx_html() {
+        let body = r#"<html><head><title>Index of /pub/</title></head><body>
+<h1>Index of /pub/</h1><hr><pre><a href="../">../</a>
+<a href="releases/">releases/</a>                                          02-Mar-2024 09:15                   -
+<a href="my%20notes.txt">my notes.txt</a>                                  01-Jan-2024 12:00                1234
+</pre><hr></body></html>"#;
+
+        let files = parse_listing(body, Some("text/html"), "/pub", "/pub/").unwrap();
+
+        assert_eq!(summary(&files), [("releases", true, 0), ("my notes.txt", false, 1234)]);
+        assert_eq!(files[1].path, "/pub/my notes.txt");
+        assert_eq!(files[1].modified.unwrap().to_rfc3339(), "2024-01-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn parses_nginx_json() {
+        let body = r#"[
+            {"name":"releases", "type":"directory", "mtime":"Sat, 02 Mar 2024 09:15:00 GMT"},
+            {"name":"notes.txt", "type":"file", "mtime":"Mon, 01 Jan 2024 12:00:00 GMT", "size":1234}
+        ]"#;
+
+        let files = parse_listing(body, Some("application/json"), "/", "/").unwrap();
+
+        assert_eq!(summary(&files), [("releases", true, 0), ("notes.txt", false, 1234)]);
+        assert_eq!(files[0].modified.unwrap().to_rfc3339(), "2024-03-02T09:15:00+00:00");
+    }
+
+    #[test]
+    fn parses_apache_table() {
+        let body = r#"<table>
+<tr><th valign="top"><img src="/icons/blank.gif" alt="[ICO]"></th><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+<tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/files/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td></tr>
+<tr><td valign="top"><a href="docs/"><img src="/icons/folder.gif" alt="[DIR]"></a></td><td><a href="docs/">docs/</a></td><td align="right">2024-01-01 12:00  </td><td align="right">  - </td></tr>
+<tr><td valign="top"><img src="/icons/text.gif" alt="[TXT]"></td><td><a href="big.iso">big.iso</a></td><td align="right">2024-02-03 04:05  </td><td align="right">4.0M</td></tr>
+</table>"#;
+
+        let files = parse_listing(body, None, "/mirror", "/files/mirror/").unwrap();
+
+        assert_eq!(summary(&files), [("docs", true, 0), ("big.iso", false, 4 * 1024 * 1024)]);
+        assert_eq!(files[1].modified.unwrap().to_rfc3339(), "2024-02-03T04:05:00+00:00");
+    }
+
+    #[test]
+    fn parses_lighttpd() {
+        let body = r#"<table summary="Directory Listing" cellpadding="0" cellspacing="0"><tbody>
+<tr class="d"><td class="n"><a href="../">Parent Directory</a>/</td><td class="m">&nbsp;</td><td class="s">- &nbsp;</td><td class="t">Directory</td></tr>
+<tr class="d"><td class="n"><a href="photos/">photos</a>/</td><td class="m">2024-Mar-02 09:15:30</td><td class="s">- &nbsp;</td><td class="t">Directory</td></tr>
+<tr><td class="n"><a href="a.txt">a.txt</a></td><td class="m">2024-Jan-01 12:00:00</td><td class="s">1.5K</td><td class="t">text/plain</td></tr>
+</tbody></table>"#;
+
+        let files = parse_listing(body, Some("text/html"), "/", "/").unwrap();
+
+        assert_eq!(summary(&files), [("photos", true, 0), ("a.txt", false, 1536)]);
+        assert_eq!(files[0].modified.unwrap().to_rfc3339(), "2024-03-02T09:15:30+00:00");
+    }
+
+    #[test]
+    fn parses_python_http_server() {
+        let body = r#"<!DOCTYPE HTML><html><body><h1>Directory listing for /</h1><hr><ul>
+<li><a href="src/">src/</a></li>
+<li><a href="Cargo.toml">Cargo.toml</a></li>
+</ul><hr></body></html>"#;
+
+        let files = parse_listing(body, Some("text/html; charset=utf-8"), "/", "/").unwrap();
+
+        assert_eq!(summary(&files), [("src", true, 0), ("Cargo.toml", false, 0)]);
+        assert!(files[1].modified.is_none());
+    }
+
+    #[test]
+    fn links_outside_the_directory_are_ignored() {
+        assert_eq!(entry_name("https://example.com/", "/"), None);
+        assert_eq!(entry_name("/other/file", "/pub/"), None);
+        assert_eq!(entry_name("sub/dir/file", "/"), None);
+        assert_eq!(entry_name("/pub/file", "/pub"), Some(("file".to_string(), false)));
+        assert_eq!(entry_name("./a%2Bb/", "/"), Some(("a+b".to_string(), true)));
+    }
+
+    #[test]
+    fn parse_size_handles_rounded_units() {
+        assert_eq!(parse_size("1234"), Some(1234));
+        assert_eq!(parse_size("2K"), Some(2048));
+        assert_eq!(parse_size("1.5MiB"), Some(1572864));
+        assert_eq!(parse_size("-"), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Plumbing between async byte streams and the places they flow to or from:
+//! the blocking SSH/FTP clients on one side and reqwest request bodies on the other.
+
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::LAST_MODIFIED;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+use crate::hosts::{ByteStream, FileStream};
+
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered between producer and consumer before the producer waits.
+pub const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// The receiving end of a chunk channel as a stream. Unlike most streams it
+/// is `Sync`, which reqwest requires of streamed request bodies.
+struct ChannelStream(mpsc::Receiver<std::io::Result<Bytes>>);
+
+impl Stream for ChannelStream {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Adapts the receiving end of a chunk channel to a `ByteStream`.
+pub fn channel_stream(rx: mpsc::Receiver<std::io::Result<Bytes>>) -> ByteStream {
+    Box::pin(ChannelStream(rx))
+}
+
+/// Turns `stream` into a request body. The returned future moves the chunks
+/// across and must be polled alongside the request, e.g. with `tokio::join!`;
+/// it finishes early if the request drops the body.
+pub fn relay_body(mut stream: ByteStream) -> (reqwest::Body, impl Future<Output = ()> + Send) {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    let pump = async move {
+        while let Some(chunk) = stream.next().await {
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    };
+    (reqwest::Body::wrap_stream(ChannelStream(rx)), pump)
+}
+
+/// Streams the body of a successful GET. Servers that don't announce a
+/// length get their body buffered so the size can still be reported.
+pub async fn response_stream(response: reqwest::Response) -> Result<FileStream> {
+    let modified = response
+        .headers()
+        .get(LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map(|dt| dt.with_timezone(&Utc));
+
+    let Some(size) = response.content_length() else {
+        let content = response.bytes().await?;
+        return Ok(FileStream {
+            size: content.len() as u64,
+            modified,
+            range: None,
+            stream: Box::pin(futures::stream::once(async move { Ok(content) })),
+        });
+    };
+
+    Ok(FileStream {
+        stream: Box::pin(response.bytes_stream().map_err(std::io::Error::other)),
+        size,
+        modified,
+        range: None,
+    })
+}
+
+/// Forwards `stream` to a blocking writer fed by `receive_chunks`. `None`
+/// marks the end; a failed chunk drops the sender without it, which aborts
+/// the write.
+pub async fn feed_chunks(mut stream: ByteStream, chunks: mpsc::Sender<Option<Bytes>>) {
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else { return };
+        if chunks.send(Some(chunk)).await.is_err() {
+            return;
+        }
+    }
+    let _ = chunks.send(None).await;
+}
+
+/// Blocking half of `feed_chunks`: writes every chunk to `writer` and returns
+/// the number of bytes written, or an error if the sender gave up early.
+pub fn receive_chunks(writer: &mut impl Write, chunks: &mut mpsc::Receiver<Option<Bytes>>) -> Result<u64> {
+    let mut written = 0;
+    loop {
+        match chunks.blocking_recv() {
+            Some(Some(chunk)) => {
+                writer.write_all(&chunk)?;
+                written += chunk.len() as u64;
+            }
+            Some(None) => return Ok(written),
+            None => anyhow::bail!("Upload aborted"),
+        }
+    }
+}
+
+/// Reads up to `limit` bytes (or to the end) from a blocking reader and sends
+/// them through `chunks`. A read error is sent on and returned; a receiver
+/// that went away just stops the transfer.
+pub fn send_chunks(
+    reader: &mut impl Read,
+    mut limit: Option<u64>,
+    chunks: &mpsc::Sender<std::io::Result<Bytes>>,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+    loop {
+        let want = limit.map_or(buffer.len(), |l| l.min(buffer.len() as u64) as usize);
+        if want == 0 {
+            return Ok(());
+        }
+
+        match reader.read(&mut buffer[..want]) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                limit = limit.map(|l| l - n as u64);
+                if chunks.blocking_send(Ok(Bytes::copy_from_slice(&buffer[..n]))).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                let kind = e.kind();
+                let _ = chunks.blocking_send(Err(e));
+                return Err(kind.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn blocking_halves_round_trip() {
+        let content: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        let source = content.clone();
+        let reader = tokio::task::spawn_blocking(move || send_chunks(&mut source.as_slice(), Some(150_000), &tx));
+        let stream = channel_stream(rx);
+
+        let (tx, mut rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        let writer = tokio::task::spawn_blocking(move || {
+            let mut sink = Vec::new();
+            receive_chunks(&mut sink, &mut rx).map(|n| (n, sink))
+        });
+
+        feed_chunks(stream, tx).await;
+        reader.await.unwrap().unwrap();
+        let (written, sink) = writer.await.unwrap().unwrap();
+        assert_eq!(written, 150_000);
+        assert_eq!(sink, &content[..150_000]);
+    }
+
+    #[tokio::test]
+    async fn failed_chunks_abort_the_writer() {
+        let stream: ByteStream = Box::pin(futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err(std::io::Error::other("client went away")),
+        ]));
+
+        let (tx, mut rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        let writer = tokio::task::spawn_blocking(move || receive_chunks(&mut Vec::new(), &mut rx));
+        feed_chunks(stream, tx).await;
+
+        let err = writer.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Upload aborted");
+    }
+}
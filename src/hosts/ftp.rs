@@ -0,0 +1,433 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use suppaftp::list::File;
+use suppaftp::native_tls::TlsConnector;
+use suppaftp::types::FileType;
+use suppaftp::{FtpError, NativeTlsConnector, NativeTlsFtpStream, Status};
+
+use crate::hosts::bridge::{channel_stream, feed_chunks, receive_chunks, send_chunks, STREAM_BUFFER_CHUNKS};
+use crate::hosts::range::RangeRequest;
+use crate::hosts::{join_path, sort_entries, staging_path, ByteStream, Capabilities, FileStream, StorageBackend, StorageError};
+use crate::models::FileInfo;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+pub struct FtpFileSystem {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    /// Upgrade the control and data connections with `AUTH TLS` (explicit FTPS).
+    tls: bool,
+}
+
+impl FtpFileSystem {
+    pub fn new(host: &str, port: u16, username: &str, password: &str, tls: bool) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            username: username.to_string(),
+            password: password.to_string(),
+            tls,
+        }
+    }
+
+    fn connect(&self) -> Result<NativeTlsFtpStream> {
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&(self.host.as_str(), self.port))?
+            .next()
+            .with_context(|| format!("Failed to resolve {}", self.host))?;
+        let mut ftp = NativeTlsFtpStream::connect_timeout(addr, CONNECT_TIMEOUT)
+            .context("Failed to connect to FTP server")?;
+
+        if self.tls {
+            let connector = TlsConnector::new().context("Failed to initialize TLS")?;
+            ftp = ftp
+                .into_secure(NativeTlsConnector::from(connector), &self.host)
+                .context("Failed to negotiate TLS with FTP server")?;
+        }
+
+        // Servers behind NAT often advertise a private address in their PASV reply
+        ftp.set_passive_nat_workaround(true);
+        ftp.login(&self.username, &self.password)
+            .context("FTP authentication failed")?;
+        ftp.transfer_type(FileType::Binary)?;
+
+        Ok(ftp)
+    }
+
+    /// Runs a synchronous FTP session on the blocking thread pool.
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut NativeTlsFtpStream) -> Result<T> + Send + 'static,
+    {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut ftp = this.connect()?;
+            let result = op(&mut ftp);
+            let _ = ftp.quit();
+            result
+        })
+        .await?
+    }
+}
+
+/// Lists `path`, preferring machine-readable MLSD and falling back to LIST
+/// on servers that predate it.
+fn list_dir(ftp: &mut NativeTlsFtpStream, path: &str) -> Result<Vec<File>> {
+    let parsed: Vec<File> = match ftp.mlsd(Some(path)) {
+        Ok(lines) => lines.iter().filter_map(|l| File::from_mlsx_line(l).ok()).collect(),
+        Err(e) if is_unsupported(&e) => ftp
+            .list(Some(path))?
+            .iter()
+            .filter_map(|l| l.parse::<File>().ok())
+            .collect(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", path)),
+    };
+
+    Ok(parsed
+        .into_iter()
+        .filter(|f| f.name() != "." && f.name() != "..")
+        .collect())
+}
+
+fn file_info(parent: &str, file: &File) -> FileInfo {
+    let is_dir = file.is_directory();
+    FileInfo {
+        name: file.name().to_string(),
+        path: join_path(parent, file.name()),
+        is_dir,
+        size: if is_dir { 0 } else { file.size() as u64 },
+        modified: modified(file.modified()),
+    }
+}
+
+/// Listings without a date parse as the epoch; report those as unknown.
+fn modified(time: SystemTime) -> Option<DateTime<Utc>> {
+    if time <= SystemTime::UNIX_EPOCH {
+        return None;
+    }
+    Some(DateTime::<Utc>::from(time))
+}
+
+fn is_unsupported(err: &FtpError) -> bool {
+    matches!(
+        err,
+        FtpError::UnexpectedResponse(r) if matches!(
+            r.status,
+            Status::BadCommand | Status::NotImplemented | Status::CommandNotImplemented
+        )
+    )
+}
+
+fn is_unavailable(err: &FtpError) -> bool {
+    matches!(err, FtpError::UnexpectedResponse(r) if r.status == Status::FileUnavailable)
+}
+
+/// Whether `path` is a directory, using CWD so it works without MLST.
+fn is_dir(ftp: &mut NativeTlsFtpStream, path: &str) -> bool {
+    ftp.cwd(path).is_ok()
+}
+
+fn exists(ftp: &mut NativeTlsFtpStream, path: &str) -> bool {
+    is_dir(ftp, path) || ftp.size(path).is_ok()
+}
+
+fn mkdir_all(ftp: &mut NativeTlsFtpStream, path: &str) -> Result<()> {
+    let mut current = String::new();
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        current.push('/');
+        current.push_str(segment);
+
+        if let Err(e) = ftp.mkdir(&current) {
+            // MKD on an existing directory fails with 550 as well
+            if !(is_unavailable(&e) && is_dir(ftp, &current)) {
+                return Err(e).with_context(|| format!("Failed to create directory {}", current));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_all(ftp: &mut NativeTlsFtpStream, path: &str) -> Result<()> {
+    if !is_dir(ftp, path) {
+        ftp.rm(path).with_context(|| format!("Failed to delete {}", path))?;
+        return Ok(());
+    }
+
+    for entry in list_dir(ftp, path)? {
+        let child = join_path(path, entry.name());
+        if entry.is_directory() {
+            remove_all(ftp, &child)?;
+        } else {
+            ftp.rm(&child).with_context(|| format!("Failed to delete {}", child))?;
+        }
+    }
+
+    ftp.rmdir(path).with_context(|| format!("Failed to remove directory {}", path))?;
+    Ok(())
+}
+
+/// Opens `path` for download, reports its size and date through `head`,
+/// then sends its content through `chunks`.
+fn stream_file(
+    ftp: &mut NativeTlsFtpStream,
+    path: &str,
+    head: oneshot::Sender<Result<(u64, Option<DateTime<Utc>>)>>,
+    chunks: mpsc::Sender<std::io::Result<Bytes>>,
+) {
+    let opened = (|| -> Result<_> {
+        let size = ftp.size(path).with_context(|| format!("Failed to stat {}", path))? as u64;
+        let modified = ftp.mdtm(path).ok().map(|t| t.and_utc());
+        let data = ftp.retr_as_stream(path).with_context(|| format!("Failed to download {}", path))?;
+        Ok((data, size, modified))
+    })();
+
+    let (mut data, size, modified) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = head.send(Err(e));
+            return;
+        }
+    };
+
+    if head.send(Ok((size, modified))).is_ok() {
+        let _ = send_chunks(&mut data, None, &chunks);
+    }
+    // Reads the transfer-complete reply; an aborted transfer just fails it
+    let _ = ftp.finalize_retr_stream(data);
+}
+
+/// Uploads chunks from `chunks` to a staging file next to `path`, then moves
+/// it over `path`. An upload that ends without the `None` end marker leaves
+/// `path` untouched.
+fn receive_file(ftp: &mut NativeTlsFtpStream, path: &str, mut chunks: mpsc::Receiver<Option<Bytes>>) -> Result<u64> {
+    if let Some(parent) = parent_of(path) {
+        mkdir_all(ftp, parent)?;
+    }
+
+    let staging = staging_path(Path::new(path)).to_string_lossy().into_owned();
+    let mut data = ftp
+        .put_with_stream(&staging)
+        .with_context(|| format!("Failed to upload {}", path))?;
+    let received = receive_chunks(&mut data, &mut chunks);
+    let finalized = ftp.finalize_put_stream(data).context("FTP upload was not completed");
+
+    let result = received.and_then(|written| {
+        finalized?;
+        replace_file(ftp, &staging, path)?;
+        Ok(written)
+    });
+    if result.is_err() {
+        let _ = ftp.rm(&staging);
+    }
+    result
+}
+
+/// Moves `from` over `path`. RNTO onto an existing file fails on some
+/// servers, so an existing file is moved aside first, and put back if the
+/// move still fails.
+fn replace_file(ftp: &mut NativeTlsFtpStream, from: &str, path: &str) -> Result<()> {
+    if ftp.size(path).is_err() {
+        return ftp
+            .rename(from, path)
+            .with_context(|| format!("Failed to move upload into place at {}", path));
+    }
+
+    let aside = staging_path(Path::new(path)).to_string_lossy().into_owned();
+    ftp.rename(path, aside.as_str()).with_context(|| format!("Failed to replace {}", path))?;
+    if let Err(e) = ftp.rename(from, path) {
+        if let Err(undo) = ftp.rename(aside.as_str(), path) {
+            log::error!("Failed to restore {} after a failed upload: {}", path, undo);
+        }
+        return Err(e).with_context(|| format!("Failed to move upload into place at {}", path));
+    }
+    if let Err(e) = ftp.rm(&aside) {
+        log::warn!("Failed to remove replaced file {}: {}", aside, e);
+    }
+    Ok(())
+}
+
+fn parent_of(path: &str) -> Option<&str> {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => Some(parent),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FtpFileSystem {
+    fn label(&self) -> &'static str {
+        "FTP"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list: true,
+            read: true,
+            write: true,
+            delete: true,
+            mkdir: true,
+            rename: true,
+            ..Default::default()
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let path = path.to_string();
+        self.run(move |ftp| {
+            let mut files: Vec<FileInfo> = list_dir(ftp, &path)?
+                .iter()
+                .map(|f| file_info(&path, f))
+                .collect();
+            sort_entries(&mut files);
+            Ok(files)
+        })
+        .await
+    }
+
+    async fn stat(&self, path: &str) -> Result<FileInfo> {
+        let path = path.to_string();
+        self.run(move |ftp| {
+            let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string();
+
+            if let Ok(line) = ftp.mlst(Some(&path)) {
+                if let Ok(file) = File::from_mlsx_line(line.trim()) {
+                    return Ok(FileInfo { name, path: path.clone(), ..file_info("/", &file) });
+                }
+            }
+
+            if is_dir(ftp, &path) {
+                return Ok(FileInfo { name, path, is_dir: true, size: 0, modified: None });
+            }
+
+            let size = ftp.size(&path).with_context(|| format!("Failed to stat {}", path))?;
+            let modified = ftp.mdtm(&path).ok().map(|t| t.and_utc());
+            Ok(FileInfo { name, path, is_dir: false, size: size as u64, modified })
+        })
+        .await
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let path = path.to_string();
+        self.run(move |ftp| {
+            let buffer = ftp
+                .retr_as_buffer(&path)
+                .with_context(|| format!("Failed to download {}", path))?;
+            Ok(buffer.into_inner())
+        })
+        .await
+    }
+
+    async fn read_stream(&self, path: &str, _range: Option<RangeRequest>) -> Result<FileStream> {
+        let this = self.clone();
+        let path = path.to_string();
+        let (head_tx, head_rx) = oneshot::channel();
+        let (chunk_tx, chunk_rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+        tokio::task::spawn_blocking(move || match this.connect() {
+            Ok(mut ftp) => {
+                stream_file(&mut ftp, &path, head_tx, chunk_tx);
+                let _ = ftp.quit();
+            }
+            Err(e) => {
+                let _ = head_tx.send(Err(e));
+            }
+        });
+
+        let (size, modified) = head_rx.await.context("FTP download ended unexpectedly")??;
+
+        Ok(FileStream {
+            stream: channel_stream(chunk_rx),
+            size,
+            modified,
+            range: None,
+        })
+    }
+
+    async fn write(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = path.to_string();
+        let content = content.to_vec();
+        self.run(move |ftp| {
+            if let Some(parent) = parent_of(&path) {
+                mkdir_all(ftp, parent)?;
+            }
+            ftp.put_file(&path, &mut Cursor::new(content))
+                .with_context(|| format!("Failed to upload {}", path))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn write_stream(&self, path: &str, stream: ByteStream) -> Result<u64> {
+        let path = path.to_string();
+        let (chunk_tx, chunk_rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+        let writer = self.run(move |ftp| receive_file(ftp, &path, chunk_rx));
+        let (written, ()) = tokio::join!(writer, feed_chunks(stream, chunk_tx));
+        written
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.run(move |ftp| remove_all(ftp, &path)).await
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.run(move |ftp| mkdir_all(ftp, &path)).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.run(move |ftp| {
+            // RNTO replaces existing files on most servers; refuse like the other backends
+            if exists(ftp, &to) {
+                return Err(StorageError::AlreadyExists(to).into());
+            }
+            if let Some(parent) = parent_of(&to) {
+                mkdir_all(ftp, parent)?;
+            }
+            ftp.rename(&from, &to)
+                .with_context(|| format!("Failed to rename {} to {}", from, to))?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mlsd_and_list_lines() {
+        let mlsd = File::from_mlsx_line("type=file;size=1234;modify=20240101120000;perm=r; notes.txt").unwrap();
+        let info = file_info("/drop", &mlsd);
+        assert_eq!((info.name.as_str(), info.path.as_str(), info.is_dir, info.size), ("notes.txt", "/drop/notes.txt", false, 1234));
+        assert_eq!(info.modified.unwrap().to_rfc3339(), "2024-01-01T12:00:00+00:00");
+
+        let posix: File = "drwxr-xr-x    2 ftp      ftp          4096 Mar 02  2024 inbox".parse().unwrap();
+        let info = file_info("/", &posix);
+        assert_eq!((info.path.as_str(), info.is_dir, info.size), ("/inbox", true, 0));
+
+        let dos: File = "01-01-24  12:00PM               512 report.csv".parse().unwrap();
+        assert_eq!(file_info("/", &dos).size, 512);
+    }
+
+    #[test]
+    fn parent_of_skips_the_root() {
+        assert_eq!(parent_of("/a/b/c.txt"), Some("/a/b"));
+        assert_eq!(parent_of("/c.txt"), None);
+        assert_eq!(parent_of("c.txt"), None);
+    }
+}
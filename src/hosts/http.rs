@@ -1,21 +1,50 @@
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, LAST_MODIFIED};
 use anyhow::{Result, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::hosts::bridge::response_stream;
+use crate::hosts::range::RangeRequest;
+use crate::hosts::{autoindex, encode_path, Capabilities, FileStream, StorageBackend};
 use crate::models::FileInfo;
 
 pub struct HttpFileSystem {
     client: Client,
+    base_url: String,
 }
 
 impl HttpFileSystem {
-    pub fn new() -> Self {
+    pub fn new(base_url: &str) -> Self {
         Self {
             client: Client::new(),
+            base_url: base_url.to_string(),
         }
     }
 
-    pub async fn list_files(&self, base_url: &str, path: &str) -> Result<Vec<FileInfo>> {
-        let url = format!("{}/{}", base_url.trim_end_matches('/'), path.trim_start_matches('/'));
-        
+    /// Listings report names decoded, so they are encoded again here.
+    fn url_for(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), encode_path(path))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HttpFileSystem {
+    fn label(&self) -> &'static str {
+        "HTTP"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list: true,
+            read: true,
+            ..Default::default()
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>> {
+        // Index pages link entries relative to a slash-terminated directory URL
+        let url = format!("{}/", self.url_for(path).trim_end_matches('/'));
+
         let response = self.client
             .get(&url)
             .send()
@@ -26,15 +55,70 @@ impl HttpFileSystem {
             anyhow::bail!("HTTP request failed with status: {}", response.status());
         }
 
-        // Parse directory listing (this is a simplified implementation)
-        // In a real scenario, you'd parse HTML or use a specific API
-        let files = vec![]; // Placeholder
-        Ok(files)
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let listing_path = response.url().path().to_string();
+        let body = response.text().await?;
+
+        autoindex::parse_listing(&body, content_type.as_deref(), path, &listing_path)
+    }
+
+    async fn stat(&self, path: &str) -> Result<FileInfo> {
+        let url = self.url_for(path);
+
+        let mut response = self.client
+            .head(&url)
+            .send()
+            .await
+            .context("Failed to send HTTP request")?;
+
+        // Some servers reject HEAD; a GET whose body is never read gives the same headers
+        if response.status() == StatusCode::METHOD_NOT_ALLOWED {
+            response = self.client
+                .get(&url)
+                .send()
+                .await
+                .context("Failed to send HTTP request")?;
+        }
+
+        if !response.status().is_success() {
+            anyhow::bail!("HTTP request failed with status: {}", response.status());
+        }
+
+        let headers = response.headers();
+        let size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let modified = headers
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map(|dt| dt.with_timezone(&Utc));
+
+        // Directory listings are served from slash-terminated URLs; servers
+        // redirect `/dir` to `/dir/`, so check the final URL after redirects
+        let is_dir = response.url().path().ends_with('/');
+
+        let trimmed = path.trim_end_matches('/');
+        let name = trimmed.rsplit('/').next().unwrap_or("").to_string();
+
+        Ok(FileInfo {
+            name,
+            path: path.to_string(),
+            is_dir,
+            size: if is_dir { 0 } else { size },
+            modified,
+        })
     }
 
-    pub async fn read_file(&self, base_url: &str, path: &str) -> Result<Vec<u8>> {
-        let url = format!("{}/{}", base_url.trim_end_matches('/'), path.trim_start_matches('/'));
-        
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let url = self.url_for(path);
+
         let response = self.client
             .get(&url)
             .send()
@@ -49,18 +133,34 @@ impl HttpFileSystem {
         Ok(bytes)
     }
 
-    pub async fn download_file(&self, url: &str) -> Result<Vec<u8>> {
+    async fn read_stream(&self, path: &str, _range: Option<RangeRequest>) -> Result<FileStream> {
         let response = self.client
-            .get(url)
+            .get(self.url_for(path))
             .send()
             .await
-            .context("Failed to download file")?;
+            .context("Failed to send HTTP request")?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Download failed with status: {}", response.status());
+            anyhow::bail!("HTTP request failed with status: {}", response.status());
         }
 
-        let bytes = response.bytes().await?.to_vec();
-        Ok(bytes)
+        response_stream(response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_names_map_back_to_their_urls() {
+        let body = r#"<html><body><pre><a href="../">../</a>
+<a href="a%20%23b%3F%25c.txt">a #b?%c.txt</a>                                01-Jan-2024 12:00                1234
+</pre></body></html>"#;
+        let files = autoindex::parse_listing(body, Some("text/html"), "/pub", "/pub/").unwrap();
+        assert_eq!(files[0].path, "/pub/a #b?%c.txt");
+
+        let host = HttpFileSystem::new("https://example.com/files/");
+        assert_eq!(host.url_for(&files[0].path), "https://example.com/files/pub/a%20%23b%3F%25c.txt");
     }
 }
@@ -1,14 +1,140 @@
+use crate::hosts::range::RangeRequest;
+use crate::hosts::{join_path, normalize_path, sort_entries, staging_path, ByteStream, Capabilities, FileStream, StorageBackend, StorageError};
 use crate::models::FileInfo;
 use anyhow::{Context, Result};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fs::Metadata;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
-pub struct LocalFileSystem;
+pub struct LocalFileSystem {
+    base_path: String,
+}
 
 impl LocalFileSystem {
-    pub async fn list_files(base_path: &str, path: &str) -> Result<Vec<FileInfo>> {
-        let full_path = Self::resolve_path(base_path, path)?;
+    pub fn new(base_path: &str) -> Self {
+        Self {
+            base_path: base_path.to_string(),
+        }
+    }
+
+    fn modified(metadata: &Metadata) -> Option<DateTime<Utc>> {
+        metadata.modified().ok().and_then(|time| {
+            let duration = time.duration_since(std::time::UNIX_EPOCH).ok()?;
+            DateTime::<Utc>::from_timestamp(duration.as_secs() as i64, duration.subsec_nanos())
+        })
+    }
+
+    pub fn file_info(name: String, path: String, metadata: &Metadata) -> FileInfo {
+        FileInfo {
+            name,
+            path,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: Self::modified(metadata),
+        }
+    }
+
+    /// Copies a file or directory tree. Symlinks are skipped rather than followed,
+    /// so a link pointing outside the base path (or at an ancestor) is never descended.
+    async fn copy_recursive(from: &Path, to: &Path) -> Result<()> {
+        let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+
+        while let Some((src, dst)) = pending.pop() {
+            let metadata = fs::symlink_metadata(&src).await?;
+
+            if metadata.file_type().is_symlink() {
+                log::warn!("Skipping symlink {} during copy", src.display());
+            } else if metadata.is_dir() {
+                fs::create_dir_all(&dst).await?;
+                let mut entries = fs::read_dir(&src).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push((entry.path(), dst.join(entry.file_name())));
+                }
+            } else {
+                fs::copy(&src, &dst).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve_path(base_path: &str, path: &str) -> Result<PathBuf> {
+        let base = Path::new(base_path)
+            .canonicalize()
+            .context("Invalid base path")?;
+
+        // `..` is resolved before anything touches the disk, so it cannot climb
+        // out through a part of the path that does not exist yet
+        let relative = normalize_path(path)?;
+        let requested = base.join(relative.trim_start_matches('/'));
+
+        // Canonicalize the deepest existing ancestor to resolve symlinks, then
+        // re-append the components that do not exist yet
+        let mut existing = requested.as_path();
+        let mut missing = Vec::new();
+        while !fs_exists(existing) {
+            missing.push(existing.file_name().context("Invalid path")?);
+            existing = existing.parent().context("Invalid path")?;
+        }
+        let mut resolved = existing.canonicalize()?;
+        resolved.extend(missing.iter().rev());
+
+        // Ensure the resolved path is within the base path
+        if !resolved.starts_with(&base) {
+            anyhow::bail!("Path traversal attempt detected");
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// Removes a staging file on drop unless the upload was committed, so a
+/// cancelled request never leaves partial files behind.
+struct StagingFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Like `Path::exists`, but a dangling symlink counts as existing.
+fn fs_exists(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+#[async_trait]
+impl StorageBackend for LocalFileSystem {
+    fn label(&self) -> &'static str {
+        "local"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list: true,
+            read: true,
+            write: true,
+            delete: true,
+            mkdir: true,
+            rename: true,
+            copy: true,
+            ranges: true,
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
 
         let mut entries = fs::read_dir(&full_path)
             .await
@@ -19,48 +145,65 @@ impl LocalFileSystem {
         while let Some(entry) = entries.next_entry().await? {
             let metadata = entry.metadata().await?;
             let file_name = entry.file_name().to_string_lossy().to_string();
+            let file_path = join_path(path, &file_name);
 
-            let modified = metadata.modified().ok().and_then(|time| {
-                let duration = time.duration_since(std::time::UNIX_EPOCH).ok()?;
-                let naive = NaiveDateTime::from_timestamp(
-                    duration.as_secs() as i64,
-                    duration.subsec_nanos(),
-                );
-                Some(DateTime::<Utc>::from_utc(naive, Utc))
-            });
-
-            let file_path = if path.is_empty() || path == "/" {
-                format!("/{}", file_name)
-            } else {
-                format!("{}/{}", path.trim_end_matches('/'), file_name)
-            };
-
-            files.push(FileInfo {
-                name: file_name,
-                path: file_path,
-                is_dir: metadata.is_dir(),
-                size: metadata.len(),
-                modified,
-            });
+            files.push(Self::file_info(file_name, file_path, &metadata));
         }
 
-        files.sort_by(|a, b| match (a.is_dir, b.is_dir) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.cmp(&b.name),
-        });
+        sort_entries(&mut files);
 
         Ok(files)
     }
 
-    pub async fn read_file(base_path: &str, path: &str) -> Result<Vec<u8>> {
-        let full_path = Self::resolve_path(base_path, path)?;
+    async fn stat(&self, path: &str) -> Result<FileInfo> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
+        let metadata = fs::metadata(&full_path)
+            .await
+            .context("Failed to read metadata")?;
+
+        let name = full_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Ok(Self::file_info(name, path.to_string(), &metadata))
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
         let content = fs::read(&full_path).await?;
         Ok(content)
     }
 
-    pub async fn write_file(base_path: &str, path: &str, content: &[u8]) -> Result<()> {
-        let full_path = Self::resolve_path(base_path, path)?;
+    async fn read_stream(&self, path: &str, range: Option<RangeRequest>) -> Result<FileStream> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
+        let mut file = fs::File::open(&full_path).await?;
+        let metadata = file.metadata().await?;
+
+        if metadata.is_dir() {
+            anyhow::bail!("{} is a directory", path);
+        }
+
+        let size = metadata.len();
+        let modified = Self::modified(&metadata);
+        let range = match range {
+            Some(range) => range.resolve(size, modified)?,
+            None => None,
+        };
+
+        let stream = match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                Box::pin(ReaderStream::new(file.take(end - start + 1))) as _
+            }
+            None => Box::pin(ReaderStream::new(file)) as _,
+        };
+
+        Ok(FileStream { stream, size, modified, range })
+    }
+
+    async fn write(&self, path: &str, content: &[u8]) -> Result<()> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
 
         if let Some(parent) = full_path.parent() {
             fs::create_dir_all(parent).await?;
@@ -70,8 +213,35 @@ impl LocalFileSystem {
         Ok(())
     }
 
-    pub async fn delete_file(base_path: &str, path: &str) -> Result<()> {
-        let full_path = Self::resolve_path(base_path, path)?;
+    async fn write_stream(&self, path: &str, mut stream: ByteStream) -> Result<u64> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
+
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut staging = StagingFile {
+            path: staging_path(&full_path),
+            committed: false,
+        };
+        let mut file = fs::File::create(&staging.path).await?;
+
+        let mut written = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&staging.path, &full_path).await?;
+        staging.committed = true;
+        Ok(written)
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
         let metadata = fs::metadata(&full_path).await?;
 
         if metadata.is_dir() {
@@ -83,29 +253,211 @@ impl LocalFileSystem {
         Ok(())
     }
 
-    pub async fn create_directory(base_path: &str, path: &str) -> Result<()> {
-        let full_path = Self::resolve_path(base_path, path)?;
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let full_path = Self::resolve_path(&self.base_path, path)?;
         fs::create_dir_all(&full_path).await?;
         Ok(())
     }
 
-    fn resolve_path(base_path: &str, path: &str) -> Result<PathBuf> {
-        let base = Path::new(base_path)
-            .canonicalize()
-            .context("Invalid base path")?;
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from_path = Self::resolve_path(&self.base_path, from)?;
+        let to_path = Self::resolve_path(&self.base_path, to)?;
 
-        let requested = base.join(path.trim_start_matches('/'));
-        let resolved = if requested.exists() {
-            requested.canonicalize()?
-        } else {
-            requested
-        };
+        // Never replace an existing target; SFTP servers refuse to as well
+        if fs::symlink_metadata(&to_path).await.is_ok() {
+            return Err(StorageError::AlreadyExists(to.to_string()).into());
+        }
 
-        // Ensure the resolved path is within the base path
-        if !resolved.starts_with(&base) {
-            anyhow::bail!("Path traversal attempt detected");
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent).await?;
         }
 
-        Ok(resolved)
+        fs::rename(&from_path, &to_path).await?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let from_path = Self::resolve_path(&self.base_path, from)?;
+        let to_path = Self::resolve_path(&self.base_path, to)?;
+
+        if to_path.starts_with(&from_path) {
+            anyhow::bail!("Cannot copy a path onto or into itself");
+        }
+        if fs_exists(&to_path) {
+            return Err(StorageError::AlreadyExists(to.to_string()).into());
+        }
+
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        Self::copy_recursive(&from_path, &to_path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, LocalFileSystem) {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("docs/nested")).unwrap();
+        std::fs::write(dir.path().join("docs/readme.md"), b"hello").unwrap();
+        std::fs::write(dir.path().join("docs/nested/deep.txt"), b"deep").unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        (dir, fs)
+    }
+
+    #[tokio::test]
+    async fn list_returns_sorted_entries_with_relative_paths() {
+        let (_dir, fs) = setup();
+
+        let files = fs.list("/docs").await.unwrap();
+
+        let paths: Vec<_> = files.iter().map(|f| (f.path.as_str(), f.is_dir)).collect();
+        assert_eq!(paths, [("/docs/nested", true), ("/docs/readme.md", false)]);
+    }
+
+    #[tokio::test]
+    async fn stat_reports_size_and_kind() {
+        let (_dir, fs) = setup();
+
+        let info = fs.stat("/docs/readme.md").await.unwrap();
+        assert_eq!(info.name, "readme.md");
+        assert_eq!(info.size, 5);
+        assert!(!info.is_dir);
+
+        assert!(fs.stat("/docs").await.unwrap().is_dir);
+        assert!(fs.stat("/missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_stream_honors_ranges() {
+        use futures::TryStreamExt;
+
+        let (_dir, fs) = setup();
+
+        let file = fs
+            .read_stream("/docs/readme.md", RangeRequest::parse("bytes=1-3", None))
+            .await
+            .unwrap();
+        assert_eq!((file.size, file.range), (5, Some((1, 3))));
+        let body: Vec<u8> = file.stream.map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+        assert_eq!(body, b"ell");
+
+        let err = fs
+            .read_stream("/docs/readme.md", RangeRequest::parse("bytes=5-", None))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RangeNotSatisfiable { size: 5 })));
+    }
+
+    #[tokio::test]
+    async fn write_stream_replaces_file_only_when_complete() {
+        let (dir, fs) = setup();
+        let chunks = |items: Vec<std::io::Result<&'static [u8]>>| -> ByteStream {
+            Box::pin(futures::stream::iter(items.into_iter().map(|c| c.map(bytes::Bytes::from_static))))
+        };
+
+        let written = fs
+            .write_stream("/docs/readme.md", chunks(vec![Ok(b"new "), Ok(b"content")]))
+            .await
+            .unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(std::fs::read(dir.path().join("docs/readme.md")).unwrap(), b"new content");
+
+        let aborted = chunks(vec![Ok(b"partial"), Err(std::io::Error::other("client went away"))]);
+        assert!(fs.write_stream("/docs/readme.md", aborted).await.is_err());
+        assert_eq!(std::fs::read(dir.path().join("docs/readme.md")).unwrap(), b"new content");
+
+        let names: Vec<_> = std::fs::read_dir(dir.path().join("docs"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2, "staging file left behind: {:?}", names);
+    }
+
+    #[tokio::test]
+    async fn rename_moves_into_new_parent() {
+        let (dir, fs) = setup();
+
+        fs.rename("/docs/readme.md", "/archive/readme.md").await.unwrap();
+
+        assert!(!dir.path().join("docs/readme.md").exists());
+        assert_eq!(std::fs::read(dir.path().join("archive/readme.md")).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn rename_refuses_to_replace_existing_target() {
+        let (dir, fs) = setup();
+
+        let err = fs.rename("/docs/readme.md", "/docs/nested/deep.txt").await.unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+        assert_eq!(std::fs::read(dir.path().join("docs/nested/deep.txt")).unwrap(), b"deep");
+    }
+
+    #[tokio::test]
+    async fn copy_duplicates_directory_tree() {
+        let (dir, fs) = setup();
+
+        fs.copy("/docs", "/backup").await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("backup/nested/deep.txt")).unwrap(), b"deep");
+        assert!(dir.path().join("docs/nested/deep.txt").exists());
+
+        let err = fs.copy("/docs/readme.md", "/backup/readme.md").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+    }
+
+    #[tokio::test]
+    async fn copy_into_itself_is_rejected() {
+        let (_dir, fs) = setup();
+
+        let err = fs.copy("/docs", "/docs/nested/again").await.unwrap_err();
+        assert!(err.to_string().contains("onto or into itself"));
+        assert!(fs.copy("/docs/readme.md", "/docs/readme.md").await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn copy_skips_symlinks() {
+        let (dir, fs) = setup();
+        let outside = TempDir::new().unwrap();
+        std::fs::write(outside.path().join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("docs/escape")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("docs/loop")).unwrap();
+
+        fs.copy("/docs", "/backup").await.unwrap();
+
+        assert!(dir.path().join("backup/readme.md").exists());
+        assert!(!dir.path().join("backup/escape").exists());
+        assert!(!dir.path().join("backup/loop").exists());
+    }
+
+    #[tokio::test]
+    async fn paths_outside_base_are_rejected() {
+        let (_dir, fs) = setup();
+
+        let err = fs.read("/../../etc/passwd").await.unwrap_err();
+        assert!(err.to_string().contains("Path traversal"));
+
+        // The missing `new` directory must not let `..` climb out of the base
+        let err = fs.write("/new/../../escape.txt", b"x").await.unwrap_err();
+        assert!(err.to_string().contains("Path traversal"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn writes_through_symlinks_outside_base_are_rejected() {
+        let (dir, fs) = setup();
+        let outside = TempDir::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+        let err = fs.write("/link/new/file.txt", b"x").await.unwrap_err();
+        assert!(err.to_string().contains("Path traversal"));
+        assert!(!outside.path().join("new").exists());
     }
 }
@@ -1,23 +1,146 @@
 pub mod local;
 pub mod http;
+mod autoindex;
 pub mod sftp;
+pub mod webdav;
+pub mod s3;
+pub mod ftp;
+pub mod pool;
+pub mod range;
+pub mod bridge;
+pub mod transfer;
+pub mod archive;
+pub mod search;
 
 use anyhow::Result;
-use crate::models::{Host, HostType, FileInfo};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use crate::models::{Host, HostConfig, HostType, FileInfo, OverwritePolicy, SshAuthMethod};
+
+pub use crate::models::Capabilities;
 use crate::auth::Encryptor;
+use crate::db::Database;
+use pool::SftpPool;
+use range::RangeRequest;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("{operation} operation not supported for {host_type} hosts")]
+    Unsupported {
+        operation: &'static str,
+        host_type: &'static str,
+    },
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    #[error("SSH host key verification failed: server presented {presented} but {trusted} is trusted")]
+    HostKeyMismatch { trusted: String, presented: String },
+    #[error("Requested range not satisfiable for a file of {size} bytes")]
+    RangeNotSatisfiable { size: u64 },
+}
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// A file opened for download.
+pub struct FileStream {
+    pub stream: ByteStream,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// Inclusive byte range the stream covers; `None` means the whole file.
+    pub range: Option<(u64, u64)>,
+}
+
+/// Common interface over every host type. Paths are relative to the host's root.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Human-readable host type, used in error messages.
+    fn label(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>>;
+
+    async fn stat(&self, path: &str) -> Result<FileInfo>;
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>>;
+
+    /// Opens `path` for streaming, limited to `range` when one is given and
+    /// still valid. Backends that cannot seek buffer the file and ignore `range`.
+    async fn read_stream(&self, path: &str, _range: Option<RangeRequest>) -> Result<FileStream> {
+        let content = Bytes::from(self.read(path).await?);
+        Ok(FileStream {
+            size: content.len() as u64,
+            modified: None,
+            range: None,
+            stream: Box::pin(futures::stream::once(async move { Ok(content) })),
+        })
+    }
+
+    async fn write(&self, _path: &str, _content: &[u8]) -> Result<()> {
+        Err(self.unsupported("Write"))
+    }
+
+    /// Writes `path` from a stream of chunks and returns the number of bytes
+    /// written. An error from the stream aborts the upload without replacing
+    /// `path`. Backends that cannot stream buffer the content and call `write`.
+    async fn write_stream(&self, path: &str, mut stream: ByteStream) -> Result<u64> {
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+
+        self.write(path, &content).await?;
+        Ok(content.len() as u64)
+    }
 
-pub async fn browse_host(host: &Host, path: &str, encryptor: &Encryptor) -> Result<Vec<FileInfo>> {
+    async fn delete(&self, _path: &str) -> Result<()> {
+        Err(self.unsupported("Delete"))
+    }
+
+    async fn mkdir(&self, _path: &str) -> Result<()> {
+        Err(self.unsupported("Create directory"))
+    }
+
+    /// Moves `from` to `to`, creating missing parents. Never replaces an
+    /// existing `to`: that fails with `StorageError::AlreadyExists`.
+    async fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(self.unsupported("Rename"))
+    }
+
+    /// Copies `from` to `to` within the host without the data passing
+    /// through the server. Like `rename`, never replaces an existing `to`.
+    async fn copy(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(self.unsupported("Copy"))
+    }
+
+    fn unsupported(&self, operation: &'static str) -> anyhow::Error {
+        StorageError::Unsupported {
+            operation,
+            host_type: self.label(),
+        }
+        .into()
+    }
+}
+
+/// Builds the backend for `host`, decrypting any stored credentials.
+/// SFTP backends draw their sessions from `sftp_pool`.
+pub fn open(host: &Host, encryptor: &Encryptor, sftp_pool: &Arc<SftpPool>) -> Result<Box<dyn StorageBackend>> {
     match &host.host_type {
         HostType::Local => {
             let base_path = host.config.path.as_ref()
                 .ok_or_else(|| anyhow::anyhow!("Local path not configured"))?;
-            local::LocalFileSystem::list_files(base_path, path).await
+            Ok(Box::new(local::LocalFileSystem::new(base_path)))
         }
         HostType::Http => {
             let base_url = host.config.url.as_ref()
                 .ok_or_else(|| anyhow::anyhow!("HTTP URL not configured"))?;
-            let http_fs = http::HttpFileSystem::new();
-            http_fs.list_files(base_url, path).await
+            Ok(Box::new(http::HttpFileSystem::new(base_url)))
         }
         HostType::Sftp => {
             let host_addr = host.config.host.as_ref()
@@ -25,86 +148,389 @@ pub async fn browse_host(host: &Host, path: &str, encryptor: &Encryptor) -> Resu
             let port = host.config.port.unwrap_or(22);
             let username = host.config.username.as_ref()
                 .ok_or_else(|| anyhow::anyhow!("SFTP username not configured"))?;
-            let password_encrypted = host.config.password_encrypted.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP password not configured"))?;
-            
-            let password = encryptor.decrypt(password_encrypted)?;
-            
-            sftp::SftpFileSystem::list_files(
+            let auth = sftp_auth(&host.config, encryptor)?;
+            let trusted_host_key = host.host_key_fingerprint.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("SFTP host key not trusted yet"))?;
+
+            Ok(Box::new(sftp::SftpFileSystem::new(
                 host_addr,
                 port,
                 username,
-                &password,
-                path,
-            ).await
+                auth,
+                trusted_host_key,
+                sftp_pool.clone(),
+            )))
         }
-    }
-}
-
-pub async fn read_file(host: &Host, path: &str, encryptor: &Encryptor) -> Result<Vec<u8>> {
-    match &host.host_type {
-        HostType::Local => {
-            let base_path = host.config.path.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("Local path not configured"))?;
-            local::LocalFileSystem::read_file(base_path, path).await
-        }
-        HostType::Http => {
+        HostType::WebDav => {
             let base_url = host.config.url.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("HTTP URL not configured"))?;
-            let http_fs = http::HttpFileSystem::new();
-            http_fs.read_file(base_url, path).await
+                .ok_or_else(|| anyhow::anyhow!("WebDAV URL not configured"))?;
+            let credentials = match (&host.config.username, &host.config.password_encrypted) {
+                (Some(username), Some(password)) => Some((username.clone(), encryptor.decrypt(password)?)),
+                _ => None,
+            };
+            Ok(Box::new(webdav::WebDavFileSystem::new(base_url, credentials)))
         }
-        HostType::Sftp => {
+        HostType::S3 => {
+            let bucket = host.config.bucket.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("S3 bucket not configured"))?;
+            let credentials = match (&host.config.access_key_encrypted, &host.config.secret_key_encrypted) {
+                (Some(access_key), Some(secret_key)) => Some(s3::S3Credentials {
+                    access_key: encryptor.decrypt(access_key)?,
+                    secret_key: encryptor.decrypt(secret_key)?,
+                }),
+                _ => None,
+            };
+
+            Ok(Box::new(s3::S3FileSystem::new(
+                host.config.endpoint.as_deref(),
+                host.config.region.as_deref().unwrap_or("us-east-1"),
+                bucket,
+                host.config.prefix.as_deref().unwrap_or(""),
+                host.config.path_style,
+                credentials,
+            )))
+        }
+        HostType::Ftp => {
             let host_addr = host.config.host.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP host not configured"))?;
-            let port = host.config.port.unwrap_or(22);
-            let username = host.config.username.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP username not configured"))?;
-            let password_encrypted = host.config.password_encrypted.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP password not configured"))?;
-            
-            let password = encryptor.decrypt(password_encrypted)?;
-            
-            sftp::SftpFileSystem::read_file(
+                .ok_or_else(|| anyhow::anyhow!("FTP host not configured"))?;
+            let password = host.config.password_encrypted.as_ref()
+                .map(|p| encryptor.decrypt(p))
+                .transpose()?;
+
+            // Without credentials the conventional anonymous login is used
+            let (username, password) = match (&host.config.username, password) {
+                (Some(username), Some(password)) => (username.as_str(), password),
+                (Some(username), None) => (username.as_str(), String::new()),
+                (None, _) => ("anonymous", "anonymous@".to_string()),
+            };
+
+            Ok(Box::new(ftp::FtpFileSystem::new(
                 host_addr,
-                port,
+                host.config.port.unwrap_or(21),
                 username,
                 &password,
-                path,
-            ).await
+                host.config.tls.unwrap_or(false),
+            )))
         }
     }
 }
 
-pub async fn write_file(host: &Host, path: &str, content: &[u8], encryptor: &Encryptor) -> Result<()> {
-    match &host.host_type {
-        HostType::Local => {
-            let base_path = host.config.path.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("Local path not configured"))?;
-            local::LocalFileSystem::write_file(base_path, path, content).await
+/// Trust-on-first-use for SFTP hosts: if no host key is pinned yet, fetch the
+/// one the server presents and record it. Must run before `open`, which only
+/// connects to SFTP hosts with a pinned key.
+pub async fn ensure_host_key(host: &mut Host, db: &Database) -> Result<()> {
+    if !matches!(host.host_type, HostType::Sftp) || host.host_key_fingerprint.is_some() {
+        return Ok(());
+    }
+
+    let host_addr = host.config.host.as_ref()
+        .ok_or_else(|| anyhow::anyhow!("SFTP host not configured"))?;
+    let presented = sftp::SftpFileSystem::fetch_host_key(host_addr, host.config.port.unwrap_or(22)).await?;
+
+    // Another request may have pinned a key meanwhile; whichever was stored first wins
+    let trusted = db.trust_host_key_if_unset(&host.id, &presented).await?;
+    if trusted == presented {
+        log::info!("Trusting host key {} for host {} on first use", presented, host.id);
+    }
+
+    host.host_key_fingerprint = Some(trusted);
+    Ok(())
+}
+
+/// Picks the SFTP authentication method from the host config and decrypts its secrets.
+fn sftp_auth(config: &HostConfig, encryptor: &Encryptor) -> Result<sftp::SftpAuth> {
+    match config.ssh_auth_method() {
+        SshAuthMethod::Password => {
+            let password_encrypted = config.password_encrypted.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("SFTP password not configured"))?;
+            Ok(sftp::SftpAuth::Password(encryptor.decrypt(password_encrypted)?))
         }
-        HostType::Http => {
-            anyhow::bail!("Write operation not supported for HTTP hosts")
+        SshAuthMethod::PublicKey => {
+            let key_encrypted = config.private_key_encrypted.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("SFTP private key not configured"))?;
+            let passphrase = config.passphrase_encrypted.as_ref()
+                .map(|p| encryptor.decrypt(p))
+                .transpose()?;
+            Ok(sftp::SftpAuth::PublicKey {
+                private_key: encryptor.decrypt(key_encrypted)?,
+                passphrase,
+            })
         }
-        HostType::Sftp => {
-            let host_addr = host.config.host.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP host not configured"))?;
-            let port = host.config.port.unwrap_or(22);
-            let username = host.config.username.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP username not configured"))?;
-            let password_encrypted = host.config.password_encrypted.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("SFTP password not configured"))?;
-            
-            let password = encryptor.decrypt(password_encrypted)?;
-            
-            sftp::SftpFileSystem::write_file(
-                host_addr,
-                port,
-                username,
-                &password,
-                path,
-                content,
-            ).await
+        SshAuthMethod::Agent => Ok(sftp::SftpAuth::Agent),
+    }
+}
+
+/// Gives up on `OverwritePolicy::AutoSuffix` after this many taken names.
+const MAX_SUFFIX_ATTEMPTS: u32 = 100;
+
+/// Runs `op` (a rename or copy onto `to`) under `policy` and returns the
+/// destination that was used. `op` must fail with `StorageError::AlreadyExists`
+/// when its destination exists; `Replace` then has `displace` move it out of
+/// the way first, normally into the trash, see
+/// `crate::trash::TrashManager::displace`.
+pub async fn apply_overwrite_policy<F, Fut, D, DFut>(
+    to: &str,
+    policy: OverwritePolicy,
+    displace: D,
+    mut op: F,
+) -> Result<String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+    D: FnOnce(String) -> DFut,
+    DFut: Future<Output = Result<()>>,
+{
+    let is_taken = |e: &anyhow::Error| matches!(e.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_)));
+
+    match policy {
+        OverwritePolicy::Fail => op(to.to_string()).await.map(|_| to.to_string()),
+        OverwritePolicy::Replace => match op(to.to_string()).await {
+            Err(e) if is_taken(&e) => {
+                displace(to.to_string()).await?;
+                op(to.to_string()).await.map(|_| to.to_string())
+            }
+            result => result.map(|_| to.to_string()),
+        },
+        OverwritePolicy::AutoSuffix => {
+            let mut candidate = to.to_string();
+            for attempt in 1..=MAX_SUFFIX_ATTEMPTS {
+                match op(candidate.clone()).await {
+                    Err(e) if is_taken(&e) => candidate = suffixed_path(to, attempt),
+                    result => return result.map(|_| candidate),
+                }
+            }
+            Err(StorageError::AlreadyExists(candidate).into())
+        }
+    }
+}
+
+/// `/dir/report.pdf` becomes `/dir/report (n).pdf`; dotfiles and names
+/// without an extension get the suffix at the end.
+fn suffixed_path(path: &str, n: u32) -> String {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    format!("{}/{} ({}){}", parent, stem, n, ext)
+}
+
+/// Resolves `.` and `..` in a host path without touching the host and
+/// returns it as `/a/b`. Paths that climb above the root are rejected, just
+/// as `LocalFileSystem::resolve_path` rejects paths outside its base.
+pub fn normalize_path(path: &str) -> Result<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    anyhow::bail!("Path traversal attempt detected");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// Whether normalized `path` is `dir` itself or lies somewhere below it.
+pub fn is_within(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    path == dir || dir.is_empty() || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Hidden directory of every host where deleted entries are kept until
+/// they are restored or purged, see `crate::trash`.
+pub const TRASH_DIR: &str = "/.fm-trash";
+
+/// Whether normalized `path` is the trash area or inside it.
+pub fn is_trash_path(path: &str) -> bool {
+    is_within(path, TRASH_DIR)
+}
+
+/// Hidden sibling of `path` that an upload is written to before it replaces `path`.
+pub fn staging_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    path.with_file_name(format!(".{}.{}.part", name, &suffix[..8]))
+}
+
+/// Joins a parent path and an entry name the way listings report paths.
+pub fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() || parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent.trim_end_matches('/'), name)
+    }
+}
+
+/// Characters escaped in each path segment of a request URL.
+const URL_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// `path` as the part of a URL after the base, without a leading slash and
+/// with each segment percent-encoded.
+pub fn encode_path(path: &str) -> String {
+    let encoded: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| utf8_percent_encode(s, URL_SEGMENT).to_string())
+        .collect();
+    encoded.join("/")
+}
+
+/// Directories first, then by name.
+pub fn sort_entries(files: &mut [FileInfo]) {
+    files.sort_by(|a, b| match (a.is_dir, b.is_dir) {
+        (true, false) => std::cmp::Ordering::Less,
+        (false, true) => std::cmp::Ordering::Greater,
+        _ => a.name.cmp(&b.name),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ReadOnly;
+
+    #[async_trait]
+    impl StorageBackend for ReadOnly {
+        fn label(&self) -> &'static str {
+            "test"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        async fn list(&self, _path: &str) -> Result<Vec<FileInfo>> {
+            Ok(vec![])
+        }
+
+        async fn stat(&self, _path: &str) -> Result<FileInfo> {
+            anyhow::bail!("not found")
+        }
+
+        async fn read(&self, _path: &str) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+    }
+
+    fn entry(name: &str, is_dir: bool) -> FileInfo {
+        FileInfo {
+            name: name.to_string(),
+            path: join_path("/", name),
+            is_dir,
+            size: 0,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn join_path_handles_root_and_trailing_slash() {
+        assert_eq!(join_path("", "a"), "/a");
+        assert_eq!(join_path("/", "a"), "/a");
+        assert_eq!(join_path("/dir", "a"), "/dir/a");
+        assert_eq!(join_path("/dir/", "a"), "/dir/a");
+    }
+
+    #[test]
+    fn sort_entries_puts_directories_first() {
+        let mut files = vec![entry("b.txt", false), entry("z", true), entry("a.txt", false), entry("c", true)];
+        sort_entries(&mut files);
+
+        let names: Vec<_> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["c", "z", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn normalize_path_rejects_escaping_the_root() {
+        assert_eq!(normalize_path("a/./b//c/").unwrap(), "/a/b/c");
+        assert_eq!(normalize_path("/a/b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("").unwrap(), "/");
+        assert!(normalize_path("/a/../../etc/passwd").is_err());
+        assert!(normalize_path("..").is_err());
+    }
+
+    #[test]
+    fn is_within_matches_whole_segments() {
+        assert!(is_within("/a/b", "/a"));
+        assert!(is_within("/a", "/a"));
+        assert!(is_within("/a", "/"));
+        assert!(!is_within("/ab", "/a"));
+        assert!(!is_within("/a", "/a/b"));
+    }
+
+    #[test]
+    fn suffixed_path_keeps_the_extension() {
+        assert_eq!(suffixed_path("/dir/report.pdf", 1), "/dir/report (1).pdf");
+        assert_eq!(suffixed_path("/dir/photos", 2), "/dir/photos (2)");
+        assert_eq!(suffixed_path("/.bashrc", 1), "/.bashrc (1)");
+    }
+
+    #[tokio::test]
+    async fn overwrite_policies_resolve_conflicts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["a.txt", "b.txt", "b (1).txt"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
         }
+        std::fs::write(dir.path().join("c.txt"), "c.txt").unwrap();
+        let fs = &local::LocalFileSystem::new(dir.path().to_str().unwrap());
+        let delete = |to: String| async move { fs.delete(&to).await };
+
+        let err = apply_overwrite_policy("/b.txt", OverwritePolicy::Fail, delete, |to| async move {
+            fs.rename("/a.txt", &to).await
+        })
+        .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+
+        let used = apply_overwrite_policy("/b.txt", OverwritePolicy::AutoSuffix, delete, |to| async move {
+            fs.rename("/a.txt", &to).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(used, "/b (2).txt");
+        assert_eq!(std::fs::read_to_string(dir.path().join("b (2).txt")).unwrap(), "a.txt");
+
+        let used = apply_overwrite_policy("/b.txt", OverwritePolicy::Replace, delete, |to| async move {
+            fs.rename("/c.txt", &to).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(used, "/b.txt");
+        assert_eq!(std::fs::read_to_string(dir.path().join("b.txt")).unwrap(), "c.txt");
+    }
+
+    #[test]
+    fn staging_path_is_a_hidden_sibling() {
+        let staged = staging_path(Path::new("/data/disk.img"));
+
+        assert_eq!(staged.parent(), Some(Path::new("/data")));
+        let name = staged.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".disk.img.") && name.ends_with(".part"), "{}", name);
+    }
+
+    #[tokio::test]
+    async fn default_operations_report_unsupported() {
+        let err = ReadOnly.delete("/a").await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::Unsupported { operation: "Delete", host_type: "test" })
+        ));
+        assert_eq!(err.to_string(), "Delete operation not supported for test hosts");
     }
 }
@@ -0,0 +1,301 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use prometheus::IntGauge;
+use ssh2::{ErrorCode, Session, Sftp};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Idle sessions older than this are closed.
+    pub idle_timeout: Duration,
+    /// How often idle sessions get an SSH keepalive.
+    pub keepalive_interval: Duration,
+    /// Sessions idle for longer than this are probed before being reused.
+    pub health_check_after: Duration,
+    /// Idle sessions kept per pool key; extra ones are closed on check-in.
+    pub max_idle_per_host: usize,
+}
+
+impl PoolConfig {
+    /// Reads `SFTP_POOL_IDLE_TIMEOUT`, `SFTP_POOL_KEEPALIVE_INTERVAL` (seconds)
+    /// and `SFTP_POOL_MAX_IDLE`, falling back to defaults.
+    pub fn from_env() -> Self {
+        fn env_u64(name: &str, default: u64) -> u64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            idle_timeout: Duration::from_secs(env_u64("SFTP_POOL_IDLE_TIMEOUT", 300)),
+            keepalive_interval: Duration::from_secs(env_u64("SFTP_POOL_KEEPALIVE_INTERVAL", 30)),
+            health_check_after: Duration::from_secs(10),
+            max_idle_per_host: env_u64("SFTP_POOL_MAX_IDLE", 4) as usize,
+        }
+    }
+}
+
+/// An authenticated SSH session with its SFTP channel.
+pub struct SftpConnection {
+    session: Session,
+    pub sftp: Sftp,
+    open_sessions: IntGauge,
+}
+
+impl SftpConnection {
+    fn is_alive(&self) -> bool {
+        self.sftp.realpath(Path::new(".")).is_ok()
+    }
+}
+
+impl Drop for SftpConnection {
+    fn drop(&mut self) {
+        self.open_sessions.dec();
+    }
+}
+
+/// Reuses SFTP sessions across requests, keyed by `user@host:port`, host key
+/// and a digest of the credentials, see `SftpFileSystem::pool_key`.
+pub struct SftpPool {
+    idle: Mutex<IdleMap<SftpConnection>>,
+    config: PoolConfig,
+    open_sessions: IntGauge,
+    idle_sessions: IntGauge,
+}
+
+impl SftpPool {
+    pub fn new(config: PoolConfig, metrics: &Metrics) -> Self {
+        Self {
+            idle: Mutex::new(IdleMap::new()),
+            config,
+            open_sessions: metrics.sftp_sessions_open.clone(),
+            idle_sessions: metrics.sftp_sessions_idle.clone(),
+        }
+    }
+
+    /// Returns an idle session for `key`, or opens one with `connect`.
+    /// Blocking; call from a blocking thread.
+    pub fn checkout<F>(&self, key: &str, connect: F) -> Result<SftpConnection>
+    where
+        F: FnOnce() -> Result<Session>,
+    {
+        while let Some((conn, since)) = self.take_idle(key) {
+            if since.elapsed() < self.config.health_check_after || conn.is_alive() {
+                return Ok(conn);
+            }
+            debug!("Dropping dead SFTP session for {}", key);
+        }
+
+        let session = connect()?;
+        session.set_keepalive(true, self.config.keepalive_interval.as_secs() as u32);
+        let sftp = session.sftp().context("Failed to create SFTP session")?;
+        self.open_sessions.inc();
+
+        Ok(SftpConnection {
+            session,
+            sftp,
+            open_sessions: self.open_sessions.clone(),
+        })
+    }
+
+    /// Hands a session back after use. Sessions that saw a transport error are closed.
+    pub fn checkin(&self, key: &str, conn: SftpConnection, healthy: bool) {
+        if !healthy {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // Anything over the per-host limit is dropped, closing the session
+        let _ = idle.push(key, conn, Instant::now(), self.config.max_idle_per_host);
+        self.idle_sessions.set(idle.len() as i64);
+    }
+
+    fn take_idle(&self, key: &str) -> Option<(SftpConnection, Instant)> {
+        let mut idle = self.idle.lock().unwrap();
+        let taken = idle.pop(key);
+        self.idle_sessions.set(idle.len() as i64);
+        taken
+    }
+
+    /// Closes expired sessions and keeps the rest alive, every keepalive interval.
+    pub fn spawn_maintenance(self: &Arc<Self>) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(pool.config.keepalive_interval);
+            loop {
+                ticker.tick().await;
+                let pool = pool.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || pool.maintain()).await {
+                    log::error!("SFTP pool maintenance failed: {}", e);
+                }
+            }
+        });
+    }
+
+    fn maintain(&self) {
+        let now = Instant::now();
+        let candidates = {
+            let mut idle = self.idle.lock().unwrap();
+            let expired = idle.drain_expired(now, self.config.idle_timeout);
+            if !expired.is_empty() {
+                info!("Closing {} idle SFTP session(s)", expired.len());
+            }
+            let rest = idle.drain_all();
+            self.idle_sessions.set(0);
+            rest
+        };
+
+        // Keepalives run outside the lock so checkouts are never blocked on the network
+        let alive: Vec<_> = candidates
+            .into_iter()
+            .filter(|(key, conn, _)| match conn.session.keepalive_send() {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("Dropping SFTP session for {} after failed keepalive: {}", key, e);
+                    false
+                }
+            })
+            .collect();
+
+        let mut idle = self.idle.lock().unwrap();
+        for (key, conn, since) in alive {
+            let _ = idle.push(&key, conn, since, self.config.max_idle_per_host);
+        }
+        self.idle_sessions.set(idle.len() as i64);
+    }
+}
+
+/// Whether `err` came from the SSH transport rather than an SFTP status reply,
+/// meaning the session it happened on should not be reused.
+pub fn is_transport_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<ssh2::Error>() {
+            return matches!(e.code(), ErrorCode::Session(_));
+        }
+        cause.is::<std::io::Error>()
+    })
+}
+
+/// Idle connections per key, most recently used last.
+struct IdleMap<T> {
+    entries: HashMap<String, Vec<(T, Instant)>>,
+}
+
+impl<T> IdleMap<T> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    /// Takes the most recently used connection for `key`.
+    fn pop(&mut self, key: &str) -> Option<(T, Instant)> {
+        let list = self.entries.get_mut(key)?;
+        let taken = list.pop();
+        if list.is_empty() {
+            self.entries.remove(key);
+        }
+        taken
+    }
+
+    /// Stores `conn`, handing it back if `key` already has `max` idle connections.
+    fn push(&mut self, key: &str, conn: T, since: Instant, max: usize) -> Option<T> {
+        let list = self.entries.entry(key.to_string()).or_default();
+        if list.len() >= max {
+            return Some(conn);
+        }
+        list.push((conn, since));
+        list.sort_by_key(|(_, since)| *since);
+        None
+    }
+
+    fn drain_expired(&mut self, now: Instant, timeout: Duration) -> Vec<T> {
+        let mut expired = Vec::new();
+        for list in self.entries.values_mut() {
+            let (old, fresh): (Vec<_>, Vec<_>) = list
+                .drain(..)
+                .partition(|(_, since)| now.duration_since(*since) >= timeout);
+            *list = fresh;
+            expired.extend(old.into_iter().map(|(conn, _)| conn));
+        }
+        self.entries.retain(|_, list| !list.is_empty());
+        expired
+    }
+
+    fn drain_all(&mut self) -> Vec<(String, T, Instant)> {
+        self.entries
+            .drain()
+            .flat_map(|(key, list)| {
+                list.into_iter()
+                    .map(move |(conn, since)| (key.clone(), conn, since))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_returns_most_recently_used_first() {
+        let mut idle = IdleMap::new();
+        let start = Instant::now();
+        idle.push("a", 1, start, 4);
+        idle.push("a", 2, start + Duration::from_secs(5), 4);
+        idle.push("b", 3, start, 4);
+
+        assert_eq!(idle.pop("a").map(|(c, _)| c), Some(2));
+        assert_eq!(idle.pop("a").map(|(c, _)| c), Some(1));
+        assert!(idle.pop("a").is_none());
+        assert_eq!(idle.len(), 1);
+    }
+
+    #[test]
+    fn push_rejects_connections_over_the_limit() {
+        let mut idle = IdleMap::new();
+        let now = Instant::now();
+
+        assert_eq!(idle.push("a", 1, now, 1), None);
+        assert_eq!(idle.push("a", 2, now, 1), Some(2));
+        assert_eq!(idle.len(), 1);
+    }
+
+    #[test]
+    fn drain_expired_only_removes_old_connections() {
+        let mut idle = IdleMap::new();
+        let start = Instant::now();
+        idle.push("a", 1, start, 4);
+        idle.push("a", 2, start + Duration::from_secs(250), 4);
+        idle.push("b", 3, start, 4);
+
+        let mut expired = idle.drain_expired(start + Duration::from_secs(300), Duration::from_secs(300));
+        expired.sort();
+
+        assert_eq!(expired, [1, 3]);
+        assert_eq!(idle.pop("a").map(|(c, _)| c), Some(2));
+        assert_eq!(idle.len(), 0);
+    }
+
+    #[test]
+    fn transport_errors_are_told_apart_from_sftp_status() {
+        let status = anyhow::Error::new(ssh2::Error::new(ErrorCode::SFTP(2), "no such file"))
+            .context("Failed to open remote file");
+        let transport = anyhow::Error::new(ssh2::Error::new(ErrorCode::Session(-7), "socket send"));
+        let io = anyhow::Error::new(std::io::Error::other("broken pipe"));
+
+        assert!(!is_transport_error(&status));
+        assert!(is_transport_error(&transport));
+        assert!(is_transport_error(&io));
+    }
+}
@@ -0,0 +1,159 @@
+//! `Range` / `If-Range` handling shared by backends that can seek.
+
+use chrono::{DateTime, Utc};
+
+use crate::hosts::StorageError;
+
+/// A single byte range as written in a `Range` header, before the file size is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeSpec {
+    /// `bytes=first-last`
+    Bounded(u64, u64),
+    /// `bytes=first-`
+    From(u64),
+    /// `bytes=-length`
+    Suffix(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeRequest {
+    spec: RangeSpec,
+    /// Validator from `If-Range`: the range only applies while it still matches.
+    if_range: Option<String>,
+}
+
+impl RangeRequest {
+    /// The bytes from `first` to `last` inclusive, unconditionally.
+    pub fn bytes(first: u64, last: u64) -> Self {
+        Self {
+            spec: RangeSpec::Bounded(first, last),
+            if_range: None,
+        }
+    }
+
+    /// Parses a `Range` header. Malformed headers and multi-range requests
+    /// yield `None`, which means the whole file is served (RFC 9110 §14.2).
+    pub fn parse(range: &str, if_range: Option<&str>) -> Option<Self> {
+        let spec = range.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (first, last) = spec.split_once('-')?;
+        let spec = match (first.trim(), last.trim()) {
+            ("", "") => return None,
+            ("", suffix) => RangeSpec::Suffix(suffix.parse().ok()?),
+            (first, "") => RangeSpec::From(first.parse().ok()?),
+            (first, last) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                if last < first {
+                    return None;
+                }
+                RangeSpec::Bounded(first, last)
+            }
+        };
+
+        Some(Self {
+            spec,
+            if_range: if_range.map(|v| v.trim().to_string()),
+        })
+    }
+
+    /// Resolves the inclusive byte range to send for a file of `size` bytes.
+    /// `Ok(None)` means the whole file should be sent because `If-Range` no
+    /// longer matches; a range past the end of the file is an error.
+    pub fn resolve(&self, size: u64, modified: Option<DateTime<Utc>>) -> Result<Option<(u64, u64)>, StorageError> {
+        if let Some(validator) = &self.if_range {
+            if !validator_matches(validator, size, modified) {
+                return Ok(None);
+            }
+        }
+
+        let range = match self.spec {
+            RangeSpec::Bounded(first, last) if first < size => Some((first, last.min(size - 1))),
+            RangeSpec::From(first) if first < size => Some((first, size - 1)),
+            RangeSpec::Suffix(length) if length > 0 && size > 0 => Some((size.saturating_sub(length), size - 1)),
+            _ => None,
+        };
+
+        range.map(Some).ok_or(StorageError::RangeNotSatisfiable { size })
+    }
+}
+
+/// Strong entity tag derived from size and modification time.
+pub fn entity_tag(size: u64, modified: Option<DateTime<Utc>>) -> Option<String> {
+    modified.map(|m| format!("\"{:x}-{:x}\"", size, m.timestamp()))
+}
+
+/// Formats a timestamp as an HTTP date, e.g. `Mon, 01 Jan 2024 12:00:00 GMT`.
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// `If-Range` holds either an entity tag or an HTTP date; both must match
+/// exactly. Weak tags never match, as required for range requests.
+fn validator_matches(validator: &str, size: u64, modified: Option<DateTime<Utc>>) -> bool {
+    if validator.starts_with('"') {
+        return entity_tag(size, modified).as_deref() == Some(validator);
+    }
+    if validator.starts_with("W/") {
+        return false;
+    }
+
+    match (DateTime::parse_from_rfc2822(validator), modified) {
+        (Ok(date), Some(modified)) => date.timestamp() == modified.timestamp(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(range: &str, size: u64) -> Result<Option<(u64, u64)>, StorageError> {
+        RangeRequest::parse(range, None).unwrap().resolve(size, None)
+    }
+
+    #[test]
+    fn parses_supported_forms() {
+        assert_eq!(resolve("bytes=0-99", 1000).unwrap(), Some((0, 99)));
+        assert_eq!(resolve("bytes=900-", 1000).unwrap(), Some((900, 999)));
+        assert_eq!(resolve("bytes=-100", 1000).unwrap(), Some((900, 999)));
+        assert_eq!(resolve("bytes=990-2000", 1000).unwrap(), Some((990, 999)));
+        assert_eq!(resolve("bytes=-5000", 1000).unwrap(), Some((0, 999)));
+    }
+
+    #[test]
+    fn ignores_malformed_and_multi_range_headers() {
+        assert_eq!(RangeRequest::parse("bytes=0-1,5-6", None), None);
+        assert_eq!(RangeRequest::parse("items=0-1", None), None);
+        assert_eq!(RangeRequest::parse("bytes=5-1", None), None);
+        assert_eq!(RangeRequest::parse("bytes=-", None), None);
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert!(matches!(resolve("bytes=1000-", 1000), Err(StorageError::RangeNotSatisfiable { size: 1000 })));
+        assert!(matches!(resolve("bytes=-0", 1000), Err(StorageError::RangeNotSatisfiable { .. })));
+        assert!(matches!(resolve("bytes=0-", 0), Err(StorageError::RangeNotSatisfiable { .. })));
+    }
+
+    #[test]
+    fn if_range_must_match_the_current_file() {
+        let modified = DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let etag = entity_tag(1000, Some(modified)).unwrap();
+        let date = http_date(modified);
+        assert_eq!(date, "Mon, 01 Jan 2024 12:00:00 GMT");
+
+        let by_tag = RangeRequest::parse("bytes=0-9", Some(&etag)).unwrap();
+        assert_eq!(by_tag.resolve(1000, Some(modified)).unwrap(), Some((0, 9)));
+        assert_eq!(by_tag.resolve(1001, Some(modified)).unwrap(), None);
+
+        let by_date = RangeRequest::parse("bytes=0-9", Some(&date)).unwrap();
+        assert_eq!(by_date.resolve(1000, Some(modified)).unwrap(), Some((0, 9)));
+        assert_eq!(by_date.resolve(1000, None).unwrap(), None);
+
+        let weak = RangeRequest::parse("bytes=0-9", Some(&format!("W/{}", etag))).unwrap();
+        assert_eq!(weak.resolve(1000, Some(modified)).unwrap(), None);
+    }
+}
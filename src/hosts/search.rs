@@ -0,0 +1,367 @@
+//! Searching a host for files by name, size, age and content. The tree is
+//! walked breadth first, so shallow matches come first, and each match is
+//! passed on as soon as it is found.
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::RegexBuilder;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::hosts::{StorageBackend, TRASH_DIR};
+use crate::models::{FileInfo, LineMatch, SearchEvent, SearchMatch, SearchOptions, SearchSummary};
+
+/// Deepest a search descends, whatever `max_depth` asks for.
+const MAX_SEARCH_DEPTH: usize = 64;
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10_000;
+/// Larger files are left out of content searches.
+const MAX_GREP_SIZE: u64 = 16 * 1024 * 1024;
+/// A NUL byte this early on marks a file as binary.
+const BINARY_CHECK_SIZE: usize = 8 * 1024;
+/// Lines are cut off here, and a file whose lines run longer is not text.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+/// Matching lines reported per file; the rest of the file isn't read.
+const MAX_LINE_MATCHES: usize = 5;
+/// Characters of a matching line that are reported.
+const LINE_PREVIEW_CHARS: usize = 200;
+/// Events buffered before a search waits for its consumer.
+const SEARCH_BUFFER: usize = 64;
+/// Size limit of compiled patterns, against patterns built to be expensive.
+const MAX_PATTERN_SIZE: usize = 1024 * 1024;
+
+/// `SearchOptions` checked and compiled, ready to match entries.
+#[derive(Debug, Clone)]
+pub struct SearchCriteria {
+    name: Option<GlobMatcher>,
+    name_regex: Option<regex::Regex>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<chrono::DateTime<chrono::Utc>>,
+    modified_before: Option<chrono::DateTime<chrono::Utc>>,
+    content: Option<regex::bytes::Regex>,
+    max_depth: usize,
+    limit: usize,
+}
+
+impl SearchCriteria {
+    /// Fails on patterns that don't compile.
+    pub fn new(options: &SearchOptions) -> Result<Self> {
+        let case_insensitive = !options.case_sensitive;
+        let name = options
+            .name
+            .as_deref()
+            .map(|glob| GlobBuilder::new(glob).case_insensitive(case_insensitive).build())
+            .transpose()
+            .context("Invalid name pattern")?
+            .map(|glob| glob.compile_matcher());
+        let regex = |pattern: &str| RegexBuilder::new(pattern).case_insensitive(case_insensitive).size_limit(MAX_PATTERN_SIZE).build();
+        let name_regex = options.name_regex.as_deref().map(regex).transpose().context("Invalid name regex")?;
+        let content = options
+            .content
+            .as_deref()
+            .map(|pattern| {
+                regex::bytes::RegexBuilder::new(pattern)
+                    .case_insensitive(case_insensitive)
+                    .size_limit(MAX_PATTERN_SIZE)
+                    .build()
+            })
+            .transpose()
+            .context("Invalid content regex")?;
+
+        Ok(Self {
+            name,
+            name_regex,
+            min_size: options.min_size,
+            max_size: options.max_size,
+            modified_after: options.modified_after,
+            modified_before: options.modified_before,
+            content,
+            max_depth: options.max_depth.unwrap_or(MAX_SEARCH_DEPTH).clamp(1, MAX_SEARCH_DEPTH),
+            limit: options.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        })
+    }
+
+    /// Whether a content search is asked for, which reads files.
+    pub fn reads_content(&self) -> bool {
+        self.content.is_some()
+    }
+
+    /// Whether `entry` passes every filter that needs no more than its listing.
+    fn matches(&self, entry: &FileInfo) -> bool {
+        let files_only = self.min_size.is_some() || self.max_size.is_some() || self.content.is_some();
+        if entry.is_dir && files_only {
+            return false;
+        }
+        if self.name.as_ref().is_some_and(|glob| !glob.is_match(&entry.name))
+            || self.name_regex.as_ref().is_some_and(|regex| !regex.is_match(&entry.name))
+            || self.min_size.is_some_and(|min| entry.size < min)
+            || self.max_size.is_some_and(|max| entry.size > max)
+        {
+            return false;
+        }
+        match entry.modified {
+            Some(modified) => {
+                self.modified_after.is_none_or(|after| modified >= after)
+                    && self.modified_before.is_none_or(|before| modified < before)
+            }
+            None => self.modified_after.is_none() && self.modified_before.is_none(),
+        }
+    }
+}
+
+/// Runs a search of `root` on `backend` in the background. Its matches and
+/// then one `Done` or `Error` event arrive on the receiver; dropping the
+/// receiver, or aborting the task, stops the search.
+pub fn spawn(
+    backend: Arc<dyn StorageBackend>,
+    root: String,
+    criteria: SearchCriteria,
+) -> (mpsc::Receiver<SearchEvent>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(SEARCH_BUFFER);
+    let task = tokio::spawn(async move {
+        let event = match search(backend.as_ref(), &root, &criteria, &tx).await {
+            Ok(summary) => SearchEvent::Done(summary),
+            Err(e) => SearchEvent::Error { error: e.to_string() },
+        };
+        let _ = tx.send(event).await;
+    });
+    (rx, task)
+}
+
+/// Sends every entry below `root` that matches `criteria` to `tx`.
+/// Directories that can't be listed are skipped, except for `root` itself.
+pub async fn search(
+    backend: &dyn StorageBackend,
+    root: &str,
+    criteria: &SearchCriteria,
+    tx: &mpsc::Sender<SearchEvent>,
+) -> Result<SearchSummary> {
+    let mut summary = SearchSummary::default();
+    let mut pending = VecDeque::from([(root.to_string(), 1)]);
+
+    while let Some((dir, depth)) = pending.pop_front() {
+        if tx.is_closed() {
+            anyhow::bail!("Search cancelled");
+        }
+        let entries = match backend.list(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e),
+            Err(e) => {
+                log::debug!("Search skipped {}: {}", dir, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        for entry in entries {
+            // Deleted entries aren't found again
+            if entry.path == TRASH_DIR {
+                continue;
+            }
+            summary.scanned += 1;
+            if entry.is_dir && depth < criteria.max_depth {
+                pending.push_back((entry.path.clone(), depth + 1));
+            }
+            if !criteria.matches(&entry) {
+                continue;
+            }
+
+            let lines = match &criteria.content {
+                Some(_) if entry.size > MAX_GREP_SIZE => continue,
+                Some(content) => match grep(backend, &entry.path, content).await {
+                    Ok(lines) if lines.is_empty() => continue,
+                    Ok(lines) => lines,
+                    Err(e) => {
+                        log::debug!("Search skipped {}: {}", entry.path, e);
+                        summary.skipped += 1;
+                        continue;
+                    }
+                },
+                None => Vec::new(),
+            };
+
+            if tx.send(SearchEvent::Match(SearchMatch { file: entry, lines })).await.is_err() {
+                anyhow::bail!("Search cancelled");
+            }
+            summary.matches += 1;
+            if summary.matches as usize >= criteria.limit {
+                summary.truncated = true;
+                return Ok(summary);
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// The first lines of the file at `path` that `pattern` matches. Binary
+/// files have no lines.
+async fn grep(backend: &dyn StorageBackend, path: &str, pattern: &regex::bytes::Regex) -> Result<Vec<LineMatch>> {
+    let mut stream = backend.read_stream(path, None).await?.stream;
+    let mut lines = LineMatcher { pattern, line: 0, matches: Vec::new() };
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut checked = false;
+
+    loop {
+        let chunk = stream.next().await.transpose()?;
+        let ended = chunk.is_none();
+        buffer.extend_from_slice(&chunk.unwrap_or_default());
+
+        // Nothing is matched before the file is known to be text
+        if !checked {
+            if buffer.len() < BINARY_CHECK_SIZE && !ended {
+                continue;
+            }
+            if buffer[..buffer.len().min(BINARY_CHECK_SIZE)].contains(&0) {
+                return Ok(Vec::new());
+            }
+            checked = true;
+        }
+
+        let mut start = 0;
+        while let Some(end) = buffer[start..].iter().position(|&b| b == b'\n') {
+            if lines.check(&buffer[start..start + end]) {
+                return Ok(lines.matches);
+            }
+            start += end + 1;
+        }
+        buffer.drain(..start);
+
+        if ended {
+            if !buffer.is_empty() {
+                lines.check(&buffer);
+            }
+            return Ok(lines.matches);
+        }
+        if buffer.len() > MAX_LINE_LENGTH {
+            return Ok(Vec::new());
+        }
+    }
+}
+
+struct LineMatcher<'a> {
+    pattern: &'a regex::bytes::Regex,
+    line: u64,
+    matches: Vec<LineMatch>,
+}
+
+impl LineMatcher<'_> {
+    /// Checks the next line; returns true once enough lines have matched.
+    fn check(&mut self, text: &[u8]) -> bool {
+        self.line += 1;
+        if self.pattern.is_match(text) {
+            let text = String::from_utf8_lossy(text);
+            self.matches.push(LineMatch {
+                line: self.line,
+                text: text.trim_end_matches('\r').chars().take(LINE_PREVIEW_CHARS).collect(),
+            });
+        }
+        self.matches.len() >= MAX_LINE_MATCHES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::local::LocalFileSystem;
+    use tempfile::TempDir;
+
+    fn host_with_tree() -> (TempDir, LocalFileSystem) {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/hosts/deep")).unwrap();
+        std::fs::write(dir.path().join("README.md"), "# Files\nA file manager.\n").unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {\n    println!(\"Hello\");\n}\n").unwrap();
+        std::fs::write(dir.path().join("src/hosts/local.rs"), "// local files\nfn open() {}").unwrap();
+        std::fs::write(dir.path().join("src/hosts/deep/blob.bin"), [0u8, 1, 2, b'f', b'n']).unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        (dir, fs)
+    }
+
+    async fn run(fs: &LocalFileSystem, root: &str, options: SearchOptions) -> (Vec<SearchMatch>, SearchSummary) {
+        let criteria = SearchCriteria::new(&options).unwrap();
+        let (tx, mut rx) = mpsc::channel(100);
+        let summary = search(fs, root, &criteria, &tx).await.unwrap();
+        drop(tx);
+        let mut matches = Vec::new();
+        while let Some(SearchEvent::Match(found)) = rx.recv().await {
+            matches.push(found);
+        }
+        (matches, summary)
+    }
+
+    fn paths(matches: &[SearchMatch]) -> Vec<&str> {
+        matches.iter().map(|m| m.file.path.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn names_are_matched_by_glob_or_regex() {
+        let (_dir, fs) = host_with_tree();
+
+        let options = SearchOptions { name: Some("*.RS".to_string()), ..Default::default() };
+        let (matches, summary) = run(&fs, "/", options).await;
+        let mut found = paths(&matches);
+        found.sort();
+        assert_eq!(found, ["/src/hosts/local.rs", "/src/main.rs"]);
+        assert_eq!((summary.matches, summary.scanned, summary.truncated), (2, 7, false));
+
+        let options = SearchOptions { name_regex: Some("^(deep|hosts)$".to_string()), ..Default::default() };
+        assert_eq!(paths(&run(&fs, "/", options).await.0), ["/src/hosts", "/src/hosts/deep"]);
+
+        let options = SearchOptions { name: Some("*.rs".to_string()), max_depth: Some(2), ..Default::default() };
+        assert_eq!(paths(&run(&fs, "/", options).await.0), ["/src/main.rs"]);
+
+        let options = SearchOptions { limit: Some(1), ..Default::default() };
+        let (matches, summary) = run(&fs, "/src", options).await;
+        assert_eq!((matches.len(), summary.truncated), (1, true));
+
+        assert!(SearchCriteria::new(&SearchOptions { name_regex: Some("(".to_string()), ..Default::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn content_is_grepped_in_text_files_only() {
+        let (_dir, fs) = host_with_tree();
+
+        let options = SearchOptions { content: Some(r"fn \w+\(".to_string()), ..Default::default() };
+        let (matches, _) = run(&fs, "/", options).await;
+        let mut found: Vec<(&str, Vec<LineMatch>)> = matches.iter().map(|m| (m.file.path.as_str(), m.lines.clone())).collect();
+        found.sort_by_key(|(path, _)| *path);
+        assert_eq!(
+            found,
+            [
+                ("/src/hosts/local.rs", vec![LineMatch { line: 2, text: "fn open() {}".to_string() }]),
+                ("/src/main.rs", vec![LineMatch { line: 1, text: "fn main() {".to_string() }]),
+            ]
+        );
+
+        let options = SearchOptions { content: Some("HELLO".to_string()), case_sensitive: true, ..Default::default() };
+        assert!(run(&fs, "/", options).await.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn size_and_age_filters_leave_out_directories() {
+        let (_dir, fs) = host_with_tree();
+
+        let options = SearchOptions { min_size: Some(20), max_size: Some(30), ..Default::default() };
+        let mut found = paths(&run(&fs, "/", options).await.0).into_iter().map(str::to_string).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["/README.md", "/src/hosts/local.rs"]);
+
+        let options = SearchOptions { modified_after: Some(chrono::Utc::now() + chrono::Duration::hours(1)), ..Default::default() };
+        assert!(run(&fs, "/", options).await.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_receivers_stop_the_search() {
+        let (_dir, fs) = host_with_tree();
+        let criteria = SearchCriteria::new(&SearchOptions::default()).unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+
+        let err = search(&fs, "/", &criteria, &tx).await.unwrap_err();
+        assert_eq!(err.to_string(), "Search cancelled");
+    }
+}
@@ -1,180 +1,659 @@
 use anyhow::{Context, Result};
-use chrono::{DateTime, NaiveDateTime, Utc};
-use ssh2::Session;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use ring::digest;
+use ssh2::{ErrorCode, FileStat, HashType, RenameFlags, Session, Sftp};
+use std::io::{Seek, SeekFrom};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::hosts::pool::{is_transport_error, SftpPool};
+use crate::hosts::range::RangeRequest;
+use crate::hosts::bridge::{channel_stream, feed_chunks, receive_chunks, send_chunks, STREAM_BUFFER_CHUNKS};
+use crate::hosts::{staging_path, ByteStream, Capabilities, FileStream, StorageBackend, StorageError};
 use crate::models::FileInfo;
 
-pub struct SftpFileSystem;
+/// Decrypted credentials for an SFTP host.
+#[derive(Clone)]
+pub enum SftpAuth {
+    Password(String),
+    PublicKey {
+        private_key: String,
+        passphrase: Option<String>,
+    },
+    /// Uses the agent reachable through the server's `SSH_AUTH_SOCK`, if
+    /// `agent_auth_enabled`.
+    Agent,
+}
+
+impl SftpAuth {
+    /// A digest of the credentials, so that pooled sessions are only handed
+    /// to hosts that would have logged in with the same ones.
+    fn digest(&self) -> String {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        let mut field = |value: &str| {
+            ctx.update(&(value.len() as u64).to_be_bytes());
+            ctx.update(value.as_bytes());
+        };
+        match self {
+            SftpAuth::Password(password) => {
+                field("password");
+                field(password);
+            }
+            SftpAuth::PublicKey { private_key, passphrase } => {
+                field("public_key");
+                field(private_key);
+                if let Some(passphrase) = passphrase {
+                    field(passphrase);
+                }
+            }
+            SftpAuth::Agent => field("agent"),
+        }
+        general_purpose::STANDARD_NO_PAD.encode(ctx.finish())
+    }
+}
+
+/// Whether SFTP hosts may authenticate through the fm server's own SSH agent.
+/// Off unless the operator sets `SFTP_AGENT_AUTH`, as every user with such a
+/// host gets to use the agent's identities against any server.
+pub fn agent_auth_enabled() -> bool {
+    std::env::var("SFTP_AGENT_AUTH").is_ok_and(|v| matches!(v.as_str(), "1" | "true"))
+}
+
+#[derive(Clone)]
+pub struct SftpFileSystem {
+    host: String,
+    port: u16,
+    username: String,
+    auth: SftpAuth,
+    trusted_host_key: String,
+    pool: Arc<SftpPool>,
+}
 
 impl SftpFileSystem {
-    pub async fn list_files(
+    pub fn new(
         host: &str,
         port: u16,
         username: &str,
-        password: &str,
-        path: &str,
-    ) -> Result<Vec<FileInfo>> {
-        // Use spawn_blocking for synchronous SSH operations
-        let host = host.to_string();
-        let username = username.to_string();
-        let password = password.to_string();
-        let path = path.to_string();
+        auth: SftpAuth,
+        trusted_host_key: &str,
+        pool: Arc<SftpPool>,
+    ) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            username: username.to_string(),
+            auth,
+            trusted_host_key: trusted_host_key.to_string(),
+            pool,
+        }
+    }
+
+    /// Connects without authenticating and returns the server's host key fingerprint.
+    pub async fn fetch_host_key(host: &str, port: u16) -> Result<String> {
+        let addr = format!("{}:{}", host, port);
 
         tokio::task::spawn_blocking(move || {
-            Self::list_files_sync(&host, port, &username, &password, &path)
+            let (_sess, presented) = Self::handshake(&addr)?;
+            Ok(presented)
         })
         .await?
     }
 
-    fn list_files_sync(
-        host: &str,
-        port: u16,
-        username: &str,
-        password: &str,
-        path: &str,
-    ) -> Result<Vec<FileInfo>> {
-        let tcp = TcpStream::connect(format!("{}:{}", host, port))
-            .context("Failed to connect to SFTP server")?;
+    /// Opens the SSH transport and returns it with the fingerprint of the host key it presented.
+    fn handshake(addr: &str) -> Result<(Session, String)> {
+        let tcp = TcpStream::connect(addr).context("Failed to connect to SFTP server")?;
 
         let mut sess = Session::new().context("Failed to create SSH session")?;
         sess.set_tcp_stream(tcp);
         sess.handshake().context("SSH handshake failed")?;
-        sess.userauth_password(username, password)
-            .context("SSH authentication failed")?;
 
-        let sftp = sess.sftp().context("Failed to create SFTP session")?;
+        let hash = sess
+            .host_key_hash(HashType::Sha256)
+            .ok_or_else(|| anyhow::anyhow!("Server did not provide a host key"))?;
+        let presented = fingerprint(hash);
 
-        let entries = sftp
-            .readdir(Path::new(path))
-            .context("Failed to read directory")?;
+        Ok((sess, presented))
+    }
 
-        let mut files = Vec::new();
+    fn authenticate(&self, sess: &Session) -> Result<()> {
+        match &self.auth {
+            SftpAuth::Password(password) => sess
+                .userauth_password(&self.username, password)
+                .context("SSH password authentication failed"),
+            SftpAuth::PublicKey {
+                private_key,
+                passphrase,
+            } => sess
+                .userauth_pubkey_memory(&self.username, None, private_key, passphrase.as_deref())
+                .context("SSH public key authentication failed"),
+            SftpAuth::Agent => {
+                // Hosts created while it was enabled stay unusable once it isn't
+                anyhow::ensure!(agent_auth_enabled(), "SSH agent authentication is disabled on this server");
 
-        for (path_buf, stat) in entries {
-            let file_name = path_buf
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("")
-                .to_string();
-
-            let file_path = path_buf.to_string_lossy().to_string();
-
-            let modified = stat.mtime.and_then(|mtime| {
-                let naive = NaiveDateTime::from_timestamp(mtime as i64, 0);
-                Some(DateTime::<Utc>::from_utc(naive, Utc))
-            });
-
-            files.push(FileInfo {
-                name: file_name,
-                path: file_path,
-                is_dir: stat.is_dir(),
-                size: stat.size.unwrap_or(0),
-                modified,
-            });
+                let mut agent = sess.agent().context("Failed to initialize SSH agent")?;
+                agent.connect().context("Failed to connect to SSH agent")?;
+                agent
+                    .list_identities()
+                    .context("Failed to list SSH agent identities")?;
+
+                // Try every identity the agent offers, not just the first one
+                for identity in agent.identities()? {
+                    if agent.userauth(&self.username, &identity).is_ok() {
+                        return Ok(());
+                    }
+                }
+
+                anyhow::bail!("SSH agent authentication failed: no identity was accepted")
+            }
         }
+    }
 
-        files.sort_by(|a, b| match (a.is_dir, b.is_dir) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.cmp(&b.name),
-        });
+    fn connect(&self) -> Result<Session> {
+        let (sess, presented) = Self::handshake(&format!("{}:{}", self.host, self.port))?;
 
-        Ok(files)
+        // Checked before authenticating so credentials never reach an impostor
+        verify_host_key(&self.trusted_host_key, &presented)?;
+        self.authenticate(&sess)?;
+
+        Ok(sess)
     }
 
-    pub async fn read_file(
-        host: &str,
-        port: u16,
-        username: &str,
-        password: &str,
-        path: &str,
-    ) -> Result<Vec<u8>> {
-        let host = host.to_string();
-        let username = username.to_string();
-        let password = password.to_string();
-        let path = path.to_string();
+    /// Sessions are shared between hosts that log in as the same user with the
+    /// same credentials on the same server, and trust the same host key. A
+    /// pooled session is never authenticated again, so without the
+    /// credentials in the key a wrong password would get a working session.
+    fn pool_key(&self) -> String {
+        format!(
+            "{}@{}:{}#{}#{}",
+            self.username,
+            self.host,
+            self.port,
+            self.trusted_host_key,
+            self.auth.digest()
+        )
+    }
 
+    /// Runs a synchronous SFTP operation on the blocking thread pool, on a pooled session.
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Sftp) -> Result<T> + Send + 'static,
+    {
+        let this = self.clone();
         tokio::task::spawn_blocking(move || {
-            Self::read_file_sync(&host, port, &username, &password, &path)
+            let key = this.pool_key();
+            let conn = this.pool.checkout(&key, || this.connect())?;
+            let result = op(&conn.sftp);
+            let healthy = result.as_ref().err().is_none_or(|e| !is_transport_error(e));
+            this.pool.checkin(&key, conn, healthy);
+            result
         })
         .await?
     }
 
-    fn read_file_sync(
-        host: &str,
-        port: u16,
-        username: &str,
-        password: &str,
-        path: &str,
-    ) -> Result<Vec<u8>> {
-        let tcp = TcpStream::connect(format!("{}:{}", host, port))
-            .context("Failed to connect to SFTP server")?;
+    /// Creates `path` and any missing parents, like `fs::create_dir_all`.
+    fn mkdir_all(sftp: &Sftp, path: &Path) -> Result<()> {
+        for dir in path_prefixes(path) {
+            match sftp.stat(&dir) {
+                Ok(stat) if stat.is_dir() => continue,
+                Ok(_) => anyhow::bail!("{} exists and is not a directory", dir.display()),
+                Err(e) if is_not_found(&e) => sftp
+                    .mkdir(&dir, 0o755)
+                    .with_context(|| format!("Failed to create directory {}", dir.display()))?,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to stat {}", dir.display()))
+                }
+            }
+        }
 
-        let mut sess = Session::new().context("Failed to create SSH session")?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake().context("SSH handshake failed")?;
-        sess.userauth_password(username, password)
-            .context("SSH authentication failed")?;
+        Ok(())
+    }
+
+    /// Removes `path`, descending into directories, like `fs::remove_dir_all`.
+    /// Symlinks are unlinked rather than followed.
+    fn remove_all(sftp: &Sftp, path: &Path) -> Result<()> {
+        let stat = sftp
+            .lstat(path)
+            .with_context(|| format!("Failed to stat {}", path.display()))?;
 
-        let sftp = sess.sftp().context("Failed to create SFTP session")?;
+        if stat.is_dir() {
+            let entries = sftp
+                .readdir(path)
+                .with_context(|| format!("Failed to read directory {}", path.display()))?;
+            for (child, _) in entries {
+                Self::remove_all(sftp, &child)?;
+            }
+            sftp.rmdir(path)
+                .with_context(|| format!("Failed to remove directory {}", path.display()))?;
+        } else {
+            sftp.unlink(path)
+                .with_context(|| format!("Failed to remove file {}", path.display()))?;
+        }
 
-        let mut remote_file = sftp
-            .open(Path::new(path))
-            .context("Failed to open remote file")?;
+        Ok(())
+    }
 
-        let mut buffer = Vec::new();
-        std::io::Read::read_to_end(&mut remote_file, &mut buffer).context("Failed to read file")?;
+    fn file_info(name: String, path: String, stat: &FileStat) -> FileInfo {
+        let modified = stat
+            .mtime
+            .and_then(|mtime| DateTime::<Utc>::from_timestamp(mtime as i64, 0));
 
-        Ok(buffer)
+        FileInfo {
+            name,
+            path,
+            is_dir: stat.is_dir(),
+            size: stat.size.unwrap_or(0),
+            modified,
+        }
     }
+}
 
-    pub async fn write_file(
-        host: &str,
-        port: u16,
-        username: &str,
-        password: &str,
-        path: &str,
-        content: &[u8],
-    ) -> Result<()> {
-        let host = host.to_string();
-        let username = username.to_string();
-        let password = password.to_string();
+/// What `stream_file` learns about a file before sending its content.
+struct StreamHead {
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    range: Option<(u64, u64)>,
+}
+
+/// Opens `path`, reports its metadata through `head`, then sends its content
+/// (or the resolved part of it) through `chunks`. Returns whether the session
+/// is still usable afterwards.
+fn stream_file(
+    sftp: &Sftp,
+    path: &Path,
+    range: Option<RangeRequest>,
+    head: oneshot::Sender<Result<StreamHead>>,
+    chunks: mpsc::Sender<std::io::Result<Bytes>>,
+) -> bool {
+    let opened = (|| -> Result<_> {
+        let mut file = sftp.open(path).context("Failed to open remote file")?;
+        let stat = file.stat().context("Failed to stat remote file")?;
+        if stat.is_dir() {
+            anyhow::bail!("{} is a directory", path.display());
+        }
+
+        let size = stat.size.unwrap_or(0);
+        let modified = stat.mtime.and_then(|t| DateTime::<Utc>::from_timestamp(t as i64, 0));
+        let range = match range {
+            Some(range) => range.resolve(size, modified)?,
+            None => None,
+        };
+        if let Some((start, _)) = range {
+            file.seek(SeekFrom::Start(start)).context("Failed to seek remote file")?;
+        }
+
+        Ok((file, StreamHead { size, modified, range }))
+    })();
+
+    let (mut file, stream_head) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let healthy = !is_transport_error(&e);
+            let _ = head.send(Err(e));
+            return healthy;
+        }
+    };
+
+    let remaining = stream_head.range.map(|(start, end)| end - start + 1);
+    if head.send(Ok(stream_head)).is_err() {
+        return true;
+    }
+
+    // A failed read may mean the connection dropped; don't pool the session again
+    send_chunks(&mut file, remaining, &chunks).is_ok()
+}
+
+/// Writes chunks from `chunks` to a staging file next to `path`, then moves it
+/// over `path`. `None` marks the end of the upload; if the sender goes away
+/// before that, the staging file is removed and `path` is left untouched.
+fn receive_file(sftp: &Sftp, path: &Path, mut chunks: mpsc::Receiver<Option<Bytes>>) -> Result<u64> {
+    if let Some(parent) = path.parent() {
+        SftpFileSystem::mkdir_all(sftp, parent)?;
+    }
+
+    let staging = staging_path(path);
+    let mut file = sftp.create(&staging).context("Failed to create remote file")?;
+
+    let received = receive_chunks(&mut file, &mut chunks);
+    drop(file);
+
+    let result = received.and_then(|written| {
+        replace_file(sftp, &staging, path)?;
+        Ok(written)
+    });
+    if result.is_err() {
+        let _ = sftp.unlink(&staging);
+    }
+    result
+}
+
+/// Moves `from` over `to`. Servers speaking SFTPv3 ignore the overwrite flag
+/// and refuse to replace an existing file, so then the existing file is moved
+/// aside first, and put back if the move still fails.
+fn replace_file(sftp: &Sftp, from: &Path, to: &Path) -> Result<()> {
+    let flags = Some(RenameFlags::ATOMIC | RenameFlags::NATIVE);
+    match sftp.rename(from, to, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE)) {
+        Ok(()) => return Ok(()),
+        Err(e) if is_target_exists(&e) && sftp.lstat(to).is_ok() => {}
+        Err(e) => return Err(e).context("Failed to move upload into place"),
+    }
+
+    let aside = staging_path(to);
+    sftp.rename(to, &aside, flags).context("Failed to replace remote file")?;
+    if let Err(e) = sftp.rename(from, to, flags) {
+        if let Err(undo) = sftp.rename(&aside, to, flags) {
+            log::error!("Failed to restore {} after a failed upload: {}", to.display(), undo);
+        }
+        return Err(e).context("Failed to move upload into place");
+    }
+    if let Err(e) = sftp.unlink(&aside) {
+        log::warn!("Failed to remove replaced file {}: {}", aside.display(), e);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl StorageBackend for SftpFileSystem {
+    fn label(&self) -> &'static str {
+        "SFTP"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list: true,
+            read: true,
+            write: true,
+            delete: true,
+            mkdir: true,
+            rename: true,
+            ranges: true,
+            ..Default::default()
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>> {
         let path = path.to_string();
-        let content = content.to_vec();
 
+        self.run(move |sftp| {
+            let entries = sftp
+                .readdir(Path::new(&path))
+                .context("Failed to read directory")?;
+
+            let mut files = Vec::new();
+
+            for (path_buf, stat) in entries {
+                let file_name = path_buf
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("")
+                    .to_string();
+
+                let file_path = path_buf.to_string_lossy().to_string();
+
+                files.push(Self::file_info(file_name, file_path, &stat));
+            }
+
+            crate::hosts::sort_entries(&mut files);
+
+            Ok(files)
+        })
+        .await
+    }
+
+    async fn stat(&self, path: &str) -> Result<FileInfo> {
+        let path = path.to_string();
+
+        self.run(move |sftp| {
+            let stat = sftp
+                .stat(Path::new(&path))
+                .context("Failed to stat remote path")?;
+
+            let name = Path::new(&path)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            Ok(Self::file_info(name, path, &stat))
+        })
+        .await
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let path = path.to_string();
+
+        self.run(move |sftp| {
+            let mut remote_file = sftp
+                .open(Path::new(&path))
+                .context("Failed to open remote file")?;
+
+            let mut buffer = Vec::new();
+            std::io::Read::read_to_end(&mut remote_file, &mut buffer)
+                .context("Failed to read file")?;
+
+            Ok(buffer)
+        })
+        .await
+    }
+
+    async fn read_stream(&self, path: &str, range: Option<RangeRequest>) -> Result<FileStream> {
+        let this = self.clone();
+        let path = PathBuf::from(path);
+        let (head_tx, head_rx) = oneshot::channel();
+        let (chunk_tx, chunk_rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+        // The session stays checked out until the whole file has been sent
         tokio::task::spawn_blocking(move || {
-            Self::write_file_sync(&host, port, &username, &password, &path, &content)
+            let key = this.pool_key();
+            match this.pool.checkout(&key, || this.connect()) {
+                Ok(conn) => {
+                    let healthy = stream_file(&conn.sftp, &path, range, head_tx, chunk_tx);
+                    this.pool.checkin(&key, conn, healthy);
+                }
+                Err(e) => {
+                    let _ = head_tx.send(Err(e));
+                }
+            }
+        });
+
+        let head = head_rx.await.context("SFTP download ended unexpectedly")??;
+
+        Ok(FileStream {
+            stream: channel_stream(chunk_rx),
+            size: head.size,
+            modified: head.modified,
+            range: head.range,
         })
-        .await?
     }
 
-    fn write_file_sync(
-        host: &str,
-        port: u16,
-        username: &str,
-        password: &str,
-        path: &str,
-        content: &[u8],
-    ) -> Result<()> {
-        let tcp = TcpStream::connect(format!("{}:{}", host, port))
-            .context("Failed to connect to SFTP server")?;
+    async fn write(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = path.to_string();
+        let content = content.to_vec();
 
-        let mut sess = Session::new().context("Failed to create SSH session")?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake().context("SSH handshake failed")?;
-        sess.userauth_password(username, password)
-            .context("SSH authentication failed")?;
+        self.run(move |sftp| {
+            let path = Path::new(&path);
+            if let Some(parent) = path.parent() {
+                Self::mkdir_all(sftp, parent)?;
+            }
 
-        let sftp = sess.sftp().context("Failed to create SFTP session")?;
+            let mut remote_file = sftp
+                .create(path)
+                .context("Failed to create remote file")?;
 
-        let mut remote_file = sftp
-            .create(Path::new(path))
-            .context("Failed to create remote file")?;
+            std::io::Write::write_all(&mut remote_file, &content)
+                .context("Failed to write file")?;
 
-        std::io::Write::write_all(&mut remote_file, content).context("Failed to write file")?;
+            Ok(())
+        })
+        .await
+    }
 
-        Ok(())
+    async fn write_stream(&self, path: &str, stream: ByteStream) -> Result<u64> {
+        let path = PathBuf::from(path);
+        let (chunk_tx, chunk_rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+        let writer = self.run(move |sftp| receive_file(sftp, &path, chunk_rx));
+        let (written, ()) = tokio::join!(writer, feed_chunks(stream, chunk_tx));
+        written
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+
+        self.run(move |sftp| Self::remove_all(sftp, Path::new(&path)))
+            .await
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+
+        self.run(move |sftp| Self::mkdir_all(sftp, Path::new(&path)))
+            .await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = from.to_string();
+        let to = to.to_string();
+
+        self.run(move |sftp| {
+            let to = Path::new(&to);
+            match sftp.lstat(to) {
+                Ok(_) => return Err(StorageError::AlreadyExists(to.display().to_string()).into()),
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e).context("Failed to stat rename target"),
+            }
+
+            if let Some(parent) = to.parent() {
+                Self::mkdir_all(sftp, parent)?;
+            }
+
+            // No OVERWRITE flag: an existing target is an error, matching local hosts
+            sftp.rename(Path::new(&from), to, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE))
+                .context("Failed to rename remote path")?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// OpenSSH-style `SHA256:<base64>` fingerprint of a host key hash.
+fn fingerprint(sha256: &[u8]) -> String {
+    format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(sha256))
+}
+
+fn verify_host_key(trusted: &str, presented: &str) -> Result<()> {
+    if trusted != presented {
+        return Err(StorageError::HostKeyMismatch {
+            trusted: trusted.to_string(),
+            presented: presented.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// `LIBSSH2_FX_NO_SUCH_FILE`
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// `LIBSSH2_FX_FAILURE`, what SFTPv3 servers report for a rename onto an
+/// existing file
+const SFTP_FAILURE: i32 = 4;
+/// `LIBSSH2_FX_FILE_ALREADY_EXISTS`
+const SFTP_FILE_ALREADY_EXISTS: i32 = 11;
+
+fn is_not_found(err: &ssh2::Error) -> bool {
+    matches!(err.code(), ErrorCode::SFTP(SFTP_NO_SUCH_FILE))
+}
+
+/// Whether a rename may have failed only because its target exists.
+fn is_target_exists(err: &ssh2::Error) -> bool {
+    matches!(err.code(), ErrorCode::SFTP(SFTP_FAILURE | SFTP_FILE_ALREADY_EXISTS))
+}
+
+/// Every directory from the top of `path` down to `path` itself, e.g.
+/// `/a/b` yields `/a` and `/a/b`. The root itself is never included.
+fn path_prefixes(path: &Path) -> Vec<PathBuf> {
+    let mut current = PathBuf::new();
+    let mut prefixes = Vec::new();
+
+    for component in path.components() {
+        current.push(component);
+        if matches!(component, Component::Normal(_)) {
+            prefixes.push(current.clone());
+        }
+    }
+
+    prefixes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::pool::PoolConfig;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn path_prefixes_walks_down_from_the_top() {
+        assert_eq!(
+            path_prefixes(Path::new("/srv/data/new")),
+            [PathBuf::from("/srv"), PathBuf::from("/srv/data"), PathBuf::from("/srv/data/new")]
+        );
+        assert_eq!(
+            path_prefixes(Path::new("rel/dir")),
+            [PathBuf::from("rel"), PathBuf::from("rel/dir")]
+        );
+        assert!(path_prefixes(Path::new("/")).is_empty());
+    }
+
+    #[test]
+    fn sessions_are_only_pooled_for_the_same_credentials() {
+        // Checkouts only hand out sessions pooled under the same key
+        let pool = Arc::new(SftpPool::new(PoolConfig::from_env(), &Metrics::new()));
+        let host = |auth: SftpAuth| SftpFileSystem::new("example.com", 22, "deploy", auth, "SHA256:abc", pool.clone());
+        let owner = host(SftpAuth::Password("right".to_string()));
+
+        assert_eq!(owner.pool_key(), host(SftpAuth::Password("right".to_string())).pool_key());
+        for other in [
+            SftpAuth::Password("wrong".to_string()),
+            SftpAuth::PublicKey { private_key: "right".to_string(), passphrase: None },
+            SftpAuth::Agent,
+        ] {
+            assert_ne!(owner.pool_key(), host(other).pool_key());
+        }
+    }
+
+    #[test]
+    fn fingerprint_matches_openssh_format() {
+        // `ssh-keygen -lf` prints unpadded base64 of the SHA-256 digest
+        assert_eq!(fingerprint(&[0u8; 32]), "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+    }
+
+    #[test]
+    fn mismatched_host_key_is_rejected() {
+        assert!(verify_host_key("SHA256:abc", "SHA256:abc").is_ok());
+
+        let err = verify_host_key("SHA256:abc", "SHA256:xyz").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::HostKeyMismatch { trusted, presented })
+                if trusted == "SHA256:abc" && presented == "SHA256:xyz"
+        ));
+    }
+
+    #[test]
+    fn only_no_such_file_counts_as_missing() {
+        let missing = ssh2::Error::new(ErrorCode::SFTP(SFTP_NO_SUCH_FILE), "no such file");
+        let denied = ssh2::Error::new(ErrorCode::SFTP(3), "permission denied");
+        let session = ssh2::Error::new(ErrorCode::Session(-7), "socket send");
+
+        assert!(is_not_found(&missing));
+        assert!(!is_not_found(&denied));
+        assert!(!is_not_found(&session));
     }
 }
@@ -0,0 +1,326 @@
+//! Copies of files and directory trees, within one host or between two.
+//! Between hosts, file contents stream through the server one chunk at a time.
+//! Copies are written under a staging name and only moved to their
+//! destination once complete, see `commit_staged`.
+
+use anyhow::Result;
+use futures::StreamExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::hosts::{staging_path, ByteStream, StorageBackend, StorageError};
+use crate::models::{FileInfo, ProgressSnapshot};
+
+/// Deepest directory nesting a walk follows. Hosts that follow symlinks can
+/// present endless trees; this turns such a loop into an error.
+const MAX_DEPTH: usize = 64;
+
+/// Counters shared between a running copy and whoever reports on it.
+#[derive(Debug, Default)]
+pub struct TransferProgress {
+    bytes_total: AtomicU64,
+    bytes_done: AtomicU64,
+    files_total: AtomicU64,
+    files_done: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl TransferProgress {
+    /// Asks the copy to stop at its next chunk; it then fails and cleans up.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            files_total: self.files_total.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts a new attempt over `entries`; a retried copy starts from zero.
+    fn begin(&self, entries: &[FileInfo]) {
+        let files = entries.iter().filter(|e| !e.is_dir);
+        self.start(files.clone().map(|e| e.size).sum(), files.count() as u64);
+    }
+
+    /// Starts a new attempt at an operation of the given size from zero.
+    pub fn start(&self, bytes_total: u64, files_total: u64) {
+        self.bytes_total.store(bytes_total, Ordering::Relaxed);
+        self.files_total.store(files_total, Ordering::Relaxed);
+        self.bytes_done.store(0, Ordering::Relaxed);
+        self.files_done.store(0, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts one more file, for operations that only find their files as they go.
+    pub fn file_found(&self) {
+        self.files_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Passes `stream` through, counting its bytes and failing it once the
+    /// operation is cancelled.
+    pub fn count(self: &Arc<Self>, stream: ByteStream) -> ByteStream {
+        let counter = self.clone();
+        Box::pin(stream.map(move |chunk| {
+            if counter.is_cancelled() {
+                return Err(std::io::Error::other("Transfer cancelled"));
+            }
+            if let Ok(chunk) = &chunk {
+                counter.advance(chunk.len() as u64);
+            }
+            chunk
+        }))
+    }
+
+    pub fn finish(&self) {
+        self.bytes_done.store(self.bytes_total.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.files_done.store(self.files_total.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Copies `from` to `to` on the same host. Hosts that can copy natively do so
+/// without the data passing through the server; their progress only moves
+/// once the whole copy is done, and they can't be cancelled midway.
+/// `to` must be a name only this copy uses, see `staging_name`; a failed
+/// copy removes it.
+pub async fn copy_within(
+    backend: &dyn StorageBackend,
+    from: &str,
+    to: &str,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    if !backend.capabilities().copy {
+        return copy_between(backend, from, backend, to, progress).await;
+    }
+
+    progress.begin(&walk(backend, from).await?);
+    if let Err(e) = backend.copy(from, to).await {
+        // Someone else got there first; what is at `to` now isn't ours
+        if !matches!(e.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))) {
+            discard(backend, to).await;
+        }
+        return Err(e);
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// Copies `from` on `source` to `to` on `target` by streaming every file.
+/// `to` must be a name only this copy uses, see `staging_name`; a failed
+/// copy removes it.
+pub async fn copy_between(
+    source: &dyn StorageBackend,
+    from: &str,
+    target: &dyn StorageBackend,
+    to: &str,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let entries = walk(source, from).await?;
+    progress.begin(&entries);
+
+    let result = copy_entries(source, from, &entries, target, to, progress).await;
+    if result.is_err() {
+        discard(target, to).await;
+    }
+    result
+}
+
+/// Removes what a failed copy wrote. `to` is a name only the copy used, so
+/// everything under it is ours.
+pub async fn discard(target: &dyn StorageBackend, to: &str) {
+    if target.stat(to).await.is_err() {
+        return;
+    }
+    if let Err(e) = target.delete(to).await {
+        log::warn!("Failed to clean up partial copy at {}: {}", to, e);
+    }
+}
+
+async fn copy_entries(
+    source: &dyn StorageBackend,
+    from: &str,
+    entries: &[FileInfo],
+    target: &dyn StorageBackend,
+    to: &str,
+    progress: &Arc<TransferProgress>,
+) -> Result<()> {
+    let root = from.trim_end_matches('/');
+
+    for entry in entries {
+        if progress.is_cancelled() {
+            anyhow::bail!("Transfer cancelled");
+        }
+
+        let dest = format!("{}{}", to.trim_end_matches('/'), &entry.path[root.len()..]);
+        if entry.is_dir {
+            target.mkdir(&dest).await?;
+            continue;
+        }
+
+        let file = source.read_stream(&entry.path, None).await?;
+        target.write_stream(&dest, progress.count(file.stream)).await?;
+        progress.file_done();
+    }
+
+    Ok(())
+}
+
+/// A name next to `to` for a copy to be written under until it is complete.
+pub fn staging_name(to: &str) -> String {
+    staging_path(Path::new(to)).to_string_lossy().into_owned()
+}
+
+/// Moves a copy written to `staging` to `to` once `written` says it is
+/// complete, and removes it otherwise. Anything that appeared at `to` while
+/// the copy ran is left alone; the move then fails with `AlreadyExists`.
+pub async fn commit_staged(target: &dyn StorageBackend, staging: &str, to: &str, written: Result<()>) -> Result<()> {
+    let result = match written {
+        Ok(()) => target.rename(staging, to).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        discard(target, staging).await;
+    }
+    result
+}
+
+/// Fails with `StorageError::AlreadyExists` if `to` exists on `target`, so
+/// a copy that could never be moved into place isn't started.
+pub async fn ensure_vacant(target: &dyn StorageBackend, to: &str) -> Result<()> {
+    if target.stat(to).await.is_ok() {
+        return Err(StorageError::AlreadyExists(to.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Lists everything under `from`, including `from` itself, with every
+/// directory ahead of its contents.
+pub async fn walk(source: &dyn StorageBackend, from: &str) -> Result<Vec<FileInfo>> {
+    let mut root = source.stat(from).await?;
+    root.path = from.to_string();
+
+    let mut entries = Vec::new();
+    let mut pending = vec![(root, 0)];
+    while let Some((entry, depth)) = pending.pop() {
+        if entry.is_dir {
+            if depth >= MAX_DEPTH {
+                anyhow::bail!("{} is nested too deeply", entry.path);
+            }
+            for child in source.list(&entry.path).await? {
+                pending.push((child, depth + 1));
+            }
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::local::LocalFileSystem;
+    use tempfile::TempDir;
+
+    fn host_with_tree() -> (TempDir, LocalFileSystem) {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("share/photos/2024")).unwrap();
+        std::fs::write(dir.path().join("share/notes.txt"), b"notes").unwrap();
+        std::fs::write(dir.path().join("share/photos/2024/cat.jpg"), vec![7; 300_000]).unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        (dir, fs)
+    }
+
+    #[tokio::test]
+    async fn copies_trees_between_hosts() {
+        let (_src_dir, source) = host_with_tree();
+        let dst_dir = TempDir::new().unwrap();
+        let target = LocalFileSystem::new(dst_dir.path().to_str().unwrap());
+        let progress = Arc::new(TransferProgress::default());
+
+        copy_between(&source, "/share", &target, "/backup/share", &progress).await.unwrap();
+
+        assert_eq!(std::fs::read(dst_dir.path().join("backup/share/notes.txt")).unwrap(), b"notes");
+        assert_eq!(std::fs::read(dst_dir.path().join("backup/share/photos/2024/cat.jpg")).unwrap().len(), 300_000);
+        assert_eq!(
+            progress.snapshot(),
+            ProgressSnapshot { bytes_total: 300_005, bytes_done: 300_005, files_total: 2, files_done: 2 }
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_destinations_are_refused() {
+        let (dir, fs) = host_with_tree();
+        let progress = Arc::new(TransferProgress::default());
+
+        let err = ensure_vacant(&fs, "/share/photos").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+        assert!(ensure_vacant(&fs, "/share/videos").await.is_ok());
+
+        // Native copies refuse on their own as well
+        let err = copy_within(&fs, "/share/notes.txt", "/share/photos", &progress).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+        assert!(dir.path().join("share/photos/2024/cat.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn cancelled_copies_clean_up() {
+        let (_src_dir, source) = host_with_tree();
+        let dst_dir = TempDir::new().unwrap();
+        let target = LocalFileSystem::new(dst_dir.path().to_str().unwrap());
+        let progress = Arc::new(TransferProgress::default());
+        progress.cancel();
+
+        let err = copy_between(&source, "/share", &target, "/backup", &progress).await.unwrap_err();
+        assert_eq!(err.to_string(), "Transfer cancelled");
+        assert!(!dst_dir.path().join("backup").exists());
+    }
+
+    #[tokio::test]
+    async fn destinations_taken_during_a_copy_are_left_alone() {
+        let (dir, fs) = host_with_tree();
+        let progress = Arc::new(TransferProgress::default());
+
+        let staging = staging_name("/backup");
+        copy_within(&fs, "/share", &staging, &progress).await.unwrap();
+        // Another writer creates the destination while the copy runs
+        std::fs::create_dir(dir.path().join("backup")).unwrap();
+        std::fs::write(dir.path().join("backup/theirs.txt"), b"theirs").unwrap();
+
+        let err = commit_staged(&fs, &staging, "/backup", Ok(())).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::AlreadyExists(_))));
+        let failed = commit_staged(&fs, &staging, "/backup", Err(anyhow::anyhow!("Transfer cancelled"))).await;
+        assert!(failed.is_err());
+
+        assert_eq!(std::fs::read(dir.path().join("backup/theirs.txt")).unwrap(), b"theirs");
+        assert!(!dir.path().join("backup/notes.txt").exists());
+        assert!(fs.stat(&staging).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn native_copies_report_completion() {
+        let (dir, fs) = host_with_tree();
+        let progress = Arc::new(TransferProgress::default());
+
+        copy_within(&fs, "/share/photos", "/photos", &progress).await.unwrap();
+
+        assert!(dir.path().join("photos/2024/cat.jpg").exists());
+        assert_eq!(progress.snapshot().bytes_done, 300_000);
+        assert_eq!(progress.snapshot().files_done, 1);
+    }
+}
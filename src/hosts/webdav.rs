@@ -0,0 +1,503 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use reqwest::{Client, Method, Response, StatusCode};
+use futures::TryStreamExt;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::hosts::bridge::{relay_body, response_stream};
+use crate::hosts::range::RangeRequest;
+use crate::hosts::{encode_path, join_path, sort_entries, ByteStream, Capabilities, FileStream, StorageBackend, StorageError};
+use crate::models::FileInfo;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop></d:propfind>"#;
+
+/// Authentication scheme requested by the server in its last `401` response.
+enum Challenge {
+    Basic,
+    Digest(digest_auth::WwwAuthenticateHeader),
+}
+
+pub struct WebDavFileSystem {
+    client: Client,
+    base_url: String,
+    credentials: Option<(String, String)>,
+    challenge: Mutex<Option<Challenge>>,
+}
+
+/// One `<response>` of a PROPFIND multistatus body.
+#[derive(Debug, PartialEq)]
+struct DavEntry {
+    /// Decoded URL path of the resource.
+    href: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl WebDavFileSystem {
+    pub fn new(base_url: &str, credentials: Option<(String, String)>) -> Self {
+        Self {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credentials,
+            challenge: Mutex::new(None),
+        }
+    }
+
+    fn url_for(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, encode_path(path))
+    }
+
+    /// Collections are addressed with a trailing slash; some servers redirect otherwise.
+    fn collection_url(&self, path: &str) -> String {
+        format!("{}/", self.url_for(path).trim_end_matches('/'))
+    }
+
+    /// Sends a request, answering one authentication challenge if the server asks for it.
+    /// The scheme is remembered so later requests authenticate up front.
+    async fn send(&self, method: Method, url: &str, headers: HeaderMap, body: Option<Bytes>) -> Result<Response> {
+        let mut answered_challenge = false;
+
+        loop {
+            let mut request = self.client.request(method.clone(), url).headers(headers.clone());
+            if let Some(body) = &body {
+                request = request.body(body.clone());
+            }
+            if let Some(authorization) = self.authorization(&method, url)? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+
+            let response = request
+                .send()
+                .await
+                .with_context(|| format!("Failed to send WebDAV {} request", method))?;
+
+            if response.status() != StatusCode::UNAUTHORIZED || answered_challenge || self.credentials.is_none() {
+                return Ok(response);
+            }
+
+            let challenge = Self::parse_challenge(response.headers())
+                .context("WebDAV server requires an unsupported authentication scheme")?;
+            *self.challenge.lock().unwrap() = Some(challenge);
+            answered_challenge = true;
+        }
+    }
+
+    /// Digest is preferred when the server offers both schemes.
+    fn parse_challenge(headers: &HeaderMap) -> Option<Challenge> {
+        let offered: Vec<&str> = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+
+        let digest = offered
+            .iter()
+            .filter(|h| h.len() >= 6 && h[..6].eq_ignore_ascii_case("digest"))
+            .find_map(|h| digest_auth::parse(h).ok());
+        if let Some(digest) = digest {
+            return Some(Challenge::Digest(digest));
+        }
+
+        offered
+            .iter()
+            .any(|h| h.len() >= 5 && h[..5].eq_ignore_ascii_case("basic"))
+            .then_some(Challenge::Basic)
+    }
+
+    fn authorization(&self, method: &Method, url: &str) -> Result<Option<String>> {
+        let Some((username, password)) = &self.credentials else {
+            return Ok(None);
+        };
+
+        let mut challenge = self.challenge.lock().unwrap();
+        let header = match challenge.as_mut() {
+            None => return Ok(None),
+            Some(Challenge::Basic) => {
+                let token = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+                format!("Basic {}", token)
+            }
+            Some(Challenge::Digest(prompt)) => {
+                let uri = reqwest::Url::parse(url)?;
+                let context = digest_auth::AuthContext::new_with_method(
+                    username.as_str(),
+                    password.as_str(),
+                    uri.path(),
+                    Option::<&[u8]>::None,
+                    digest_auth::HttpMethod(Cow::Owned(method.to_string())),
+                );
+                prompt
+                    .respond(&context)
+                    .context("Failed to answer WebDAV digest challenge")?
+                    .to_header_string()
+            }
+        };
+
+        Ok(Some(header))
+    }
+
+    async fn propfind(&self, url: &str, depth: &'static str) -> Result<(String, Vec<DavEntry>)> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Depth", HeaderValue::from_static(depth));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/xml; charset=utf-8"));
+
+        let response = self
+            .send(propfind_method(), url, headers, Some(Bytes::from_static(PROPFIND_BODY.as_bytes())))
+            .await?;
+        let response = check_status(response, "PROPFIND")?;
+
+        let request_path = decode_path(response.url().path());
+        let body = response.text().await?;
+        Ok((request_path, parse_multistatus(&body)?))
+    }
+
+    /// Creates every missing collection down to and including `path`.
+    async fn mkcol_all(&self, path: &str) -> Result<()> {
+        let mut current = String::new();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current.push('/');
+            current.push_str(segment);
+
+            let url = self.collection_url(&current);
+            let response = self.send(Method::from_bytes(b"MKCOL")?, &url, HeaderMap::new(), None).await?;
+
+            // 405 is the answer for a collection that already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_status(response, "MKCOL")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn create_parent(&self, path: &str) -> Result<()> {
+        match path.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, _)) if !parent.is_empty() => self.mkcol_all(parent).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// MOVE and COPY never replace an existing destination, matching the other backends.
+    async fn transfer(&self, method: &'static [u8], from: &str, to: &str) -> Result<()> {
+        self.create_parent(to).await?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Destination", HeaderValue::from_str(&self.url_for(to))?);
+        headers.insert("Overwrite", HeaderValue::from_static("F"));
+        headers.insert("Depth", HeaderValue::from_static("infinity"));
+
+        let method = Method::from_bytes(method)?;
+        let response = self.send(method.clone(), &self.url_for(from), headers, None).await?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(StorageError::AlreadyExists(to.to_string()).into());
+        }
+
+        check_status(response, method.as_str()).map(|_| ())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavFileSystem {
+    fn label(&self) -> &'static str {
+        "WebDAV"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list: true,
+            read: true,
+            write: true,
+            delete: true,
+            mkdir: true,
+            rename: true,
+            copy: true,
+            ..Default::default()
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let (request_path, entries) = self.propfind(&self.collection_url(path), "1").await?;
+        let own_path = request_path.trim_end_matches('/');
+
+        let mut files: Vec<FileInfo> = entries
+            .into_iter()
+            .filter(|e| e.href.trim_end_matches('/') != own_path)
+            .map(|e| {
+                let name = entry_name(&e.href).to_string();
+                FileInfo {
+                    path: join_path(path, &name),
+                    name,
+                    is_dir: e.is_dir,
+                    size: e.size,
+                    modified: e.modified,
+                }
+            })
+            .collect();
+
+        sort_entries(&mut files);
+        Ok(files)
+    }
+
+    async fn stat(&self, path: &str) -> Result<FileInfo> {
+        let (_, entries) = self.propfind(&self.url_for(path), "0").await?;
+        let entry = entries
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("WebDAV server returned no properties for {}", path))?;
+
+        Ok(FileInfo {
+            name: entry_name(path).to_string(),
+            path: path.to_string(),
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified: entry.modified,
+        })
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let response = self.send(Method::GET, &self.url_for(path), HeaderMap::new(), None).await?;
+        let response = check_status(response, "GET")?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn read_stream(&self, path: &str, _range: Option<RangeRequest>) -> Result<FileStream> {
+        let response = self.send(Method::GET, &self.url_for(path), HeaderMap::new(), None).await?;
+        response_stream(check_status(response, "GET")?).await
+    }
+
+    async fn write(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.create_parent(path).await?;
+
+        let body = Bytes::copy_from_slice(content);
+        let response = self.send(Method::PUT, &self.url_for(path), HeaderMap::new(), Some(body)).await?;
+        check_status(response, "PUT").map(|_| ())
+    }
+
+    async fn write_stream(&self, path: &str, stream: ByteStream) -> Result<u64> {
+        self.create_parent(path).await?;
+        let url = self.url_for(path);
+
+        // A streamed body can't be sent again after a 401, so learn the scheme first
+        if self.credentials.is_some() && self.challenge.lock().unwrap().is_none() {
+            self.send(Method::OPTIONS, &url, HeaderMap::new(), None).await?;
+        }
+
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let counted = stream.inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
+
+        let (body, pump) = relay_body(Box::pin(counted));
+        let mut request = self.client.put(&url).body(body);
+        if let Some(authorization) = self.authorization(&Method::PUT, &url)? {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        let (response, ()) = tokio::join!(request.send(), pump);
+        let response = response.context("Failed to send WebDAV PUT request")?;
+        check_status(response, "PUT")?;
+        Ok(written.load(Ordering::Relaxed))
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let response = self.send(Method::DELETE, &self.url_for(path), HeaderMap::new(), None).await?;
+        check_status(response, "DELETE").map(|_| ())
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        self.mkcol_all(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.transfer(b"MOVE", from, to).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.transfer(b"COPY", from, to).await
+    }
+}
+
+fn propfind_method() -> Method {
+    Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method")
+}
+
+fn check_status(response: Response, method: &str) -> Result<Response> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        anyhow::bail!("WebDAV {} failed with status: {}", method, response.status())
+    }
+}
+
+fn decode_path(path: &str) -> String {
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+fn entry_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or("")
+}
+
+/// Parses a `207 Multi-Status` PROPFIND response. Namespace prefixes vary
+/// between servers, so elements are matched on their local name.
+fn parse_multistatus(body: &str) -> Result<Vec<DavEntry>> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut current: Option<DavEntry> = None;
+    let mut element = Vec::new();
+
+    loop {
+        match reader.read_event().context("Invalid WebDAV PROPFIND response")? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                if name == b"response" {
+                    current = Some(DavEntry {
+                        href: String::new(),
+                        is_dir: false,
+                        size: 0,
+                        modified: None,
+                    });
+                } else if name == b"collection" {
+                    if let Some(entry) = current.as_mut() {
+                        entry.is_dir = true;
+                    }
+                }
+                element = name;
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                if let Some(entry) = current.as_mut() {
+                    entry.is_dir = true;
+                }
+            }
+            Event::Text(text) => {
+                let Some(entry) = current.as_mut() else {
+                    continue;
+                };
+                let value = text.unescape()?;
+                match element.as_slice() {
+                    b"href" => {
+                        // Some servers answer with absolute URLs instead of paths
+                        let path = match reqwest::Url::parse(&value) {
+                            Ok(url) => url.path().to_string(),
+                            Err(_) => value.into_owned(),
+                        };
+                        entry.href = decode_path(&path);
+                    }
+                    b"getcontentlength" => entry.size = value.trim().parse().unwrap_or(0),
+                    b"getlastmodified" => {
+                        entry.modified = DateTime::parse_from_rfc2822(value.trim())
+                            .ok()
+                            .map(|dt| dt.with_timezone(&Utc));
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"response" {
+                    entries.extend(current.take());
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTISTATUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/dav/Photos/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/dav/Photos/2024%20Trip/</D:href>
+    <D:propstat>
+      <D:prop><D:resourcetype><D:collection/></D:resourcetype><D:getlastmodified>Sat, 02 Mar 2024 09:15:00 GMT</D:getlastmodified></D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+    <D:propstat><D:prop><D:getcontentlength/></D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>https://nas.local/dav/Photos/a&amp;b.jpg</D:href>
+    <D:propstat>
+      <D:prop><D:resourcetype/><D:getcontentlength>2048</D:getcontentlength></D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+</D:multistatus>"#;
+
+    #[test]
+    fn parses_multistatus_entries() {
+        let entries = parse_multistatus(MULTISTATUS).unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].href, "/dav/Photos/");
+        assert!(entries[0].is_dir);
+
+        assert_eq!(entries[1].href, "/dav/Photos/2024 Trip/");
+        assert!(entries[1].is_dir);
+        assert_eq!(entries[1].modified.unwrap().to_rfc3339(), "2024-03-02T09:15:00+00:00");
+
+        assert_eq!(entries[2], DavEntry {
+            href: "/dav/Photos/a&b.jpg".to_string(),
+            is_dir: false,
+            size: 2048,
+            modified: None,
+        });
+    }
+
+    #[test]
+    fn parses_other_namespace_prefixes() {
+        let body = r#"<multistatus xmlns="DAV:"><response><href>/f.txt</href>
+<propstat><prop><resourcetype></resourcetype><getcontentlength>7</getcontentlength></prop></propstat></response></multistatus>"#;
+
+        let entries = parse_multistatus(body).unwrap();
+
+        assert_eq!(entries, [DavEntry { href: "/f.txt".to_string(), is_dir: false, size: 7, modified: None }]);
+    }
+
+    #[test]
+    fn url_for_encodes_each_segment() {
+        let fs = WebDavFileSystem::new("https://nas.local/dav/", None);
+
+        assert_eq!(fs.url_for("/My Files/a#1?.txt"), "https://nas.local/dav/My%20Files/a%231%3F.txt");
+        assert_eq!(fs.collection_url("/"), "https://nas.local/dav/");
+        assert_eq!(fs.collection_url("/docs"), "https://nas.local/dav/docs/");
+    }
+
+    #[test]
+    fn digest_challenge_is_preferred_over_basic() {
+        let mut headers = HeaderMap::new();
+        headers.append(WWW_AUTHENTICATE, HeaderValue::from_static(r#"Basic realm="nas""#));
+        headers.append(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Digest realm="nas", nonce="abc", qop="auth", algorithm=MD5"#),
+        );
+
+        assert!(matches!(WebDavFileSystem::parse_challenge(&headers), Some(Challenge::Digest(_))));
+
+        headers.remove(WWW_AUTHENTICATE);
+        headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static(r#"Basic realm="nas""#));
+        assert!(matches!(WebDavFileSystem::parse_challenge(&headers), Some(Challenge::Basic)));
+    }
+}
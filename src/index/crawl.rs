@@ -0,0 +1,239 @@
+//! Reading the files of a local host into the index. Crawls skip entries
+//! whose size and modification time haven't changed, so only the first
+//! crawl of a host reads everything.
+
+use anyhow::{Context, Result};
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use super::store::{Document, IndexStore};
+use crate::hosts::{is_trash_path, join_path};
+use crate::hosts::local::LocalFileSystem;
+
+/// Entries written to the index in one transaction.
+const BATCH_SIZE: usize = 256;
+/// How much of a file is checked for NUL bytes before it counts as text.
+const BINARY_CHECK_SIZE: usize = 8 * 1024;
+/// Extensions of the files whose text is indexed, besides files without one
+/// such as `README` or `Makefile`.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "text", "md", "markdown", "rst", "adoc", "org", "tex", "csv", "tsv", "log", "json", "yaml", "yml",
+    "toml", "ini", "cfg", "conf", "env", "properties", "xml", "html", "htm", "css", "scss", "less", "svg", "rs",
+    "py", "rb", "go", "java", "kt", "kts", "scala", "swift", "c", "h", "cc", "cpp", "cxx", "hpp", "cs", "fs",
+    "js", "mjs", "cjs", "jsx", "ts", "tsx", "vue", "svelte", "php", "pl", "pm", "lua", "r", "jl", "dart", "ex",
+    "exs", "erl", "hrl", "hs", "ml", "mli", "clj", "el", "vim", "sh", "bash", "zsh", "fish", "ps1", "bat", "sql",
+    "graphql", "proto", "gradle", "cmake", "mk", "dockerfile", "gitignore", "editorconfig",
+];
+
+/// Brings the index of one host up to date with its files.
+pub struct Crawler<'a> {
+    pub store: &'a IndexStore,
+    pub host_id: &'a str,
+    /// The host's base path, canonicalized.
+    pub base: &'a Path,
+    /// Text files larger than this are indexed by name only.
+    pub max_text_size: u64,
+    /// Marks the entries found, see `IndexStore::sweep`.
+    pub crawl: i64,
+}
+
+impl Crawler<'_> {
+    /// Indexes `path` and everything below it. Subdirectories that can't
+    /// be read are left out; failing to read `path` itself is an error.
+    pub async fn index_tree(&self, path: &str) -> Result<()> {
+        let mut pending = vec![(self.base.join(path.trim_start_matches('/')), path.to_string())];
+        let mut batch = Vec::new();
+        let mut first = true;
+
+        while let Some((dir, dir_path)) = pending.pop() {
+            let metadata = match fs::symlink_metadata(&dir).await {
+                Ok(metadata) => metadata,
+                Err(e) if first => return Err(e).with_context(|| format!("Failed to read {}", dir_path)),
+                Err(e) => {
+                    log::debug!("Skipping {} while indexing: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            first = false;
+            if dir_path != "/" {
+                if let Some(doc) = self.document(&dir, &dir_path, &metadata).await? {
+                    batch.push(doc);
+                }
+            }
+            if !metadata.is_dir() {
+                continue;
+            }
+
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::debug!("Skipping {} while indexing: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if is_staging_name(&name) {
+                    continue;
+                }
+                let path = join_path(&dir_path, &name);
+                if is_trash_path(&path) {
+                    continue;
+                }
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                if metadata.is_dir() {
+                    pending.push((entry.path(), path));
+                } else if let Some(doc) = self.document(&entry.path(), &path, &metadata).await? {
+                    batch.push(doc);
+                }
+
+                if batch.len() >= BATCH_SIZE {
+                    self.store.put(self.host_id, &batch, self.crawl).await?;
+                    batch.clear();
+                }
+            }
+        }
+
+        self.store.put(self.host_id, &batch, self.crawl).await
+    }
+
+    /// Brings the index up to date with whatever happened to the entry at
+    /// `file`, an absolute path below the base path.
+    pub async fn refresh(&self, file: &Path) -> Result<()> {
+        let Ok(relative) = file.strip_prefix(self.base) else {
+            return Ok(());
+        };
+        if relative.components().any(|c| is_staging_name(&c.as_os_str().to_string_lossy())) {
+            return Ok(());
+        }
+        let path = format!("/{}", relative.to_string_lossy());
+        if is_trash_path(&path) {
+            return Ok(());
+        }
+
+        let metadata = match fs::symlink_metadata(file).await {
+            Ok(metadata) if !metadata.file_type().is_symlink() => metadata,
+            Ok(_) => return self.store.remove(self.host_id, &path).await,
+            Err(e) if e.kind() == ErrorKind::NotFound => return self.store.remove(self.host_id, &path).await,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path)),
+        };
+
+        // A directory new to the index was created or moved in along with
+        // its contents; one already indexed only had an entry change
+        if metadata.is_dir() && path != "/" && !self.store.contains(self.host_id, &path).await? {
+            return self.index_tree(&path).await;
+        }
+        if let Some(doc) = self.document(file, &path, &metadata).await? {
+            self.store.put(self.host_id, &[doc], self.crawl).await?;
+        }
+        Ok(())
+    }
+
+    /// The entry at `path` as it goes into the index, or `None` if it is
+    /// indexed as it is already, or is a link.
+    async fn document(&self, file: &Path, path: &str, metadata: &Metadata) -> Result<Option<Document>> {
+        if metadata.file_type().is_symlink() || path == "/" {
+            return Ok(None);
+        }
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        let info = LocalFileSystem::file_info(name, path.to_string(), metadata);
+        if self.store.keep_if_unchanged(self.host_id, &info, self.crawl).await? {
+            return Ok(None);
+        }
+
+        let text = if !info.is_dir && info.size <= self.max_text_size && is_text_name(&info.name) {
+            read_text(file).await
+        } else {
+            None
+        };
+        Ok(Some(Document { file: info, text }))
+    }
+}
+
+/// The host's base path as the index sees it, which is how the filesystem
+/// reports paths in change events.
+pub fn canonical_base(base_path: &str) -> Result<PathBuf> {
+    Path::new(base_path).canonicalize().context("Invalid base path")
+}
+
+fn is_text_name(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, extension)) => TEXT_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(extension)),
+        None => true,
+    }
+}
+
+/// Uploads are written to a hidden `.name.xxxxxxxx.part` file first.
+fn is_staging_name(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".part")
+}
+
+/// The file's text, unless it turns out to be binary.
+async fn read_text(file: &Path) -> Option<String> {
+    let bytes = fs::read(file).await.ok()?;
+    if bytes[..bytes.len().min(BINARY_CHECK_SIZE)].contains(&0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::store::{match_expression, Query};
+    use tempfile::TempDir;
+
+    async fn paths(store: &IndexStore, query: &str) -> Vec<String> {
+        let expression = match_expression(query).unwrap();
+        let host_ids = ["host-1".to_string()];
+        let query = Query { expression: &expression, host_ids: &host_ids, path: "/", limit: 10, offset: 0 };
+        let mut paths: Vec<String> = store.search(&query).await.unwrap().into_iter().map(|h| h.file.path).collect();
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn crawls_index_text_files_and_follow_changes() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("src/util")).unwrap();
+        std::fs::write(root.join("README"), "A tool for parsing invoices").unwrap();
+        std::fs::write(root.join("src/util/parse.rs"), "fn parse_invoice() {}").unwrap();
+        std::fs::write(root.join("src/logo.png"), b"\x89PNG invoice").unwrap();
+        std::fs::write(root.join("src/data.txt"), b"invoice\0binary").unwrap();
+        std::fs::write(root.join("src/.upload.txt.0123abcd.part"), "invoice").unwrap();
+        std::os::unix::fs::symlink(root.join("README"), root.join("link")).unwrap();
+        std::fs::create_dir_all(root.join(".fm-trash/item-1")).unwrap();
+        std::fs::write(root.join(".fm-trash/item-1/invoice.txt"), "invoice").unwrap();
+
+        let store = IndexStore::open(&dir.path().join("index.db")).await.unwrap();
+        let base = canonical_base(root.to_str().unwrap()).unwrap();
+        let crawler = Crawler { store: &store, host_id: "host-1", base: &base, max_text_size: 1024, crawl: 1 };
+        crawler.index_tree("/").await.unwrap();
+
+        assert_eq!(paths(&store, "invoice").await, ["/README", "/src/util/parse.rs"]);
+        assert_eq!(paths(&store, "util").await, ["/src/util", "/src/util/parse.rs"]);
+        assert!(paths(&store, "link").await.is_empty());
+
+        std::fs::rename(root.join("src/util"), root.join("src/helpers")).unwrap();
+        std::fs::write(root.join("README"), "Nothing to see").unwrap();
+        for path in ["src/util", "src/helpers", "README"] {
+            crawler.refresh(&base.join(path)).await.unwrap();
+        }
+        assert_eq!(paths(&store, "invoice").await, ["/src/helpers/parse.rs"]);
+        assert!(paths(&store, "util").await.is_empty());
+
+        // A later crawl drops what went missing in between
+        std::fs::remove_dir_all(root.join("src/helpers")).unwrap();
+        let crawler = Crawler { crawl: 2, ..crawler };
+        crawler.index_tree("/").await.unwrap();
+        store.sweep("host-1", 2).await.unwrap();
+        assert!(paths(&store, "invoice").await.is_empty());
+        assert_eq!(paths(&store, "logo").await, ["/src/logo.png"]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Optional full-text index of local hosts, for searches too slow to run
+//! against the disk each time. Every local host is crawled into the index
+//! on startup and when it is added, kept up to date through inotify and
+//! crawled again now and then for whatever inotify missed.
+
+mod crawl;
+mod store;
+
+pub use store::{match_expression, Query};
+
+use anyhow::{Context, Result};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::models::{Host, HostType, IndexHit};
+use crawl::{canonical_base, Crawler};
+use store::IndexStore;
+
+/// How long changes are collected before the index is updated with them.
+const INDEX_DEBOUNCE: Duration = Duration::from_secs(1);
+/// Lower bound of `SEARCH_INDEX_RECRAWL_INTERVAL`, in seconds.
+const MIN_RECRAWL_INTERVAL: u64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// Where the index is kept; no index without one.
+    pub path: Option<PathBuf>,
+    /// Text files larger than this are indexed by name only.
+    pub max_text_size: u64,
+    /// Time between crawls of each host after the first.
+    pub recrawl_interval: Duration,
+}
+
+impl IndexConfig {
+    /// Reads `SEARCH_INDEX_PATH`, `SEARCH_INDEX_MAX_FILE_SIZE` and
+    /// `SEARCH_INDEX_RECRAWL_INTERVAL`, falling back to defaults.
+    pub fn from_env() -> Self {
+        fn env_u64(name: &str, default: u64) -> u64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            path: std::env::var("SEARCH_INDEX_PATH").ok().filter(|p| !p.is_empty()).map(PathBuf::from),
+            max_text_size: env_u64("SEARCH_INDEX_MAX_FILE_SIZE", 1024 * 1024),
+            recrawl_interval: Duration::from_secs(
+                env_u64("SEARCH_INDEX_RECRAWL_INTERVAL", 6 * 60 * 60).max(MIN_RECRAWL_INTERVAL),
+            ),
+        }
+    }
+}
+
+/// What the watcher of a host reports.
+enum Change {
+    Path(PathBuf),
+    /// Events were lost, so only a crawl can tell what changed.
+    Rescan,
+}
+
+/// The task keeping one host's part of the index up to date.
+struct HostIndex {
+    task: JoinHandle<()>,
+    crawling: Arc<AtomicBool>,
+}
+
+pub struct Indexer {
+    config: IndexConfig,
+    store: Option<Arc<IndexStore>>,
+    hosts: Mutex<HashMap<String, HostIndex>>,
+}
+
+impl Indexer {
+    /// Opens the index if `config` has a path for it.
+    pub async fn new(config: IndexConfig) -> Result<Self> {
+        let store = match &config.path {
+            Some(path) => Some(Arc::new(IndexStore::open(path).await?)),
+            None => None,
+        };
+        Ok(Self {
+            config,
+            store,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.store.is_some()
+    }
+
+    /// Starts indexing `hosts`, and drops hosts deleted since the index was
+    /// last used.
+    pub async fn start(&self, hosts: &[Host]) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let local: Vec<String> = hosts.iter().filter(|h| is_indexed(h)).map(|h| h.id.clone()).collect();
+        store.retain_hosts(&local).await?;
+        for host in hosts {
+            self.add_host(host);
+        }
+        Ok(())
+    }
+
+    /// Starts indexing `host`, if it is a local host.
+    pub fn add_host(&self, host: &Host) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let Some(base_path) = host.config.path.clone().filter(|_| is_indexed(host)) else {
+            return;
+        };
+
+        let crawling = Arc::new(AtomicBool::new(true));
+        let task = tokio::spawn(index_host(
+            store.clone(),
+            self.config.clone(),
+            host.id.clone(),
+            base_path,
+            crawling.clone(),
+        ));
+        if let Some(previous) = self.hosts.lock().unwrap().insert(host.id.clone(), HostIndex { task, crawling }) {
+            previous.task.abort();
+        }
+    }
+
+    /// Stops indexing the host and forgets what was indexed.
+    pub async fn remove_host(&self, host_id: &str) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if let Some(host) = self.hosts.lock().unwrap().remove(host_id) {
+            host.task.abort();
+        }
+        store.remove(host_id, "/").await
+    }
+
+    /// Whether any of the hosts is still being crawled, so results may be
+    /// missing entries.
+    pub fn is_crawling(&self, host_ids: &[String]) -> bool {
+        let hosts = self.hosts.lock().unwrap();
+        host_ids
+            .iter()
+            .filter_map(|id| hosts.get(id))
+            .any(|host| host.crawling.load(Ordering::Relaxed))
+    }
+
+    pub async fn search(&self, query: &Query<'_>) -> Result<Vec<IndexHit>> {
+        let store = self.store.as_ref().context("Search index is not enabled")?;
+        store.search(query).await
+    }
+}
+
+fn is_indexed(host: &Host) -> bool {
+    matches!(host.host_type, HostType::Local) && host.config.path.is_some()
+}
+
+/// Crawls the host right away and every `recrawl_interval`, and updates the
+/// index with changes reported in between. Runs until aborted.
+async fn index_host(
+    store: Arc<IndexStore>,
+    config: IndexConfig,
+    host_id: String,
+    base_path: String,
+    crawling: Arc<AtomicBool>,
+) {
+    let base = match canonical_base(&base_path) {
+        Ok(base) => base,
+        Err(e) => {
+            log::warn!("Not indexing host {}: {:#}", host_id, e);
+            crawling.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    // Watching starts before the first crawl, so nothing changed during it is missed
+    let (tx, mut changes) = mpsc::unbounded_channel();
+    let watcher = match watch(&base, tx) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            log::warn!("Changes to host {} are only indexed when it is crawled: {:#}", host_id, e);
+            None
+        }
+    };
+
+    let mut crawl = match store.last_crawl(&host_id).await {
+        Ok(crawl) => crawl,
+        Err(e) => {
+            log::warn!("Not indexing host {}: {:#}", host_id, e);
+            crawling.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+    let mut recrawl = tokio::time::interval(config.recrawl_interval);
+    let watching = watcher.is_some();
+
+    loop {
+        let crawler = Crawler {
+            store: &store,
+            host_id: &host_id,
+            base: &base,
+            max_text_size: config.max_text_size,
+            crawl,
+        };
+        let rescan = tokio::select! {
+            _ = recrawl.tick() => true,
+            Some(change) = changes.recv(), if watching => apply_changes(&crawler, change, &mut changes).await,
+        };
+        if !rescan {
+            continue;
+        }
+
+        crawling.store(true, Ordering::Relaxed);
+        let crawler = Crawler { crawl: crawl + 1, ..crawler };
+        let crawled = async {
+            crawler.index_tree("/").await?;
+            store.sweep(&host_id, crawler.crawl).await
+        };
+        match crawled.await {
+            Ok(()) => crawl += 1,
+            Err(e) => log::warn!("Failed to index host {}: {:#}", host_id, e),
+        }
+        crawling.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Updates the index with `first` and the changes reported with it, and
+/// tells whether a crawl is needed instead.
+async fn apply_changes(crawler: &Crawler<'_>, first: Change, changes: &mut mpsc::UnboundedReceiver<Change>) -> bool {
+    tokio::time::sleep(INDEX_DEBOUNCE).await;
+
+    // Sorted, so a new directory is indexed before what's inside it
+    let mut paths = BTreeSet::new();
+    for change in std::iter::once(first).chain(std::iter::from_fn(|| changes.try_recv().ok())) {
+        match change {
+            Change::Path(path) => {
+                paths.insert(path);
+            }
+            Change::Rescan => return true,
+        }
+    }
+
+    for path in paths {
+        if let Err(e) = crawler.refresh(&path).await {
+            log::warn!("Failed to index {} on host {}: {:#}", path.display(), crawler.host_id, e);
+        }
+    }
+    false
+}
+
+fn watch(base: &Path, changes: mpsc::UnboundedSender<Change>) -> Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) if event.need_rescan() => {
+            let _ = changes.send(Change::Rescan);
+        }
+        Ok(event) => {
+            for path in event.paths {
+                let _ = changes.send(Change::Path(path));
+            }
+        }
+        Err(e) => {
+            log::warn!("Index watch failed: {}", e);
+            let _ = changes.send(Change::Rescan);
+        }
+    })?;
+    watcher
+        .watch(base, RecursiveMode::Recursive)
+        .context("Failed to watch base path")?;
+    Ok(watcher)
+}
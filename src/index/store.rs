@@ -0,0 +1,431 @@
+//! The index itself: an SQLite database of its own, holding every indexed
+//! entry and an FTS5 table over their names, paths and text.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow};
+use sqlx::{Row, SqlitePool};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::models::{FileInfo, IndexHit};
+
+/// Search terms looked at; the rest of a longer query is ignored.
+const MAX_QUERY_TERMS: usize = 32;
+/// Tokens around a content match shown in its snippet.
+const SNIPPET_TOKENS: u32 = 24;
+/// Relative weight of matches in the name, path and text columns.
+const RANK: &str = "bm25(entries_text, 10.0, 4.0, 1.0)";
+/// Marks FTS5 puts around matched tokens, replaced by `<mark>` once the
+/// text around them is escaped. Indexed text never contains them.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// An entry as it goes into the index, with the text of text files.
+pub struct Document {
+    pub file: FileInfo,
+    pub text: Option<String>,
+}
+
+/// What to look for, and where.
+pub struct Query<'a> {
+    /// An FTS5 expression from `match_expression`.
+    pub expression: &'a str,
+    pub host_ids: &'a [String],
+    /// Only entries at or below this path, unless it is `/`.
+    pub path: &'a str,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+pub struct IndexStore {
+    pool: SqlitePool,
+}
+
+impl IndexStore {
+    /// Opens the index at `path`, creating it if needed.
+    pub async fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory for search index at {:?}", parent))?;
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await
+            .context("Failed to open search index")?;
+
+        // `crawl` is the last crawl that found the entry, so whatever an
+        // earlier crawl left behind can be swept once a new one is done
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS entries (
+                id INTEGER PRIMARY KEY,
+                host_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                is_dir INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                modified TEXT,
+                crawl INTEGER NOT NULL,
+                UNIQUE (host_id, path)
+            );
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to create search index entries table")?;
+
+        // Rows share their rowid with `entries`
+        sqlx::query(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS entries_text USING fts5(name, path, content, tokenize = 'unicode61 remove_diacritics 2')",
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to create search index text table")?;
+
+        Ok(Self { pool })
+    }
+
+    /// The number of the last crawl of `host_id`, 0 if it was never crawled.
+    pub async fn last_crawl(&self, host_id: &str) -> Result<i64> {
+        sqlx::query_scalar("SELECT COALESCE(MAX(crawl), 0) FROM entries WHERE host_id = ?")
+            .bind(host_id)
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to query search index")
+    }
+
+    /// Marks an entry as found by `crawl` if it is indexed with the same size
+    /// and modification time, and tells whether it was.
+    pub async fn keep_if_unchanged(&self, host_id: &str, file: &FileInfo, crawl: i64) -> Result<bool> {
+        let kept = sqlx::query(
+            "UPDATE entries SET crawl = ? WHERE host_id = ? AND path = ? AND is_dir = ? AND size = ? AND modified IS ?",
+        )
+        .bind(crawl)
+        .bind(host_id)
+        .bind(&file.path)
+        .bind(file.is_dir)
+        .bind(file.size as i64)
+        .bind(file.modified.map(|m| m.to_rfc3339()))
+        .execute(&self.pool)
+        .await
+        .context("Failed to update search index")?;
+
+        Ok(kept.rows_affected() > 0)
+    }
+
+    pub async fn contains(&self, host_id: &str, path: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM entries WHERE host_id = ? AND path = ?")
+            .bind(host_id)
+            .bind(path)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query search index")?;
+
+        Ok(row.is_some())
+    }
+
+    /// Adds or replaces entries, in one transaction.
+    pub async fn put(&self, host_id: &str, documents: &[Document], crawl: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("Failed to update search index")?;
+
+        for doc in documents {
+            let id: i64 = sqlx::query_scalar(
+                r#"
+                INSERT INTO entries (host_id, path, is_dir, size, modified, crawl)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (host_id, path) DO UPDATE SET
+                    is_dir = excluded.is_dir,
+                    size = excluded.size,
+                    modified = excluded.modified,
+                    crawl = excluded.crawl
+                RETURNING id
+            "#,
+            )
+            .bind(host_id)
+            .bind(&doc.file.path)
+            .bind(doc.file.is_dir)
+            .bind(doc.file.size as i64)
+            .bind(doc.file.modified.map(|m| m.to_rfc3339()))
+            .bind(crawl)
+            .fetch_one(&mut *tx)
+            .await
+            .context("Failed to update search index")?;
+
+            sqlx::query("DELETE FROM entries_text WHERE rowid = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to update search index")?;
+            sqlx::query("INSERT INTO entries_text (rowid, name, path, content) VALUES (?, ?, ?, ?)")
+                .bind(id)
+                .bind(unmarked(&doc.file.name))
+                .bind(unmarked(&doc.file.path))
+                .bind(doc.text.as_deref().map(unmarked))
+                .execute(&mut *tx)
+                .await
+                .context("Failed to update search index")?;
+        }
+
+        tx.commit().await.context("Failed to update search index")
+    }
+
+    /// Removes `path` and everything below it; `/` removes the whole host.
+    pub async fn remove(&self, host_id: &str, path: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("Failed to update search index")?;
+        for sql in deletions("host_id = ? AND (? = '/' OR path = ? OR (path > ? || '/' AND path < ? || '0'))") {
+            sqlx::query(&sql)
+                .bind(host_id)
+                .bind(path)
+                .bind(path)
+                .bind(path)
+                .bind(path)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to update search index")?;
+        }
+        tx.commit().await.context("Failed to update search index")
+    }
+
+    /// Removes the entries of `host_id` that `crawl` didn't find.
+    pub async fn sweep(&self, host_id: &str, crawl: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("Failed to update search index")?;
+        for sql in deletions("host_id = ? AND crawl < ?") {
+            sqlx::query(&sql)
+                .bind(host_id)
+                .bind(crawl)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to update search index")?;
+        }
+        tx.commit().await.context("Failed to update search index")
+    }
+
+    /// Removes every host but `host_ids`.
+    pub async fn retain_hosts(&self, host_ids: &[String]) -> Result<()> {
+        let host_ids = serde_json::to_string(host_ids)?;
+        let mut tx = self.pool.begin().await.context("Failed to update search index")?;
+        for sql in deletions("host_id NOT IN (SELECT value FROM json_each(?))") {
+            sqlx::query(&sql)
+                .bind(&host_ids)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to update search index")?;
+        }
+        tx.commit().await.context("Failed to update search index")
+    }
+
+    /// The best matches first, with their matched terms highlighted.
+    pub async fn search(&self, query: &Query<'_>) -> Result<Vec<IndexHit>> {
+        let host_ids = serde_json::to_string(query.host_ids)?;
+        let sql = format!(
+            r#"
+            SELECT e.host_id, e.path, e.is_dir, e.size, e.modified, {rank} AS rank,
+                highlight(entries_text, 0, char(2), char(3)) AS name_highlight,
+                highlight(entries_text, 1, char(2), char(3)) AS path_highlight,
+                snippet(entries_text, 2, char(2), char(3), '…', {tokens}) AS snippet
+            FROM entries_text JOIN entries e ON e.id = entries_text.rowid
+            WHERE entries_text MATCH ?
+                AND e.host_id IN (SELECT value FROM json_each(?))
+                AND (? = '/' OR e.path = ? OR (e.path > ? || '/' AND e.path < ? || '0'))
+            ORDER BY rank
+            LIMIT ? OFFSET ?
+        "#,
+            rank = RANK,
+            tokens = SNIPPET_TOKENS,
+        );
+
+        let rows = sqlx::query(&sql)
+            .bind(query.expression)
+            .bind(host_ids)
+            .bind(query.path)
+            .bind(query.path)
+            .bind(query.path)
+            .bind(query.path)
+            .bind(query.limit)
+            .bind(query.offset)
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to search index")?;
+
+        rows.iter().map(hit_from_row).collect()
+    }
+}
+
+/// Statements deleting the entries matching `condition`, text first.
+fn deletions(condition: &str) -> [String; 2] {
+    [
+        format!("DELETE FROM entries_text WHERE rowid IN (SELECT id FROM entries WHERE {})", condition),
+        format!("DELETE FROM entries WHERE {}", condition),
+    ]
+}
+
+fn hit_from_row(r: &SqliteRow) -> Result<IndexHit> {
+    let path: String = r.try_get("path")?;
+    let modified: Option<String> = r.try_get("modified")?;
+    let modified = modified
+        .map(|m| DateTime::parse_from_rfc3339(&m).map(|dt| dt.with_timezone(&Utc)))
+        .transpose()
+        .context("Failed to parse indexed modification time")?;
+    let rank: f64 = r.try_get("rank")?;
+    let snippet: Option<String> = r.try_get("snippet")?;
+
+    Ok(IndexHit {
+        host_id: r.try_get("host_id")?,
+        file: FileInfo {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            is_dir: r.try_get("is_dir")?,
+            size: r.try_get::<i64, _>("size")? as u64,
+            modified,
+        },
+        // bm25 is lower for better matches
+        score: -rank,
+        name_highlight: highlighted(&r.try_get::<String, _>("name_highlight")?),
+        path_highlight: highlighted(&r.try_get::<String, _>("path_highlight")?),
+        // Without a match in the text, FTS5 still returns its beginning
+        snippet: snippet.filter(|s| s.contains(MATCH_START)).map(|s| highlighted(&s)),
+    })
+}
+
+/// Turns a query as users type it into an FTS5 expression: every word must
+/// appear, as a prefix, so `rep` finds `report.pdf`. Words that span
+/// several tokens, like `main.rs`, match those tokens in a row. `None` if
+/// there is nothing to search for.
+pub fn match_expression(query: &str) -> Option<String> {
+    let phrases: Vec<String> = query
+        .split_whitespace()
+        .filter_map(|word| {
+            let tokens: Vec<&str> = word.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()).collect();
+            (!tokens.is_empty()).then(|| format!("\"{}\"*", tokens.join(" ")))
+        })
+        .take(MAX_QUERY_TERMS)
+        .collect();
+
+    (!phrases.is_empty()).then(|| phrases.join(" "))
+}
+
+/// Escapes `text` for HTML and wraps its matches in `<mark>`.
+fn highlighted(text: &str) -> String {
+    let mut html = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+fn unmarked(text: &str) -> String {
+    text.replace([MATCH_START, MATCH_END], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn document(path: &str, text: Option<&str>) -> Document {
+        Document {
+            file: FileInfo {
+                name: path.rsplit('/').next().unwrap().to_string(),
+                path: path.to_string(),
+                is_dir: text.is_none() && !path.contains('.'),
+                size: text.map_or(0, |t| t.len() as u64),
+                modified: None,
+            },
+            text: text.map(str::to_string),
+        }
+    }
+
+    async fn search(store: &IndexStore, query: &str, path: &str) -> Vec<IndexHit> {
+        let expression = match_expression(query).unwrap();
+        let host_ids = ["host-1".to_string()];
+        let query = Query { expression: &expression, host_ids: &host_ids, path, limit: 10, offset: 0 };
+        store.search(&query).await.unwrap()
+    }
+
+    #[test]
+    fn queries_become_prefix_phrases() {
+        assert_eq!(match_expression("quarterly rep").unwrap(), r#""quarterly"* "rep"*"#);
+        assert_eq!(match_expression("main.rs \"OR\" NEAR(").unwrap(), r#""main rs"* "OR"* "NEAR"*"#);
+        assert_eq!(match_expression("  ./ -- "), None);
+    }
+
+    #[tokio::test]
+    async fn names_rank_above_text_and_matches_are_highlighted() {
+        let dir = TempDir::new().unwrap();
+        let store = IndexStore::open(&dir.path().join("index.db")).await.unwrap();
+        store
+            .put(
+                "host-1",
+                &[
+                    document("/docs", None),
+                    document("/docs/notes.md", Some("Ideas for the <budget> report, due in May")),
+                    document("/docs/budget.xlsx", Some("")),
+                    document("/archive/budget-2019.txt", Some("old")),
+                ],
+                1,
+            )
+            .await
+            .unwrap();
+        store.put("host-2", &[document("/budget.txt", Some("budget"))], 1).await.unwrap();
+
+        let hits = search(&store, "budg", "/").await;
+        let paths: Vec<&str> = hits.iter().map(|h| h.file.path.as_str()).collect();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[2], "/docs/notes.md");
+        let sheet = hits.iter().find(|h| h.file.name == "budget.xlsx").unwrap();
+        assert_eq!(sheet.name_highlight, "<mark>budget</mark>.xlsx");
+        assert_eq!(sheet.path_highlight, "/docs/<mark>budget</mark>.xlsx");
+        assert_eq!(sheet.snippet, None);
+        assert_eq!(hits[2].snippet.as_deref(), Some("Ideas for the &lt;<mark>budget</mark>&gt; report, due in May"));
+
+        let hits = search(&store, "budget", "/docs").await;
+        assert_eq!(hits.len(), 2);
+        assert!(search(&store, "budget", "/doc").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removals_and_sweeps_take_whole_subtrees() {
+        let dir = TempDir::new().unwrap();
+        let store = IndexStore::open(&dir.path().join("index.db")).await.unwrap();
+        let docs = [
+            document("/share", None),
+            document("/share/a.txt", Some("alpha")),
+            document("/share/sub/b.txt", Some("alpha")),
+            document("/shared.txt", Some("alpha")),
+        ];
+        store.put("host-1", &docs, 1).await.unwrap();
+        assert!(store.keep_if_unchanged("host-1", &docs[1].file, 2).await.unwrap());
+        assert!(store.keep_if_unchanged("host-1", &docs[3].file, 2).await.unwrap());
+        let changed = FileInfo { size: 99, ..docs[2].file.clone() };
+        assert!(!store.keep_if_unchanged("host-1", &changed, 2).await.unwrap());
+
+        store.sweep("host-1", 2).await.unwrap();
+        assert_eq!(store.last_crawl("host-1").await.unwrap(), 2);
+        assert!(!store.contains("host-1", "/share/sub/b.txt").await.unwrap());
+        assert_eq!(search(&store, "alpha", "/").await.len(), 2);
+
+        store.remove("host-1", "/share").await.unwrap();
+        let hits = search(&store, "alpha", "/").await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].file.path, "/shared.txt");
+
+        store.remove("host-1", "/").await.unwrap();
+        assert_eq!(store.last_crawl("host-1").await.unwrap(), 0);
+    }
+}
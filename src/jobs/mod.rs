@@ -0,0 +1,435 @@
+//! Background execution of long file operations. Jobs are persisted in the
+//! `jobs` table, started oldest first by a bounded set of workers, and put
+//! back in the queue when the server stops while they run.
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Notify};
+
+use crate::auth::Encryptor;
+use crate::db::Database;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::transfer::{self, TransferProgress};
+use crate::hosts::{self, archive, StorageBackend};
+use crate::models::{Job, JobEvent, JobKind, JobStatus, JobUpdate, ProgressSnapshot};
+use crate::trash::TrashManager;
+
+/// Queued jobs are also picked up on this interval, in case a wake-up was missed.
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a running job's progress is written to the database.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Events a slow subscriber may fall behind by before it starts missing some.
+const EVENT_BUFFER: usize = 256;
+/// Weight of the latest tick in the smoothed transfer rate.
+const RATE_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// Jobs running at once across all hosts.
+    pub workers: usize,
+    /// Jobs running at once that touch the same host, as source or target.
+    pub per_host: usize,
+}
+
+impl JobConfig {
+    /// Reads `JOB_WORKERS` and `JOB_HOST_CONCURRENCY`, falling back to defaults.
+    pub fn from_env() -> Self {
+        fn env_usize(name: &str, default: usize) -> usize {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            workers: env_usize("JOB_WORKERS", 4),
+            per_host: env_usize("JOB_HOST_CONCURRENCY", 2),
+        }
+    }
+}
+
+struct RunningJob {
+    hosts: Vec<String>,
+    progress: Arc<TransferProgress>,
+}
+
+pub struct JobManager {
+    db: Arc<Database>,
+    sftp_pool: Arc<SftpPool>,
+    trash: Arc<TrashManager>,
+    config: JobConfig,
+    running: Mutex<HashMap<String, RunningJob>>,
+    wake: Notify,
+    events: broadcast::Sender<JobEvent>,
+}
+
+impl JobManager {
+    pub fn new(db: Arc<Database>, sftp_pool: Arc<SftpPool>, trash: Arc<TrashManager>, config: JobConfig) -> Self {
+        Self {
+            db,
+            sftp_pool,
+            trash,
+            config,
+            running: Mutex::new(HashMap::new()),
+            wake: Notify::new(),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    /// Requeues jobs interrupted by a restart and starts the dispatcher.
+    pub fn spawn(self: &Arc<Self>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            match manager.db.requeue_interrupted_jobs().await {
+                Ok(0) => {}
+                Ok(count) => info!("Requeued {} job(s) interrupted by a restart", count),
+                Err(e) => error!("Failed to requeue interrupted jobs: {}", e),
+            }
+
+            loop {
+                if let Err(e) = manager.dispatch().await {
+                    error!("Failed to dispatch jobs: {}", e);
+                }
+                tokio::select! {
+                    _ = manager.wake.notified() => {}
+                    _ = tokio::time::sleep(DISPATCH_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Tells the dispatcher a job was queued.
+    pub fn notify(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Events of every user's jobs, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, job: &Job, update: JobUpdate) {
+        // Nobody listening is fine
+        let _ = self.events.send(JobEvent {
+            user_id: job.user_id.clone(),
+            job_id: job.id.clone(),
+            kind: job.kind,
+            update,
+        });
+    }
+
+    /// Live progress of a running job, fresher than what the database holds.
+    pub fn progress(&self, job_id: &str) -> Option<ProgressSnapshot> {
+        let running = self.running.lock().unwrap();
+        running.get(job_id).map(|job| job.progress.snapshot())
+    }
+
+    /// Cancels a queued job, or asks a running one to stop. Returns false if
+    /// the job is neither.
+    pub async fn cancel(&self, job: &Job) -> Result<bool> {
+        if self.db.cancel_queued_job(&job.id).await? {
+            self.publish(job, JobUpdate::Cancelled);
+            return Ok(true);
+        }
+
+        let running = self.running.lock().unwrap();
+        match running.get(&job.id) {
+            Some(job) => {
+                job.progress.cancel();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Starts queued jobs, oldest first, while workers are free and their
+    /// hosts are below the per-host limit.
+    async fn dispatch(self: &Arc<Self>) -> Result<()> {
+        for job in self.db.get_queued_jobs().await? {
+            let hosts = job_hosts(&job);
+            {
+                let running = self.running.lock().unwrap();
+                if running.len() >= self.config.workers {
+                    break;
+                }
+                let busy = |host: &String| running.values().filter(|r| r.hosts.contains(host)).count();
+                if hosts.iter().any(|host| busy(host) >= self.config.per_host) {
+                    continue;
+                }
+            }
+
+            // Registered before it is marked running, so a cancel that no
+            // longer finds the job queued always finds it here
+            let progress = Arc::new(TransferProgress::default());
+            self.running.lock().unwrap().insert(
+                job.id.clone(),
+                RunningJob { hosts, progress: progress.clone() },
+            );
+
+            // Skips jobs cancelled since they were listed
+            let started = self.db.start_job(&job.id).await;
+            if !matches!(started, Ok(true)) {
+                self.running.lock().unwrap().remove(&job.id);
+                started?;
+                continue;
+            }
+
+            let manager = self.clone();
+            tokio::spawn(async move { manager.run(job, progress).await });
+        }
+
+        Ok(())
+    }
+
+    async fn run(self: Arc<Self>, job: Job, progress: Arc<TransferProgress>) {
+        let execution = self.execute(&job, &progress);
+        tokio::pin!(execution);
+
+        let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut meter = RateMeter::default();
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut execution => break outcome,
+                _ = ticker.tick() => {
+                    let snapshot = self.save_progress(&job.id, &progress).await;
+                    self.publish(&job, meter.update(snapshot, Instant::now()));
+                }
+            }
+        };
+        self.save_progress(&job.id, &progress).await;
+
+        let (status, result_path, error) = match outcome {
+            Ok(path) => (JobStatus::Completed, path, None),
+            Err(_) if progress.is_cancelled() => (JobStatus::Cancelled, None, None),
+            Err(e) => {
+                warn!("Job {} failed: {:#}", job.id, e);
+                (JobStatus::Failed, None, Some(e.to_string()))
+            }
+        };
+        if let Err(e) = self.db.finish_job(&job.id, status, result_path.as_deref(), error.as_deref()).await {
+            error!("Failed to record outcome of job {}: {}", job.id, e);
+        }
+
+        let update = match (status, error) {
+            (JobStatus::Cancelled, _) => JobUpdate::Cancelled,
+            (_, Some(error)) => JobUpdate::Failed { error },
+            _ => JobUpdate::Completed { result_path },
+        };
+        self.publish(&job, update);
+
+        self.running.lock().unwrap().remove(&job.id);
+        self.wake.notify_one();
+    }
+
+    async fn save_progress(&self, job_id: &str, progress: &TransferProgress) -> ProgressSnapshot {
+        let snapshot = progress.snapshot();
+        if let Err(e) = self.db.set_job_progress(job_id, &snapshot).await {
+            warn!("Failed to save progress of job {}: {}", job_id, e);
+        }
+        snapshot
+    }
+
+    /// Runs the operation and returns where its result ended up.
+    async fn execute(&self, job: &Job, progress: &Arc<TransferProgress>) -> Result<Option<String>> {
+        // Cancelled between being registered and marked running
+        if progress.is_cancelled() {
+            anyhow::bail!("Transfer cancelled");
+        }
+        let host: Arc<dyn StorageBackend> = self.open_host(&job.host_id).await?.into();
+
+        if job.kind == JobKind::Delete {
+            // Hosts that can't move entries have no trash to move them into
+            if job.permanent || !host.capabilities().rename {
+                host.delete(&job.path).await?;
+            } else {
+                self.trash.trash(&job.user_id, &job.host_id, host.as_ref(), &job.path).await?;
+            }
+            return Ok(None);
+        }
+
+        let to = job.target_path.as_deref().context("Job has no target path")?;
+        let target_host = job.target_host_id.as_deref().filter(|id| *id != job.host_id);
+        let target = match target_host {
+            Some(id) => Some(self.open_host(id).await?),
+            None => None,
+        };
+        let same_host = target.is_none();
+        let (source, target) = (host.as_ref(), target.as_deref().unwrap_or(host.as_ref()));
+
+        // Left behind by an attempt that was interrupted by a restart
+        if let Some(partial) = &job.partial_path {
+            if target.stat(partial).await.is_ok() {
+                info!("Removing partial copy {} of job {}", partial, job.id);
+                target.delete(partial).await?;
+            }
+            self.db.set_job_partial_path(&job.id, None).await?;
+        }
+
+        let target_host_id = target_host.unwrap_or(&job.host_id);
+        let displace = |to: String| async move { self.trash.displace(&job.user_id, target_host_id, target, &to).await };
+        let path = hosts::apply_overwrite_policy(to, job.overwrite, displace, |to| {
+            let host = host.clone();
+            async move {
+                if job.kind == JobKind::Move && same_host {
+                    return source.rename(&job.path, &to).await;
+                }
+
+                transfer::ensure_vacant(target, &to).await?;
+                if job.kind == JobKind::Compress {
+                    // Already written under a staging name that a failure removes
+                    return archive::compress(host, &job.sources, &to, progress).await;
+                }
+
+                let staging = transfer::staging_name(&to);
+                self.db.set_job_partial_path(&job.id, Some(&staging)).await?;
+                let written = match job.kind {
+                    JobKind::Extract => archive::extract(source, &job.path, &staging, progress).await,
+                    _ if same_host => transfer::copy_within(source, &job.path, &staging, progress).await,
+                    _ => transfer::copy_between(source, &job.path, target, &staging, progress).await,
+                };
+                let done = transfer::commit_staged(target, &staging, &to, written).await;
+                self.db.set_job_partial_path(&job.id, None).await?;
+                done
+            }
+        })
+        .await?;
+
+        // The copy is complete, so losing the source is safe from here on
+        if job.kind == JobKind::Move && !same_host {
+            source.delete(&job.path).await?;
+        }
+
+        Ok(Some(path))
+    }
+
+    async fn open_host(&self, host_id: &str) -> Result<Box<dyn StorageBackend>> {
+        let mut host = self
+            .db
+            .get_host(host_id)
+            .await?
+            .with_context(|| format!("Host {} no longer exists", host_id))?;
+        hosts::ensure_host_key(&mut host, &self.db).await?;
+
+        let encryptor = Encryptor::new()?;
+        hosts::open(&host, &encryptor, &self.sftp_pool)
+    }
+}
+
+/// Turns the progress seen on each tick into a rate and time left.
+#[derive(Default)]
+struct RateMeter {
+    last: Option<(Instant, u64)>,
+    rate: Option<f64>,
+}
+
+impl RateMeter {
+    fn update(&mut self, progress: ProgressSnapshot, now: Instant) -> JobUpdate {
+        match self.last {
+            // A copy that restarts counts from zero again
+            Some((_, bytes)) if progress.bytes_done < bytes => self.rate = None,
+            Some((at, bytes)) => {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                if elapsed > 0.0 {
+                    let sample = (progress.bytes_done - bytes) as f64 / elapsed;
+                    self.rate = Some(match self.rate {
+                        Some(rate) => rate + RATE_SMOOTHING * (sample - rate),
+                        None => sample,
+                    });
+                }
+            }
+            None => {}
+        }
+        self.last = Some((now, progress.bytes_done));
+
+        let rate = self.rate.unwrap_or(0.0);
+        let remaining = progress.bytes_total.saturating_sub(progress.bytes_done);
+        JobUpdate::Progress {
+            progress,
+            rate: rate as u64,
+            eta_seconds: (rate >= 1.0).then(|| (remaining as f64 / rate).ceil() as u64),
+        }
+    }
+}
+
+/// Hosts a job reads from or writes to.
+fn job_hosts(job: &Job) -> Vec<String> {
+    let mut hosts = vec![job.host_id.clone()];
+    if let Some(target) = &job.target_host_id {
+        if *target != job.host_id {
+            hosts.push(target.clone());
+        }
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OverwritePolicy;
+    use chrono::Utc;
+
+    fn job(kind: JobKind, target_host_id: Option<&str>) -> Job {
+        Job {
+            id: "job-1".to_string(),
+            user_id: "user-1".to_string(),
+            kind,
+            host_id: "host-a".to_string(),
+            path: "/share".to_string(),
+            target_host_id: target_host_id.map(str::to_string),
+            target_path: target_host_id.map(|_| "/backup".to_string()),
+            sources: Vec::new(),
+            overwrite: OverwritePolicy::Fail,
+            permanent: false,
+            status: JobStatus::Queued,
+            progress: ProgressSnapshot::default(),
+            result_path: None,
+            error: None,
+            attempts: 0,
+            partial_path: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn jobs_count_against_every_host_they_touch() {
+        assert_eq!(job_hosts(&job(JobKind::Delete, None)), ["host-a"]);
+        assert_eq!(job_hosts(&job(JobKind::Move, Some("host-a"))), ["host-a"]);
+        assert_eq!(job_hosts(&job(JobKind::Copy, Some("host-b"))), ["host-a", "host-b"]);
+    }
+
+    #[test]
+    fn rate_and_eta_follow_progress() {
+        let progress = |bytes_done| ProgressSnapshot { bytes_total: 10_000, bytes_done, files_total: 1, files_done: 0 };
+        let start = Instant::now();
+        let mut meter = RateMeter::default();
+
+        let JobUpdate::Progress { rate, eta_seconds, .. } = meter.update(progress(0), start) else { panic!() };
+        assert_eq!((rate, eta_seconds), (0, None));
+
+        let JobUpdate::Progress { rate, eta_seconds, .. } = meter.update(progress(1_000), start + Duration::from_secs(1)) else { panic!() };
+        assert_eq!((rate, eta_seconds), (1_000, Some(9)));
+
+        // Smoothed towards the faster tick rather than jumping to it
+        let JobUpdate::Progress { rate, eta_seconds, .. } = meter.update(progress(3_000), start + Duration::from_secs(2)) else { panic!() };
+        assert_eq!((rate, eta_seconds), (1_300, Some(6)));
+    }
+
+    #[test]
+    fn events_are_flat_json() {
+        let event = JobEvent {
+            user_id: "user-1".to_string(),
+            job_id: "job-1".to_string(),
+            kind: JobKind::Copy,
+            update: JobUpdate::Failed { error: "Disk full".to_string() },
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"event": "job_failed", "job_id": "job-1", "kind": "copy", "error": "Disk full"})
+        );
+    }
+}
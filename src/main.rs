@@ -1,3 +1,4 @@
+use actix::Actor;
 use actix_web::{web, App, HttpServer, middleware};
 use actix_cors::Cors;
 use std::sync::Arc;
@@ -7,8 +8,12 @@ mod api;
 mod auth;
 mod db;
 mod hosts;
+mod index;
+mod jobs;
 mod models;
 mod metrics;
+mod trash;
+mod watch;
 mod ws;
 
 use rust_embed::RustEmbed;
@@ -55,6 +60,53 @@ async fn main() -> std::io::Result<()> {
     // Initialize metrics
     let metrics = Arc::new(metrics::Metrics::new());
 
+    // Shared SFTP sessions
+    let sftp_pool = Arc::new(hosts::pool::SftpPool::new(
+        hosts::pool::PoolConfig::from_env(),
+        &metrics,
+    ));
+    sftp_pool.spawn_maintenance();
+
+    // Deleted files, kept until restored or past their retention period
+    let trash = Arc::new(trash::TrashManager::new(
+        db.clone(),
+        sftp_pool.clone(),
+        trash::TrashConfig::from_env(),
+    ));
+    trash.spawn();
+
+    // Background jobs
+    let jobs = Arc::new(jobs::JobManager::new(
+        db.clone(),
+        sftp_pool.clone(),
+        trash.clone(),
+        jobs::JobConfig::from_env(),
+    ));
+    jobs.spawn();
+
+    // Directory change notifications
+    let watches = Arc::new(
+        watch::WatchManager::new(watch::WatchConfig::from_env(), sftp_pool.clone())
+            .expect("Failed to initialize directory watching"),
+    );
+    watches.spawn();
+
+    // Full-text index of local hosts, if enabled
+    let indexer = Arc::new(
+        index::Indexer::new(index::IndexConfig::from_env())
+            .await
+            .expect("Failed to open search index"),
+    );
+    let hosts = db.get_all_hosts().await.expect("Failed to load hosts");
+    indexer.start(&hosts).await.expect("Failed to start indexing");
+
+    // Delivers events to the WebSocket sessions of the users they belong to
+    let ws_hub = ws::Hub::new(jobs.subscribe(), watches.subscribe()).start();
+
+    let upload_config = api::UploadConfig::from_env();
+    let active_uploads = Arc::new(api::ActiveUploads::default());
+    api::spawn_upload_expiry(db.clone(), upload_config.clone(), active_uploads.clone());
+
     let host = std::env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let bind_addr = format!("{}:{}", host, port);
@@ -66,6 +118,7 @@ async fn main() -> std::io::Result<()> {
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
+            .expose_any_header()
             .supports_credentials();
 
         App::new()
@@ -73,6 +126,14 @@ async fn main() -> std::io::Result<()> {
             .wrap(cors)
             .app_data(web::Data::new(db.clone()))
             .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(sftp_pool.clone()))
+            .app_data(web::Data::new(upload_config.clone()))
+            .app_data(web::Data::new(active_uploads.clone()))
+            .app_data(web::Data::new(jobs.clone()))
+            .app_data(web::Data::new(watches.clone()))
+            .app_data(web::Data::new(ws_hub.clone()))
+            .app_data(web::Data::new(indexer.clone()))
+            .app_data(web::Data::new(trash.clone()))
             .service(
                 web::scope("/api")
                     .configure(api::configure)
@@ -1,4 +1,4 @@
-use prometheus::{Encoder, TextEncoder, Registry, Counter, Histogram, HistogramOpts, Opts};
+use prometheus::{Encoder, TextEncoder, Registry, Counter, Histogram, HistogramOpts, IntGauge, Opts};
 use actix_web::{web, HttpResponse};
 use std::sync::Arc;
 use anyhow::Result;
@@ -9,6 +9,8 @@ pub struct Metrics {
     pub request_duration: Histogram,
     pub file_uploads: Counter,
     pub file_downloads: Counter,
+    pub sftp_sessions_open: IntGauge,
+    pub sftp_sessions_idle: IntGauge,
 }
 
 impl Metrics {
@@ -35,12 +37,24 @@ impl Metrics {
         ).unwrap();
         registry.register(Box::new(file_downloads.clone())).unwrap();
 
+        let sftp_sessions_open = IntGauge::with_opts(
+            Opts::new("sftp_sessions_open", "Number of open SFTP sessions, idle or in use")
+        ).unwrap();
+        registry.register(Box::new(sftp_sessions_open.clone())).unwrap();
+
+        let sftp_sessions_idle = IntGauge::with_opts(
+            Opts::new("sftp_sessions_idle", "Number of idle SFTP sessions waiting in the pool")
+        ).unwrap();
+        registry.register(Box::new(sftp_sessions_idle.clone())).unwrap();
+
         Self {
             registry,
             requests_total,
             request_duration,
             file_uploads,
             file_downloads,
+            sftp_sessions_open,
+            sftp_sessions_idle,
         }
     }
 
@@ -2,6 +2,7 @@ use chrono::{DateTime, Utc};
 use serde::de;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::str::FromStr;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +21,9 @@ pub struct Host {
     pub host_type: HostType,
     pub config: HostConfig,
     pub created_at: DateTime<Utc>,
+    /// SSH host key trusted for SFTP hosts, recorded on first connect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_key_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +35,43 @@ pub enum HostType {
     Http,
     #[serde(rename = "sftp")]
     Sftp,
+    #[serde(rename = "webdav")]
+    WebDav,
+    #[serde(rename = "s3")]
+    S3,
+    #[serde(rename = "ftp")]
+    Ftp,
+}
+
+impl HostType {
+    /// Identifier stored in the `hosts.host_type` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HostType::Local => "local",
+            HostType::Http => "http",
+            HostType::Sftp => "sftp",
+            HostType::WebDav => "webdav",
+            HostType::S3 => "s3",
+            HostType::Ftp => "ftp",
+        }
+    }
+
+}
+
+impl FromStr for HostType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "local" => Ok(HostType::Local),
+            "http" => Ok(HostType::Http),
+            "sftp" => Ok(HostType::Sftp),
+            "webdav" => Ok(HostType::WebDav),
+            "s3" => Ok(HostType::S3),
+            "ftp" => Ok(HostType::Ftp),
+            other => Err(format!("Unknown host type '{}'", other)),
+        }
+    }
 }
 
 fn deserialize_option_u16_from_string_or_number<'de, D>(
@@ -62,7 +103,7 @@ where
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct HostConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
@@ -80,6 +121,446 @@ pub struct HostConfig {
     pub username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub password_encrypted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_method: Option<SshAuthMethod>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_encrypted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase_encrypted: Option<String>,
+    /// S3 endpoint such as `http://localhost:9000` for MinIO; AWS when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    /// Key prefix the host is rooted at inside the bucket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Address the bucket as `endpoint/bucket` rather than `bucket.endpoint`.
+    /// Defaults to true for custom endpoints, which rarely have per-bucket DNS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_style: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key_encrypted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key_encrypted: Option<String>,
+    /// Use explicit TLS (`AUTH TLS`) for FTP hosts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    /// Seconds between listings of watched directories on SFTP and HTTP
+    /// hosts, which can't report changes themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval: Option<u64>,
+}
+
+/// How SFTP hosts authenticate. When unset, a stored private key is preferred
+/// over a password; the agent is only used when selected explicitly.
+///
+/// `Agent` talks to the agent of the fm server process (its `SSH_AUTH_SOCK`)
+/// and is only accepted when the operator enables it with `SFTP_AGENT_AUTH`;
+/// libssh2 offers no way to point a single connection at another socket, so
+/// there is no per-host agent setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SshAuthMethod {
+    Password,
+    PublicKey,
+    Agent,
+}
+
+/// Operations a backend supports, reported to clients alongside listings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    pub list: bool,
+    pub read: bool,
+    pub write: bool,
+    pub delete: bool,
+    pub mkdir: bool,
+    pub rename: bool,
+    /// Copies within the host natively. Any readable host can still be
+    /// copied from, with the data streaming through the server.
+    pub copy: bool,
+    /// Downloads honor `Range` requests.
+    pub ranges: bool,
+}
+
+/// Container a directory or selection of paths is downloaded as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// What a rename or copy does when its destination already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    /// Refuse with 409 Conflict.
+    #[default]
+    Fail,
+    /// Delete the existing destination first.
+    Replace,
+    /// Pick a free name such as `report (1).pdf`.
+    AutoSuffix,
+}
+
+impl OverwritePolicy {
+    /// Identifier stored in the `jobs.overwrite` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverwritePolicy::Fail => "fail",
+            OverwritePolicy::Replace => "replace",
+            OverwritePolicy::AutoSuffix => "auto_suffix",
+        }
+    }
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fail" => Ok(OverwritePolicy::Fail),
+            "replace" => Ok(OverwritePolicy::Replace),
+            "auto_suffix" => Ok(OverwritePolicy::AutoSuffix),
+            other => Err(format!("Unknown overwrite policy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Copy `path` to `target_path`, possibly on another host.
+    Copy,
+    /// Move `path` to `target_path`, possibly on another host.
+    Move,
+    /// Delete `path` and everything below it.
+    Delete,
+    /// Extract the archive at `path` into the new directory `target_path`.
+    Extract,
+    /// Archive `sources` into the new file `target_path` on the same host.
+    Compress,
+}
+
+impl JobKind {
+    /// Identifier stored in the `jobs.kind` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Copy => "copy",
+            JobKind::Move => "move",
+            JobKind::Delete => "delete",
+            JobKind::Extract => "extract",
+            JobKind::Compress => "compress",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "copy" => Ok(JobKind::Copy),
+            "move" => Ok(JobKind::Move),
+            "delete" => Ok(JobKind::Delete),
+            "extract" => Ok(JobKind::Extract),
+            "compress" => Ok(JobKind::Compress),
+            other => Err(format!("Unknown job kind '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Identifier stored in the `jobs.status` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether the job has stopped and can only be retried.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "completed" => Ok(JobStatus::Completed),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            other => Err(format!("Unknown job status '{}'", other)),
+        }
+    }
+}
+
+/// Bytes and files transferred so far by a copy or move.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ProgressSnapshot {
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    pub files_total: u64,
+    pub files_done: u64,
+}
+
+/// A long-running file operation executed in the background by the job workers.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub user_id: String,
+    pub kind: JobKind,
+    pub host_id: String,
+    pub path: String,
+    /// Destination host of a copy or move; the source host when absent.
+    pub target_host_id: Option<String>,
+    pub target_path: Option<String>,
+    /// Everything a compress job archives; `path` is the first of them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    pub overwrite: OverwritePolicy,
+    /// Whether a delete job deletes for good instead of moving to the trash.
+    pub permanent: bool,
+    pub status: JobStatus,
+    #[serde(flatten)]
+    pub progress: ProgressSnapshot,
+    /// Where a finished copy, move, extraction or archive ended up, after
+    /// applying `overwrite`.
+    pub result_path: Option<String>,
+    pub error: Option<String>,
+    /// Number of times the job has been started.
+    pub attempts: u32,
+    /// Staging name a copy was writing to when it was interrupted; removed
+    /// before the job runs again.
+    #[serde(skip)]
+    pub partial_path: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A deleted file or directory, kept in its host's trash area until it is
+/// restored or purged.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashItem {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub host_id: String,
+    /// Where the entry was, and goes back to when restored.
+    pub original_path: String,
+    /// Where the entry is kept meanwhile.
+    #[serde(skip)]
+    pub trash_path: String,
+    pub is_dir: bool,
+    /// Size of a file; 0 for directories.
+    pub size: u64,
+    pub deleted_at: DateTime<Utc>,
+    /// When the item is purged automatically; never without a retention period.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A change in a job's state, pushed to its owner's WebSocket sessions.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobEvent {
+    #[serde(skip)]
+    pub user_id: String,
+    pub job_id: String,
+    pub kind: JobKind,
+    #[serde(flatten)]
+    pub update: JobUpdate,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum JobUpdate {
+    #[serde(rename = "job_progress")]
+    Progress {
+        #[serde(flatten)]
+        progress: ProgressSnapshot,
+        /// Bytes per second, smoothed over the last few seconds.
+        rate: u64,
+        /// Seconds left at the current rate; absent while the rate is unknown.
+        eta_seconds: Option<u64>,
+    },
+    #[serde(rename = "job_completed")]
+    Completed { result_path: Option<String> },
+    #[serde(rename = "job_failed")]
+    Failed { error: String },
+    #[serde(rename = "job_cancelled")]
+    Cancelled,
+}
+
+/// What a search of a host looks for. Every filter given must match.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// Directory the search starts in; the root when absent.
+    pub path: Option<String>,
+    /// Glob the entry's name must match, e.g. `*.rs`.
+    pub name: Option<String>,
+    /// Regular expression the entry's name must match.
+    pub name_regex: Option<String>,
+    /// Match names and content case-sensitively; they are not by default.
+    pub case_sensitive: bool,
+    /// Size bounds in bytes; directories don't match when either is given.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Regular expression to look for in the lines of text files. Only
+    /// files with a matching line match then.
+    pub content: Option<String>,
+    /// Levels below `path` to descend into; 1 searches `path` alone.
+    pub max_depth: Option<usize>,
+    /// Matches after which the search stops.
+    pub limit: Option<usize>,
+}
+
+/// A line of a file that matched a content search.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineMatch {
+    /// Counted from 1.
+    pub line: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchMatch {
+    #[serde(flatten)]
+    pub file: FileInfo,
+    /// Matching lines of a content search, up to a handful.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lines: Vec<LineMatch>,
+}
+
+/// How a finished search went.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SearchSummary {
+    pub matches: u64,
+    /// Files and directories looked at.
+    pub scanned: u64,
+    /// Directories and files that couldn't be read.
+    pub skipped: u64,
+    /// Whether the search stopped at its `limit`.
+    pub truncated: bool,
+}
+
+/// What a running search reports, as NDJSON lines or WebSocket messages.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum SearchEvent {
+    #[serde(rename = "search_match")]
+    Match(SearchMatch),
+    #[serde(rename = "search_done")]
+    Done(SearchSummary),
+    #[serde(rename = "search_error")]
+    Error { error: String },
+}
+
+/// An entry found in the search index. Highlights are HTML, with matched
+/// terms in `<mark>` and everything else escaped.
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexHit {
+    pub host_id: String,
+    #[serde(flatten)]
+    pub file: FileInfo,
+    /// Higher is better; only comparable within one set of results.
+    pub score: f64,
+    pub name_highlight: String,
+    pub path_highlight: String,
+    /// The part of a text file around a match in its content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// A change to an entry of a watched directory, pushed to the WebSocket
+/// sessions watching it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangeEvent {
+    pub host_id: String,
+    /// The watched directory the entry is in.
+    pub directory: String,
+    #[serde(flatten)]
+    pub change: FileChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event")]
+pub enum FileChange {
+    #[serde(rename = "file_created")]
+    Created { path: String },
+    #[serde(rename = "file_modified")]
+    Modified { path: String },
+    #[serde(rename = "file_deleted")]
+    Deleted { path: String },
+    /// Only reported when both names are in watched directories; otherwise
+    /// the entry was deleted from one or created in the other.
+    #[serde(rename = "file_renamed")]
+    Renamed { from: String, to: String },
+}
+
+impl FileChange {
+    /// The path the entry has after the change.
+    pub fn path(&self) -> &str {
+        match self {
+            FileChange::Created { path } | FileChange::Modified { path } | FileChange::Deleted { path } => path,
+            FileChange::Renamed { to, .. } => to,
+        }
+    }
+}
+
+/// A resumable (tus) upload being staged on the server before it is written to its host.
+#[derive(Debug, Clone)]
+pub struct TusUpload {
+    pub id: String,
+    pub user_id: String,
+    pub host_id: String,
+    pub path: String,
+    /// Total size declared with `Upload-Length`.
+    pub length: u64,
+    /// Bytes received and persisted so far.
+    pub offset: u64,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -133,6 +614,7 @@ pub struct BrowseRequest {
 pub struct BrowseResponse {
     pub path: String,
     pub files: Vec<FileInfo>,
+    pub capabilities: Capabilities,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -155,12 +637,42 @@ impl User {
         UserInfo {
             id: self.id.clone(),
             username: self.username.clone(),
-            created_at: self.created_at.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl HostConfig {
+    /// The SFTP authentication method in effect, applying the defaults described on `SshAuthMethod`.
+    pub fn ssh_auth_method(&self) -> SshAuthMethod {
+        self.auth_method.unwrap_or(if self.private_key_encrypted.is_some() {
+            SshAuthMethod::PublicKey
+        } else {
+            SshAuthMethod::Password
+        })
+    }
+
+    /// Copy safe to return to clients, with every stored secret removed.
+    pub fn redacted(&self) -> Self {
+        Self {
+            password_encrypted: None,
+            private_key_encrypted: None,
+            passphrase_encrypted: None,
+            access_key_encrypted: None,
+            secret_key_encrypted: None,
+            ..self.clone()
         }
     }
 }
 
 impl Host {
+    pub fn redacted(&self) -> Self {
+        Self {
+            config: self.config.redacted(),
+            ..self.clone()
+        }
+    }
+
     pub fn new(user_id: String, name: String, host_type: HostType, config: HostConfig) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
@@ -169,6 +681,34 @@ impl Host {
             host_type,
             config,
             created_at: Utc::now(),
+            host_key_fingerprint: None,
+        }
+    }
+}
+
+impl Job {
+    /// A new job waiting in the queue, with no target yet.
+    pub fn new(user_id: String, kind: JobKind, host_id: String, path: String) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user_id,
+            kind,
+            host_id,
+            path,
+            target_host_id: None,
+            target_path: None,
+            sources: Vec::new(),
+            overwrite: OverwritePolicy::default(),
+            permanent: false,
+            status: JobStatus::Queued,
+            progress: ProgressSnapshot::default(),
+            result_path: None,
+            error: None,
+            attempts: 0,
+            partial_path: None,
+            created_at: now,
+            updated_at: now,
         }
     }
 }
@@ -0,0 +1,278 @@
+//! Deletes that can be undone. A deleted file or directory is moved into the
+//! trash area of its own host and recorded in the `trash` table, from where
+//! it can be restored or purged; items older than the retention period are
+//! purged automatically.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::Encryptor;
+use crate::db::Database;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::{self, StorageBackend, TRASH_DIR};
+use crate::models::{OverwritePolicy, TrashItem};
+
+/// How often items past the retention period are looked for.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct TrashConfig {
+    /// How long items are kept; until purged by hand when `None`.
+    pub retention: Option<chrono::Duration>,
+}
+
+impl TrashConfig {
+    /// Reads `TRASH_RETENTION_DAYS`, where 0 keeps items until they are
+    /// purged by hand. Defaults to 30 days.
+    pub fn from_env() -> Self {
+        let days = std::env::var("TRASH_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .filter(|&v| v >= 0)
+            .unwrap_or(30);
+
+        Self {
+            retention: (days > 0).then(|| chrono::Duration::days(days)),
+        }
+    }
+}
+
+pub struct TrashManager {
+    db: Arc<Database>,
+    sftp_pool: Arc<SftpPool>,
+    config: TrashConfig,
+}
+
+impl TrashManager {
+    pub fn new(db: Arc<Database>, sftp_pool: Arc<SftpPool>, config: TrashConfig) -> Self {
+        Self { db, sftp_pool, config }
+    }
+
+    /// Starts purging expired items, now and every `PURGE_INTERVAL`.
+    pub fn spawn(self: &Arc<Self>) {
+        if self.config.retention.is_none() {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = manager.purge_expired().await {
+                    error!("Failed to purge expired trash: {:#}", e);
+                }
+            }
+        });
+    }
+
+    /// Moves `path` on the host into its trash area and records it for `user_id`.
+    pub async fn trash(&self, user_id: &str, host_id: &str, backend: &dyn StorageBackend, path: &str) -> Result<TrashItem> {
+        let file = backend.stat(path).await?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let trash_path = move_to_trash(backend, path, &id).await?;
+
+        let item = TrashItem {
+            id,
+            user_id: user_id.to_string(),
+            host_id: host_id.to_string(),
+            original_path: path.to_string(),
+            trash_path,
+            is_dir: file.is_dir,
+            size: if file.is_dir { 0 } else { file.size },
+            deleted_at: Utc::now(),
+            expires_at: None,
+        };
+        if let Err(e) = self.db.create_trash_item(&item).await {
+            // Unrecorded, the item could never be restored, so undo the move
+            if let Err(undo) = backend.rename(&item.trash_path, path).await {
+                error!("Failed to move {} back out of the trash: {:#}", path, undo);
+            }
+            return Err(e);
+        }
+        Ok(self.with_expiry(item))
+    }
+
+    /// Moves an entry that an overwrite replaces into the trash, so it can be
+    /// restored like a deleted one. Hosts without a trash delete it.
+    pub async fn displace(&self, user_id: &str, host_id: &str, backend: &dyn StorageBackend, path: &str) -> Result<()> {
+        if !backend.capabilities().rename {
+            return backend.delete(path).await;
+        }
+        self.trash(user_id, host_id, backend, path).await.map(|_| ())
+    }
+
+    /// Moves the item back to where it was deleted from, or next to it under
+    /// `policy`, and returns where it went.
+    pub async fn restore(&self, item: &TrashItem, backend: &dyn StorageBackend, policy: OverwritePolicy) -> Result<String> {
+        let displace = |to: String| async move { self.displace(&item.user_id, &item.host_id, backend, &to).await };
+        let path = restore_from_trash(backend, item, policy, displace).await?;
+        self.db.delete_trash_item(&item.id).await?;
+        Ok(path)
+    }
+
+    pub async fn purge(&self, item: &TrashItem, backend: &dyn StorageBackend) -> Result<()> {
+        purge_from_trash(backend, item).await?;
+        self.db.delete_trash_item(&item.id).await
+    }
+
+    /// Sets when the item expires, if it does.
+    pub fn with_expiry(&self, item: TrashItem) -> TrashItem {
+        TrashItem {
+            expires_at: self.config.retention.map(|retention| item.deleted_at + retention),
+            ..item
+        }
+    }
+
+    async fn purge_expired(&self) -> Result<()> {
+        let Some(retention) = self.config.retention else {
+            return Ok(());
+        };
+        let expired = self.db.get_trash_deleted_before(Utc::now() - retention).await?;
+
+        let mut by_host: HashMap<String, Vec<TrashItem>> = HashMap::new();
+        for item in expired {
+            by_host.entry(item.host_id.clone()).or_default().push(item);
+        }
+
+        for (host_id, items) in by_host {
+            // An unreachable host is tried again on the next round
+            let backend = match self.open_host(&host_id).await {
+                Ok(backend) => backend,
+                Err(e) => {
+                    warn!("Failed to purge expired trash of host {}: {:#}", host_id, e);
+                    continue;
+                }
+            };
+            let mut purged = 0;
+            for item in &items {
+                match self.purge(item, backend.as_ref()).await {
+                    Ok(()) => purged += 1,
+                    Err(e) => warn!("Failed to purge {} from the trash of host {}: {:#}", item.original_path, host_id, e),
+                }
+            }
+            info!("Purged {} expired item(s) from the trash of host {}", purged, host_id);
+        }
+        Ok(())
+    }
+
+    async fn open_host(&self, host_id: &str) -> Result<Box<dyn StorageBackend>> {
+        let mut host = self
+            .db
+            .get_host(host_id)
+            .await?
+            .with_context(|| format!("Host {} no longer exists", host_id))?;
+        hosts::ensure_host_key(&mut host, &self.db).await?;
+
+        let encryptor = Encryptor::new()?;
+        hosts::open(&host, &encryptor, &self.sftp_pool)
+    }
+}
+
+/// Every item gets a directory of its own in the trash area, so entries
+/// deleted under the same name never collide and keep their name.
+fn item_dir(id: &str) -> String {
+    hosts::join_path(TRASH_DIR, id)
+}
+
+/// Moves `path` into the trash area as item `id` and returns where it went.
+pub async fn move_to_trash(backend: &dyn StorageBackend, path: &str, id: &str) -> Result<String> {
+    let name = path.rsplit('/').next().filter(|name| !name.is_empty()).context("Cannot delete the root directory")?;
+    let trash_path = hosts::join_path(&item_dir(id), name);
+    backend.rename(path, &trash_path).await?;
+    Ok(trash_path)
+}
+
+/// Moves the item back under `policy`; see `hosts::apply_overwrite_policy`
+/// for `displace`.
+pub async fn restore_from_trash<D, DFut>(
+    backend: &dyn StorageBackend,
+    item: &TrashItem,
+    policy: OverwritePolicy,
+    displace: D,
+) -> Result<String>
+where
+    D: FnOnce(String) -> DFut,
+    DFut: Future<Output = Result<()>>,
+{
+    let path = hosts::apply_overwrite_policy(&item.original_path, policy, displace, |to| async move {
+        backend.rename(&item.trash_path, &to).await
+    })
+    .await?;
+
+    // Only the item's own directory is left, empty
+    if let Err(e) = backend.delete(&item_dir(&item.id)).await {
+        log::debug!("Failed to remove trash directory of {}: {:#}", item.id, e);
+    }
+    Ok(path)
+}
+
+/// Deletes the item for good. One that is already gone from the host, say
+/// removed by hand, counts as purged.
+pub async fn purge_from_trash(backend: &dyn StorageBackend, item: &TrashItem) -> Result<()> {
+    let dir = item_dir(&item.id);
+    match backend.delete(&dir).await {
+        Err(_) if backend.stat(&dir).await.is_err() => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::local::LocalFileSystem;
+    use tempfile::TempDir;
+
+    fn item(id: &str, original_path: &str, trash_path: String) -> TrashItem {
+        TrashItem {
+            id: id.to_string(),
+            user_id: "user-1".to_string(),
+            host_id: "host-1".to_string(),
+            original_path: original_path.to_string(),
+            trash_path,
+            is_dir: true,
+            size: 0,
+            deleted_at: Utc::now(),
+            expires_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn trashed_entries_can_be_restored_or_purged() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("projects/app/src")).unwrap();
+        std::fs::write(dir.path().join("projects/app/src/main.rs"), b"fn main() {}").unwrap();
+        std::fs::write(dir.path().join("projects/todo.txt"), b"todo").unwrap();
+        let fs = LocalFileSystem::new(dir.path().to_str().unwrap());
+        let delete = |to: String| {
+            let fs = &fs;
+            async move { fs.delete(&to).await }
+        };
+
+        let trash_path = move_to_trash(&fs, "/projects/app", "item-1").await.unwrap();
+        assert_eq!(trash_path, "/.fm-trash/item-1/app");
+        assert!(!dir.path().join("projects/app").exists());
+        assert!(dir.path().join(".fm-trash/item-1/app/src/main.rs").exists());
+
+        // Something new took the name meanwhile
+        std::fs::create_dir(dir.path().join("projects/app")).unwrap();
+        let app = item("item-1", "/projects/app", trash_path);
+        let err = restore_from_trash(&fs, &app, OverwritePolicy::Fail, delete).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<hosts::StorageError>(), Some(hosts::StorageError::AlreadyExists(_))));
+        let restored = restore_from_trash(&fs, &app, OverwritePolicy::AutoSuffix, delete).await.unwrap();
+        assert_eq!(restored, "/projects/app (1)");
+        assert_eq!(std::fs::read(dir.path().join("projects/app (1)/src/main.rs")).unwrap(), b"fn main() {}");
+        assert!(!dir.path().join(".fm-trash/item-1").exists());
+
+        let todo = item("item-2", "/projects/todo.txt", move_to_trash(&fs, "/projects/todo.txt", "item-2").await.unwrap());
+        purge_from_trash(&fs, &todo).await.unwrap();
+        assert!(!dir.path().join(".fm-trash/item-2").exists());
+        purge_from_trash(&fs, &todo).await.unwrap();
+
+        assert_eq!(move_to_trash(&fs, "/", "item-3").await.unwrap_err().to_string(), "Cannot delete the root directory");
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use super::WatchKey;
+use crate::hosts::local::LocalFileSystem;
+use crate::models::{ChangeEvent, FileChange};
+
+/// A watched directory as one host sees it. Hosts sharing a base path can
+/// watch the same directory under different keys.
+struct WatchedDir {
+    key: WatchKey,
+    base: PathBuf,
+}
+
+type WatchedDirs = HashMap<PathBuf, Vec<WatchedDir>>;
+
+/// Watches local directories, without their subdirectories, through inotify.
+pub struct LocalWatcher {
+    /// Also held for the whole of `watch` and `unwatch`, so the two don't race.
+    watcher: Mutex<RecommendedWatcher>,
+    /// Never held while calling into `watcher`, whose event thread needs it.
+    dirs: Arc<Mutex<WatchedDirs>>,
+}
+
+impl LocalWatcher {
+    pub fn new(changes: mpsc::UnboundedSender<ChangeEvent>) -> Result<Self> {
+        let dirs = Arc::new(Mutex::new(WatchedDirs::new()));
+        let watched = dirs.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                for change in translate(&watched.lock().unwrap(), event) {
+                    let _ = changes.send(change);
+                }
+            }
+            Err(e) => log::warn!("Directory watch failed: {}", e),
+        })?;
+
+        Ok(Self {
+            watcher: Mutex::new(watcher),
+            dirs,
+        })
+    }
+
+    pub fn watch(&self, key: &WatchKey, base_path: &str) -> Result<()> {
+        let base = Path::new(base_path).canonicalize().context("Invalid base path")?;
+        let dir = LocalFileSystem::resolve_path(base_path, &key.path)?;
+        if !dir.is_dir() {
+            anyhow::bail!("{} is not a directory", key.path);
+        }
+
+        let mut watcher = self.watcher.lock().unwrap();
+        let watched = self.dirs.lock().unwrap().contains_key(&dir);
+        if !watched {
+            watcher
+                .watch(&dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {}", key.path))?;
+        }
+
+        let entry = WatchedDir { key: key.clone(), base };
+        self.dirs.lock().unwrap().entry(dir).or_default().push(entry);
+        Ok(())
+    }
+
+    pub fn unwatch(&self, key: &WatchKey) {
+        let mut watcher = self.watcher.lock().unwrap();
+        let unused = {
+            let mut dirs = self.dirs.lock().unwrap();
+            let Some(dir) = dirs.iter().find(|(_, w)| w.iter().any(|w| w.key == *key)).map(|(dir, _)| dir.clone())
+            else {
+                return;
+            };
+            let watchers = dirs.get_mut(&dir).unwrap();
+            watchers.retain(|w| w.key != *key);
+            if !watchers.is_empty() {
+                return;
+            }
+            dirs.remove(&dir);
+            dir
+        };
+
+        // Fails if the directory is gone, which ended the watch already
+        if let Err(e) = watcher.unwatch(&unused) {
+            log::debug!("Failed to unwatch {}: {}", unused.display(), e);
+        }
+    }
+}
+
+/// The changes an inotify event amounts to, once for every watch of the
+/// directory it happened in.
+fn translate(dirs: &WatchedDirs, event: Event) -> Vec<ChangeEvent> {
+    let changes = |path: &Path, change: fn(String) -> FileChange| -> Vec<ChangeEvent> {
+        located(dirs, path)
+            .map(|(key, path)| ChangeEvent {
+                host_id: key.host_id.clone(),
+                directory: key.path.clone(),
+                change: change(path),
+            })
+            .collect()
+    };
+    let created: fn(String) -> FileChange = |path| FileChange::Created { path };
+    let modified: fn(String) -> FileChange = |path| FileChange::Modified { path };
+    let deleted: fn(String) -> FileChange = |path| FileChange::Deleted { path };
+
+    let paths = event.paths.iter();
+    match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            let (from, to) = (&event.paths[0], &event.paths[1]);
+            if from.parent() != to.parent() {
+                // Moved between directories: gone from one, new in the other
+                let mut split = changes(from, deleted);
+                split.extend(changes(to, created));
+                return split;
+            }
+
+            located(dirs, from)
+                .zip(located(dirs, to))
+                .map(|((key, from), (_, to))| ChangeEvent {
+                    host_id: key.host_id.clone(),
+                    directory: key.path.clone(),
+                    change: FileChange::Renamed { from, to },
+                })
+                .collect()
+        }
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.flat_map(|p| changes(p, created)).collect()
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            paths.flat_map(|p| changes(p, deleted)).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(_)) => paths
+            .flat_map(|p| changes(p, if p.exists() { created } else { deleted }))
+            .collect(),
+        EventKind::Modify(_) => paths.flat_map(|p| changes(p, modified)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The watches of the directory `path` is in, with the path each host knows it by.
+fn located<'a>(dirs: &'a WatchedDirs, path: &'a Path) -> impl Iterator<Item = (&'a WatchKey, String)> + 'a {
+    let watchers = path.parent().and_then(|dir| dirs.get(dir));
+    watchers.into_iter().flatten().filter_map(move |w| {
+        let relative = path.strip_prefix(&w.base).ok()?;
+        Some((&w.key, format!("/{}", relative.to_string_lossy())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn next_change(rx: &mut mpsc::UnboundedReceiver<ChangeEvent>) -> ChangeEvent {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_changes_in_watched_directories() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("share/photos")).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = LocalWatcher::new(tx).unwrap();
+        let key = WatchKey { host_id: "host-1".to_string(), path: "/share".to_string() };
+        watcher.watch(&key, dir.path().to_str().unwrap()).unwrap();
+
+        // Subdirectories aren't watched
+        std::fs::write(dir.path().join("share/photos/cat.jpg"), b"cat").unwrap();
+        std::fs::write(dir.path().join("share/notes.txt"), b"notes").unwrap();
+        let change = next_change(&mut rx).await;
+        assert_eq!(change.directory, "/share");
+        assert_eq!(change.change, FileChange::Created { path: "/share/notes.txt".to_string() });
+
+        std::fs::rename(dir.path().join("share/notes.txt"), dir.path().join("share/todo.txt")).unwrap();
+        let renamed = FileChange::Renamed { from: "/share/notes.txt".to_string(), to: "/share/todo.txt".to_string() };
+        while next_change(&mut rx).await.change != renamed {}
+
+        watcher.unwatch(&key);
+        std::fs::write(dir.path().join("share/late.txt"), b"late").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while let Ok(change) = rx.try_recv() {
+            assert_ne!(change.change.path(), "/share/late.txt");
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Tells WebSocket sessions about changes in the directories they are looking
+//! at. Local hosts are watched with inotify, SFTP and HTTP hosts are polled.
+//! Changes are held back briefly so a burst of writes to one file is reported once.
+
+mod local;
+mod poll;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::auth::Encryptor;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::{self, normalize_path, StorageError};
+use crate::models::{ChangeEvent, FileChange, Host, HostType};
+use local::LocalWatcher;
+use poll::Poller;
+
+/// How long changes are collected before they are sent.
+const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(300);
+/// Events a slow subscriber may fall behind by before it starts missing some.
+const EVENT_BUFFER: usize = 256;
+/// Bounds of a host's `poll_interval`, in seconds.
+pub const MIN_POLL_INTERVAL: u64 = 5;
+pub const MAX_POLL_INTERVAL: u64 = 3600;
+
+/// A directory of a host that sessions are watching.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct WatchKey {
+    pub host_id: String,
+    pub path: String,
+}
+
+/// How changes in a watched directory are noticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum WatchMode {
+    /// Reported by the host's filesystem as they happen.
+    Notify,
+    /// Found by listing the directory every `interval` seconds.
+    Poll { interval: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    /// Directories watched at once, across all sessions.
+    pub max_watches: usize,
+    /// Seconds between polls of hosts without their own `poll_interval`.
+    pub poll_interval: u64,
+}
+
+impl WatchConfig {
+    /// Reads `WATCH_LIMIT` and `WATCH_POLL_INTERVAL`, falling back to defaults.
+    pub fn from_env() -> Self {
+        fn env_u64(name: &str, default: u64) -> u64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_watches: env_u64("WATCH_LIMIT", 256) as usize,
+            poll_interval: env_u64("WATCH_POLL_INTERVAL", 30).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL),
+        }
+    }
+}
+
+pub struct WatchManager {
+    config: WatchConfig,
+    sftp_pool: Arc<SftpPool>,
+    local: LocalWatcher,
+    poller: Poller,
+    /// Sessions watching each directory, and how it is watched.
+    watches: Mutex<HashMap<WatchKey, (usize, WatchMode)>>,
+    changes: Mutex<Option<mpsc::UnboundedReceiver<ChangeEvent>>>,
+    events: broadcast::Sender<ChangeEvent>,
+}
+
+impl WatchManager {
+    pub fn new(config: WatchConfig, sftp_pool: Arc<SftpPool>) -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            config,
+            sftp_pool,
+            local: LocalWatcher::new(tx.clone()).context("Failed to start directory watcher")?,
+            poller: Poller::new(tx),
+            watches: Mutex::new(HashMap::new()),
+            changes: Mutex::new(Some(rx)),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        })
+    }
+
+    /// Starts passing debounced changes on to subscribers.
+    pub fn spawn(self: &Arc<Self>) {
+        let Some(mut changes) = self.changes.lock().unwrap().take() else {
+            return;
+        };
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            let mut pending = PendingChanges::default();
+            let mut ticker = tokio::time::interval(DEBOUNCE_INTERVAL);
+            loop {
+                tokio::select! {
+                    change = changes.recv() => match change {
+                        Some(change) => pending.push(change),
+                        None => return,
+                    },
+                    _ = ticker.tick() => {
+                        for change in pending.take() {
+                            // Nobody listening is fine
+                            let _ = events.send(change);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Changes in every watched directory, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Starts watching `path` on `host` for one more session. Every successful
+    /// call must be matched by a `release` of the returned key.
+    pub fn acquire(&self, host: &Host, path: &str) -> Result<(WatchKey, WatchMode)> {
+        let key = WatchKey {
+            host_id: host.id.clone(),
+            path: normalize_path(path)?,
+        };
+
+        let mut watches = self.watches.lock().unwrap();
+        if let Some((sessions, mode)) = watches.get_mut(&key) {
+            *sessions += 1;
+            return Ok((key, *mode));
+        }
+        if watches.len() >= self.config.max_watches {
+            anyhow::bail!("Too many directories are being watched");
+        }
+
+        let mode = match host.host_type {
+            HostType::Local => {
+                let base_path = host.config.path.as_deref().context("Local path not configured")?;
+                self.local.watch(&key, base_path)?;
+                WatchMode::Notify
+            }
+            HostType::Sftp | HostType::Http => {
+                let backend = hosts::open(host, &Encryptor::new()?, &self.sftp_pool)?;
+                let interval = host.config.poll_interval.unwrap_or(self.config.poll_interval);
+                self.poller.watch(&key, backend.into(), Duration::from_secs(interval));
+                WatchMode::Poll { interval }
+            }
+            _ => {
+                return Err(StorageError::Unsupported {
+                    operation: "Watch",
+                    host_type: host.host_type.as_str(),
+                }
+                .into());
+            }
+        };
+
+        watches.insert(key.clone(), (1, mode));
+        Ok((key, mode))
+    }
+
+    /// Stops watching for one session; the directory is no longer watched
+    /// once no session is left.
+    pub fn release(&self, key: &WatchKey) {
+        let mut watches = self.watches.lock().unwrap();
+        let Some((sessions, mode)) = watches.get_mut(key) else {
+            return;
+        };
+        *sessions -= 1;
+        if *sessions == 0 {
+            match *mode {
+                WatchMode::Notify => self.local.unwatch(key),
+                WatchMode::Poll { .. } => self.poller.unwatch(key),
+            }
+            watches.remove(key);
+        }
+    }
+}
+
+/// Changes collected since the last send, merged per entry.
+#[derive(Default)]
+struct PendingChanges {
+    changes: Vec<ChangeEvent>,
+    /// Entries created and deleted again since the last send, as
+    /// `(host_id, directory, path)`.
+    vanished: HashSet<(String, String, String)>,
+}
+
+impl PendingChanges {
+    fn push(&mut self, mut event: ChangeEvent) {
+        if let FileChange::Renamed { from, to } = &event.change {
+            // The deleting half of the rename cancelled out a new entry
+            let entry = (event.host_id.clone(), event.directory.clone(), from.clone());
+            if self.vanished.remove(&entry) {
+                event.change = FileChange::Created { path: to.clone() };
+                return self.push(event);
+            }
+
+            // inotify reports a rename as its two halves as well as a whole
+            let is_half = |pending: &ChangeEvent| {
+                pending.host_id == event.host_id
+                    && pending.directory == event.directory
+                    && match &pending.change {
+                        FileChange::Deleted { path } => path == from,
+                        FileChange::Created { path } => path == to,
+                        _ => false,
+                    }
+            };
+            self.changes.retain(|pending| !is_half(pending));
+        }
+
+        let earlier = self.changes.iter().position(|pending| {
+            pending.host_id == event.host_id
+                && pending.directory == event.directory
+                && pending.change.path() == event.change.path()
+        });
+        let Some(index) = earlier else {
+            self.changes.push(event);
+            return;
+        };
+
+        match merge(&self.changes[index].change, event.change) {
+            Some(change) => self.changes[index].change = change,
+            None => {
+                let gone = self.changes.remove(index);
+                let path = gone.change.path().to_string();
+                self.vanished.insert((gone.host_id, gone.directory, path));
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<ChangeEvent> {
+        self.vanished.clear();
+        std::mem::take(&mut self.changes)
+    }
+}
+
+/// What two changes to the same entry amount to, if anything.
+fn merge(earlier: &FileChange, later: FileChange) -> Option<FileChange> {
+    use FileChange::*;
+
+    match (earlier, later) {
+        (Created { .. }, Modified { path }) => Some(Created { path }),
+        (Created { .. }, Deleted { .. }) => None,
+        (Deleted { .. }, Created { path }) => Some(Modified { path }),
+        (Renamed { .. }, Modified { .. }) => Some(earlier.clone()),
+        (Renamed { from, .. }, Deleted { .. }) => Some(Deleted { path: from.clone() }),
+        (_, later) => Some(later),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(change: FileChange) -> ChangeEvent {
+        ChangeEvent {
+            host_id: "host-1".to_string(),
+            directory: "/share".to_string(),
+            change,
+        }
+    }
+
+    fn created(path: &str) -> FileChange {
+        FileChange::Created { path: path.to_string() }
+    }
+
+    fn modified(path: &str) -> FileChange {
+        FileChange::Modified { path: path.to_string() }
+    }
+
+    fn deleted(path: &str) -> FileChange {
+        FileChange::Deleted { path: path.to_string() }
+    }
+
+    #[test]
+    fn bursts_are_merged_per_entry() {
+        let mut pending = PendingChanges::default();
+        pending.push(event(created("/share/a.txt")));
+        pending.push(event(modified("/share/a.txt")));
+        pending.push(event(modified("/share/b.txt")));
+        pending.push(event(modified("/share/a.txt")));
+        pending.push(event(created("/share/tmp")));
+        pending.push(event(deleted("/share/tmp")));
+
+        assert_eq!(pending.take(), [event(created("/share/a.txt")), event(modified("/share/b.txt"))]);
+        assert!(pending.take().is_empty());
+    }
+
+    #[test]
+    fn rename_halves_are_folded_into_the_rename() {
+        let mut pending = PendingChanges::default();
+        pending.push(event(deleted("/share/old.txt")));
+        pending.push(event(created("/share/new.txt")));
+        pending.push(event(FileChange::Renamed {
+            from: "/share/old.txt".to_string(),
+            to: "/share/new.txt".to_string(),
+        }));
+        pending.push(event(modified("/share/new.txt")));
+
+        assert_eq!(
+            pending.take(),
+            [event(FileChange::Renamed {
+                from: "/share/old.txt".to_string(),
+                to: "/share/new.txt".to_string(),
+            })]
+        );
+
+        // Created and renamed before anyone was told about it
+        pending.push(event(created("/share/draft.txt")));
+        pending.push(event(deleted("/share/draft.txt")));
+        pending.push(event(created("/share/final.txt")));
+        pending.push(event(FileChange::Renamed {
+            from: "/share/draft.txt".to_string(),
+            to: "/share/final.txt".to_string(),
+        }));
+
+        assert_eq!(pending.take(), [event(created("/share/final.txt"))]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use super::WatchKey;
+use crate::hosts::StorageBackend;
+use crate::models::{ChangeEvent, FileChange, FileInfo};
+
+/// Watches directories of hosts that can't report changes themselves by
+/// listing them again and again. Renames show up as a deletion and a creation.
+pub struct Poller {
+    changes: mpsc::UnboundedSender<ChangeEvent>,
+    tasks: Mutex<HashMap<WatchKey, JoinHandle<()>>>,
+}
+
+impl Poller {
+    pub fn new(changes: mpsc::UnboundedSender<ChangeEvent>) -> Self {
+        Self {
+            changes,
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn watch(&self, key: &WatchKey, backend: Arc<dyn StorageBackend>, interval: Duration) {
+        let task = tokio::spawn(poll(key.clone(), backend, interval, self.changes.clone()));
+        if let Some(previous) = self.tasks.lock().unwrap().insert(key.clone(), task) {
+            previous.abort();
+        }
+    }
+
+    pub fn unwatch(&self, key: &WatchKey) {
+        if let Some(task) = self.tasks.lock().unwrap().remove(key) {
+            task.abort();
+        }
+    }
+}
+
+async fn poll(
+    key: WatchKey,
+    backend: Arc<dyn StorageBackend>,
+    interval: Duration,
+    changes: mpsc::UnboundedSender<ChangeEvent>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut previous: Option<Vec<FileInfo>> = None;
+    let mut failing = false;
+
+    loop {
+        ticker.tick().await;
+        let current = match backend.list(&key.path).await {
+            Ok(current) => current,
+            Err(e) => {
+                // Said once; the host may well come back
+                if !failing {
+                    log::warn!("Failed to poll {} on host {}: {}", key.path, key.host_id, e);
+                    failing = true;
+                }
+                continue;
+            }
+        };
+        failing = false;
+
+        if let Some(previous) = &previous {
+            for change in diff(previous, &current) {
+                let event = ChangeEvent {
+                    host_id: key.host_id.clone(),
+                    directory: key.path.clone(),
+                    change,
+                };
+                if changes.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+        previous = Some(current);
+    }
+}
+
+/// The changes that turn listing `previous` into `current`.
+fn diff(previous: &[FileInfo], current: &[FileInfo]) -> Vec<FileChange> {
+    let before: HashMap<&str, &FileInfo> = previous.iter().map(|e| (e.path.as_str(), e)).collect();
+    let after: HashMap<&str, &FileInfo> = current.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    let mut changes = Vec::new();
+    for entry in current {
+        let path = entry.path.clone();
+        match before.get(entry.path.as_str()) {
+            None => changes.push(FileChange::Created { path }),
+            Some(old) if old.is_dir != entry.is_dir || old.size != entry.size || old.modified != entry.modified => {
+                changes.push(FileChange::Modified { path })
+            }
+            Some(_) => {}
+        }
+    }
+    for entry in previous {
+        if !after.contains_key(entry.path.as_str()) {
+            changes.push(FileChange::Deleted { path: entry.path.clone() });
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::local::LocalFileSystem;
+    use tempfile::TempDir;
+
+    fn file(path: &str, size: u64) -> FileInfo {
+        FileInfo {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            is_dir: false,
+            size,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn listings_are_diffed_by_path() {
+        let previous = [file("/share/a.txt", 1), file("/share/b.txt", 2), file("/share/c.txt", 3)];
+        let current = [file("/share/a.txt", 1), file("/share/b.txt", 20), file("/share/d.txt", 4)];
+
+        assert_eq!(
+            diff(&previous, &current),
+            [
+                FileChange::Modified { path: "/share/b.txt".to_string() },
+                FileChange::Created { path: "/share/d.txt".to_string() },
+                FileChange::Deleted { path: "/share/c.txt".to_string() },
+            ]
+        );
+        assert!(diff(&current, &current).is_empty());
+    }
+
+    #[tokio::test]
+    async fn polls_until_unwatched() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("share")).unwrap();
+        let backend = Arc::new(LocalFileSystem::new(dir.path().to_str().unwrap()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let poller = Poller::new(tx);
+        let key = WatchKey { host_id: "host-1".to_string(), path: "/share".to_string() };
+
+        poller.watch(&key, backend, Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(dir.path().join("share/notes.txt"), b"notes").unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(event.directory, "/share");
+        assert_eq!(event.change, FileChange::Created { path: "/share/notes.txt".to_string() });
+
+        // The channel closes once the polling task is gone too
+        poller.unwatch(&key);
+        drop(poller);
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use actix::{Actor, AsyncContext, Context, Handler, Message, Recipient, StreamHandler};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::broadcast;
+
+use super::WsMessage;
+use crate::models::{ChangeEvent, JobEvent};
+use crate::watch::WatchKey;
+
+/// Routes events to the WebSocket sessions of the user they belong to, and
+/// directory changes to the sessions watching the directory.
+pub struct Hub {
+    sessions: HashMap<u64, Recipient<WsMessage>>,
+    users: HashMap<String, HashSet<u64>>,
+    watchers: HashMap<WatchKey, HashSet<u64>>,
+    job_events: Option<broadcast::Receiver<JobEvent>>,
+    change_events: Option<broadcast::Receiver<ChangeEvent>>,
+}
+
+impl Hub {
+    pub fn new(job_events: broadcast::Receiver<JobEvent>, change_events: broadcast::Receiver<ChangeEvent>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            users: HashMap::new(),
+            watchers: HashMap::new(),
+            job_events: Some(job_events),
+            change_events: Some(change_events),
+        }
+    }
+
+    fn send_to(&self, session_ids: Option<&HashSet<u64>>, text: String) {
+        for id in session_ids.into_iter().flatten() {
+            if let Some(session) = self.sessions.get(id) {
+                session.do_send(WsMessage(text.clone()));
+            }
+        }
+    }
+}
+
+impl Actor for Hub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(rx) = self.job_events.take() {
+            ctx.add_stream(broadcast_stream(rx));
+        }
+        if let Some(rx) = self.change_events.take() {
+            ctx.add_stream(broadcast_stream(rx));
+        }
+    }
+}
+
+/// The events of a broadcast subscription, skipping over any the hub was too
+/// slow to take.
+fn broadcast_stream<T: Clone + Send + 'static>(rx: broadcast::Receiver<T>) -> impl futures::Stream<Item = T> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("WebSocket hub missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+impl StreamHandler<JobEvent> for Hub {
+    fn handle(&mut self, event: JobEvent, _ctx: &mut Self::Context) {
+        if let Some(sessions) = self.users.get(&event.user_id) {
+            self.send_to(Some(sessions), serde_json::to_string(&event).unwrap());
+        }
+    }
+
+    // The hub outlives the job subscription
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+impl StreamHandler<ChangeEvent> for Hub {
+    fn handle(&mut self, event: ChangeEvent, _ctx: &mut Self::Context) {
+        let key = WatchKey { host_id: event.host_id.clone(), path: event.directory.clone() };
+        if let Some(sessions) = self.watchers.get(&key) {
+            self.send_to(Some(sessions), serde_json::to_string(&event).unwrap());
+        }
+    }
+
+    // The hub outlives the watch subscription
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+/// Registers an authenticated session for its user's events.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Connect {
+    pub user_id: String,
+    pub session_id: u64,
+    pub session: Recipient<WsMessage>,
+}
+
+impl Handler<Connect> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) {
+        self.sessions.insert(msg.session_id, msg.session);
+        self.users.entry(msg.user_id).or_default().insert(msg.session_id);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub user_id: String,
+    pub session_id: u64,
+}
+
+impl Handler<Disconnect> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) {
+        self.sessions.remove(&msg.session_id);
+        if let Some(sessions) = self.users.get_mut(&msg.user_id) {
+            sessions.remove(&msg.session_id);
+            if sessions.is_empty() {
+                self.users.remove(&msg.user_id);
+            }
+        }
+        self.watchers.retain(|_, sessions| {
+            sessions.remove(&msg.session_id);
+            !sessions.is_empty()
+        });
+    }
+}
+
+/// Routes changes in a directory to a session. The session has already
+/// checked that its user may see the directory.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Watch {
+    pub session_id: u64,
+    pub key: WatchKey,
+}
+
+impl Handler<Watch> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _ctx: &mut Self::Context) {
+        self.watchers.entry(msg.key).or_default().insert(msg.session_id);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unwatch {
+    pub session_id: u64,
+    pub key: WatchKey,
+}
+
+impl Handler<Unwatch> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unwatch, _ctx: &mut Self::Context) {
+        if let Some(sessions) = self.watchers.get_mut(&msg.key) {
+            sessions.remove(&msg.session_id);
+            if sessions.is_empty() {
+                self.watchers.remove(&msg.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{JobKind, JobUpdate};
+    use std::sync::{Arc, Mutex};
+
+    /// Stands in for a session and keeps what it is sent.
+    struct Inbox(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Inbox {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Inbox {
+        type Result = ();
+
+        fn handle(&mut self, msg: WsMessage, _ctx: &mut Self::Context) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    fn event(user_id: &str) -> JobEvent {
+        JobEvent {
+            user_id: user_id.to_string(),
+            job_id: "job-1".to_string(),
+            kind: JobKind::Delete,
+            update: JobUpdate::Cancelled,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn events_only_reach_their_users_sessions() {
+        let (tx, rx) = broadcast::channel(8);
+        let hub = Hub::new(rx, broadcast::channel(8).1).start();
+
+        let (alice, bob) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        for (session_id, user_id, inbox) in [(1, "alice", &alice), (2, "alice", &alice), (3, "bob", &bob)] {
+            let session = Inbox(inbox.clone()).start().recipient();
+            hub.send(Connect { user_id: user_id.to_string(), session_id, session }).await.unwrap();
+        }
+        hub.send(Disconnect { user_id: "alice".to_string(), session_id: 2 }).await.unwrap();
+
+        tx.send(event("alice")).unwrap();
+        tx.send(event("carol")).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(*alice.lock().unwrap(), [r#"{"job_id":"job-1","kind":"delete","event":"job_cancelled"}"#]);
+        assert!(bob.lock().unwrap().is_empty());
+    }
+}
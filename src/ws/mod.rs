@@ -1,19 +1,109 @@
-use actix::{Actor, StreamHandler, Handler, Message, ActorContext, AsyncContext};
+mod hub;
+
+pub use hub::Hub;
+
+use actix::{
+    Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Handler, Message, SpawnHandle, StreamHandler, WrapFuture,
+};
+use anyhow::Context;
 use actix_web::{web, HttpRequest, HttpResponse, Error};
 use actix_web_actors::ws;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::auth::jwt::Claims;
+use crate::auth::{verify_jwt, Encryptor};
+use crate::db::Database;
+use crate::hosts::pool::SftpPool;
+use crate::hosts::search::{self, SearchCriteria};
+use crate::hosts::{self, normalize_path, StorageBackend};
+use crate::models::{SearchEvent, SearchOptions};
+use crate::watch::{WatchKey, WatchManager, WatchMode};
+
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a session opened without a token has to send its `auth` message.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Directories one session may watch at once.
+const MAX_SESSION_WATCHES: usize = 16;
+/// Searches one session may run at once.
+const MAX_SESSION_SEARCHES: usize = 4;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct WsSession {
+    id: u64,
     hb: Instant,
+    /// Set once the session is authenticated; events are only delivered then.
+    claims: Option<Claims>,
+    hub: Addr<Hub>,
+    db: Arc<Database>,
+    watches: Arc<WatchManager>,
+    sftp_pool: Arc<SftpPool>,
+    /// Directories this session receives changes of.
+    watching: HashSet<WatchKey>,
+    /// Running searches by the client's id for them; cancelling one drops
+    /// its results, which stops it.
+    searches: HashMap<String, SpawnHandle>,
+}
+
+/// Requests an authenticated session can send. Anything else is echoed back.
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ClientMessage {
+    Watch { host_id: String, path: String },
+    Unwatch { host_id: String, path: String },
+    Search {
+        search_id: String,
+        host_id: String,
+        #[serde(flatten)]
+        options: SearchOptions,
+    },
+    CancelSearch { search_id: String },
+}
+
+/// An event of one of the session's searches.
+#[derive(Serialize)]
+struct SearchUpdate {
+    search_id: String,
+    #[serde(flatten)]
+    event: SearchEvent,
+}
+
+/// Confirms a `watch` request and says how changes will be noticed.
+#[derive(Serialize)]
+struct Watching<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    key: &'a WatchKey,
+    #[serde(flatten)]
+    mode: WatchMode,
 }
 
 impl WsSession {
-    pub fn new() -> Self {
-        Self { hb: Instant::now() }
+    pub fn new(
+        claims: Option<Claims>,
+        hub: Addr<Hub>,
+        db: Arc<Database>,
+        watches: Arc<WatchManager>,
+        sftp_pool: Arc<SftpPool>,
+    ) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            hb: Instant::now(),
+            claims,
+            hub,
+            db,
+            watches,
+            sftp_pool,
+            watching: HashSet::new(),
+            searches: HashMap::new(),
+        }
     }
 
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
@@ -25,6 +115,174 @@ impl WsSession {
             ctx.ping(b"");
         });
     }
+
+    /// Binds the session to the token's user and ends it when the token expires.
+    fn authenticate(&mut self, claims: Claims, ctx: &mut ws::WebsocketContext<Self>) {
+        let expires_in = (claims.exp - Utc::now().timestamp()).max(0) as u64;
+        ctx.run_later(Duration::from_secs(expires_in), |_, ctx| {
+            Self::reject(ctx, "Token expired");
+        });
+
+        self.hub.do_send(hub::Connect {
+            user_id: claims.sub.clone(),
+            session_id: self.id,
+            session: ctx.address().recipient(),
+        });
+        self.claims = Some(claims);
+    }
+
+    fn send_error(ctx: &mut ws::WebsocketContext<Self>, error: &str) {
+        ctx.text(json!({ "event": "error", "error": error }).to_string());
+    }
+
+    fn reject(ctx: &mut ws::WebsocketContext<Self>, error: &str) {
+        Self::send_error(ctx, error);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(error.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    /// Handles the first message of a session opened without a token, which
+    /// must be `{"event": "auth", "token": "..."}`.
+    fn handshake(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        #[derive(Deserialize)]
+        struct AuthMessage {
+            event: String,
+            token: String,
+        }
+
+        let claims = match serde_json::from_str::<AuthMessage>(text) {
+            Ok(msg) if msg.event == "auth" => verify_jwt(&msg.token),
+            _ => return Self::reject(ctx, "Authentication required"),
+        };
+        match claims {
+            Ok(claims) => {
+                ctx.text(json!({ "event": "authenticated", "username": claims.username }).to_string());
+                self.authenticate(claims, ctx);
+            }
+            Err(_) => Self::reject(ctx, "Invalid token"),
+        }
+    }
+
+    /// Starts sending changes in `path` once the host turns out to be the user's.
+    fn watch(&mut self, host_id: String, path: String, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.watching.len() >= MAX_SESSION_WATCHES {
+            return Self::send_error(ctx, "Too many directories are being watched");
+        }
+
+        let Some(user_id) = self.claims.as_ref().map(|c| c.sub.clone()) else {
+            return;
+        };
+        let db = self.db.clone();
+        let lookup = async move { db.get_host(&host_id).await };
+
+        // Acquired once back in the actor, so a session stopped meanwhile
+        // doesn't leave a watch behind
+        ctx.spawn(lookup.into_actor(self).map(move |host, act, ctx| {
+            let host = match host {
+                Ok(Some(host)) if host.user_id == user_id => host,
+                Ok(Some(_)) => return Self::send_error(ctx, "Access denied"),
+                Ok(None) => return Self::send_error(ctx, "Host not found"),
+                Err(e) => return Self::send_error(ctx, &format!("Failed to get host: {}", e)),
+            };
+
+            let (key, mode) = match act.watches.acquire(&host, &path) {
+                Ok(watch) => watch,
+                Err(e) => return Self::send_error(ctx, &format!("Failed to watch {}: {}", path, e)),
+            };
+            if act.watching.insert(key.clone()) {
+                act.hub.do_send(hub::Watch { session_id: act.id, key: key.clone() });
+            } else {
+                act.watches.release(&key);
+            }
+
+            let reply = Watching { event: "watching", key: &key, mode };
+            ctx.text(serde_json::to_string(&reply).unwrap());
+        }));
+    }
+
+    fn unwatch(&mut self, host_id: String, path: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let key = match normalize_path(&path) {
+            Ok(path) => WatchKey { host_id, path },
+            Err(e) => return Self::send_error(ctx, &e.to_string()),
+        };
+        if self.watching.remove(&key) {
+            self.hub.do_send(hub::Unwatch { session_id: self.id, key: key.clone() });
+            self.watches.release(&key);
+        }
+        ctx.text(json!({ "event": "unwatched", "host_id": key.host_id, "path": key.path }).to_string());
+    }
+
+    fn send_search_error(ctx: &mut ws::WebsocketContext<Self>, search_id: String, error: String) {
+        let update = SearchUpdate { search_id, event: SearchEvent::Error { error } };
+        ctx.text(serde_json::to_string(&update).unwrap());
+    }
+
+    /// Starts a search of one of the user's hosts, whose events are sent
+    /// tagged with `search_id`.
+    fn search(&mut self, search_id: String, host_id: String, options: SearchOptions, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.searches.contains_key(&search_id) {
+            return Self::send_search_error(ctx, search_id, "A search with this id is running".to_string());
+        }
+        if self.searches.len() >= MAX_SESSION_SEARCHES {
+            return Self::send_search_error(ctx, search_id, "Too many searches are running".to_string());
+        }
+        let root = normalize_path(options.path.as_deref().unwrap_or("/"));
+        let criteria = SearchCriteria::new(&options);
+        let (root, criteria) = match (root, criteria) {
+            (Ok(root), Ok(criteria)) => (root, criteria),
+            (Err(e), _) | (_, Err(e)) => return Self::send_search_error(ctx, search_id, format!("{:#}", e)),
+        };
+
+        let Some(user_id) = self.claims.as_ref().map(|c| c.sub.clone()) else {
+            return;
+        };
+        let (db, sftp_pool) = (self.db.clone(), self.sftp_pool.clone());
+        let open = async move {
+            let mut host = match db.get_host(&host_id).await? {
+                Some(host) if host.user_id == user_id => host,
+                Some(_) => anyhow::bail!("Access denied"),
+                None => anyhow::bail!("Host not found"),
+            };
+            hosts::ensure_host_key(&mut host, &db).await.context("Failed to verify host key")?;
+            let backend: Arc<dyn StorageBackend> = hosts::open(&host, &Encryptor::new()?, &sftp_pool)?.into();
+            Ok(backend)
+        };
+
+        // Cancelling before the host is open cancels opening it
+        let key = search_id.clone();
+        let opening = ctx.spawn(open.into_actor(self).map(move |backend, act, ctx| {
+            let backend = match backend {
+                Ok(backend) => backend,
+                Err(e) => {
+                    act.searches.remove(&search_id);
+                    return Self::send_search_error(ctx, search_id, e.to_string());
+                }
+            };
+
+            let (rx, _) = search::spawn(backend, root, criteria);
+            let id = search_id.clone();
+            let updates = futures::stream::unfold(rx, move |mut rx| {
+                let search_id = id.clone();
+                async move {
+                    let event = rx.recv().await?;
+                    Some((SearchUpdate { search_id, event }, rx))
+                }
+            });
+            let handle = ctx.add_stream(updates);
+            act.searches.insert(search_id, handle);
+        }));
+        self.searches.insert(key, opening);
+    }
+
+    fn cancel_search(&mut self, search_id: String, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.searches.remove(&search_id) {
+            ctx.cancel_future(handle);
+        }
+        ctx.text(json!({ "event": "search_cancelled", "search_id": search_id }).to_string());
+    }
 }
 
 impl Actor for WsSession {
@@ -32,6 +290,29 @@ impl Actor for WsSession {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+
+        match self.claims.take() {
+            Some(claims) => self.authenticate(claims, ctx),
+            None => {
+                ctx.run_later(AUTH_TIMEOUT, |act, ctx| {
+                    if act.claims.is_none() {
+                        Self::reject(ctx, "Authentication required");
+                    }
+                });
+            }
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for key in self.watching.drain() {
+            self.watches.release(&key);
+        }
+        if let Some(claims) = &self.claims {
+            self.hub.do_send(hub::Disconnect {
+                user_id: claims.sub.clone(),
+                session_id: self.id,
+            });
+        }
     }
 }
 
@@ -45,13 +326,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
             Ok(ws::Message::Pong(_)) => {
                 self.hb = Instant::now();
             }
-            Ok(ws::Message::Text(text)) => {
-                // Handle incoming text messages
-                let response = json!({
-                    "event": "echo",
-                    "data": text.to_string()
-                });
-                ctx.text(serde_json::to_string(&response).unwrap());
+            Ok(ws::Message::Text(text)) if self.claims.is_none() => self.handshake(&text, ctx),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(ClientMessage::Watch { host_id, path }) => self.watch(host_id, path, ctx),
+                Ok(ClientMessage::Unwatch { host_id, path }) => self.unwatch(host_id, path, ctx),
+                Ok(ClientMessage::Search { search_id, host_id, options }) => self.search(search_id, host_id, options, ctx),
+                Ok(ClientMessage::CancelSearch { search_id }) => self.cancel_search(search_id, ctx),
+                Err(_) => {
+                    let response = json!({
+                        "event": "echo",
+                        "data": text.to_string()
+                    });
+                    ctx.text(serde_json::to_string(&response).unwrap());
+                }
+            },
+            Ok(ws::Message::Binary(_)) if self.claims.is_none() => {
+                Self::reject(ctx, "Authentication required");
             }
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             Ok(ws::Message::Close(reason)) => {
@@ -63,6 +353,18 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     }
 }
 
+impl StreamHandler<SearchUpdate> for WsSession {
+    fn handle(&mut self, update: SearchUpdate, ctx: &mut Self::Context) {
+        if matches!(update.event, SearchEvent::Done(_) | SearchEvent::Error { .. }) {
+            self.searches.remove(&update.search_id);
+        }
+        ctx.text(serde_json::to_string(&update).unwrap());
+    }
+
+    /// A search ending is no reason to end the session.
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct WsMessage(pub String);
@@ -75,7 +377,39 @@ impl Handler<WsMessage> for WsSession {
     }
 }
 
-pub async fn ws_handler(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    let resp = ws::start(WsSession::new(), &req, stream);
-    resp
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    token: Option<String>,
+}
+
+/// Opens a session authenticated by the `token` query parameter, or one that
+/// has to authenticate with its first message. Browsers can't set headers on
+/// a WebSocket handshake, and the first message keeps the token out of URLs.
+pub async fn ws_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<WsQuery>,
+    hub: web::Data<Addr<Hub>>,
+    db: web::Data<Arc<Database>>,
+    watches: web::Data<Arc<WatchManager>>,
+    sftp_pool: web::Data<Arc<SftpPool>>,
+) -> Result<HttpResponse, Error> {
+    let claims = match query.token.as_deref().map(verify_jwt) {
+        Some(Ok(claims)) => Some(claims),
+        Some(Err(_)) => {
+            return Ok(HttpResponse::Unauthorized().json(json!({
+                "error": "Invalid token"
+            })));
+        }
+        None => None,
+    };
+
+    let session = WsSession::new(
+        claims,
+        hub.get_ref().clone(),
+        db.get_ref().clone(),
+        watches.get_ref().clone(),
+        sftp_pool.get_ref().clone(),
+    );
+    ws::start(session, &req, stream)
 }